@@ -1,24 +1,60 @@
 use anyhow::{Context, Result};
 use clap::Parser;
-use console::{style, Term};
-use dialoguer::{theme::ColorfulTheme, Confirm};
+use console::{style, Style};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use crossterm::terminal;
+use dialoguer::{theme::ColorfulTheme, Confirm, Select};
 use regex::Regex;
 use reqwest::Client;
-use serde_json::{json, Value};
+use serde_json::json;
+use std::collections::HashMap;
 use std::process::Command;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use std::{env, fs};
-use toml;
 
-#[derive(Debug)]
+mod output;
+mod serve;
+mod storage;
+
+use output::{Themed, UiTerm};
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
 struct ExecutionHistory {
+    model: String,
     command: String,
     output: String,
     success: bool,
     attempt: u32,
+    /// 用户回答未达到预期目标时补充的说明，会在下一次尝试时发给 AI
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    feedback: Option<String>,
+    /// 生成该命令时使用的 --seed 以及响应中的 system_fingerprint
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_fingerprint: Option<String>,
+    /// 响应中服务商实际使用的模型（可能与请求的别名不同）以及请求的温度
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    /// 生成该命令时使用的重试策略（same 不记录）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strategy: Option<AttemptsStrategy>,
+    /// 填写占位符之前的命令，保存为片段时保留占位符
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    template: Option<String>,
 }
 
 #[derive(Parser)]
-#[command(author, version, about = "AI驱动的shell命令助手")]
+#[command(
+    author,
+    version,
+    about = "AI驱动的shell命令助手",
+    args_override_self = true
+)]
 struct Cli {
     #[command(subcommand)]
     command: Option<Commands>,
@@ -31,13 +67,302 @@ struct Cli {
     #[arg(short, long)]
     dry_run: bool,
 
-    /// 显示详细输出
+    /// 只把生成的命令输出到 stdout（其余信息输出到 stderr）而不执行，危险命令的退出码为 2
+    #[arg(long)]
+    print: bool,
+
+    /// 显示详细输出（默认开启，使用 --quiet 关闭）
     #[arg(short, long, default_value = "true")]
     verbose: bool,
 
+    /// 不显示详细输出：执行目录和解释器、token 用量、实际模型等
+    #[arg(short, long, conflicts_with = "verbose")]
+    quiet: bool,
+
     /// 显示调试信息
     #[arg(short = 'D', long)]
     debug: bool,
+
+    /// 仅本次运行使用的模型，不会修改保存的配置
+    #[arg(short, long)]
+    model: Option<String>,
+
+    /// 同时向多个模型发送请求，使用最先成功返回的结果 (例如 gpt-4o,gpt-4o-mini)
+    #[arg(long, value_delimiter = ',', value_name = "MODELS")]
+    race: Vec<String>,
+
+    /// 按指定 shell 的语法生成命令；与当前 shell 不一致时只显示命令，不会执行
+    #[arg(long, value_enum, value_name = "SHELL")]
+    syntax: Option<TargetShell>,
+
+    /// 按 token 用量限制重试：累计用量（服务商返回的 usage，包括逐项解释、安装建议等附带请求）
+    /// 达到该值后不再发送请求。尝试次数上限仍然有效；服务商没有返回用量时停止自动重试
+    #[arg(long, value_name = "TOKENS")]
+    max_cost_tokens: Option<u64>,
+
+    /// 重试时如何调整参数：same 保持不变，warmer 逐次升高温度，escalate 失败若干次后换用更强的模型。
+    /// 覆盖配置中的 [generation] attempts_strategy
+    #[arg(long, value_enum, value_name = "STRATEGY")]
+    attempts_strategy: Option<AttemptsStrategy>,
+
+    /// 固定随机种子并把温度设为 0，让相同的需求尽量生成相同的命令
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+
+    /// 原样显示模型的回复，不去掉代码块标记、不拆分说明文字；生成命令时只显示不执行（隐含 --dry-run）
+    #[arg(long)]
+    raw_output: bool,
+
+    /// 发送请求前显示估算的 token 数和费用，并询问是否继续
+    #[arg(long)]
+    estimated_cost: bool,
+
+    /// 同时生成多个候选命令，先在本地做预检（程序是否存在、语法是否正确），再从列表中选择
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::value_parser!(u8).range(1..=MAX_CANDIDATES as i64),
+        conflicts_with = "race"
+    )]
+    candidates: Option<u8>,
+
+    /// 执行前检查命令中用到的程序是否存在于 PATH 中
+    #[arg(long)]
+    function_check: bool,
+
+    /// 只输出 ASCII 字符（不支持 Unicode/emoji 的终端会自动启用）
+    #[arg(long)]
+    ascii: bool,
+
+    /// 禁止任何交互式提示，需要交互时直接报错退出
+    #[arg(long)]
+    no_interactive: bool,
+
+    /// 结构化日志级别 (error/warn/info/debug/trace)，也可通过 RUST_LOG 设置
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<String>,
+
+    /// 调用 AI 之前运行的脚本，可通过 ASKAI_PROMPT 环境变量获取操作描述
+    #[arg(long, value_name = "SCRIPT")]
+    pre_hook: Option<String>,
+
+    /// 会话结束后监视这些文件或目录，内容变化时带上改动重新生成命令，按 Ctrl+C 退出（可重复指定）
+    #[arg(long, value_name = "PATH")]
+    watch: Vec<std::path::PathBuf>,
+
+    /// 命令执行成功后运行的脚本，可通过 ASKAI_PROMPT、ASKAI_COMMAND、ASKAI_EXIT_CODE 环境变量获取执行信息
+    #[arg(long, value_name = "SCRIPT")]
+    post_hook: Option<String>,
+
+    /// 在提示中附带当前目录的文件列表（遵循 .gitignore），让模型使用真实的文件名
+    #[arg(long)]
+    with_ls: bool,
+
+    /// 检测本机安装了哪些常用工具并告诉模型，避免生成使用未安装工具的命令
+    #[arg(long)]
+    detect_tools: bool,
+
+    /// 在提示中附带从该提交到 HEAD 的 git diff（例如 HEAD~3），让模型了解最近的改动
+    #[arg(long, value_name = "COMMIT")]
+    show_diff_since: Option<String>,
+
+    /// 在提示中附带执行记录里与本次需求相似、最近一次成功执行的命令，作为正面示例
+    #[arg(long)]
+    with_last_success: bool,
+
+    /// 允许在输入确认短语后执行被判定为危险的命令
+    #[arg(long)]
+    allow_dangerous: bool,
+
+    /// 读取响应时超过该毫秒数没有收到新数据则中止并重新请求
+    #[arg(long, value_name = "MS")]
+    response_timeout: Option<u64>,
+
+    /// 生成的命令超过该字符数时拒绝执行（默认使用配置中的 max_command_length）
+    #[arg(long, value_name = "CHARS")]
+    max_command_length: Option<usize>,
+
+    /// 是否输出颜色：auto 时根据终端和 NO_COLOR 环境变量自动判断
+    #[arg(long, value_enum, default_value_t = ColorChoice::Auto, value_name = "WHEN")]
+    color: ColorChoice,
+
+    /// 命令输出的显示格式（只影响显示，历史记录中保存原始输出）
+    #[arg(long, value_enum, default_value_t = OutputFormat::Plain, value_name = "FORMAT")]
+    format_output: OutputFormat,
+
+    /// 仅本次运行使用该文件的内容作为系统提示（替换内置提示和 ask learn 保存的提示）
+    #[arg(long, value_name = "PATH")]
+    system_prompt_file: Option<std::path::PathBuf>,
+
+    /// 仅本次运行使用该文件的内容作为重试时的用户消息模板，可用变量：
+    /// {prompt}、{command}、{output}、{success}、{attempt}
+    #[arg(long, value_name = "PATH")]
+    user_message_template: Option<std::path::PathBuf>,
+
+    /// 输出最终组装的系统提示后退出，不会调用 API
+    #[arg(long)]
+    show_prompt: bool,
+
+    /// 以退出码判断是否达到目标：成功时不再询问，只在命令执行失败时重试
+    #[arg(long)]
+    retry_on_error_only: bool,
+
+    /// 命令执行成功时直接结束，不再询问；执行失败时先确认再重试，而不是自动重试
+    #[arg(long)]
+    no_confirm_success: bool,
+
+    /// 教学模式：生成命令后逐个解释其中的参数和选项（也可在配置中设置 [ui] teach = true）
+    #[arg(long)]
+    ask_each_flag: bool,
+
+    /// 把以 && 连接的命令拆开逐个执行，每一步之前都会确认，某一步失败时停止
+    #[arg(long)]
+    split_commands: bool,
+
+    /// 严格模式：多行脚本在前面加上 set -eu（支持时还有 pipefail），某一行失败时立即停止并报告失败
+    #[arg(long)]
+    strict: bool,
+
+    /// 在同一次请求中让模型附带一行理由，显示在命令上方（不会被执行）
+    #[arg(long)]
+    with_explanation: bool,
+
+    /// 在输出的每一行前加上 ISO 8601 格式的 UTC 时间，便于把输出重定向到日志文件
+    #[arg(long)]
+    prepend_timestamp: bool,
+
+    /// 为本次运行写入的执行记录标记分组ID，不指定时每次运行生成一个新的ID
+    #[arg(long, value_name = "UUID")]
+    session_id: Option<uuid::Uuid>,
+}
+
+#[derive(Clone, Copy, PartialEq, clap::ValueEnum)]
+enum OutputFormat {
+    Json,
+    Csv,
+    Table,
+    Plain,
+}
+
+/// 重试策略：决定每次尝试使用的温度和模型
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, clap::ValueEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "lowercase")]
+enum AttemptsStrategy {
+    /// 每次尝试使用相同的参数
+    #[default]
+    Same,
+    /// 每次尝试把温度升高 temperature_step
+    Warmer,
+    /// 失败 escalate_after 次后换用 escalate_model
+    Escalate,
+}
+
+impl AttemptsStrategy {
+    fn name(self) -> &'static str {
+        match self {
+            AttemptsStrategy::Same => "same",
+            AttemptsStrategy::Warmer => "warmer",
+            AttemptsStrategy::Escalate => "escalate",
+        }
+    }
+}
+
+/// --syntax 可选的目标 shell
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+enum TargetShell {
+    Sh,
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+    Cmd,
+}
+
+impl TargetShell {
+    /// 提示中使用的名称
+    fn label(self) -> &'static str {
+        match self {
+            TargetShell::Sh => "POSIX sh",
+            TargetShell::Bash => "bash",
+            TargetShell::Zsh => "zsh",
+            TargetShell::Fish => "fish",
+            TargetShell::Powershell => "PowerShell",
+            TargetShell::Cmd => "Windows cmd.exe",
+        }
+    }
+
+    /// 用该 shell 执行一条命令所需的程序和参数
+    fn interpreter(self) -> (&'static str, &'static str) {
+        match self {
+            TargetShell::Sh => ("sh", "-c"),
+            TargetShell::Bash => ("bash", "-c"),
+            TargetShell::Zsh => ("zsh", "-c"),
+            TargetShell::Fish => ("fish", "-c"),
+            TargetShell::Powershell if !find_in_path("pwsh") => ("powershell", "-Command"),
+            TargetShell::Powershell => ("pwsh", "-Command"),
+            TargetShell::Cmd => ("cmd", "/C"),
+        }
+    }
+
+    /// 是否兼容 POSIX sh 的语法（临时脚本、严格模式和 `-n` 语法检查都依赖这一点）
+    fn is_posix(self) -> bool {
+        matches!(self, TargetShell::Sh | TargetShell::Bash | TargetShell::Zsh)
+    }
+
+    /// 是否与当前用户的 shell（$SHELL，或默认执行命令所用的 sh / cmd）一致，
+    /// 并且本机装有该 shell。一致时命令会改用该 shell 执行，见 `SYNTAX_SHELL`
+    fn matches_current_shell(self) -> bool {
+        let (program, _) = self.interpreter();
+        let default = if cfg!(target_os = "windows") {
+            TargetShell::Cmd
+        } else {
+            TargetShell::Sh
+        };
+        let current = env::var("SHELL")
+            .ok()
+            .and_then(|shell| {
+                std::path::Path::new(&shell)
+                    .file_stem()
+                    .map(|name| name.to_string_lossy().to_lowercase())
+            })
+            .unwrap_or_default();
+        let name = match self {
+            TargetShell::Sh => "sh",
+            TargetShell::Bash => "bash",
+            TargetShell::Zsh => "zsh",
+            TargetShell::Fish => "fish",
+            TargetShell::Powershell => "pwsh",
+            TargetShell::Cmd => "cmd",
+        };
+        (self == default
+            || name == current
+            || (self == TargetShell::Powershell && current == "powershell"))
+            && find_in_path(program)
+    }
+}
+
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+/// 根据 --color 开启或关闭 stdout/stderr 的颜色输出
+fn apply_color_choice(choice: ColorChoice) {
+    let enabled = match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // https://no-color.org：设置了非空的 NO_COLOR 时不输出颜色，否则由 console 根据终端判断
+        ColorChoice::Auto if env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) => {
+            false
+        }
+        ColorChoice::Auto => return,
+    };
+    console::set_colors_enabled(enabled);
+    console::set_colors_enabled_stderr(enabled);
 }
 
 #[derive(Parser)]
@@ -49,10 +374,262 @@ enum Commands {
         #[arg(index = 1)]
         config_type: String,
 
-        /// 配置项 (key=value)
-        #[arg(index = 2)]
-        config_value: String,
+        /// 配置项 (key=value)，可以同时指定多个；值为 - 时从标准输入读取（例如 api_key=-）
+        #[arg(index = 2, required = true, num_args = 1..)]
+        config_values: Vec<String>,
+    },
+
+    /// 检查命令是否会被危险命令检测拦截（不会执行命令）
+    #[command(name = "check-danger")]
+    CheckDanger {
+        /// 要检查的命令
+        #[arg(index = 1)]
+        command: String,
+    },
+
+    /// 将同一个需求同时发送给两个模型并对比生成的命令（不会执行命令）
+    #[command(name = "diff-models")]
+    DiffModels {
+        /// 第一个模型
+        #[arg(long)]
+        model1: String,
+
+        /// 第二个模型
+        #[arg(long)]
+        model2: String,
+
+        /// 你想执行的操作描述
+        #[arg(index = 1)]
+        prompt: String,
+    },
+
+    /// 管理配置
+    #[command(name = "config")]
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// 以 `export KEY=VALUE` 格式输出程序会读取的环境变量
+    #[command(name = "env")]
+    Env,
+
+    /// 显示当前使用的身份：用户、服务商、API 地址、模型和语言
+    #[command(name = "whoami")]
+    Whoami,
+
+    /// 固定当前项目使用的模型
+    #[command(name = "pin")]
+    Pin {
+        /// 模型ID (例如 gpt-4o-2024-08-06)
+        #[arg(index = 1, required_unless_present = "show")]
+        model: Option<String>,
+
+        /// 写入用户全局配置而不是项目配置
+        #[arg(long)]
+        global: bool,
+
+        /// 显示当前固定的模型及其来源
+        #[arg(long, conflicts_with_all = ["model", "global"])]
+        show: bool,
+    },
+
+    /// 根据“未达到预期目标”的历史记录，让 AI 给出系统提示的改进建议
+    #[command(name = "learn")]
+    Learn,
+
+    /// 先让 AI 把需求拆分为编号的步骤，再为每个步骤生成命令（不会执行命令）
+    #[command(name = "trace")]
+    Trace {
+        /// 你想执行的操作描述
+        #[arg(index = 1)]
+        prompt: String,
+    },
+
+    /// 为一条复杂的命令生成逐项解释和便于记忆的口诀（不会执行命令）
+    #[command(name = "mnemonic")]
+    Mnemonic {
+        /// 要记忆的命令
+        #[arg(index = 1)]
+        command: String,
+    },
+
+    /// 让 AI 对命令做安全审查：可能的风险、访问的数据、发起的网络请求以及总体风险等级（不会执行命令）
+    #[command(name = "security-audit")]
+    SecurityAudit {
+        /// 要审查的命令
+        #[arg(index = 1)]
+        command: String,
+    },
+
+    /// 让 AI 把命令改写得更易读或更高效，并与原命令并排对比（不会执行命令）
+    #[command(name = "refactor")]
+    Refactor {
+        /// 要改写的命令
+        #[arg(index = 1)]
+        command: String,
+    },
+
+    /// 估算一段文本的 token 数，以及分别作为系统提示、用户消息和回复时的费用（不会发送请求）
+    #[command(name = "tokenize")]
+    Tokenize {
+        /// 要估算的文本，省略时从标准输入读取
+        #[arg(index = 1)]
+        text: Option<String>,
+    },
+
+    /// 依次为文件中的每个需求生成命令；带有 expect 的需求会执行命令并检查输出，
+    /// 任意一项失败时以非零退出码结束，可用于在 CI 中回归测试提示
+    #[command(name = "batch")]
+    Batch {
+        /// JSONL 文件，每行形如 {"prompt": "...", "expect": "正则表达式"}，expect 可省略
+        #[arg(index = 1)]
+        file: std::path::PathBuf,
+    },
+
+    /// 以 HTTP 服务的方式运行：POST /generate 接收 {"prompt": "..."}，以 JSON 返回生成的命令。
+    /// 服务端只生成命令，从不执行
+    #[command(name = "serve")]
+    Serve {
+        /// 监听的端口
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+
+        /// 监听的地址，默认只接受本机连接
+        #[arg(long, default_value = "127.0.0.1")]
+        host: std::net::IpAddr,
+
+        /// 允许请求中的 model 字段覆盖配置的模型（默认忽略该字段）
+        #[arg(long)]
+        allow_model_override: bool,
+    },
+
+    /// 执行生成的命令后运行测试命令，测试通过即达到目标，失败时把测试输出交给 AI 重试
+    #[command(name = "run-tests")]
+    RunTests {
+        /// 测试命令，例如 pytest
+        #[arg(long, value_name = "COMMAND")]
+        test_cmd: String,
+
+        /// 你想执行的操作描述
+        #[arg(index = 1)]
+        prompt: String,
+    },
+
+    /// 依次处理多个需求，每一步达到目标后把命令的输出交给下一步，例如
+    /// `ask chain "列出运行中的容器" -- "停止上面找到的容器"`
+    #[command(name = "chain")]
+    Chain {
+        /// 以 `--` 分隔的多个需求
+        #[arg(required = true, num_args = 1.., allow_hyphen_values = true)]
+        prompts: Vec<String>,
+    },
+
+    /// 查看执行记录
+    #[command(name = "history")]
+    History {
+        #[command(subcommand)]
+        action: Option<HistoryAction>,
+
+        /// 只显示带有该分组ID（--session-id）的记录
+        #[arg(long, value_name = "UUID")]
+        session: Option<uuid::Uuid>,
+
+        /// 同时显示最终达成目标之前的各次尝试
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// 确认后清空执行记录
+    #[command(name = "clear-history")]
+    ClearHistory {
+        /// 只删除该日期（UTC，格式为 YYYY-MM-DD）之前的记录
+        #[arg(long, value_name = "DATE", value_parser = parse_date)]
+        before: Option<u64>,
+    },
+
+    /// 管理执行记录的分组ID
+    #[command(name = "session")]
+    Session {
+        #[command(subcommand)]
+        action: SessionAction,
+    },
+
+    /// 继续之前暂存的会话，重新回答命令是否达到了预期目标
+    #[command(name = "resume")]
+    Resume {
+        /// 会话ID，不指定时继续最近暂存的会话
+        #[arg(index = 1)]
+        id: Option<String>,
+
+        /// 列出所有暂存的会话
+        #[arg(long, conflicts_with_all = ["id", "clean"])]
+        list: bool,
+
+        /// 删除超过指定天数的会话（默认 7 天，0 表示全部删除）
+        #[arg(
+            long,
+            value_name = "DAYS",
+            num_args = 0..=1,
+            default_missing_value = "7",
+            conflicts_with = "id"
+        )]
+        clean: Option<u64>,
+    },
+}
+
+#[derive(Parser)]
+enum HistoryAction {
+    /// 把编号范围内成功执行的命令导出为脚本，需求作为注释写在每条命令之前
+    #[command(name = "export")]
+    Export {
+        /// 起始编号（`ask history` 中显示的编号），默认从第一条开始
+        #[arg(long, value_name = "N")]
+        from: Option<usize>,
+
+        /// 结束编号（包含），默认到最后一条
+        #[arg(long, value_name = "M")]
+        to: Option<usize>,
+
+        /// 脚本文件路径
+        #[arg(long, short, value_name = "PATH")]
+        output: std::path::PathBuf,
+
+        /// 文件已存在时覆盖
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Parser)]
+enum SessionAction {
+    /// 输出一个新的分组ID，可传给 --session-id
+    #[command(name = "new")]
+    New,
+}
+
+#[derive(Parser)]
+enum ConfigAction {
+    /// 重新运行配置向导
+    #[command(name = "init")]
+    Init,
+
+    /// 用 $EDITOR 编辑配置文件，保存后校验，格式错误时保留原文件
+    #[command(name = "edit")]
+    Edit,
+
+    /// 保存每次运行时默认使用的选项（例如 --dry-run），不带参数时清除。
+    /// 命令行中给出的同名选项会替换默认值，`--no-<flag>` 可以在某次运行中关闭默认开启的开关
+    #[command(name = "set-default-flags")]
+    SetDefaultFlags {
+        /// 默认选项
+        #[arg(num_args = 0.., allow_hyphen_values = true, trailing_var_arg = true)]
+        flags: Vec<String>,
     },
+
+    /// 切换服务商：重新选择服务商并填写其相关配置，其余设置保持不变
+    #[command(name = "provider")]
+    Provider,
 }
 
 const DANGEROUS_COMMANDS: [&str; 6] = [
@@ -162,7 +739,80 @@ python hello.py
   - 用户手动终止
 "#;
 
+// Prompt used by `ask trace` to break a task into steps
+const TRACE_PROMPT_EN: &str = r#"You are a Shell command expert. Break the user's task into a short numbered plan of sub-tasks that can each be done with one shell command.
+Reply with the numbered list only, one sub-task per line in the form "1. ...", without commands, explanations or code block markers.
+"#;
+
+const TRACE_PROMPT_ZH: &str = r#"你是一个Shell命令专家。请把用户的需求拆分为一个简短的编号计划，每个子任务都可以用一条shell命令完成。
+只回复编号列表，每行一个子任务，格式为“1. ...”，不要包含命令、解释或代码块标记。
+"#;
+
+// Prompt used by the teaching mode to explain each token of a command
+const ANNOTATE_PROMPT_EN: &str = r#"You are a Shell command teacher. Explain the shell command given by the user token by token for someone learning the shell.
+Split it into the program names, subcommands, flags (keep a flag together with its value) and arguments, in the order they appear.
+Reply with a JSON array only, without code block markers, in the form [{"token": "-l", "explanation": "use the long listing format"}].
+Keep each explanation to one short sentence.
+"#;
+
+const ANNOTATE_PROMPT_ZH: &str = r#"你是一个Shell命令老师。请为正在学习shell的用户逐个解释用户给出的命令。
+按出现的顺序把命令拆分为程序名、子命令、选项（选项和它的值放在一起）以及参数。
+只回复一个JSON数组，不要包含代码块标记，格式为 [{"token": "-l", "explanation": "以长格式列出文件"}]。
+每条解释只用一句简短的话。
+"#;
+
+// Prompt used by `ask mnemonic` to explain a command and make it easy to remember
+const MNEMONIC_PROMPT_EN: &str = r#"You are a Shell command teacher. Help the user remember the shell command they give you.
+Split it into the program names, subcommands, flags (keep a flag together with its value) and arguments, in the order they appear, and explain each part in one short sentence.
+Then make up a short, memorable catchphrase or acronym built from the flags, for example "Create gZipped Verbose File = Crazy Zebras Visit Frequently" for tar -czvf.
+Reply with a JSON object only, without code block markers, in the form {"mnemonic": "...", "flags": [{"token": "-c", "explanation": "create a new archive"}]}.
+"#;
+
+const MNEMONIC_PROMPT_ZH: &str = r#"你是一个Shell命令老师。请帮助用户记住他给出的shell命令。
+按出现的顺序把命令拆分为程序名、子命令、选项（选项和它的值放在一起）以及参数，每一项用一句简短的话解释。
+然后根据其中的选项编一句简短、好记的口诀或缩写，例如 tar -czvf 可以记作“Create gZipped Verbose File：创建、压缩、显示过程、指定文件”。
+只回复一个JSON对象，不要包含代码块标记，格式为 {"mnemonic": "...", "flags": [{"token": "-c", "explanation": "创建新的归档"}]}。
+"#;
+
+const SECURITY_AUDIT_PROMPT_EN: &str = r#"You are a security reviewer for shell commands. Analyze the command the user gives you without running it.
+List the concrete risks (for example remote code execution, data loss, privilege escalation, persistence, leaking secrets), the files, directories, environment variables or credentials it reads or changes, and every network connection it makes with its destination.
+Then rate the overall risk as one of "low", "medium", "high" or "critical", and summarize the verdict in one or two sentences.
+Reply with a JSON object only, without code block markers, in the form {"risk_level": "high", "summary": "...", "risks": ["..."], "data_access": ["..."], "network": ["..."]}. Use an empty list when there is nothing to report.
+"#;
+
+const SECURITY_AUDIT_PROMPT_ZH: &str = r#"你是一名shell命令安全审查员。请在不执行的前提下分析用户给出的命令。
+列出具体的风险（例如远程代码执行、数据丢失、权限提升、持久化、泄露密钥），命令读取或修改的文件、目录、环境变量或凭据，以及它发起的每一个网络连接和目标地址。
+然后把总体风险评为 "low"、"medium"、"high" 或 "critical" 之一，并用一两句话总结结论。
+只回复一个JSON对象，不要包含代码块标记，格式为 {"risk_level": "high", "summary": "...", "risks": ["..."], "data_access": ["..."], "network": ["..."]}。没有可报告的内容时使用空列表。
+"#;
+
+const REFACTOR_PROMPT_EN: &str = r#"You are a shell expert. Rewrite the command the user gives you so that it is more readable, more robust or more efficient, without changing what it does.
+The rewritten command must be equivalent: same files affected, same output and same side effects. Prefer standard tools, handle file names with spaces safely, and avoid needless pipes or subshells. If the command is already good, return it unchanged.
+Reply with a JSON object only, without code block markers, in the form {"command": "...", "improvements": ["..."]}, where each improvement explains one change in a short sentence.
+"#;
+
+const REFACTOR_PROMPT_ZH: &str = r#"你是一名shell专家。请把用户给出的命令改写得更易读、更健壮或更高效，但不改变它的作用。
+改写后的命令必须与原命令等价：影响的文件、输出和副作用都相同。优先使用标准工具，安全地处理包含空格的文件名，避免不必要的管道或子shell。如果命令已经足够好，原样返回。
+只回复一个JSON对象，不要包含代码块标记，格式为 {"command": "...", "improvements": ["..."]}，每一项改进用一句简短的话说明一处修改。
+"#;
+
+// Prompt used by `ask learn` to improve the system prompt from failed attempts
+const LEARN_PROMPT_EN: &str = r#"You maintain the system prompt of an AI assistant that turns user requests into shell commands.
+You will be given the current system prompt and a list of commands that ran but did not achieve what the user wanted.
+Based on these failed attempts, update the system prompt so that similar mistakes are avoided in the future.
+Keep the existing structure and rules unless they caused the failures, and keep the changes minimal.
+Reply with the complete updated system prompt only, without explanations or code block markers.
+"#;
+
+const LEARN_PROMPT_ZH: &str = r#"你负责维护一个把用户需求转换为shell命令的AI助手的系统提示。
+你会得到当前的系统提示，以及一些执行了但没有达到用户预期目标的命令。
+请根据这些失败的尝试更新系统提示，避免以后出现类似的错误。
+除非现有的结构和规则导致了这些失败，否则请保留它们，并尽量少做修改。
+只回复完整的更新后的系统提示，不要包含任何解释或代码块标记。
+"#;
+
 // UI text translations
+#[cfg_attr(test, derive(serde::Serialize))]
 struct UiText {
     thinking: &'static str,
     generated_command: &'static str,
@@ -179,8 +829,239 @@ struct UiText {
     api_key_prompt: &'static str,
     model_prompt: &'static str,
     language_prompt: &'static str,
+    provider_prompt: &'static str,
+    provider_custom: &'static str,
+    azure_endpoint_prompt: &'static str,
+    azure_deployment_prompt: &'static str,
+    azure_api_version_prompt: &'static str,
+    unknown_provider: &'static str,
     provide_description: &'static str,
     config_updated: &'static str,
+    race_winner: &'static str,
+    attempt_temperature: &'static str,
+    session_model: &'static str,
+    model_override_marker: &'static str,
+    interactive_prompt_required: &'static str,
+    cancel_hint: &'static str,
+    request_cancelled: &'static str,
+    cancel_action_prompt: &'static str,
+    cancel_reenter: &'static str,
+    cancel_quit: &'static str,
+    new_description_prompt: &'static str,
+    program_not_found: &'static str,
+    suggest_install_prompt: &'static str,
+    install_suggestion: &'static str,
+    response_api_error: &'static str,
+    response_unexpected_shape: &'static str,
+    response_no_choices: &'static str,
+    response_null_content: &'static str,
+    response_content_filtered: &'static str,
+    check_danger_blocked: &'static str,
+    check_danger_pattern: &'static str,
+    check_danger_allowed: &'static str,
+    diff_models_identical: &'static str,
+    model_pinned: &'static str,
+    pinned_model: &'static str,
+    pinned_model_source: &'static str,
+    pre_hook_failed: &'static str,
+    post_hook_failed: &'static str,
+    config_format_error: &'static str,
+    unknown_config_key: &'static str,
+    unknown_config_type: &'static str,
+    language_preference_required: &'static str,
+    missing_model_id: &'static str,
+    debug_info: &'static str,
+    debug_system_prompt: &'static str,
+    debug_user_prompt: &'static str,
+    debug_raw_response: &'static str,
+    learn_no_failures: &'static str,
+    learn_analyzing: &'static str,
+    learn_no_changes: &'static str,
+    learn_suggested_diff: &'static str,
+    learn_apply_prompt: &'static str,
+    learn_applied: &'static str,
+    execution_directory: &'static str,
+    execution_shell: &'static str,
+    dangerous_command_override: &'static str,
+    danger_confirm_prompt: &'static str,
+    danger_confirm_mismatch: &'static str,
+    goal_yes: &'static str,
+    goal_no: &'static str,
+    goal_later: &'static str,
+    session_saved: &'static str,
+    session_not_found: &'static str,
+    no_sessions: &'static str,
+    sessions_cleaned: &'static str,
+    resume_task: &'static str,
+    response_stalled_retry: &'static str,
+    response_stalled: &'static str,
+    retry_cooldown: &'static str,
+    command_too_long: &'static str,
+    dry_run_danger_advisory: &'static str,
+    config_edit_prompt: &'static str,
+    config_unchanged: &'static str,
+    config_edit_invalid: &'static str,
+    trace_plan: &'static str,
+    trace_step: &'static str,
+    trace_no_plan: &'static str,
+    trace_not_executed: &'static str,
+    debug_request_id: &'static str,
+    invalid_config_value: &'static str,
+    context_window_warning: &'static str,
+    context_trim_prompt: &'static str,
+    context_trimmed: &'static str,
+    context_length_exceeded: &'static str,
+    annotations_title: &'static str,
+    annotation_failed: &'static str,
+    annotation_parse_error: &'static str,
+    split_step: &'static str,
+    split_continue_prompt: &'static str,
+    split_stopped: &'static str,
+    split_failed: &'static str,
+    running_tests: &'static str,
+    tests_passed: &'static str,
+    tests_failed: &'static str,
+    tests_failed_context: &'static str,
+    config_dir_not_writable: &'static str,
+    config_save_failed: &'static str,
+    tls_verify_disabled: &'static str,
+    danger_reason: &'static str,
+    danger_custom_pattern: &'static str,
+    api_key_keep_prompt: &'static str,
+    validator_warning: &'static str,
+    validator_denied: &'static str,
+    validator_failed: &'static str,
+    validator_timeout: &'static str,
+    validator_exit_code: &'static str,
+    mnemonic_title: &'static str,
+    mnemonic_parse_error: &'static str,
+    no_history: &'static str,
+    history_success: &'static str,
+    history_failure: &'static str,
+    history_goal_missed: &'static str,
+    feedback_prompt: &'static str,
+    summary_title: &'static str,
+    summary_prompt: &'static str,
+    summary_command: &'static str,
+    summary_attempts: &'static str,
+    summary_duration: &'static str,
+    follow_up_keys: &'static str,
+    copied: &'static str,
+    snippet_saved: &'static str,
+    shell_history_added: &'static str,
+    history_summary: &'static str,
+    default_flags_invalid: &'static str,
+    default_flags_options_only: &'static str,
+    default_flags_current: &'static str,
+    default_flags_ignored: &'static str,
+    retry_after_failure_prompt: &'static str,
+    candidates_title: &'static str,
+    preflight_passed: &'static str,
+    preflight_failed: &'static str,
+    preflight_missing_program: &'static str,
+    preflight_syntax_error: &'static str,
+    estimated_cost: &'static str,
+    estimated_cost_unknown: &'static str,
+    estimated_cost_prompt: &'static str,
+    seed_unsupported: &'static str,
+    syntax_dry_run: &'static str,
+    chain_step: &'static str,
+    chain_stopped: &'static str,
+    path_generalized: &'static str,
+    path_kept: &'static str,
+    unsupported_language: &'static str,
+    token_budget_reached: &'static str,
+    token_usage_missing: &'static str,
+    model_rerouted: &'static str,
+    response_model: &'static str,
+    system_fingerprint: &'static str,
+    export_file_exists: &'static str,
+    export_nothing: &'static str,
+    history_exported: &'static str,
+    audit_parse_error: &'static str,
+    risk_level: &'static str,
+    risk_low: &'static str,
+    risk_medium: &'static str,
+    risk_high: &'static str,
+    risk_critical: &'static str,
+    audit_risks: &'static str,
+    audit_data_access: &'static str,
+    audit_network: &'static str,
+    audit_none: &'static str,
+    retry_cooldown_plain: &'static str,
+    error_prefix: &'static str,
+    attempts_strategy: &'static str,
+    escalate_model_missing: &'static str,
+    escalate_model: &'static str,
+    escalate_cost: &'static str,
+    escalate_cost_unknown: &'static str,
+    escalate_budget: &'static str,
+    pipe_to_shell_warning: &'static str,
+    pipe_to_shell_reason: &'static str,
+    refactor_parse_error: &'static str,
+    refactor_unchanged: &'static str,
+    refactor_original: &'static str,
+    refactor_improved: &'static str,
+    refactor_changes: &'static str,
+    refactor_improvements: &'static str,
+    refactor_dangerous: &'static str,
+    placeholder_value_prompt: &'static str,
+    placeholder_filled: &'static str,
+    auto_confirm_countdown: &'static str,
+    auto_confirm_aborted: &'static str,
+    raw_output_dry_run: &'static str,
+    history_write_skipped: &'static str,
+    tokenize_no_text: &'static str,
+    tokenize_characters: &'static str,
+    tokenize_tokens: &'static str,
+    tokenize_as_system: &'static str,
+    tokenize_as_user: &'static str,
+    tokenize_as_completion: &'static str,
+    tokenize_price_unknown: &'static str,
+    tokenize_note: &'static str,
+    tokenize_model: &'static str,
+    batch_blocked: &'static str,
+    batch_placeholders: &'static str,
+    batch_mismatch: &'static str,
+    batch_pass: &'static str,
+    batch_fail: &'static str,
+    batch_summary: &'static str,
+    strict_mode: &'static str,
+    token_usage: &'static str,
+    token_usage_value: &'static str,
+    token_cache_hit: &'static str,
+    token_cost: &'static str,
+    whoami_user: &'static str,
+    whoami_provider: &'static str,
+    whoami_api: &'static str,
+    whoami_api_key: &'static str,
+    whoami_model: &'static str,
+    whoami_language: &'static str,
+    whoami_config: &'static str,
+    whoami_not_set: &'static str,
+    prompt_too_long: &'static str,
+    prompt_output_trimmed: &'static str,
+    watch_missing: &'static str,
+    watch_waiting: &'static str,
+    watch_changed: &'static str,
+    watch_stopped: &'static str,
+    config_stdin_once: &'static str,
+    config_stdin_empty: &'static str,
+    serve_listening: &'static str,
+    missing_tool_no_manager: &'static str,
+    missing_tool_install_prompt: &'static str,
+    missing_tool_sudo_warning: &'static str,
+    missing_tool_rerun_prompt: &'static str,
+    clear_history_confirm: &'static str,
+    clear_history_done: &'static str,
+    last_success_example: &'static str,
+    last_success_none: &'static str,
+    split_rest_together: &'static str,
+    token_usage_unknown_stop: &'static str,
+    serve_public_warning: &'static str,
+    serve_no_token: &'static str,
+    preflight_unknown_flag: &'static str,
+    no_model_configured: &'static str,
 }
 
 const UI_TEXT_EN: UiText = UiText {
@@ -199,9 +1080,242 @@ const UI_TEXT_EN: UiText = UiText {
     base_url_prompt: "Enter API base URL",
     api_key_prompt: "Enter API key",
     model_prompt: "Enter model name",
-    language_prompt: "Enter language (en/zh)",
+    language_prompt: "Select interface language",
+    provider_prompt: "Select your AI provider",
+    provider_custom: "Custom URL (OpenAI compatible)",
+    azure_endpoint_prompt: "Enter Azure OpenAI endpoint (https://<resource>.openai.azure.com)",
+    azure_deployment_prompt: "Enter deployment name",
+    azure_api_version_prompt: "Enter API version",
+    unknown_provider: "Unknown provider:",
     provide_description: "Please provide an operation description",
     config_updated: "Configuration updated",
+    race_winner: "🏁 Fastest model:",
+    attempt_temperature: "🌡️  Temperature:",
+    session_model: "🧠 Model:",
+    model_override_marker: " (override)",
+    interactive_prompt_required: "An interactive prompt is required but --no-interactive is set:",
+    cancel_hint: "(press Esc to cancel)",
+    request_cancelled: "⏹️  Request cancelled",
+    cancel_action_prompt: "What would you like to do?",
+    cancel_reenter: "Re-enter the task description",
+    cancel_quit: "Quit",
+    new_description_prompt: "Task description",
+    program_not_found: "⚠️  Command `{}` not found in PATH",
+    suggest_install_prompt: "Do you want the AI to suggest an installation command?",
+    install_suggestion: "📦 Suggested installation command:",
+    response_api_error: "The API returned an error",
+    response_unexpected_shape:
+        "Unexpected response format from the API, run with --debug to see the raw response.",
+    response_no_choices: "The API response contains no choices.",
+    response_null_content: "The API response contains no message content.",
+    response_content_filtered: "The response was blocked by the provider's content filter.",
+    check_danger_blocked: "🛑 This command would be blocked",
+    check_danger_pattern: "Matched pattern:",
+    check_danger_allowed: "✅ This command would not be blocked",
+    diff_models_identical: "✅ Both models generated the same command",
+    model_pinned: "📌 Model pinned in",
+    pinned_model: "📌 Pinned model:",
+    pinned_model_source: "Source:",
+    pre_hook_failed: "⚠️  Pre-hook failed, aborting:",
+    post_hook_failed: "⚠️  Post-hook failed:",
+    config_format_error: "Invalid config format, expected key=value",
+    unknown_config_key: "Unknown config key:",
+    unknown_config_type: "Unknown config type:",
+    language_preference_required: "🌐 Please choose your interface language",
+    missing_model_id: "Please provide a model ID, or use --show to see the pinned model",
+    debug_info: "🔍 Debug info:",
+    debug_system_prompt: "System prompt:",
+    debug_user_prompt: "User prompt:",
+    debug_raw_response: "Raw response:",
+    learn_no_failures:
+        "No commands have been marked as missing their goal yet, nothing to learn from.",
+    learn_analyzing: "🧠 Analyzing {} failed attempts...",
+    learn_no_changes: "✅ No changes suggested for the system prompt",
+    learn_suggested_diff: "📝 Suggested system prompt changes:",
+    learn_apply_prompt: "Apply the updated system prompt?",
+    learn_applied: "✅ System prompt saved to",
+    execution_directory: "📂 Working directory:",
+    execution_shell: "🐚 Shell:",
+    dangerous_command_override: "⚠️  Warning: Potentially dangerous command detected!",
+    danger_confirm_prompt: "Type \"{}\" to execute it anyway",
+    danger_confirm_mismatch: "Confirmation phrase did not match, the command was not executed.",
+    goal_yes: "Yes",
+    goal_no: "No, try again",
+    goal_later: "Don't know yet, ask me later",
+    session_saved: "⏸️  Session saved, run `ask resume {}` to answer later",
+    session_not_found: "Session not found:",
+    no_sessions: "No saved sessions",
+    sessions_cleaned: "🧹 Removed {} session(s)",
+    resume_task: "📋 Task:",
+    response_stalled_retry: "⚠️  No data received for {} ms, retrying...",
+    response_stalled: "No data received from the API for {} ms, giving up after retrying.",
+    retry_cooldown: "⏳ Retrying in {}s (press Enter to retry now)",
+    command_too_long: "⚠️  Warning: The command is {} characters long, exceeding the limit of {}, execution refused!",
+    dry_run_danger_advisory: "⚠️  Advisory: this command would be refused as dangerous (dry-run, nothing was executed)",
+    config_edit_prompt: "Edit the config file",
+    config_unchanged: "Configuration unchanged",
+    config_edit_invalid: "The edited config is invalid, changes were discarded and the original file was kept:",
+    trace_plan: "🗺️  Plan:",
+    trace_step: "▶ Step {}: {}",
+    trace_no_plan: "The AI did not return a numbered plan:",
+    trace_not_executed: "Nothing was executed.",
+    debug_request_id: "Request ID:",
+    invalid_config_value: "Invalid value for config key",
+    context_window_warning: "⚠️  The prompt is about {} tokens, close to or over this model's context window of {} tokens",
+    context_trim_prompt: "Trim the prompt automatically (previous output first, then attached context)?",
+    context_trimmed: "✂️  Prompt trimmed to about {} tokens",
+    context_length_exceeded: "❌ The request is larger than the model's context window. Use a shorter description, fewer attachments (--with-ls, --detect-tools) or a model with a larger context window. API message:",
+    annotations_title: "📖 What each part does (generated by the model, may be inaccurate):",
+    annotation_failed: "⚠️  Unable to explain the command:",
+    annotation_parse_error: "The model did not reply with a list of explanations:",
+    split_step: "▶ Step {}/{}:",
+    split_continue_prompt: "Run this step?",
+    split_stopped: "⏹️  Remaining steps skipped",
+    split_failed: "❌ Step {} failed, the remaining steps were not run",
+    running_tests: "🧪 Running tests:",
+    tests_passed: "✅ Tests passed, goal achieved",
+    tests_failed: "❌ Tests failed, the output will be sent to the AI for the next attempt",
+    tests_failed_context: "\n\nThe test command `{}` failed after running the command:\n{}",
+    config_dir_not_writable: "❌ The config directory {} is not writable, fix its permissions before configuring",
+    config_save_failed: "❌ Unable to save the config file {}",
+    tls_verify_disabled: "⚠️  TLS certificate verification is disabled (tls_verify = false), the connection to the API can be intercepted",
+    danger_reason: "Why it is dangerous:",
+    danger_custom_pattern: "It matches one of the dangerous_patterns in your [safety] config",
+    api_key_keep_prompt: "Enter API key (leave empty to keep the current key)",
+    validator_warning: "⚠️  The safety validator flagged this command:",
+    validator_denied: "⛔ The safety validator rejected this command:",
+    validator_failed: "Unable to run the safety validator:",
+    validator_timeout: "The safety validator did not finish within {} seconds",
+    validator_exit_code: "The safety validator exited with code {}",
+    mnemonic_title: "💡 How to remember it:",
+    mnemonic_parse_error: "The model did not reply with a mnemonic:",
+    no_history: "No history records found",
+    history_success: "✅",
+    history_failure: "❌",
+    history_goal_missed: "⚠️ ",
+    feedback_prompt: "What should be different? (optional, sent to the AI with the next attempt)",
+    summary_title: "🎉 Goal achieved",
+    summary_prompt: "Task",
+    summary_command: "Command",
+    summary_attempts: "Attempts",
+    summary_duration: "Total time",
+    follow_up_keys: "c copy · s save as snippet · h add to shell history · any other key to finish (closes after 10s)",
+    copied: "Copied to the clipboard ({})",
+    snippet_saved: "Saved as a snippet in {}",
+    shell_history_added: "Added to {} (available in new shell sessions)",
+    history_summary: "({} attempts, {})",
+    default_flags_invalid: "These default flags are not valid:",
+    default_flags_options_only: "Default flags can only contain options, not a request or a subcommand",
+    default_flags_current: "Default flags:",
+    default_flags_ignored: "⚠️  The default flags in [defaults] could not be used with this command and were ignored",
+    retry_after_failure_prompt: "The command failed. Generate a new command and try again?",
+    candidates_title: "Choose one of the candidate commands (checked locally without running them):",
+    preflight_passed: "✓",
+    preflight_failed: "✗",
+    preflight_missing_program: "`{}` not found in PATH",
+    preflight_syntax_error: "syntax error: {}",
+    estimated_cost: "💰 Estimated request: ~{} tokens (~${} at current model pricing)",
+    estimated_cost_unknown: "💰 Estimated request: ~{} tokens (no known price for {}, set [api] price_per_million)",
+    estimated_cost_prompt: "Send the request?",
+    seed_unsupported: "⚠️  {} does not support --seed, the seed is not sent and results may differ between runs",
+    syntax_dry_run: "ℹ️  Generating {} syntax, which differs from your current shell; the command will be shown but not executed",
+    chain_step: "🔗 Step {}/{}: {}",
+    chain_stopped: "Chain stopped: step {} did not reach its goal, so the remaining steps were skipped",
+    path_generalized: "🔒 Replaced {} with {} ({}x)",
+    path_kept: "🔒 Kept the literal path in {} place(s) where replacing it could change what the command does",
+    unsupported_language: "Unsupported language: {} (expected en or zh)",
+    token_budget_reached: "⚠️  Token budget reached ({} of {} tokens used), program terminated.",
+    token_usage_missing: "⚠️  The provider did not report token usage, so --max-cost-tokens cannot count this request",
+    model_rerouted: "⚠️  Requested model {} but the provider answered with {}",
+    response_model: "🧾 Response model:",
+    system_fingerprint: "🧾 System fingerprint:",
+    export_file_exists: "{} already exists, use --force to overwrite it",
+    export_nothing: "No successful commands in the selected range",
+    history_exported: "✅ Exported {} command(s) to {}",
+    audit_parse_error: "Unable to parse the security review returned by the AI:",
+    risk_level: "🛡️  Risk level:",
+    risk_low: "low",
+    risk_medium: "medium",
+    risk_high: "high",
+    risk_critical: "critical",
+    audit_risks: "Risks:",
+    audit_data_access: "Data accessed:",
+    audit_network: "Network calls:",
+    audit_none: "(none)",
+    retry_cooldown_plain: "⏳ Retrying in {}s",
+    error_prefix: "Error:",
+    attempts_strategy: "🔁 Retry strategy:",
+    escalate_model_missing: "attempts_strategy = escalate requires [generation] escalate_model; retrying with the same model",
+    escalate_model: "⬆️  {} failed attempt(s), switching to {}",
+    escalate_cost: "Price per million tokens (input / output): ${} / ${} → ${} / ${}",
+    escalate_cost_unknown: "The price of {} is unknown; set [api] price_per_million to see the cost",
+    escalate_budget: "{} tokens left in the --max-cost-tokens budget",
+    pipe_to_shell_warning: "⚠️  High risk: this command pipes data into a shell (`{}`)",
+    pipe_to_shell_reason: "The script is run as soon as it is downloaded or generated, so you cannot review it first; a compromised or mistyped source runs arbitrary code on this machine. Only continue if you trust the source, or download the script and read it before running it.",
+    refactor_parse_error: "Could not read the rewritten command from the reply:",
+    refactor_unchanged: "The command is already fine; no changes were suggested",
+    refactor_original: "Original",
+    refactor_improved: "Improved",
+    refactor_changes: "🔀 Changes:",
+    refactor_improvements: "💡 Improvements:",
+    refactor_dangerous: "The rewritten command matches a dangerous pattern; review it carefully before using it",
+    placeholder_value_prompt: "Value for {}",
+    placeholder_filled: "▶️  Command to run:",
+    auto_confirm_countdown: "⏳ Executing in {}... Press Ctrl+C to abort or any key to proceed immediately",
+    auto_confirm_aborted: "Aborted, the command was not executed.",
+    raw_output_dry_run: "--raw-output shows the model's reply unprocessed; it will not be executed",
+    history_write_skipped: "Another ask session is writing the history; this entry was not recorded",
+    tokenize_no_text: "Give the text to estimate, or pipe it on standard input",
+    tokenize_characters: "Characters",
+    tokenize_tokens: "Estimated tokens",
+    tokenize_as_system: "As the system prompt (input)",
+    tokenize_as_user: "As the request (input, with the system prompt)",
+    tokenize_as_completion: "As a reply (output)",
+    tokenize_price_unknown: "The price of {} is unknown; set [api] price_per_million to see the cost",
+    tokenize_note: "Estimated with about 4 ASCII characters or 1 other character per token; the exact count depends on the model's tokenizer.",
+    tokenize_model: "Prices for model",
+    batch_blocked: "blocked by the safety checks ({})",
+    batch_placeholders: "the command contains placeholders and cannot run unattended",
+    batch_mismatch: "the output does not match /{}/ (exit code {})",
+    batch_pass: "✅ PASS",
+    batch_fail: "❌ FAIL:",
+    batch_summary: "{} passed, {} failed, {} generated only",
+    strict_mode: "🛡️  Strict mode: the script stops at the first failing line",
+    token_usage: "🧮 Tokens:",
+    token_usage_value: "{} in + {} out",
+    token_cache_hit: ", cache hit {} ({}%)",
+    token_cost: ", ${}",
+    whoami_user: "User",
+    whoami_provider: "Provider",
+    whoami_api: "API",
+    whoami_api_key: "API key",
+    whoami_model: "Model",
+    whoami_language: "Language",
+    whoami_config: "Config file",
+    whoami_not_set: "(not set)",
+    prompt_too_long: "The prompt is {} characters long, over the limit of {} ([safety] max_prompt_chars), so it was not sent. Shorten the input or raise the limit.",
+    prompt_output_trimmed: "✂️  The previous output was shortened to keep the prompt within {} characters",
+    watch_missing: "Watched path does not exist:",
+    watch_waiting: "👀 Watching {} for changes (press Ctrl+C to stop)",
+    watch_changed: "🔄 Changed: {}, generating a new command",
+    watch_stopped: "Stopped watching",
+    config_stdin_once: "Only one value can be read from standard input (key=-)",
+    config_stdin_empty: "No value for {} was given on standard input",
+    serve_listening: "Serving on {} (commands are generated, never executed). Press Ctrl+C to stop.",
+    missing_tool_no_manager: "⚠️  `{}` is not installed and no supported package manager was found",
+    missing_tool_install_prompt: "`{}` is not installed. Install it with `{}`?",
+    missing_tool_sudo_warning: "⚠️  The installation command runs with sudo and changes the system",
+    missing_tool_rerun_prompt: "Installed. Run the original command again?",
+    clear_history_confirm: "Are you sure you want to delete {} history records?",
+    clear_history_done: "🧹 Deleted {} history record(s)",
+    last_success_example: "📎 Example from history:",
+    last_success_none: "No earlier successful command for a similar request was found in the history",
+    split_rest_together: "The cd target can't be resolved beforehand, running the remaining steps together:",
+    token_usage_unknown_stop: "⚠️  Token usage is unknown, so --max-cost-tokens stopped automatic retries.",
+    serve_public_warning: "⚠️  Listening on {}, which other machines can reach: anyone who can connect can spend your API quota",
+    serve_no_token: "Set ASKAI_SERVE_TOKEN to require an `Authorization: Bearer <token>` header",
+    preflight_unknown_flag: "`{}` --help does not list `{}`",
+    no_model_configured: "No model is configured yet",
 };
 
 const UI_TEXT_ZH: UiText = UiText {
@@ -219,399 +1333,9509 @@ const UI_TEXT_ZH: UiText = UiText {
     base_url_prompt: "请输入API基础URL",
     api_key_prompt: "请输入API密钥",
     model_prompt: "请输入模型名称",
-    language_prompt: "请输入语言 (en/zh)",
+    language_prompt: "请选择界面语言",
+    provider_prompt: "请选择AI服务商",
+    provider_custom: "自定义地址（兼容 OpenAI 接口）",
+    azure_endpoint_prompt: "请输入 Azure OpenAI 终结点 (https://<resource>.openai.azure.com)",
+    azure_deployment_prompt: "请输入部署名称",
+    azure_api_version_prompt: "请输入API版本",
+    unknown_provider: "未知的服务商：",
     provide_description: "请提供操作描述",
     config_updated: "配置已更新",
+    race_winner: "🏁 最快返回的模型：",
+    attempt_temperature: "🌡️  Temperature：",
+    session_model: "🧠 模型：",
+    model_override_marker: "（临时指定）",
+    interactive_prompt_required: "需要交互式确认，但已设置 --no-interactive：",
+    cancel_hint: "（按 Esc 取消）",
+    request_cancelled: "⏹️  请求已取消",
+    cancel_action_prompt: "接下来要做什么？",
+    cancel_reenter: "重新输入操作描述",
+    cancel_quit: "退出",
+    new_description_prompt: "操作描述",
+    program_not_found: "⚠️  命令 `{}` 未在 PATH 中找到",
+    suggest_install_prompt: "是否需要AI给出安装命令？",
+    install_suggestion: "📦 建议的安装命令：",
+    response_api_error: "API返回错误",
+    response_unexpected_shape: "API响应格式无法识别，使用 --debug 查看原始响应。",
+    response_no_choices: "API响应中没有任何候选结果。",
+    response_null_content: "API响应中没有消息内容。",
+    response_content_filtered: "响应被服务商的内容过滤器拦截。",
+    check_danger_blocked: "🛑 该命令会被拦截",
+    check_danger_pattern: "命中的模式：",
+    check_danger_allowed: "✅ 该命令不会被拦截",
+    diff_models_identical: "✅ 两个模型生成的命令完全相同",
+    model_pinned: "📌 模型已固定到",
+    pinned_model: "📌 固定的模型：",
+    pinned_model_source: "来源：",
+    pre_hook_failed: "⚠️  pre-hook 执行失败，已中止：",
+    post_hook_failed: "⚠️  post-hook 执行失败：",
+    config_format_error: "配置格式错误，应为 key=value",
+    unknown_config_key: "未知的配置项：",
+    unknown_config_type: "未知的配置类型：",
+    language_preference_required: "🌐 需要设置语言偏好",
+    missing_model_id: "请提供模型 ID，或使用 --show 查看当前固定的模型",
+    debug_info: "🔍 调试信息：",
+    debug_system_prompt: "系统提示：",
+    debug_user_prompt: "用户提示：",
+    debug_raw_response: "原始响应：",
+    learn_no_failures: "还没有被标记为未达到预期目标的命令，无需学习。",
+    learn_analyzing: "🧠 正在分析 {} 次失败的尝试...",
+    learn_no_changes: "✅ 系统提示无需修改",
+    learn_suggested_diff: "📝 建议的系统提示修改：",
+    learn_apply_prompt: "是否应用更新后的系统提示？",
+    learn_applied: "✅ 系统提示已保存到",
+    execution_directory: "📂 工作目录：",
+    execution_shell: "🐚 Shell：",
+    dangerous_command_override: "⚠️  警告：检测到潜在的危险命令！",
+    danger_confirm_prompt: "输入“{}”以继续执行",
+    danger_confirm_mismatch: "确认短语不匹配，命令未执行。",
+    goal_yes: "是",
+    goal_no: "否，重新尝试",
+    goal_later: "还不确定，稍后再回答",
+    session_saved: "⏸️  会话已暂存，稍后运行 `ask resume {}` 继续",
+    session_not_found: "未找到会话：",
+    no_sessions: "没有暂存的会话",
+    sessions_cleaned: "🧹 已删除 {} 个会话",
+    resume_task: "📋 操作描述：",
+    response_stalled_retry: "⚠️  {} 毫秒内没有收到新数据，正在重试...",
+    response_stalled: "{} 毫秒内没有收到 API 的新数据，重试后仍然失败。",
+    retry_cooldown: "⏳ {} 秒后重试（按回车立即重试）",
+    command_too_long: "⚠️  警告：命令长度为 {} 个字符，超过了 {} 的限制，拒绝执行！",
+    dry_run_danger_advisory:
+        "⚠️  提示：该命令会被判定为危险命令而拒绝执行（dry-run 模式，未执行任何命令）",
+    config_edit_prompt: "编辑配置文件",
+    config_unchanged: "配置未修改",
+    config_edit_invalid: "编辑后的配置无效，修改已丢弃，保留原配置文件：",
+    trace_plan: "🗺️  执行计划：",
+    trace_step: "▶ 第 {} 步：{}",
+    trace_no_plan: "AI 没有返回编号的执行计划：",
+    trace_not_executed: "没有执行任何命令。",
+    debug_request_id: "请求ID：",
+    invalid_config_value: "配置项的值无效：",
+    context_window_warning: "⚠️  提示约有 {} 个 token，接近或超过该模型 {} 个 token 的上下文窗口",
+    context_trim_prompt: "是否自动裁剪提示（先裁剪上一次的输出，再移除附加的上下文）？",
+    context_trimmed: "✂️  提示已裁剪到约 {} 个 token",
+    context_length_exceeded: "❌ 请求超出了模型的上下文窗口。请缩短描述、减少附加内容（--with-ls、--detect-tools）或换用上下文窗口更大的模型。API 返回：",
+    annotations_title: "📖 各部分的作用（由模型生成，可能不准确）：",
+    annotation_failed: "⚠️  无法解释该命令：",
+    annotation_parse_error: "模型没有返回解释列表：",
+    split_step: "▶ 第 {}/{} 步：",
+    split_continue_prompt: "是否执行这一步？",
+    split_stopped: "⏹️  已跳过剩余的步骤",
+    split_failed: "❌ 第 {} 步执行失败，剩余的步骤没有执行",
+    running_tests: "🧪 正在运行测试：",
+    tests_passed: "✅ 测试通过，已达到预期目标",
+    tests_failed: "❌ 测试未通过，测试输出会发送给 AI 用于下一次尝试",
+    tests_failed_context: "\n\n命令执行后，测试命令 `{}` 未通过：\n{}",
+    config_dir_not_writable: "❌ 配置目录 {} 不可写，请先修改它的权限再进行配置",
+    config_save_failed: "❌ 无法保存配置文件 {}",
+    tls_verify_disabled: "⚠️  已关闭 TLS 证书校验（tls_verify = false），与 API 的连接可能被劫持",
+    danger_reason: "危险原因：",
+    danger_custom_pattern: "命中了 [safety] 配置中的 dangerous_patterns",
+    api_key_keep_prompt: "请输入API密钥（留空则保留当前密钥）",
+    validator_warning: "⚠️  安全校验脚本对该命令给出了警告：",
+    validator_denied: "⛔ 安全校验脚本拒绝了该命令：",
+    validator_failed: "无法运行安全校验脚本：",
+    validator_timeout: "安全校验脚本未在 {} 秒内完成",
+    validator_exit_code: "安全校验脚本的退出码为 {}",
+    mnemonic_title: "💡 记忆口诀：",
+    mnemonic_parse_error: "模型没有返回记忆口诀：",
+    no_history: "没有找到执行记录",
+    history_success: "✅",
+    history_failure: "❌",
+    history_goal_missed: "⚠️ ",
+    feedback_prompt: "哪里不符合预期？（可选，会在下一次尝试时发给 AI）",
+    summary_title: "🎉 已达成目标",
+    summary_prompt: "需求",
+    summary_command: "命令",
+    summary_attempts: "尝试次数",
+    summary_duration: "总耗时",
+    follow_up_keys: "c 复制 · s 保存为片段 · h 加入 shell 历史 · 其他任意键结束（10 秒后自动关闭）",
+    copied: "已复制到剪贴板（{}）",
+    snippet_saved: "已保存为片段：{}",
+    shell_history_added: "已加入 {}（在新的 shell 会话中可用）",
+    history_summary: "（尝试 {} 次，耗时 {}）",
+    default_flags_invalid: "这些默认选项无效：",
+    default_flags_options_only: "默认选项只能包含选项，不能包含需求描述或子命令",
+    default_flags_current: "默认选项：",
+    default_flags_ignored: "⚠️  [defaults] 中的默认选项无法用于本次命令，已忽略",
+    retry_after_failure_prompt: "命令执行失败，是否生成新的命令并重试？",
+    candidates_title: "请选择一个候选命令（已在本地检查，未执行）：",
+    preflight_passed: "✓",
+    preflight_failed: "✗",
+    preflight_missing_program: "`{}` 未在 PATH 中找到",
+    preflight_syntax_error: "语法错误：{}",
+    estimated_cost: "💰 预计本次请求约 {} 个 token（按当前模型价格约 ${}）",
+    estimated_cost_unknown: "💰 预计本次请求约 {} 个 token（没有 {} 的价格信息，可设置 [api] price_per_million）",
+    estimated_cost_prompt: "是否发送请求？",
+    seed_unsupported: "⚠️  {} 不支持 --seed，不会发送种子，每次运行的结果可能不同",
+    syntax_dry_run: "ℹ️  将生成 {} 语法的命令，与当前 shell 不一致，只显示命令而不执行",
+    chain_step: "🔗 第 {}/{} 步：{}",
+    chain_stopped: "链式执行已停止：第 {} 步未达到目标，已跳过剩余步骤",
+    path_generalized: "🔒 已将 {} 替换为 {}（{} 处）",
+    path_kept: "🔒 有 {} 处路径保持原样，替换后可能改变命令的含义",
+    unsupported_language: "不支持的语言：{}（可选 en 或 zh）",
+    token_budget_reached: "⚠️  已达到 token 预算（已使用 {} / {} 个 token），程序终止。",
+    token_usage_missing: "⚠️  服务商没有返回 token 用量，--max-cost-tokens 无法统计这次请求",
+    model_rerouted: "⚠️  请求的模型是 {}，但服务商使用了 {}",
+    response_model: "🧾 实际使用的模型：",
+    system_fingerprint: "🧾 System fingerprint：",
+    export_file_exists: "{} 已存在，使用 --force 覆盖",
+    export_nothing: "所选范围内没有执行成功的命令",
+    history_exported: "✅ 已将 {} 条命令导出到 {}",
+    audit_parse_error: "无法解析 AI 返回的安全审查结果：",
+    risk_level: "🛡️  风险等级：",
+    risk_low: "低",
+    risk_medium: "中",
+    risk_high: "高",
+    risk_critical: "严重",
+    audit_risks: "风险：",
+    audit_data_access: "访问的数据：",
+    audit_network: "网络请求：",
+    audit_none: "（无）",
+    retry_cooldown_plain: "⏳ {} 秒后重试",
+    error_prefix: "错误：",
+    attempts_strategy: "🔁 重试策略：",
+    escalate_model_missing: "attempts_strategy = escalate 需要设置 [generation] escalate_model，将继续使用当前模型重试",
+    escalate_model: "⬆️  已失败 {} 次，换用 {}",
+    escalate_cost: "每百万 token 价格（输入 / 输出）：${} / ${} → ${} / ${}",
+    escalate_cost_unknown: "未知 {} 的价格，可设置 [api] price_per_million 查看费用",
+    escalate_budget: "--max-cost-tokens 预算剩余 {} token",
+    pipe_to_shell_warning: "⚠️  高风险：该命令把管道中的数据交给 shell 执行（`{}`）",
+    pipe_to_shell_reason: "脚本在下载或生成后会被立即执行，无法事先审阅；来源被篡改或地址写错都会在本机执行任意代码。只有在信任来源时才继续，或者先下载脚本、阅读后再执行。",
+    refactor_parse_error: "无法从回复中读取改写后的命令：",
+    refactor_unchanged: "命令已经足够好，没有建议的修改",
+    refactor_original: "原命令",
+    refactor_improved: "改写后",
+    refactor_changes: "🔀 改动：",
+    refactor_improvements: "💡 改进：",
+    refactor_dangerous: "改写后的命令命中了危险模式，使用前请仔细检查",
+    placeholder_value_prompt: "{} 的值",
+    placeholder_filled: "▶️  将要执行的命令：",
+    auto_confirm_countdown: "⏳ {} 秒后执行……按 Ctrl+C 取消，按任意其他键立即执行",
+    auto_confirm_aborted: "已取消，命令没有执行。",
+    raw_output_dry_run: "--raw-output 会原样显示模型的回复，不会执行",
+    history_write_skipped: "其他 ask 会话正在写入历史记录，本条记录已跳过",
+    tokenize_no_text: "请提供要估算的文本，或通过标准输入传入",
+    tokenize_characters: "字符数",
+    tokenize_tokens: "估算 token 数",
+    tokenize_as_system: "作为系统提示（输入）",
+    tokenize_as_user: "作为用户需求（输入，含系统提示）",
+    tokenize_as_completion: "作为模型回复（输出）",
+    tokenize_price_unknown: "未知 {} 的价格，可设置 [api] price_per_million 查看费用",
+    tokenize_note: "按每 4 个 ASCII 字符或每个其他字符约 1 个 token 估算，实际数量取决于模型的分词器。",
+    tokenize_model: "计价模型",
+    batch_blocked: "被安全检查拦截（{}）",
+    batch_placeholders: "命令包含占位符，无法自动执行",
+    batch_mismatch: "输出不匹配 /{}/（退出码 {}）",
+    batch_pass: "✅ 通过",
+    batch_fail: "❌ 失败：",
+    batch_summary: "{} 项通过，{} 项失败，{} 项只生成了命令",
+    strict_mode: "🛡️  严格模式：脚本在第一个失败的行处停止",
+    token_usage: "🧮 Token 用量：",
+    token_usage_value: "输入 {} + 输出 {}",
+    token_cache_hit: "，缓存命中 {}（{}%）",
+    token_cost: "，${}",
+    whoami_user: "用户",
+    whoami_provider: "服务商",
+    whoami_api: "API",
+    whoami_api_key: "API 密钥",
+    whoami_model: "模型",
+    whoami_language: "语言",
+    whoami_config: "配置文件",
+    whoami_not_set: "（未设置）",
+    prompt_too_long: "提示长度为 {} 个字符，超过了 {} 的限制（[safety] max_prompt_chars），因此没有发送。请缩短输入或调高限制。",
+    prompt_output_trimmed: "✂️  上一次的输出已被截短，使提示不超过 {} 个字符",
+    watch_missing: "要监视的路径不存在：",
+    watch_waiting: "👀 正在监视 {} 的变化（按 Ctrl+C 退出）",
+    watch_changed: "🔄 已变化：{}，重新生成命令",
+    watch_stopped: "已停止监视",
+    config_stdin_once: "只能有一个配置项从标准输入读取（key=-）",
+    config_stdin_empty: "标准输入中没有提供 {} 的值",
+    serve_listening: "服务已启动：{}（只生成命令，不会执行）。按 Ctrl+C 停止。",
+    missing_tool_no_manager: "⚠️  `{}` 未安装，且没有找到支持的包管理器",
+    missing_tool_install_prompt: "`{}` 未安装。是否使用 `{}` 安装？",
+    missing_tool_sudo_warning: "⚠️  安装命令会通过 sudo 以管理员权限修改系统",
+    missing_tool_rerun_prompt: "安装完成，是否重新执行原命令？",
+    clear_history_confirm: "确定要删除 {} 条执行记录吗？",
+    clear_history_done: "🧹 已删除 {} 条执行记录",
+    last_success_example: "📎 历史中的示例：",
+    last_success_none: "执行记录中没有与本次需求相似的成功命令",
+    split_rest_together: "无法预先确定 cd 的目标目录，剩下的步骤将作为一条命令一起执行：",
+    token_usage_unknown_stop: "⚠️  无法得知 token 用量，--max-cost-tokens 已停止自动重试。",
+    serve_public_warning: "⚠️  正在监听 {}，其他机器也可以访问：任何能连接的人都可以消耗你的 API 额度",
+    serve_no_token: "设置 ASKAI_SERVE_TOKEN 可以要求请求带上 `Authorization: Bearer <token>` 头",
+    preflight_unknown_flag: "`{}` 的 --help 中没有 `{}` 选项",
+    no_model_configured: "尚未配置模型",
+};
+
+// ASCII-only variant for terminals and fonts without emoji support.
+// Only the emoji markers are replaced; UI_TEXT_ZH_ASCII keeps the Chinese wording
+// for consoles that can show CJK text (e.g. a GBK code page) but not emoji.
+const UI_TEXT_EN_ASCII: UiText = UiText {
+    thinking: "[..] Thinking...",
+    generated_command: "[>] Generated command:",
+    dangerous_command_warning:
+        "[!] Warning: Potentially dangerous command detected, execution refused!",
+    executing_command: "[>] Executing command...",
+    command_success: "[OK] Command executed successfully!",
+    command_failure: "[X] Command execution failed:",
+    max_attempts_reached: "[!] Maximum number of attempts reached, program terminated.",
+    first_run_config: "[*] First run requires configuration",
+    config_saved: "[OK] Configuration saved",
+    race_winner: "[>] Fastest model:",
+    attempt_temperature: "[i] Temperature:",
+    session_model: "[i] Model:",
+    request_cancelled: "[-] Request cancelled",
+    program_not_found: "[!] Command `{}` not found in PATH",
+    install_suggestion: "[>] Suggested installation command:",
+    check_danger_blocked: "[X] This command would be blocked",
+    check_danger_allowed: "[OK] This command would not be blocked",
+    diff_models_identical: "[OK] Both models generated the same command",
+    model_pinned: "[*] Model pinned in",
+    pinned_model: "[*] Pinned model:",
+    pre_hook_failed: "[!] Pre-hook failed, aborting:",
+    post_hook_failed: "[!] Post-hook failed:",
+    language_preference_required: "[*] Please choose your interface language",
+    debug_info: "[?] Debug info:",
+    learn_analyzing: "[..] Analyzing {} failed attempts...",
+    learn_no_changes: "[OK] No changes suggested for the system prompt",
+    learn_suggested_diff: "[>] Suggested system prompt changes:",
+    learn_applied: "[OK] System prompt saved to",
+    execution_directory: "[i] Working directory:",
+    execution_shell: "[i] Shell:",
+    dangerous_command_override: "[!] Warning: Potentially dangerous command detected!",
+    session_saved: "[-] Session saved, run `ask resume {}` to answer later",
+    sessions_cleaned: "[OK] Removed {} session(s)",
+    resume_task: "[i] Task:",
+    response_stalled_retry: "[!] No data received for {} ms, retrying...",
+    retry_cooldown: "[..] Retrying in {}s (press Enter to retry now)",
+    command_too_long: "[!] Warning: The command is {} characters long, exceeding the limit of {}, execution refused!",
+    dry_run_danger_advisory: "[!] Advisory: this command would be refused as dangerous (dry-run, nothing was executed)",
+    trace_plan: "[>] Plan:",
+    trace_step: "> Step {}: {}",
+    context_window_warning: "[!] The prompt is about {} tokens, close to or over this model's context window of {} tokens",
+    context_trimmed: "[-] Prompt trimmed to about {} tokens",
+    context_length_exceeded: "[x] The request is larger than the model's context window. Use a shorter description, fewer attachments (--with-ls, --detect-tools) or a model with a larger context window. API message:",
+    annotations_title: "[?] What each part does (generated by the model, may be inaccurate):",
+    annotation_failed: "[!] Unable to explain the command:",
+    split_step: "> Step {}/{}:",
+    split_stopped: "[-] Remaining steps skipped",
+    split_failed: "[x] Step {} failed, the remaining steps were not run",
+    running_tests: "[~] Running tests:",
+    tests_passed: "[ok] Tests passed, goal achieved",
+    tests_failed: "[x] Tests failed, the output will be sent to the AI for the next attempt",
+    config_dir_not_writable: "[x] The config directory {} is not writable, fix its permissions before configuring",
+    config_save_failed: "[x] Unable to save the config file {}",
+    tls_verify_disabled: "[!] TLS certificate verification is disabled (tls_verify = false), the connection to the API can be intercepted",
+    validator_warning: "[WARN] The safety validator flagged this command:",
+    validator_denied: "[DENIED] The safety validator rejected this command:",
+    mnemonic_title: "[i] How to remember it:",
+    history_success: "[OK]",
+    history_failure: "[FAIL]",
+    history_goal_missed: "[MISS]",
+    summary_title: "[DONE] Goal achieved",
+    follow_up_keys: "c copy | s save as snippet | h add to shell history | any other key to finish (closes after 10s)",
+    default_flags_ignored: "[WARN] The default flags in [defaults] could not be used with this command and were ignored",
+    preflight_passed: "[ok]",
+    preflight_failed: "[x]",
+    estimated_cost: "[$] Estimated request: ~{} tokens (~${} at current model pricing)",
+    estimated_cost_unknown: "[$] Estimated request: ~{} tokens (no known price for {}, set [api] price_per_million)",
+    seed_unsupported: "[WARN] {} does not support --seed, the seed is not sent and results may differ between runs",
+    syntax_dry_run: "[i] Generating {} syntax, which differs from your current shell; the command will be shown but not executed",
+    chain_step: "[chain] Step {}/{}: {}",
+    path_generalized: "[privacy] Replaced {} with {} ({}x)",
+    path_kept: "[privacy] Kept the literal path in {} place(s) where replacing it could change what the command does",
+    token_budget_reached: "[!] Token budget reached ({} of {} tokens used), program terminated.",
+    token_usage_missing: "[WARN] The provider did not report token usage, so --max-cost-tokens cannot count this request",
+    model_rerouted: "[WARN] Requested model {} but the provider answered with {}",
+    response_model: "[i] Response model:",
+    system_fingerprint: "[i] System fingerprint:",
+    history_exported: "[ok] Exported {} command(s) to {}",
+    risk_level: "Risk level:",
+    retry_cooldown_plain: "Retrying in {}s",
+    attempts_strategy: "[i] Retry strategy:",
+    escalate_model: "[^] {} failed attempt(s), switching to {}",
+    escalate_cost: "Price per million tokens (input / output): ${} / ${} -> ${} / ${}",
+    pipe_to_shell_warning: "[!] High risk: this command pipes data into a shell (`{}`)",
+    refactor_changes: "[~] Changes:",
+    refactor_improvements: "[*] Improvements:",
+    placeholder_filled: "[>] Command to run:",
+    auto_confirm_countdown: "[~] Executing in {}... Press Ctrl+C to abort or any key to proceed immediately",
+    batch_pass: "[ok] PASS",
+    batch_fail: "[x] FAIL:",
+    strict_mode: "Strict mode: the script stops at the first failing line",
+    token_usage: "[i] Tokens:",
+    prompt_output_trimmed: "[!] The previous output was shortened to keep the prompt within {} characters",
+    watch_waiting: "[i] Watching {} for changes (press Ctrl+C to stop)",
+    watch_changed: "[~] Changed: {}, generating a new command",
+    missing_tool_no_manager: "[!] `{}` is not installed and no supported package manager was found",
+    missing_tool_sudo_warning: "[!] The installation command runs with sudo and changes the system",
+    clear_history_done: "[OK] Deleted {} history record(s)",
+    last_success_example: "[i] Example from history:",
+    token_usage_unknown_stop: "[!] Token usage is unknown, so --max-cost-tokens stopped automatic retries.",
+    serve_public_warning: "[!] Listening on {}, which other machines can reach: anyone who can connect can spend your API quota",
+    ..UI_TEXT_EN
+};
+
+// Chinese counterpart of UI_TEXT_EN_ASCII with the same markers.
+const UI_TEXT_ZH_ASCII: UiText = UiText {
+    thinking: "[..] 正在思考中...",
+    generated_command: "[>] 生成的命令：",
+    dangerous_command_warning: "[!] 警告：检测到潜在的危险命令，拒绝执行！",
+    executing_command: "[>] 正在执行命令...",
+    command_success: "[OK] 命令执行成功！",
+    command_failure: "[X] 命令执行失败：",
+    max_attempts_reached: "[!] 已达到最大尝试次数，程序终止。",
+    first_run_config: "[*] 首次运行需要进行配置",
+    config_saved: "[OK] 配置已保存",
+    race_winner: "[>] 最快返回的模型：",
+    attempt_temperature: "[i] Temperature：",
+    session_model: "[i] 模型：",
+    request_cancelled: "[-] 请求已取消",
+    program_not_found: "[!] 命令 `{}` 未在 PATH 中找到",
+    install_suggestion: "[>] 建议的安装命令：",
+    check_danger_blocked: "[X] 该命令会被拦截",
+    check_danger_allowed: "[OK] 该命令不会被拦截",
+    diff_models_identical: "[OK] 两个模型生成的命令完全相同",
+    model_pinned: "[*] 模型已固定到",
+    pinned_model: "[*] 固定的模型：",
+    pre_hook_failed: "[!] pre-hook 执行失败，已中止：",
+    post_hook_failed: "[!] post-hook 执行失败：",
+    language_preference_required: "[*] 需要设置语言偏好",
+    debug_info: "[?] 调试信息：",
+    learn_analyzing: "[..] 正在分析 {} 次失败的尝试...",
+    learn_no_changes: "[OK] 系统提示无需修改",
+    learn_suggested_diff: "[>] 建议的系统提示修改：",
+    learn_applied: "[OK] 系统提示已保存到",
+    execution_directory: "[i] 工作目录：",
+    execution_shell: "[i] Shell：",
+    dangerous_command_override: "[!] 警告：检测到潜在的危险命令！",
+    session_saved: "[-] 会话已暂存，稍后运行 `ask resume {}` 继续",
+    sessions_cleaned: "[OK] 已删除 {} 个会话",
+    resume_task: "[i] 操作描述：",
+    response_stalled_retry: "[!] {} 毫秒内没有收到新数据，正在重试...",
+    retry_cooldown: "[..] {} 秒后重试（按回车立即重试）",
+    command_too_long: "[!] 警告：命令长度为 {} 个字符，超过了 {} 的限制，拒绝执行！",
+    dry_run_danger_advisory: "[!] 提示：该命令会被判定为危险命令而拒绝执行（dry-run 模式，未执行任何命令）",
+    trace_plan: "[>] 执行计划：",
+    trace_step: "> 第 {} 步：{}",
+    context_window_warning: "[!] 提示约有 {} 个 token，接近或超过该模型 {} 个 token 的上下文窗口",
+    context_trimmed: "[-] 提示已裁剪到约 {} 个 token",
+    context_length_exceeded: "[x] 请求超出了模型的上下文窗口。请缩短描述、减少附加内容（--with-ls、--detect-tools）或换用上下文窗口更大的模型。API 返回：",
+    annotations_title: "[?] 各部分的作用（由模型生成，可能不准确）：",
+    annotation_failed: "[!] 无法解释该命令：",
+    split_step: "> 第 {}/{} 步：",
+    split_stopped: "[-] 已跳过剩余的步骤",
+    split_failed: "[x] 第 {} 步执行失败，剩余的步骤没有执行",
+    running_tests: "[~] 正在运行测试：",
+    tests_passed: "[ok] 测试通过，已达到预期目标",
+    tests_failed: "[x] 测试未通过，测试输出会发送给 AI 用于下一次尝试",
+    config_dir_not_writable: "[x] 配置目录 {} 不可写，请先修改它的权限再进行配置",
+    config_save_failed: "[x] 无法保存配置文件 {}",
+    tls_verify_disabled: "[!] 已关闭 TLS 证书校验（tls_verify = false），与 API 的连接可能被劫持",
+    validator_warning: "[WARN] 安全校验脚本对该命令给出了警告：",
+    validator_denied: "[DENIED] 安全校验脚本拒绝了该命令：",
+    mnemonic_title: "[i] 记忆口诀：",
+    history_success: "[OK]",
+    history_failure: "[FAIL]",
+    history_goal_missed: "[MISS]",
+    summary_title: "[DONE] 已达成目标",
+    follow_up_keys: "c 复制 | s 保存为片段 | h 加入 shell 历史 | 其他任意键结束（10 秒后自动关闭）",
+    default_flags_ignored: "[WARN] [defaults] 中的默认选项无法用于本次命令，已忽略",
+    preflight_passed: "[ok]",
+    preflight_failed: "[x]",
+    estimated_cost: "[$] 预计本次请求约 {} 个 token（按当前模型价格约 ${}）",
+    estimated_cost_unknown: "[$] 预计本次请求约 {} 个 token（没有 {} 的价格信息，可设置 [api] price_per_million）",
+    seed_unsupported: "[WARN] {} 不支持 --seed，不会发送种子，每次运行的结果可能不同",
+    syntax_dry_run: "[i] 将生成 {} 语法的命令，与当前 shell 不一致，只显示命令而不执行",
+    chain_step: "[chain] 第 {}/{} 步：{}",
+    path_generalized: "[privacy] 已将 {} 替换为 {}（{} 处）",
+    path_kept: "[privacy] 有 {} 处路径保持原样，替换后可能改变命令的含义",
+    token_budget_reached: "[!] 已达到 token 预算（已使用 {} / {} 个 token），程序终止。",
+    token_usage_missing: "[WARN] 服务商没有返回 token 用量，--max-cost-tokens 无法统计这次请求",
+    model_rerouted: "[WARN] 请求的模型是 {}，但服务商使用了 {}",
+    response_model: "[i] 实际使用的模型：",
+    system_fingerprint: "[i] System fingerprint：",
+    history_exported: "[ok] 已将 {} 条命令导出到 {}",
+    risk_level: "风险等级：",
+    retry_cooldown_plain: "{} 秒后重试",
+    attempts_strategy: "[i] 重试策略：",
+    escalate_model: "[^] 已失败 {} 次，换用 {}",
+    escalate_cost: "每百万 token 价格（输入 / 输出）：${} / ${} -> ${} / ${}",
+    pipe_to_shell_warning: "[!] 高风险：该命令把管道中的数据交给 shell 执行（`{}`）",
+    refactor_changes: "[~] 改动：",
+    refactor_improvements: "[*] 改进：",
+    placeholder_filled: "[>] 将要执行的命令：",
+    auto_confirm_countdown: "[~] {} 秒后执行……按 Ctrl+C 取消，按任意其他键立即执行",
+    batch_pass: "[ok] 通过",
+    batch_fail: "[x] 失败：",
+    strict_mode: "严格模式：脚本在第一个失败的行处停止",
+    token_usage: "[i] Token 用量：",
+    prompt_output_trimmed: "[!] 上一次的输出已被截短，使提示不超过 {} 个字符",
+    watch_waiting: "[i] 正在监视 {} 的变化（按 Ctrl+C 退出）",
+    watch_changed: "[~] 已变化：{}，重新生成命令",
+    missing_tool_no_manager: "[!] `{}` 未安装，且没有找到支持的包管理器",
+    missing_tool_sudo_warning: "[!] 安装命令会通过 sudo 以管理员权限修改系统",
+    clear_history_done: "[OK] 已删除 {} 条执行记录",
+    last_success_example: "[i] 历史中的示例：",
+    token_usage_unknown_stop: "[!] 无法得知 token 用量，--max-cost-tokens 已停止自动重试。",
+    serve_public_warning: "[!] 正在监听 {}，其他机器也可以访问：任何能连接的人都可以消耗你的 API 额度",
+    ..UI_TEXT_ZH
 };
 
+/// 设置 --ascii 或检测到终端不支持 Unicode 时，界面只输出 ASCII 字符
+static ASCII_MODE: AtomicBool = AtomicBool::new(false);
+
+fn ascii_mode() -> bool {
+    ASCII_MODE.load(Ordering::Relaxed)
+}
+
+/// 根据终端类型和 locale 判断终端能否正常显示 Unicode/emoji
+fn terminal_supports_unicode() -> bool {
+    if cfg!(target_os = "windows") {
+        return env::var("WT_SESSION").is_ok() || env::var("TERM_PROGRAM").is_ok();
+    }
+    if matches!(env::var("TERM").as_deref(), Ok("linux") | Ok("dumb")) {
+        return false;
+    }
+    // 与 setlocale 的优先级一致：LC_ALL > LC_CTYPE > LANG
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"]
+        .iter()
+        .filter_map(|name| env::var(name).ok())
+        .find(|value| !value.is_empty());
+    match locale {
+        Some(locale) => {
+            let locale = locale.to_lowercase();
+            locale.contains("utf-8") || locale.contains("utf8")
+        }
+        None => true,
+    }
+}
+
 fn get_ui_text(language: &str) -> &'static UiText {
-    match language {
-        "zh" => &UI_TEXT_ZH,
-        _ => &UI_TEXT_EN,
+    match (language, ascii_mode()) {
+        ("zh", false) => &UI_TEXT_ZH,
+        ("zh", true) => &UI_TEXT_ZH_ASCII,
+        (_, false) => &UI_TEXT_EN,
+        (_, true) => &UI_TEXT_EN_ASCII,
     }
 }
 
-fn get_prompt(language: &str) -> &'static str {
+/// 交互式提示使用的主题，ASCII 模式下替换掉所有 Unicode 符号，颜色跟随当前配色
+fn dialog_theme() -> ColorfulTheme {
+    let theme = ColorfulTheme::default();
+    let mut theme = if !ascii_mode() {
+        theme
+    } else {
+        ColorfulTheme {
+            prompt_suffix: style(">".to_string()).for_stderr().black().bright(),
+            success_prefix: style("+".to_string()).for_stderr().positive(),
+            success_suffix: style("-".to_string()).for_stderr().black().bright(),
+            error_prefix: style("x".to_string()).for_stderr().red(),
+            checked_item_prefix: style("[x]".to_string()).for_stderr().positive(),
+            unchecked_item_prefix: style("[ ]".to_string()).for_stderr().black(),
+            active_item_prefix: style(">".to_string()).for_stderr().positive(),
+            picked_item_prefix: style(">".to_string()).for_stderr().positive(),
+            ..theme
+        }
+    };
+    theme.defaults_style = theme.defaults_style.code();
+    theme.active_item_style = theme.active_item_style.code();
+    theme.values_style = theme.values_style.positive();
+    theme.prompt_prefix = theme.prompt_prefix.notice();
+    theme.success_prefix = theme.success_prefix.positive();
+    theme.checked_item_prefix = theme.checked_item_prefix.positive();
+    theme.active_item_prefix = theme.active_item_prefix.positive();
+    theme.picked_item_prefix = theme.picked_item_prefix.positive();
+    theme
+}
+
+fn builtin_prompt(language: &str) -> &'static str {
     match language {
         "zh" => PROMPT_ZH,
         _ => PROMPT_EN,
     }
 }
 
-fn is_dangerous_command(command: &str) -> bool {
-    DANGEROUS_COMMANDS
-        .iter()
-        .any(|dangerous| command.to_lowercase().contains(dangerous))
+/// `ask learn` 保存的系统提示，每种界面语言一个文件（例如 system_prompt.zh.txt），
+/// 切换语言后不会用到另一种语言的提示
+fn get_system_prompt_path(language: &str) -> Result<std::path::PathBuf> {
+    Ok(get_config_dir()?.join(format!("system_prompt.{}.txt", language)))
 }
 
-fn clean_command_output(command: &str) -> String {
-    let re = Regex::new(r"```(?:shell|bash)?\s*\n?([\s\S]*?)```").unwrap();
-    if let Some(captures) = re.captures(command) {
-        captures.get(1).unwrap().as_str().trim().to_string()
-    } else {
-        command.trim().to_string()
+/// 当前使用的系统提示：`ask learn` 为该语言保存的自定义提示优先，其次是旧版本不区分语言的
+/// system_prompt.txt，否则使用内置提示
+fn get_prompt(language: &str) -> Result<String> {
+    let legacy = get_config_dir()?.join("system_prompt.txt");
+    for path in [get_system_prompt_path(language)?, legacy] {
+        if path.is_file() {
+            return fs::read_to_string(&path)
+                .with_context(|| format!("Unable to read system prompt file {}", path.display()));
+        }
     }
+    Ok(builtin_prompt(language).to_string())
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct Config {
-    api: ApiConfig,
-    language: String,
+/// 读取 --system-prompt-file 指定的系统提示
+fn read_system_prompt_file(cli: &Cli) -> Result<Option<String>> {
+    cli.system_prompt_file
+        .as_ref()
+        .map(|path| {
+            fs::read_to_string(path)
+                .with_context(|| format!("Unable to read system prompt file {}", path.display()))
+        })
+        .transpose()
 }
 
-#[derive(serde::Deserialize, serde::Serialize)]
-struct ApiConfig {
-    base_url: String,
-    api_key: String,
-    model: String,
+/// 读取 --user-message-template 指定的模板
+fn read_user_message_template(cli: &Cli) -> Result<Option<String>> {
+    cli.user_message_template
+        .as_ref()
+        .map(|path| {
+            fs::read_to_string(path)
+                .with_context(|| format!("Unable to read user message template {}", path.display()))
+        })
+        .transpose()
 }
 
-fn get_system_language() -> String {
-    // Try to get system language from environment variables
-    let lang = env::var("LANG")
-        .or_else(|_| env::var("LC_ALL"))
-        .or_else(|_| env::var("LANGUAGE"))
-        .unwrap_or_else(|_| String::from("en_US.UTF-8"));
-
-    // Extract language code from format like "en_US.UTF-8"
-    if lang.starts_with("zh") {
-        "zh".to_string()
+/// 组装发送给模型的完整系统提示，优先级：--system-prompt-file > `ask learn` 保存的提示 > 内置提示，
+/// 之后依次追加 [prompt] 配置的偏好说明、--with-explanation 的格式要求和系统信息
+fn build_system_prompt(config: &Config, options: &RequestOptions) -> Result<String> {
+    let prompt = match &options.system_prompt {
+        Some(prompt) => prompt.clone(),
+        None => get_prompt(&config.language)?,
+    };
+    let rationale = if options.with_explanation {
+        match config.language.as_str() {
+            "zh" => RATIONALE_PROMPT_ZH,
+            _ => RATIONALE_PROMPT_EN,
+        }
     } else {
-        "en".to_string()
-    }
-}
-
-fn get_config_dir() -> Result<std::path::PathBuf> {
-    let home = dirs::home_dir().context("Unable to get home directory")?;
-    let config_dir = home.join(".askai");
-    if !config_dir.exists() {
-        fs::create_dir_all(&config_dir).context("Unable to create config directory")?;
-    }
-    Ok(config_dir)
-}
-
-fn get_config_path() -> Result<std::path::PathBuf> {
-    Ok(get_config_dir()?.join("config.toml"))
+        ""
+    };
+    let syntax = match options.syntax {
+        Some(shell) => match config.language.as_str() {
+            "zh" => SYNTAX_PROMPT_ZH,
+            _ => SYNTAX_PROMPT_EN,
+        }
+        .replace("{}", shell.label()),
+        None => String::new(),
+    };
+    let placeholders = match (options.placeholders, config.language.as_str()) {
+        (false, _) => "",
+        (true, "zh") => PLACEHOLDER_PROMPT_ZH,
+        (true, _) => PLACEHOLDER_PROMPT_EN,
+    };
+    Ok(format!(
+        "{}{}{}{}{}\n{}",
+        prompt,
+        get_prompt_extras(&config.language, &config.prompt),
+        rationale,
+        syntax,
+        placeholders,
+        get_system_info()
+    ))
 }
 
-fn load_config() -> Result<Config> {
-    let config_path = get_config_path()?;
-    if !config_path.exists() {
-        // Get system language as default
-        let default_language = get_system_language();
-        // Get UI text based on system language
-        let ui_text = get_ui_text(&default_language);
+/// 需求中包含 `{name}` 形式的参数时追加的说明，执行前由用户填写占位符的值
+const PLACEHOLDER_PROMPT_EN: &str =
+    "\nThe request contains parameters written as {name}. Keep each of them in the command as a named placeholder written as {{name}} instead of inventing a value. The placeholders are replaced with properly quoted values before the command runs, so do not put quotes around them.\n";
+const PLACEHOLDER_PROMPT_ZH: &str =
+    "\n需求中包含 {name} 形式的参数。请在命令中把它们保留为 {{name}} 形式的命名占位符，不要自行填写值。执行前占位符会被替换为正确加引号的值，因此不要在占位符外面加引号。\n";
 
-        println!("{}", style(ui_text.first_run_config).blue().bold());
-        println!();
+/// --syntax 追加的说明，优先于下面系统环境信息中的 Shell 类型
+const SYNTAX_PROMPT_EN: &str =
+    "\nWrite the command in {} syntax, regardless of the shell listed in the environment information below.\n";
+const SYNTAX_PROMPT_ZH: &str =
+    "\n无论下面的系统环境信息中是什么 Shell，都使用 {} 的语法编写命令。\n";
 
-        let base_url = dialoguer::Input::<String>::with_theme(&ColorfulTheme::default())
-            .with_prompt(ui_text.base_url_prompt)
-            .default(String::from("https://api.openai.com/v1"))
-            .interact()?;
+/// --with-explanation 要求模型在命令前附带的一行理由
+const RATIONALE_PROMPT_EN: &str =
+    "\nStart your reply with a single line `# why: <one sentence explaining why this command does what the user wants>`, followed by the command.\n";
+const RATIONALE_PROMPT_ZH: &str =
+    "\n回复的第一行写 `# why: <用一句话说明这条命令为什么能满足用户的需求>`，之后再写命令。\n";
 
-        let api_key = dialoguer::Password::with_theme(&ColorfulTheme::default())
-            .with_prompt(ui_text.api_key_prompt)
-            .interact()?;
+/// 取出回复中 `# why:` 开头的理由行，返回去掉该行后的回复和理由
+fn split_rationale(reply: &str) -> (String, Option<String>) {
+    let marker = Regex::new(r"^\s*#\s*(?i:why)\s*[:：]\s*(.+?)\s*$").unwrap();
+    let mut rationale = None;
+    let lines: Vec<&str> = reply
+        .lines()
+        .filter(|line| {
+            if rationale.is_none() {
+                if let Some(captures) = marker.captures(line) {
+                    rationale = Some(captures[1].to_string());
+                    return false;
+                }
+            }
+            true
+        })
+        .collect();
+    (lines.join("\n"), rationale)
+}
 
-        let model = dialoguer::Input::<String>::with_theme(&ColorfulTheme::default())
-            .with_prompt(ui_text.model_prompt)
-            .default(String::from("gpt-3.5-turbo"))
-            .interact()?;
-
-        let language = dialoguer::Input::<String>::with_theme(&ColorfulTheme::default())
-            .with_prompt(ui_text.language_prompt)
-            .default(default_language)
-            .interact()?;
-
-        let config = Config {
-            api: ApiConfig {
-                base_url,
-                api_key,
-                model,
-            },
-            language,
+/// 根据 [prompt] 配置生成追加到系统提示末尾的偏好说明
+fn get_prompt_extras(language: &str, prompt_config: &PromptConfig) -> String {
+    let mut extras = String::new();
+    if let Some(script_language) = &prompt_config.prefer_language {
+        let instruction = match language {
+            "zh" => "- 当需要编写脚本时，优先使用 {} 而不是 python，并相应调整上面的示例\n",
+            _ => "- When a script is needed, prefer {} over Python and adapt the example above accordingly\n",
         };
-
-        save_config(&config)?;
-        println!("{}", style(ui_text.config_saved).green().bold());
-        return Ok(config);
+        extras.push_str(&instruction.replace("{}", script_language));
     }
-    let config_str = fs::read_to_string(&config_path).context("Unable to read config file")?;
+    if prompt_config.no_python {
+        extras.push_str(match language {
+            "zh" => "- 不要使用 python，改用 shell 或其他脚本语言实现\n",
+            _ => "- Never use Python; use shell or another scripting language instead\n",
+        });
+    }
+    if extras.is_empty() {
+        return extras;
+    }
+    let heading = match language {
+        "zh" => "\n用户偏好：\n",
+        _ => "\nUser preferences:\n",
+    };
+    format!("{}{}", heading, extras)
+}
 
-    // 尝试解析配置文件，如果失败可能是旧版本配置缺少language字段
-    match toml::from_str::<Config>(&config_str) {
-        Ok(config) => Ok(config),
-        Err(_) => {
-            // 尝试解析为不包含language字段的旧版本配置
-            #[derive(serde::Deserialize)]
-            struct OldConfig {
-                api: ApiConfig,
-            }
-
-            let old_config: OldConfig =
-                toml::from_str(&config_str).context("Unable to parse config file")?;
-
-            // 获取系统默认语言
-            let default_language = get_system_language();
-            // 获取对应语言的UI文本
-            let ui_text = get_ui_text(&default_language);
-
-            // 提示用户选择语言
-            println!("{}", style("需要设置语言偏好").blue().bold());
-            let language = dialoguer::Input::<String>::with_theme(&ColorfulTheme::default())
-                .with_prompt(ui_text.language_prompt)
-                .default(default_language)
-                .interact()?;
+const SHELL_BUILTINS: [&str; 52] = [
+    ".", ":", "[", "[[", "alias", "bg", "break", "case", "cd", "command", "continue", "declare",
+    "do", "done", "echo", "elif", "else", "esac", "eval", "exec", "exit", "export", "false", "fg",
+    "fi", "for", "function", "getopts", "hash", "history", "if", "jobs", "kill", "let", "local",
+    "printf", "pwd", "read", "readonly", "return", "set", "shift", "source", "test", "then",
+    "trap", "true", "type", "ulimit", "umask", "unset", "while",
+];
 
-            // 创建新的配置并保存
-            let config = Config {
-                api: old_config.api,
-                language,
-            };
+/// 在 PATH 中查找可执行程序，效果等同于 `which`
+fn find_in_path(program: &str) -> bool {
+    if program.contains(std::path::MAIN_SEPARATOR) || program.contains('/') {
+        return std::path::Path::new(program).is_file();
+    }
+    let Some(paths) = env::var_os("PATH") else {
+        return false;
+    };
+    let extensions: Vec<String> = if cfg!(target_os = "windows") {
+        env::var("PATHEXT")
+            .unwrap_or_else(|_| String::from(".EXE;.CMD;.BAT;.COM"))
+            .split(';')
+            .map(|ext| ext.to_lowercase())
+            .collect()
+    } else {
+        vec![String::new()]
+    };
+    env::split_paths(&paths).any(|dir| {
+        extensions
+            .iter()
+            .any(|ext| dir.join(format!("{}{}", program, ext)).is_file())
+    })
+}
 
-            save_config(&config)?;
-            println!("{}", style(ui_text.config_saved).green().bold());
+/// --detect-tools 检测的常用工具，模型经常会用到但不一定安装了
+const DETECTED_TOOLS: [&str; 36] = [
+    "jq", "yq", "rg", "fd", "fzf", "bat", "eza", "tree", "curl", "wget", "git", "gh", "rsync",
+    "zip", "unzip", "7z", "tar", "ffmpeg", "convert", "python3", "python", "node", "npm", "deno",
+    "go", "cargo", "docker", "podman", "kubectl", "brew", "apt", "dnf", "yum", "pacman", "winget",
+    "choco",
+];
 
-            Ok(config)
+/// 检测常用工具是否存在于 PATH 中，生成告诉模型哪些工具可用的说明
+fn tool_availability(language: &str) -> String {
+    let (available, missing): (Vec<&str>, Vec<&str>) =
+        DETECTED_TOOLS.iter().partition(|tool| find_in_path(tool));
+    let join = |tools: Vec<&str>| {
+        if tools.is_empty() {
+            String::from("-")
+        } else {
+            tools.join(", ")
         }
+    };
+    let (available, missing) = (join(available), join(missing));
+    match language {
+        "zh" => format!(
+            "本机已安装的工具：{}\n本机未安装（不要使用）的工具：{}",
+            available, missing
+        ),
+        _ => format!(
+            "Available tools: {}\nNOT available (do not use): {}",
+            available, missing
+        ),
     }
 }
 
-fn save_config(config: &Config) -> Result<()> {
-    let config_path = get_config_path()?;
-    let config_str = toml::to_string_pretty(config).context("Unable to serialize config")?;
-    fs::write(&config_path, config_str).context("Unable to save config file")?;
-    Ok(())
+/// --show-diff-since 附带的 diff 的字符数上限
+const GIT_DIFF_MAX_CHARS: usize = 2000;
+
+/// 从 `since` 到 HEAD 的改动统计和 diff，超过上限时截断
+fn git_diff_context(language: &str, since: &str) -> Result<String> {
+    let output = Command::new("git")
+        // --end-of-options 防止以 - 开头的参数被当作 git 的选项
+        .args([
+            "diff",
+            "--stat",
+            "--unified=3",
+            "--end-of-options",
+            since,
+            "HEAD",
+            "--",
+        ])
+        .output()
+        .context("Failed to run git diff")?;
+    if !output.status.success() {
+        return Err(anyhow::anyhow!(
+            "git diff {} HEAD: {}",
+            since,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let mut diff = String::from_utf8_lossy(&output.stdout)
+        .trim_end()
+        .to_string();
+    if diff.chars().count() > GIT_DIFF_MAX_CHARS {
+        diff = diff.chars().take(GIT_DIFF_MAX_CHARS).collect();
+        diff.push_str("\n...");
+    }
+    if diff.is_empty() {
+        diff.push('-');
+    }
+    Ok(match language {
+        "zh" => format!("自 {} 以来代码的改动（git diff）：\n{}", since, diff),
+        _ => format!("Code changes since {} (git diff):\n{}", since, diff),
+    })
 }
 
-fn set_config(config_type: &str, config_value: &str) -> Result<()> {
-    let mut config = if let Ok(existing_config) = load_config() {
-        existing_config
-    } else {
-        Config {
-            api: ApiConfig {
-                base_url: String::from("https://api.openai.com/v1"),
-                api_key: String::new(),
-                model: String::from("gpt-3.5-turbo"),
-            },
-            language: String::from("en"),
-        }
-    };
+/// 执行记录中的需求与本次需求的相似度至少达到该值，才作为 --with-last-success 的示例
+const LAST_SUCCESS_MIN_SIMILARITY: f64 = 0.3;
 
-    let parts: Vec<&str> = config_value.split('=').collect();
-    if parts.len() != 2 {
-        return Err(anyhow::anyhow!("配置格式错误，应为 key=value"));
+/// 把需求拆成小写的词，中文按单个汉字拆分
+fn prompt_words(prompt: &str) -> std::collections::HashSet<String> {
+    let mut words = std::collections::HashSet::new();
+    let mut word = String::new();
+    for c in prompt.chars().flat_map(char::to_lowercase) {
+        if ('\u{4e00}'..='\u{9fff}').contains(&c) {
+            words.extend((!word.is_empty()).then(|| std::mem::take(&mut word)));
+            words.insert(c.to_string());
+        } else if c.is_alphanumeric() {
+            word.push(c);
+        } else if !word.is_empty() {
+            words.insert(std::mem::take(&mut word));
+        }
     }
+    if !word.is_empty() {
+        words.insert(word);
+    }
+    words
+}
 
-    let key = parts[0];
-    let value = parts[1];
-
-    match config_type {
-        "config" => match key {
-            "base_url" => config.api.base_url = value.to_string(),
-            "api_key" => config.api.api_key = value.to_string(),
-            "model" => config.api.model = value.to_string(),
-            "language" => config.language = value.to_string(),
-            _ => return Err(anyhow::anyhow!("未知的配置项: {}", key)),
-        },
-        _ => return Err(anyhow::anyhow!("未知的配置类型: {}", config_type)),
+/// 两个需求的相似度（词集合的 Jaccard 系数，0 到 1）
+fn prompt_similarity(left: &str, right: &str) -> f64 {
+    let (left, right) = (prompt_words(left), prompt_words(right));
+    let union = left.union(&right).count();
+    if union == 0 {
+        return 0.0;
     }
+    left.intersection(&right).count() as f64 / union as f64
+}
 
-    save_config(&config)?;
-    println!("{}", style("Configuration updated").green().bold());
-    Ok(())
+/// 执行记录中需求与 `prompt` 相似、最近一次执行成功且用户确认达到了目标的记录。
+/// 缺少程序时自动执行的安装命令以及稍后再回答的记录没有确认过，不会被选中
+fn last_success(prompt: &str) -> Result<Option<HistoryEntry>> {
+    Ok(load_history()?.into_iter().rev().find(|entry| {
+        entry.success
+            && entry.goal_achieved == Some(true)
+            && prompt_similarity(&entry.prompt, prompt) >= LAST_SUCCESS_MIN_SIMILARITY
+    }))
 }
 
-async fn get_ai_response(
-    prompt: &str,
-    history: Option<&ExecutionHistory>,
-    debug: bool,
-) -> Result<String> {
-    let client = Client::new();
-    let config = load_config()?;
-    let base_url = &config.api.base_url;
-    let api_key = &config.api.api_key;
-    let model = &config.api.model;
-
-    let system_info = get_system_info();
-    let full_prompt = format!(
-        "{}
-{}",
-        get_prompt(&config.language),
-        system_info
-    );
-    let user_prompt = match history {
-        Some(h) => format!(
-            "用户的问题为：{}
-上一次执行的命令是：{}
-执行结果是：{}
-执行是否成功：{}
-这是第{}次尝试。
-请根据上述信息分析执行结果，判断是否达到预期目标，如果没有达到目标，分析原因并生成改进的命令。",
-            prompt, h.command, h.output, h.success, h.attempt
+/// 把之前成功的命令作为正面示例告诉模型，与重试时附带的失败记录相互独立
+fn last_success_context(language: &str, entry: &HistoryEntry) -> String {
+    match language {
+        "zh" => format!(
+            "之前一个相似的需求“{}”使用下面的命令成功完成，适用时请沿用相同的写法和约定：\n{}",
+            entry.prompt, entry.command
         ),
-        None => format!(
-            "现在，用户的问题为：{}，请你根据用户的问题生成对应的shell命令来实现用户的需求。",
-            prompt
+        _ => format!(
+            "This command worked for a similar earlier request \"{}\"; follow the same patterns and conventions where they apply:\n{}",
+            entry.prompt, entry.command
         ),
-    };
+    }
+}
 
-    if debug {
-        println!("{}", style("🔍 调试信息：").blue().bold());
-        println!("{}", style("系统提示：").blue());
-        println!("{}", full_prompt);
-        println!("{}", style("用户提示：").blue());
-        println!("{}", user_prompt);
-        println!();
+/// 会把 heredoc 内容当作脚本执行的程序
+const SCRIPT_RUNNERS: [&str; 8] = ["sh", "bash", "zsh", "dash", "ksh", "ash", "fish", "ssh"];
+
+/// 命令中每一行的类型
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum LineKind {
+    /// 普通的命令行（包括 heredoc 的起始行）
+    Command,
+    /// heredoc 的内容，只是数据（例如写入文件）
+    HeredocData,
+    /// heredoc 的内容，会被交给 shell 执行（例如 `bash <<EOF`）
+    HeredocScript,
+    /// heredoc 的结束标记
+    HeredocEnd,
+}
+
+/// 按照 shell 的规则标记每一行是否属于 heredoc：支持 `<<-` 去除制表符、带引号的结束标记
+/// 以及同一行中的多个 heredoc，不会把 `<<<` here-string 当作 heredoc
+fn classify_lines(lines: &[&str]) -> Vec<LineKind> {
+    let heredoc =
+        Regex::new(r#"(?:^|[^<])<<(-?)[ \t]*(?:'([^']+)'|"([^"]+)"|\\?([A-Za-z_][\w.-]*))"#)
+            .unwrap();
+    let mut kinds = Vec::with_capacity(lines.len());
+    let mut index = 0;
+    while index < lines.len() {
+        let line = lines[index];
+        kinds.push(LineKind::Command);
+        index += 1;
+
+        let body_kind = if line_programs(line)
+            .iter()
+            .any(|program| SCRIPT_RUNNERS.contains(&program.as_str()))
+        {
+            LineKind::HeredocScript
+        } else {
+            LineKind::HeredocData
+        };
+        // 注释中的 `<<` 不会开始 heredoc
+        for captures in heredoc.captures_iter(split_comment(line).0) {
+            let strip_tabs = &captures[1] == "-";
+            let end = (2..=4)
+                .find_map(|group| captures.get(group))
+                .map(|end| end.as_str())
+                .unwrap_or_default();
+            while index < lines.len() {
+                let candidate = if strip_tabs {
+                    lines[index].trim_start_matches('\t')
+                } else {
+                    lines[index]
+                };
+                index += 1;
+                if candidate == end {
+                    kinds.push(LineKind::HeredocEnd);
+                    break;
+                }
+                kinds.push(body_kind);
+            }
+        }
     }
+    kinds
+}
 
-    let response = client
-        .post(&format!("{}/chat/completions", base_url))
-        .header("Authorization", format!("Bearer {}", api_key))
-        .json(&json!({
-            "model": model,
-            "messages": [
-                {
-                    "role": "system",
-                    "content": full_prompt,
-                },
+/// 按照 shell 的规则把一行拆成代码和行尾注释：`#` 只有出现在单词开头且不在引号内时才开始注释，
+/// 因此 `$#`、`${#var}`、`a#b` 和 `'#'` 都不会被当作注释
+fn split_comment(line: &str) -> (&str, Option<&str>) {
+    let mut quote = None;
+    let mut escaped = false;
+    let mut previous = None;
+    for (index, c) in line.char_indices() {
+        if escaped {
+            // 转义的空白不会结束单词，`a\ #b` 中的 `#` 不是注释
+            escaped = false;
+            previous = Some('\\');
+            continue;
+        } else if let Some(open) = quote {
+            if c == open {
+                quote = None;
+            } else if c == '\\' && open == '"' {
+                escaped = true;
+            }
+        } else {
+            match c {
+                '\\' => escaped = true,
+                '\'' | '"' => quote = Some(c),
+                '#' if previous
+                    .map(|p: char| p.is_whitespace() || ";&|()".contains(p))
+                    .unwrap_or(true) =>
                 {
-                    "role": "user",
-                    "content": user_prompt,
+                    return (&line[..index], Some(&line[index..]));
                 }
-            ]
-        }))
-        .send()
-        .await
-        .context("Failed to send request")?;
+                _ => {}
+            }
+        }
+        previous = Some(c);
+    }
+    (line, None)
+}
 
-    let response_json: Value = response.json().await.context("Failed to parse response")?;
-    let command = response_json["choices"][0]["message"]["content"]
-        .as_str()
-        .context("Failed to get command from response")?;
+/// 提取一行命令中每一段（以 `&&`、`||`、`|`、`;` 分隔）实际调用的程序名，
+/// 跳过变量赋值和 `sudo` 前缀
+fn line_programs(line: &str) -> Vec<String> {
+    let separators = Regex::new(r"&&|\|\||\||;").unwrap();
+    let mut programs: Vec<String> = Vec::new();
+    for segment in separators.split(split_comment(line).0) {
+        let program = segment
+            .split_whitespace()
+            .map(|token| token.trim_start_matches(['(', '{', '!']))
+            .find(|token| !token.is_empty() && !token.contains('=') && *token != "sudo");
+        if let Some(program) = program {
+            if !programs.iter().any(|p| p == program) {
+                programs.push(program.to_string());
+            }
+        }
+    }
+    programs
+}
 
-    Ok(clean_command_output(command))
+/// 提取命令中实际调用的程序名，跳过 heredoc 的内容
+fn invoked_programs(command: &str) -> Vec<String> {
+    let lines: Vec<&str> = command.lines().collect();
+    let mut programs: Vec<String> = Vec::new();
+    for (line, kind) in lines.iter().zip(classify_lines(&lines)) {
+        if kind != LineKind::Command {
+            continue;
+        }
+        for program in line_programs(line) {
+            if !programs.contains(&program) {
+                programs.push(program);
+            }
+        }
+    }
+    programs
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let cli = Cli::parse();
+/// heredoc 起始行把内容写入的文件（`> file`、`>> file`、`| tee file`），
+/// 内容没有写入文件（例如只是输出或交给其他程序）时返回空列表
+fn heredoc_targets(line: &str) -> Vec<String> {
+    let redirect = Regex::new(r#"(?:^|[^0-9<>&])>>?\s*([^\s;&|<>]+)"#).unwrap();
+    let tee = Regex::new(r#"\btee\s+((?:-\S+\s+)*)([^\s;&|<>]+)"#).unwrap();
+    let code = split_comment(line).0;
+    redirect
+        .captures_iter(code)
+        .map(|captures| captures[1].to_string())
+        .chain(
+            tee.captures_iter(code)
+                .map(|captures| captures[2].to_string()),
+        )
+        .map(|target| target.trim_matches(['\'', '"']).to_string())
+        .filter(|target| !target.is_empty() && !target.starts_with("/dev/"))
+        .collect()
+}
 
-    if let Some(Commands::Set {
-        config_type,
-        config_value,
-    }) = cli.command
-    {
-        return set_config(&config_type, &config_value);
+/// 危险检测需要分析的文本。heredoc 的内容只有在写入文件、且之后的命令没有再用到该文件时
+/// 才视为数据跳过；交给 shell 执行、内容去向不明或写入的文件之后会被执行（例如
+/// `cat <<EOF > x.sh` 之后 `bash x.sh`）时都保留
+fn executable_text(command: &str) -> String {
+    let lines: Vec<&str> = command.lines().collect();
+    let kinds = classify_lines(&lines);
+    let mut skipped = vec![false; lines.len()];
+    let mut start = 0;
+    for (index, kind) in kinds.iter().enumerate() {
+        match kind {
+            LineKind::Command => start = index,
+            LineKind::HeredocData => {
+                let targets = heredoc_targets(lines[start]);
+                let used_later = lines
+                    .iter()
+                    .zip(&kinds)
+                    .skip(index + 1)
+                    .filter(|(_, kind)| **kind == LineKind::Command)
+                    .any(|(line, _)| {
+                        targets.iter().any(|target| {
+                            let name = target.rsplit('/').next().unwrap_or(target);
+                            line.contains(name)
+                        })
+                    });
+                skipped[index] = !targets.is_empty() && !used_later;
+            }
+            _ => {}
+        }
     }
+    lines
+        .iter()
+        .zip(skipped)
+        .filter(|(_, skipped)| !skipped)
+        .map(|(line, _)| *line)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-    let prompt = cli
-        .prompt
-        .ok_or_else(|| anyhow::anyhow!("请提供操作描述"))?;
-    let term = Term::stdout();
-    let mut history: Option<ExecutionHistory> = None;
-    let max_attempts = 3;
-    let config = load_config()?;
-    let ui_text = get_ui_text(&config.language);
+/// generalize_paths 的结果：每种替换（原路径、替换为、次数）以及因为可能改变命令含义而保留原样的次数
+struct GeneralizedPaths {
+    command: String,
+    substitutions: Vec<(String, String, usize)>,
+    kept: usize,
+}
 
-    let mut attempt = 1;
-    while attempt <= max_attempts {
-        term.write_line(&format!("{}", style(ui_text.thinking).blue()))?;
-        let command = get_ai_response(prompt.as_str(), history.as_ref(), cli.debug).await?;
+/// 把命令中的主目录替换为 `~`、当前目录替换为 `.`，只替换不会改变命令含义的位置：
+/// - 不在引号内且位于单词开头时替换为 `~` / `.`；在其他位置（例如 `host:/path`、`--dir=/path`）保持原样
+/// - 双引号内 `~` 不会展开、`.` 可能是正则表达式的一部分，因此替换为同样会展开的 `$HOME` / `$PWD`
+/// - 单引号内（sed、awk 的脚本等）和 heredoc 的内容原样保留
+/// - 命令中有 cd / pushd 时，之后的相对路径会指向别处，因此不替换当前目录
+/// - 命令中有 ssh、docker 等程序时，路径可能属于远程主机或容器（例如 `docker -v` 的挂载路径），
+///   因此全部保持原样
+///
+/// `~` 和 `$HOME` 是 POSIX shell 的写法，调用方只应在命令交给 POSIX shell 执行时使用
+fn generalize_paths(command: &str, home: &str, cwd: &str) -> GeneralizedPaths {
+    const REMOTE_PROGRAMS: [&str; 5] = ["ssh", "docker", "podman", "kubectl", "lxc"];
 
-        term.write_line("")?;
-        term.write_line(&format!(
-            "{}",
-            style(ui_text.generated_command).blue().bold()
-        ))?;
-        term.write_line(&format!("{}", style(&command).cyan()))?;
-        term.write_line("")?;
+    let lines: Vec<&str> = command.lines().collect();
+    let programs = invoked_programs(command);
+    let changes_directory = programs
+        .iter()
+        .any(|program| program == "cd" || program == "pushd");
+    let remote = programs.iter().any(|program| {
+        let name = program.rsplit('/').next().unwrap_or(program);
+        REMOTE_PROGRAMS.contains(&name)
+    });
+    // (路径, 不在引号内时的替换, 双引号内的替换)，较长的路径优先匹配
+    let mut targets: Vec<(&str, &str, &str)> = Vec::new();
+    if cwd.len() > 1 && cwd != home {
+        targets.push((cwd, ".", "$PWD"));
+    }
+    if home.len() > 1 {
+        targets.push((home, "~", "$HOME"));
+    }
+    targets.sort_by_key(|(path, _, _)| std::cmp::Reverse(path.len()));
 
-        if is_dangerous_command(&command) {
-            term.write_line(&format!(
-                "{}",
-                style(ui_text.dangerous_command_warning).red().bold()
-            ))?;
-            return Ok(());
-        }
+    // 返回从 rest 开头匹配的路径，路径之后必须是 `/` 或者路径的结尾，`/home/user2` 不会匹配 `/home/user`
+    let match_at = |rest: &str| {
+        targets.iter().copied().find(|(path, _, _)| {
+            rest.strip_prefix(path)
+                .map(|after| {
+                    after
+                        .chars()
+                        .next()
+                        .map(|c| c == '/' || !(c.is_alphanumeric() || "._-".contains(c)))
+                        .unwrap_or(true)
+                })
+                .unwrap_or(false)
+        })
+    };
 
-        if !cli.dry_run {
-            if Confirm::with_theme(&ColorfulTheme::default())
-                .with_prompt(ui_text.execute_command_prompt)
-                .default(false)
-                .interact()?
-            {
-                term.write_line("")?;
-                term.write_line(&format!("{}", style(ui_text.executing_command).yellow()))?;
-
-                #[cfg(target_os = "windows")]
-                let output = Command::new("cmd")
-                    .args(["/C", &command])
-                    .output()
-                    .context("Failed to execute command")?;
-
-                #[cfg(not(target_os = "windows"))]
-                let output = Command::new("sh")
-                    .arg("-c")
-                    .arg(&command)
-                    .output()
-                    .context("Failed to execute command")?;
-
-                let success = output.status.success();
-                let output_text = if success {
-                    String::from_utf8_lossy(&output.stdout).to_string()
-                } else {
-                    String::from_utf8_lossy(&output.stderr).to_string()
+    let mut substitutions: Vec<(String, String, usize)> = Vec::new();
+    let mut kept = 0;
+    let mut quote: Option<char> = None;
+    let mut result: Vec<String> = Vec::with_capacity(lines.len());
+    for (line, kind) in lines.iter().zip(classify_lines(&lines)) {
+        // heredoc 的内容只统计不替换
+        let editable = kind == LineKind::Command;
+        let mut output = String::with_capacity(line.len());
+        let mut escaped = false;
+        let mut previous: Option<char> = None;
+        let mut index = 0;
+        while index < line.len() {
+            let rest = &line[index..];
+            if let (false, Some((path, unquoted, quoted))) = (escaped, match_at(rest)) {
+                let word_start = previous
+                    .map(|p| p.is_whitespace() || ";&|(".contains(p))
+                    .unwrap_or(true);
+                let replacement = match quote {
+                    _ if !editable || remote || (path == cwd && changes_directory) => None,
+                    Some('"') => Some(quoted),
+                    None if word_start => Some(unquoted),
+                    _ => None,
                 };
-
-                if success {
-                    term.write_line(&format!("{}", style(ui_text.command_success).green()))?;
-                } else {
-                    term.write_line(&format!("{}", style(ui_text.command_failure).red()))?;
+                match replacement {
+                    Some(replacement) => {
+                        output.push_str(replacement);
+                        match substitutions
+                            .iter_mut()
+                            .find(|(from, to, _)| from == path && to == replacement)
+                        {
+                            Some((_, _, count)) => *count += 1,
+                            None => {
+                                substitutions.push((path.to_string(), replacement.to_string(), 1))
+                            }
+                        }
+                    }
+                    None => {
+                        output.push_str(path);
+                        kept += 1;
+                    }
                 }
+                index += path.len();
+                previous = path.chars().last();
+                continue;
+            }
 
-                if !output_text.is_empty() {
-                    term.write_line("")?;
-                    term.write_line(&output_text)?;
+            let c = rest.chars().next().unwrap_or_default();
+            if !editable {
+                // heredoc 的内容不影响引号的状态
+            } else if escaped {
+                escaped = false;
+            } else if let Some(open) = quote {
+                if c == open {
+                    quote = None;
+                } else if c == '\\' && open == '"' {
+                    escaped = true;
                 }
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '\'' || c == '"' {
+                quote = Some(c);
+            }
+            output.push(c);
+            previous = Some(c);
+            index += c.len_utf8();
+        }
+        result.push(output);
+    }
 
-                if success {
-                    if !Confirm::with_theme(&ColorfulTheme::default())
-                        .with_prompt(ui_text.goal_achieved_prompt)
-                        .default(true)
-                        .interact()?
-                    {
-                        history = Some(ExecutionHistory {
-                            command,
-                            output: output_text,
-                            success,
-                            attempt,
-                        });
-                        attempt += 1;
-                        continue;
-                    }
-                    return Ok(());
+    GeneralizedPaths {
+        command: result.join("\n"),
+        substitutions,
+        kept,
+    }
+}
+
+/// 显示 privacy.generalize_paths 做了哪些替换，以及有多少处路径保持原样
+fn show_generalized_paths(
+    term: &UiTerm,
+    ui_text: &UiText,
+    result: &GeneralizedPaths,
+) -> Result<()> {
+    for (from, to, count) in &result.substitutions {
+        output::note(
+            term,
+            &ui_text
+                .path_generalized
+                .replacen("{}", from, 1)
+                .replacen("{}", to, 1)
+                .replacen("{}", &count.to_string(), 1),
+        )?;
+    }
+    if result.kept > 0 {
+        output::warning(
+            term,
+            &ui_text.path_kept.replace("{}", &result.kept.to_string()),
+        )?;
+    }
+    Ok(())
+}
+
+/// 为显示命令添加样式，heredoc 的内容使用不同的颜色，注释显示为暗色
+fn styled_command(command: &str) -> String {
+    let lines: Vec<&str> = command.lines().collect();
+    lines
+        .iter()
+        .zip(classify_lines(&lines))
+        .map(|(line, kind)| match kind {
+            LineKind::HeredocData => style(line).positive().to_string(),
+            LineKind::HeredocScript => style(line).notice().to_string(),
+            LineKind::Command => match split_comment(line) {
+                (code, Some(comment)) => {
+                    format!("{}{}", styled_code(code), style(comment).secondary())
                 }
+                (_, None) => styled_code(line),
+            },
+            LineKind::HeredocEnd => style(line).code().to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
 
-                history = Some(ExecutionHistory {
-                    command,
-                    output: output_text,
-                    success,
-                    attempt,
-                });
-                attempt += 1;
-                continue;
+/// 命令本身显示为青色，其中的占位符加粗显示为洋红色
+fn styled_code(code: &str) -> String {
+    let mut styled = String::new();
+    let mut last = 0;
+    for placeholder in placeholder_regex().find_iter(code) {
+        styled.push_str(&style(&code[last..placeholder.start()]).code().to_string());
+        styled.push_str(&style(placeholder.as_str()).magenta().bold().to_string());
+        last = placeholder.end();
+    }
+    styled.push_str(&style(&code[last..]).code().to_string());
+    styled
+}
+
+/// 返回命令中既不是 shell 内建命令、也不在 PATH 中的程序
+fn find_missing_programs(command: &str) -> Vec<String> {
+    invoked_programs(command)
+        .into_iter()
+        .filter(|program| !SHELL_BUILTINS.contains(&program.as_str()) && !find_in_path(program))
+        .collect()
+}
+
+/// 命令执行失败的原因是找不到某个程序时返回该程序名：根据 bash、dash、zsh 的
+/// "command not found" 提示识别，退出码为 127 但没有提示时取命令中第一个不在 PATH 中的程序
+fn missing_program_from_failure(command: &str, exit_code: i32, stderr: &str) -> Option<String> {
+    let missing = find_missing_programs(command);
+    let re = Regex::new(
+        r"(?m)(?:command not found: ([^\s:]+)\s*$|([^\s:]+): (?:command )?not found\s*$)",
+    )
+    .unwrap();
+    let reported = re
+        .captures_iter(stderr)
+        .filter_map(|captures| captures.get(1).or_else(|| captures.get(2)))
+        .map(|program| program.as_str().to_string())
+        .find(|program| missing.contains(program));
+    reported.or_else(|| missing.first().filter(|_| exit_code == 127).cloned())
+}
+
+/// 支持的包管理器：程序名、安装前需要先执行的更新软件包索引的命令、安装命令以及
+/// 是否需要 root 权限，按检测顺序排列
+const PACKAGE_MANAGERS: [(&str, Option<&str>, &str, bool); 9] = [
+    ("brew", None, "brew install", false),
+    // 容器镜像等环境中 apt 的软件包索引经常是空的或过期的，直接安装会找不到软件包
+    (
+        "apt-get",
+        Some("apt-get update"),
+        "apt-get install -y",
+        true,
+    ),
+    ("dnf", None, "dnf install -y", true),
+    ("yum", None, "yum install -y", true),
+    ("pacman", None, "pacman -S --noconfirm", true),
+    ("zypper", None, "zypper install -y", true),
+    ("apk", None, "apk add", true),
+    ("scoop", None, "scoop install", false),
+    ("choco", None, "choco install -y", false),
+];
+
+/// 程序名与软件包名不同的常见工具，以及使用这个软件包名的包管理器（空表示全部）
+const PACKAGE_NAMES: [(&str, &str, &[&str]); 4] = [
+    ("rg", "ripgrep", &[]),
+    ("convert", "imagemagick", &[]),
+    ("7z", "p7zip", &[]),
+    // Debian、Ubuntu 和 Fedora 中 fd 已被其他软件包占用，其他发行版和 Homebrew 中就叫 fd
+    ("fd", "fd-find", &["apt-get", "dnf", "yum"]),
+];
+
+/// 用检测到的包管理器生成安装 `program` 的命令，找不到包管理器时返回 None。
+/// 需要 root 权限而当前用户不是 root 时命令以 sudo 开头，第二个返回值为 true
+fn install_command_for(program: &str) -> Option<(String, bool)> {
+    let manager = PACKAGE_MANAGERS
+        .iter()
+        .find(|(manager, _, _, _)| find_in_path(manager))?;
+    #[cfg(unix)]
+    // SAFETY：geteuid 没有参数，总是成功，也不会读写任何内存
+    let is_root = unsafe { libc::geteuid() } == 0;
+    #[cfg(not(unix))]
+    let is_root = false;
+    Some(package_install_command(*manager, program, is_root))
+}
+
+/// 用指定的包管理器安装 `program` 的命令，以及命令是否以 sudo 执行
+fn package_install_command(
+    (manager, refresh, install, needs_root): (&str, Option<&str>, &str, bool),
+    program: &str,
+    is_root: bool,
+) -> (String, bool) {
+    let package = PACKAGE_NAMES
+        .iter()
+        .find(|(name, _, managers)| {
+            *name == program && (managers.is_empty() || managers.contains(&manager))
+        })
+        .map_or(program, |(_, package, _)| package);
+    let sudo = needs_root && !is_root;
+    let prefix = if sudo { "sudo " } else { "" };
+    let install = format!("{}{} {}", prefix, install, package);
+    let command = match refresh {
+        Some(refresh) => format!("{}{} && {}", prefix, refresh, install),
+        None => install,
+    };
+    (command, sudo)
+}
+
+/// 返回命令命中的第一个危险模式（内置列表优先，其次是用户配置的模式）。
+/// 内置模式只在命令或单词的边界处匹配（`dd` 不会匹配 `git add`），用户配置的模式按子串匹配。
+/// 只作为数据的 heredoc 内容不参与检测
+fn find_dangerous_pattern(command: &str, extra_patterns: &[String]) -> Option<String> {
+    let command = executable_text(command).to_lowercase();
+    DANGEROUS_COMMANDS
+        .iter()
+        .find(|pattern| builtin_pattern_regex(pattern).is_match(&command))
+        .map(|pattern| pattern.to_string())
+        .or_else(|| {
+            extra_patterns
+                .iter()
+                .find(|pattern| command.contains(&pattern.to_lowercase()))
+                .cloned()
+        })
+}
+
+/// 内置危险模式对应的正则表达式：以字母或数字开头的模式前面不能紧挨着单词字符，
+/// 单个单词的模式后面也不能紧挨着单词字符（`mkfs.ext4` 仍然匹配 `mkfs`，`ddrescue` 不匹配 `dd`）
+fn builtin_pattern_regex(pattern: &str) -> Regex {
+    let word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric());
+    let mut re = regex::escape(&pattern.to_lowercase());
+    if word(pattern.chars().next()) {
+        re = format!("(?:^|[^a-z0-9_.-]){}", re);
+    }
+    if word(pattern.chars().last()) && !pattern.contains(char::is_whitespace) {
+        re = format!("{}(?:$|[^a-z0-9_-])", re);
+    }
+    Regex::new(&re).unwrap()
+}
+
+/// 返回命令中把管道交给 shell 执行的部分（例如 `| sudo bash`）。
+/// 这类命令会直接执行下载或临时生成的脚本，无法事先审阅，因此视为高风险；
+/// 注释和 heredoc 内容不参与检测
+fn find_pipe_to_shell(command: &str) -> Option<String> {
+    // `||` 不算管道；shell 前面可以有 sudo（及其选项，包括 `-u root` 这样带值的）或 env 赋值，
+    // shell 可以写成任意路径。引号内的 `|` 只是普通字符，先把引号内的内容去掉再查找
+    let re = Regex::new(
+        r"(?:^|[^|\\])\|\s*((?:sudo(?:\s+(?:-[ugCDhpRrTU]\s+[^\s-]\S*|-\S+))*\s+)?(?:env(?:\s+-\S+)*\s+(?:\w+=\S*\s+)*)?(?:[^\s|;&]*/)?(?:sh|bash|zsh|dash|ksh|fish))(?:$|[\s;&|)])",
+    )
+    .unwrap();
+    executable_text(command).lines().find_map(|line| {
+        let code = mask_quoted(split_comment(line).0);
+        re.captures(&code)
+            .map(|captures| format!("| {}", &captures[1]))
+    })
+}
+
+/// 内置危险模式的风险说明（英文、中文）
+const DANGER_EXPLANATIONS: [(&str, &str, &str); 6] = [
+    (
+        "rm -rf",
+        "rm -rf deletes files and directories recursively and without asking for confirmation; a wrong path can wipe out data that cannot be recovered",
+        "rm -rf 会递归并强制删除文件和目录，不会请求确认；路径写错可能删除无法恢复的数据",
+    ),
+    (
+        "mkfs",
+        "mkfs creates a new file system on a device, erasing everything stored on it",
+        "mkfs 会在设备上创建新的文件系统，设备上原有的所有数据都会被清除",
+    ),
+    (
+        "dd",
+        "dd copies raw bytes between files and devices; writing to the wrong device overwrites disks or partitions without any check",
+        "dd 会在文件和设备之间直接复制原始数据，写错设备会不经任何检查地覆盖磁盘或分区",
+    ),
+    (
+        "> /dev/",
+        "Redirecting output to a device file can overwrite a disk or corrupt hardware state",
+        "把输出重定向到设备文件可能覆盖磁盘或破坏设备状态",
+    ),
+    (
+        "chmod -R",
+        "chmod -R changes permissions of every file below a directory; on the wrong directory it can break the system or expose private files",
+        "chmod -R 会修改目录下所有文件的权限，作用在错误的目录上可能导致系统无法正常工作或泄露私密文件",
+    ),
+    (
+        ":(){ :|:& };:",
+        "This is a fork bomb: it keeps starting copies of itself until the system runs out of resources and freezes",
+        "这是一个 fork 炸弹：它会不断复制自身，直到系统资源耗尽而卡死",
+    ),
+];
+
+/// 危险模式的风险说明，用户在配置中添加的模式使用通用说明
+fn danger_explanation(pattern: &str, language: &str) -> &'static str {
+    let ui_text = get_ui_text(language);
+    DANGER_EXPLANATIONS
+        .iter()
+        .find(|(known, _, _)| *known == pattern)
+        .map(|(_, en, zh)| match language {
+            "zh" => *zh,
+            _ => *en,
+        })
+        .unwrap_or(ui_text.danger_custom_pattern)
+}
+
+/// 命中的危险模式及其风险说明，每项一行
+fn danger_details(pattern: &str, language: &str) -> String {
+    let ui_text = get_ui_text(language);
+    format!(
+        "{} {}\n{} {}",
+        ui_text.check_danger_pattern,
+        pattern,
+        ui_text.danger_reason,
+        danger_explanation(pattern, language)
+    )
+}
+
+/// 外部校验脚本的最长运行时间，超时视为警告
+const VALIDATOR_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 安全检查的结论，按严格程度从低到高排列
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Verdict {
+    Allow,
+    Warn,
+    Deny,
+}
+
+/// 调用 `[safety] validator` 检查命令，返回结论以及要展示给用户的说明（脚本的 stderr）。
+/// 退出码 0 为允许、2 为拒绝；其他退出码、超时或无法运行脚本都视为警告
+fn run_validator(validator: &str, command: &str, ui_text: &UiText) -> (Verdict, String) {
+    use std::io::{Read, Write};
+    use std::process::Stdio;
+
+    let child = Command::new(validator)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(err) => {
+            return (
+                Verdict::Warn,
+                format!("{} {}", ui_text.validator_failed, err),
+            )
+        }
+    };
+    if let Some(mut stdin) = child.stdin.take() {
+        // 脚本可能不读取 stdin 就退出，写入失败不影响结果
+        let _ = stdin.write_all(command.as_bytes());
+    }
+    let mut stderr = child.stderr.take();
+    let reader = std::thread::spawn(move || {
+        let mut message = String::new();
+        if let Some(stderr) = stderr.as_mut() {
+            let _ = stderr.read_to_string(&mut message);
+        }
+        message
+    });
+
+    let started = Instant::now();
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if started.elapsed() < VALIDATOR_TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(50));
+            }
+            _ => {
+                let _ = child.kill();
+                let _ = child.wait();
+                break None;
             }
-            return Ok(());
         }
+    };
+    let Some(status) = status else {
+        // 脚本启动的子进程可能仍占用 stderr，超时后不再等待读取
+        let timeout = ui_text
+            .validator_timeout
+            .replace("{}", &VALIDATOR_TIMEOUT.as_secs().to_string());
+        return (Verdict::Warn, timeout);
+    };
+    let verdict = match status.code() {
+        Some(0) => Verdict::Allow,
+        Some(2) => Verdict::Deny,
+        _ => Verdict::Warn,
+    };
+    let message = reader.join().unwrap_or_default().trim().to_string();
+    let message = if message.is_empty() && verdict == Verdict::Warn {
+        ui_text.validator_exit_code.replace(
+            "{}",
+            &status
+                .code()
+                .map_or_else(|| String::from("?"), |code| code.to_string()),
+        )
+    } else {
+        message
+    };
+    (verdict, message)
+}
+
+/// 未配置校验脚本时直接允许
+fn validate_command(config: &Config, command: &str) -> (Verdict, String) {
+    match &config.safety.validator {
+        Some(validator) => run_validator(validator, command, get_ui_text(&config.language)),
+        None => (Verdict::Allow, String::new()),
+    }
+}
+
+/// 展示校验脚本给出的警告或拒绝原因，允许时不输出
+fn show_validator_verdict(
+    term: &UiTerm,
+    ui_text: &UiText,
+    verdict: Verdict,
+    message: &str,
+) -> Result<()> {
+    let title = match verdict {
+        Verdict::Allow => return Ok(()),
+        Verdict::Warn => style(ui_text.validator_warning).notice().bold(),
+        Verdict::Deny => style(ui_text.validator_denied).red().bold(),
+    };
+    term.write_line(&format!("{}", title))?;
+    if !message.is_empty() {
+        term.write_line(message)?;
+    }
+    Ok(())
+}
+
+fn check_danger(command: &str) -> Result<()> {
+    let config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
 
+    let term = UiTerm::stdout();
+    let (verdict, message) = validate_command(&config, command);
+    show_validator_verdict(&term, ui_text, verdict, &message)?;
+    if verdict == Verdict::Deny {
         return Ok(());
     }
 
-    term.write_line(&format!(
-        "{}",
-        style(ui_text.max_attempts_reached).red().bold()
-    ))?;
+    match find_dangerous_pattern(command, &config.safety.dangerous_patterns) {
+        Some(pattern) => {
+            output::error(&term, ui_text.check_danger_blocked)?;
+            term.write_line(&danger_details(&pattern, &config.language))?;
+        }
+        None => output::success(&term, ui_text.check_danger_allowed)?,
+    }
+    if let Some(segment) = find_pipe_to_shell(command) {
+        show_pipe_to_shell_warning(&term, ui_text, &segment)?;
+    }
+    Ok(())
+}
+
+/// 提示命令会把管道中的数据交给 shell 执行，`segment` 为命中的部分（例如 `| sudo bash`）
+fn show_pipe_to_shell_warning(term: &UiTerm, ui_text: &UiText, segment: &str) -> Result<()> {
+    output::error(term, &ui_text.pipe_to_shell_warning.replace("{}", segment))?;
+    term.write_line(ui_text.pipe_to_shell_reason)?;
+    term.write_line("")?;
     Ok(())
 }
+
+/// AI 返回的结果：可执行的命令以及附带的说明文字
+#[derive(Debug)]
+struct AiResponse {
+    command: String,
+    explanation: Option<String>,
+    /// --with-explanation 时模型给出的一行理由
+    rationale: Option<String>,
+    /// 响应中的 system_fingerprint，用于判断 --seed 的结果能否复现
+    system_fingerprint: Option<String>,
+    /// 这次请求消耗的 token 数，服务商没有返回 usage 时为 None
+    total_tokens: Option<u64>,
+    /// 输入、输出分别的 token 数，用于计算费用和缓存命中率
+    usage: Option<TokenUsage>,
+    /// 响应中的 model，即服务商实际使用的模型版本
+    response_model: Option<String>,
+}
+
+/// 判断一行文本是否更像自然语言说明而不是命令
+fn looks_like_prose(line: &str) -> bool {
+    let line = line.trim();
+    let Some(first) = line.split_whitespace().next() else {
+        return false;
+    };
+    if line.starts_with('#') || SHELL_BUILTINS.contains(&first) || find_in_path(first) {
+        return false;
+    }
+    if line.chars().any(|c| ('\u{4e00}'..='\u{9fff}').contains(&c)) {
+        return true;
+    }
+    let capitalized = first.chars().next().is_some_and(char::is_uppercase)
+        && first
+            .chars()
+            .skip(1)
+            .all(|c| c.is_lowercase() || c == ',' || c == ':');
+    let sentence_end = line.ends_with(['.', '!', '?', ':']);
+    let words = line.split_whitespace().count();
+    capitalized && words >= 3 && (sentence_end || words >= 6)
+}
+
+/// 将“命令 + 后续说明文字”形式的输出拆分开，只把命令部分交给 shell 执行。
+/// heredoc、反斜杠续行等多行脚本内部不会被拆分。
+fn split_explanation(output: &str) -> AiResponse {
+    let lines: Vec<&str> = output.lines().collect();
+    let kinds = classify_lines(&lines);
+    let mut continued = false;
+
+    for (index, line) in lines.iter().enumerate() {
+        if kinds[index] != LineKind::Command {
+            continue;
+        }
+        // 带行尾注释的命令只根据代码部分判断，注释里的句子不会被当作说明文字
+        let code = match split_comment(line) {
+            (code, Some(_)) if !code.trim().is_empty() => code,
+            _ => line,
+        };
+        if index == 0 && looks_like_prose(code) {
+            break;
+        }
+        if index > 0 && !continued && looks_like_prose(code) {
+            let command = lines[..index].join("\n").trim().to_string();
+            let explanation = lines[index..].join("\n").trim().to_string();
+            return AiResponse {
+                command,
+                explanation: Some(explanation),
+                rationale: None,
+                system_fingerprint: None,
+                total_tokens: None,
+                usage: None,
+                response_model: None,
+            };
+        }
+        let code = split_comment(line).0.trim_end();
+        continued = code.ends_with(['\\', '|']) || code.ends_with("&&");
+    }
+
+    AiResponse {
+        command: output.trim().to_string(),
+        explanation: None,
+        rationale: None,
+        system_fingerprint: None,
+        total_tokens: None,
+        usage: None,
+        response_model: None,
+    }
+}
+
+/// 去掉代码块标记。代码块的起止标记必须独占一行，heredoc 内容中的 ``` 不会被当作代码块的结束。
+/// 同时统一换行符为 `\n`，避免 CRLF 残留的 `\r` 成为命令的一部分
+fn clean_command_output(command: &str) -> String {
+    let lines: Vec<&str> = command
+        .lines()
+        .map(|line| line.trim_end_matches('\r'))
+        .collect();
+    let Some(open) = lines
+        .iter()
+        .position(|line| line.trim_start().starts_with("```"))
+    else {
+        return lines.join("\n").trim().to_string();
+    };
+
+    // 单行代码块：```ls -la```
+    let opening = lines[open].trim();
+    if opening.len() > 6 && opening.ends_with("```") {
+        return opening.trim_matches('`').trim().to_string();
+    }
+
+    let body = &lines[open + 1..];
+    let kinds = classify_lines(body);
+    let close = body
+        .iter()
+        .zip(&kinds)
+        .position(|(line, kind)| *kind == LineKind::Command && line.trim() == "```")
+        .unwrap_or(body.len());
+    body[..close].join("\n").trim().to_string()
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Config {
+    api: ApiConfig,
+    language: String,
+    #[serde(default)]
+    safety: SafetyConfig,
+    #[serde(default)]
+    prompt: PromptConfig,
+    #[serde(default)]
+    generation: GenerationConfig,
+    #[serde(default)]
+    history: HistoryConfig,
+    #[serde(default)]
+    ui: UiConfig,
+    #[serde(default)]
+    defaults: DefaultsConfig,
+    #[serde(default)]
+    privacy: PrivacyConfig,
+    #[serde(default)]
+    behavior: BehaviorConfig,
+}
+
+impl Config {
+    fn new(api: ApiConfig, language: String) -> Self {
+        Config {
+            api,
+            language,
+            safety: SafetyConfig::default(),
+            prompt: PromptConfig::default(),
+            generation: GenerationConfig::default(),
+            history: HistoryConfig::default(),
+            ui: UiConfig::default(),
+            defaults: DefaultsConfig::default(),
+            privacy: PrivacyConfig::default(),
+            behavior: BehaviorConfig::default(),
+        }
+    }
+}
+
+/// 每次运行时自动加在命令行参数之前的默认选项，命令行中再次指定时以命令行为准
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct DefaultsConfig {
+    #[serde(default)]
+    flags: Vec<String>,
+}
+
+/// 执行命令前后的交互方式
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct BehaviorConfig {
+    /// 设置后不再询问是否执行，而是倒计时该毫秒数后自动执行，期间可以按 Ctrl+C 取消。
+    /// 危险命令仍需输入确认短语
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    confirm_threshold_ms: Option<u64>,
+    /// 命令执行成功（退出码为 0）时直接视为达到目标，不再询问，等同于 --no-confirm-success
+    #[serde(default)]
+    skip_goal_prompt_on_success: bool,
+    /// 命令执行失败时也询问是否达到目标（例如 grep 没有匹配时退出码为 1），而不是直接重试
+    #[serde(default)]
+    goal_prompt_on_failure: bool,
+    /// 重试时发送给模型的命令输出最多保留的字符数，不设置时不截断
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_output_chars: Option<usize>,
+    /// 输出超过 max_output_chars 时保留哪一部分
+    #[serde(default)]
+    output_truncation_strategy: OutputTruncationStrategy,
+}
+
+/// 截断过长的命令输出时保留的部分
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum OutputTruncationStrategy {
+    /// 保留开头，适合错误出现在最前面的日志
+    Head,
+    /// 保留末尾，适合最终状态在最后的进度输出
+    #[default]
+    Tail,
+    /// 保留中间的一段
+    Middle,
+}
+
+/// 截断位置的标记
+const TRUNCATION_MARKER: &str = "[...]";
+
+/// 发送给模型的命令输出：设置了 [behavior] max_output_chars 时按策略截断
+fn output_for_prompt(config: &Config, output: &str) -> String {
+    match config.behavior.max_output_chars {
+        Some(max_chars) => truncate_output(
+            output,
+            max_chars,
+            config.behavior.output_truncation_strategy,
+        ),
+        None => output.to_string(),
+    }
+}
+
+/// 按策略把输出截断到不超过 `max_chars` 个字符（包括截断标记）；
+/// `max_chars` 小到放不下标记时只截断、不加标记
+fn truncate_output(output: &str, max_chars: usize, strategy: OutputTruncationStrategy) -> String {
+    let chars: Vec<char> = output.chars().collect();
+    if chars.len() <= max_chars {
+        return output.to_string();
+    }
+    // 标记和换行占用的字符数
+    let markers = match strategy {
+        OutputTruncationStrategy::Middle => 2,
+        _ => 1,
+    };
+    let overhead = (TRUNCATION_MARKER.len() + 1) * markers;
+    let (keep, marked) = match max_chars.checked_sub(overhead) {
+        Some(keep) if keep > 0 => (keep, true),
+        _ => (max_chars, false),
+    };
+    let start = match strategy {
+        OutputTruncationStrategy::Head => 0,
+        OutputTruncationStrategy::Tail => chars.len() - keep,
+        OutputTruncationStrategy::Middle => (chars.len() - keep) / 2,
+    };
+    let kept: String = chars[start..start + keep].iter().collect();
+    if !marked {
+        return kept;
+    }
+    match strategy {
+        OutputTruncationStrategy::Head => format!("{}\n{}", kept, TRUNCATION_MARKER),
+        OutputTruncationStrategy::Tail => format!("{}\n{}", TRUNCATION_MARKER, kept),
+        OutputTruncationStrategy::Middle => {
+            format!("{}\n{}\n{}", TRUNCATION_MARKER, kept, TRUNCATION_MARKER)
+        }
+    }
+}
+
+/// 隐私相关的设置
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct PrivacyConfig {
+    /// 把生成的命令中的主目录替换为 `~`、当前目录替换为 `.`，避免保存下来的脚本暴露用户名和目录名
+    #[serde(default)]
+    generalize_paths: bool,
+}
+
+/// 追加到系统提示中的个人偏好
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct PromptConfig {
+    /// 需要编写脚本时优先使用的语言，例如 rust、node、ruby
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    prefer_language: Option<String>,
+    /// 禁止使用 python
+    #[serde(default)]
+    no_python: bool,
+}
+
+/// 生成命令时使用的模型参数
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct GenerationConfig {
+    /// 每次尝试依次使用的 temperature，尝试次数超出列表长度时沿用最后一个值；
+    /// 为空时不发送 temperature，使用服务商默认值
+    #[serde(default)]
+    temperature_schedule: Vec<f64>,
+    /// 命令执行失败后自动重试前等待的秒数，避免短时间内连续请求触发服务商的限流
+    #[serde(default)]
+    retry_cooldown_secs: u64,
+    /// 重试策略，可通过 --attempts-strategy 临时覆盖
+    #[serde(default)]
+    attempts_strategy: AttemptsStrategy,
+    /// warmer 策略每次尝试升高的温度，默认 0.2
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature_step: Option<f64>,
+    /// escalate 策略换用的模型，例如从 gpt-4o-mini 换成 gpt-4o
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    escalate_model: Option<String>,
+    /// escalate 策略在失败多少次之后换用模型，默认 1
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    escalate_after: Option<u32>,
+}
+
+const DEFAULT_TEMPERATURE_STEP: f64 = 0.2;
+/// warmer 策略的温度上限；部分服务商（如 Anthropic）不接受大于 1 的温度
+const MAX_WARMER_TEMPERATURE: f64 = 1.0;
+
+/// 界面相关的设置
+#[derive(serde::Deserialize, serde::Serialize)]
+struct UiConfig {
+    /// 教学模式：生成命令后逐个解释命令中的参数，等同于 --ask-each-flag
+    #[serde(default)]
+    teach: bool,
+    /// 等待较长时间后需要用户输入时响铃提醒
+    #[serde(default)]
+    bell: bool,
+    /// 等待超过该秒数后才响铃
+    #[serde(default = "default_bell_after_secs")]
+    bell_after_secs: u64,
+    /// 配色：auto 根据终端背景自动选择，也可以固定为 dark 或 light
+    #[serde(default)]
+    theme: output::ThemeSetting,
+}
+
+fn default_bell_after_secs() -> u64 {
+    5
+}
+
+impl Default for UiConfig {
+    fn default() -> Self {
+        UiConfig {
+            teach: false,
+            bell: false,
+            bell_after_secs: default_bell_after_secs(),
+            theme: output::ThemeSetting::default(),
+        }
+    }
+}
+
+/// 执行记录的持久化设置
+#[derive(Default, serde::Deserialize, serde::Serialize)]
+struct HistoryConfig {
+    /// 写入历史记录和暂存会话之前，输出中匹配这些正则表达式的内容会被替换为 [REDACTED]
+    #[serde(default)]
+    redact_patterns: Vec<String>,
+    /// 写入每条记录的操作者，用于多人共用的机器上区分是谁执行的命令；不设置时使用 $USER
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    operator: Option<String>,
+}
+
+impl HistoryConfig {
+    /// 检查 redact_patterns 是否都是有效的正则表达式。无效的模式会让脱敏失败、记录无法写入，
+    /// 因此在加载配置时就报错，而不是执行命令之后才发现
+    fn check_redact_patterns(&self) -> Result<()> {
+        for pattern in &self.redact_patterns {
+            Regex::new(pattern).with_context(|| {
+                format!("Invalid regex `{}` in [history] redact_patterns", pattern)
+            })?;
+        }
+        Ok(())
+    }
+
+    /// 本次运行的操作者：配置中的 operator，其次是 $USER（Windows 上为 %USERNAME%）
+    fn operator(&self) -> Option<String> {
+        self.operator
+            .clone()
+            .or_else(|| env::var("USER").ok())
+            .or_else(|| env::var("USERNAME").ok())
+            .filter(|operator| !operator.trim().is_empty())
+    }
+}
+
+/// 把输出中匹配 `patterns` 的内容替换为 [REDACTED]
+fn redact_output(output: &str, patterns: &[String]) -> Result<String> {
+    let mut output = output.to_string();
+    for pattern in patterns {
+        let re =
+            Regex::new(pattern).with_context(|| format!("Invalid redact pattern `{}`", pattern))?;
+        output = re.replace_all(&output, "[REDACTED]").into_owned();
+    }
+    Ok(output)
+}
+
+/// 返回第 `attempt` 次尝试（从 1 开始）应使用的 temperature
+fn temperature_for_attempt(schedule: &[f64], attempt: u32) -> Option<f64> {
+    let index = (attempt as usize)
+        .saturating_sub(1)
+        .min(schedule.len().checked_sub(1)?);
+    schedule.get(index).copied()
+}
+
+/// warmer 策略：以 temperature_schedule 的第一项（未设置时为 0）为起点，每次尝试升高 `step`
+fn warmer_temperature(schedule: &[f64], step: f64, attempt: u32) -> f64 {
+    let base = schedule.first().copied().unwrap_or(0.0);
+    let temperature = base + step * f64::from(attempt.saturating_sub(1));
+    // 避免浮点误差显示为 0.6000000000000001
+    ((temperature * 100.0).round() / 100.0).min(MAX_WARMER_TEMPERATURE)
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct SafetyConfig {
+    /// 在内置危险命令列表之外，额外拦截的命令片段
+    #[serde(default)]
+    dangerous_patterns: Vec<String>,
+    /// 使用 --allow-dangerous 执行危险命令前必须完整输入的确认短语
+    #[serde(default = "default_danger_confirm_phrase")]
+    danger_confirm_phrase: String,
+    /// 生成的命令超过该字符数时拒绝执行，可通过 --max-command-length 临时覆盖
+    #[serde(default = "default_max_command_length")]
+    max_command_length: usize,
+    /// 发送给模型的提示（系统提示加用户消息）超过该字符数时不发送，0 表示不限制
+    #[serde(default = "default_max_prompt_chars")]
+    max_prompt_chars: usize,
+    /// 外部策略脚本：从 stdin 读取命令，退出码 0/1/2 分别表示允许/警告/拒绝
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    validator: Option<String>,
+}
+
+fn default_danger_confirm_phrase() -> String {
+    String::from("yes, I understand")
+}
+
+fn default_max_command_length() -> usize {
+    2000
+}
+
+fn default_max_prompt_chars() -> usize {
+    100_000
+}
+
+impl Default for SafetyConfig {
+    fn default() -> Self {
+        SafetyConfig {
+            dangerous_patterns: Vec::new(),
+            danger_confirm_phrase: default_danger_confirm_phrase(),
+            max_command_length: default_max_command_length(),
+            max_prompt_chars: default_max_prompt_chars(),
+            validator: None,
+        }
+    }
+}
+
+#[derive(serde::Deserialize, serde::Serialize)]
+struct ApiConfig {
+    #[serde(default)]
+    provider: Provider,
+    base_url: String,
+    api_key: String,
+    /// 模型名称；Azure 下为部署名称
+    model: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    azure_api_version: Option<String>,
+    /// 每次请求携带一个随机 UUID 的请求头名称（例如 X-Request-ID），便于在网关日志中关联请求
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_id_header: Option<String>,
+    /// 模型的上下文窗口大小（token 数），覆盖内置的常见模型列表
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    context_window: Option<usize>,
+    /// 每百万 token 的价格（美元），格式为 [输入, 输出]，覆盖内置的常见模型价格
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    price_per_million: Option<[f64; 2]>,
+    /// OpenAI 的组织和项目ID，以 OpenAI-Organization / OpenAI-Project 请求头发送，用于费用归属
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    organization: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    project: Option<String>,
+    /// 额外信任的 CA 证书（PEM 文件路径），用于内部 CA 签发证书的私有 API 地址
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tls_ca_cert: Option<String>,
+    /// 是否校验 TLS 证书，关闭后连接可能被中间人劫持，仅用于排查问题
+    #[serde(default = "default_tls_verify")]
+    tls_verify: bool,
+}
+
+fn default_tls_verify() -> bool {
+    true
+}
+
+const DEFAULT_AZURE_API_VERSION: &str = "2024-06-01";
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Provider {
+    #[default]
+    OpenAI,
+    Azure,
+    Anthropic,
+    Gemini,
+    Ollama,
+    OpenRouter,
+    DeepSeek,
+    Custom,
+}
+
+impl Provider {
+    const ALL: [Provider; 8] = [
+        Provider::OpenAI,
+        Provider::Azure,
+        Provider::Anthropic,
+        Provider::Gemini,
+        Provider::Ollama,
+        Provider::OpenRouter,
+        Provider::DeepSeek,
+        Provider::Custom,
+    ];
+
+    fn from_name(name: &str) -> Option<Provider> {
+        Provider::ALL
+            .into_iter()
+            .find(|provider| provider.name() == name.to_lowercase())
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Provider::OpenAI => "openai",
+            Provider::Azure => "azure",
+            Provider::Anthropic => "anthropic",
+            Provider::Gemini => "gemini",
+            Provider::Ollama => "ollama",
+            Provider::OpenRouter => "openrouter",
+            Provider::DeepSeek => "deepseek",
+            Provider::Custom => "custom",
+        }
+    }
+
+    fn label(self, ui_text: &UiText) -> &'static str {
+        match self {
+            Provider::OpenAI => "OpenAI",
+            Provider::Azure => "Azure OpenAI",
+            Provider::Anthropic => "Anthropic",
+            Provider::Gemini => "Google Gemini",
+            Provider::Ollama => "Ollama",
+            Provider::OpenRouter => "OpenRouter",
+            Provider::DeepSeek => "DeepSeek",
+            Provider::Custom => ui_text.provider_custom,
+        }
+    }
+
+    /// OpenAI 兼容接口的默认地址；Azure 和自定义服务需要用户填写
+    fn default_base_url(self) -> &'static str {
+        match self {
+            Provider::OpenAI => "https://api.openai.com/v1",
+            Provider::Anthropic => "https://api.anthropic.com/v1",
+            Provider::Gemini => "https://generativelanguage.googleapis.com/v1beta/openai",
+            Provider::Ollama => "http://localhost:11434/v1",
+            Provider::OpenRouter => "https://openrouter.ai/api/v1",
+            Provider::DeepSeek => "https://api.deepseek.com",
+            Provider::Azure | Provider::Custom => "",
+        }
+    }
+
+    fn default_model(self) -> &'static str {
+        match self {
+            Provider::OpenAI | Provider::Azure | Provider::Custom => "gpt-4o-mini",
+            Provider::Anthropic => "claude-3-5-sonnet-latest",
+            Provider::Gemini => "gemini-2.0-flash",
+            Provider::Ollama => "llama3.1",
+            Provider::OpenRouter => "openai/gpt-4o-mini",
+            Provider::DeepSeek => "deepseek-chat",
+        }
+    }
+
+    /// 本地运行的服务不需要 API 密钥
+    fn requires_api_key(self) -> bool {
+        self != Provider::Ollama
+    }
+
+    /// 是否接受 seed 参数；Anthropic 和 Gemini 的兼容接口不支持，发送后可能返回 400
+    fn supports_seed(self) -> bool {
+        !matches!(self, Provider::Anthropic | Provider::Gemini)
+    }
+}
+
+/// 根据服务商构造 chat/completions 请求的地址和认证方式
+fn chat_completions_request(
+    client: &Client,
+    api: &ApiConfig,
+    model: &str,
+) -> reqwest::RequestBuilder {
+    match api.provider {
+        Provider::Azure => {
+            let api_version = api
+                .azure_api_version
+                .as_deref()
+                .unwrap_or(DEFAULT_AZURE_API_VERSION);
+            client
+                .post(format!(
+                    "{}/openai/deployments/{}/chat/completions?api-version={}",
+                    api.base_url.trim_end_matches('/'),
+                    model,
+                    api_version
+                ))
+                .header("api-key", &api.api_key)
+        }
+        _ => {
+            let mut request = client.post(format!("{}/chat/completions", api.base_url));
+            if !api.api_key.is_empty() {
+                request = request.header("Authorization", format!("Bearer {}", api.api_key));
+            }
+            if api.provider == Provider::OpenAI {
+                if let Some(organization) = &api.organization {
+                    request = request.header("OpenAI-Organization", organization);
+                }
+                if let Some(project) = &api.project {
+                    request = request.header("OpenAI-Project", project);
+                }
+            }
+            request
+        }
+    }
+}
+
+fn get_system_language() -> String {
+    // Try to get system language from environment variables
+    let lang = env::var("LANG")
+        .or_else(|_| env::var("LC_ALL"))
+        .or_else(|_| env::var("LANGUAGE"))
+        .unwrap_or_else(|_| String::from("en_US.UTF-8"));
+
+    // Extract language code from format like "en_US.UTF-8"
+    if lang.starts_with("zh") {
+        "zh".to_string()
+    } else {
+        "en".to_string()
+    }
+}
+
+fn get_config_dir() -> Result<std::path::PathBuf> {
+    let home = dirs::home_dir().context("Unable to get home directory")?;
+    let config_dir = home.join(".askai");
+    if !config_dir.exists() {
+        fs::create_dir_all(&config_dir).with_context(|| {
+            format!("Unable to create config directory {}", config_dir.display())
+        })?;
+    }
+    Ok(config_dir)
+}
+
+/// 检查配置目录是否可写。在收集 API 密钥等输入之前调用，避免输入完成后才发现无法保存；
+/// 只读取配置时不做检查，只读挂载的配置目录仍然可以正常使用
+fn ensure_config_dir_writable(ui_text: &UiText) -> Result<()> {
+    let dir = get_config_dir()?;
+    let probe = dir.join(format!(".write-test-{}", std::process::id()));
+    match fs::write(&probe, b"") {
+        Ok(()) => {
+            let _ = fs::remove_file(&probe);
+            Ok(())
+        }
+        Err(err) => Err(anyhow::anyhow!(
+            "{} ({})",
+            ui_text
+                .config_dir_not_writable
+                .replace("{}", &dir.display().to_string()),
+            err
+        )),
+    }
+}
+
+fn get_config_path() -> Result<std::path::PathBuf> {
+    Ok(get_config_dir()?.join("config.toml"))
+}
+
+const PROJECT_CONFIG_FILE: &str = ".askai.toml";
+
+/// 项目级配置，保存在项目目录的 `.askai.toml` 中，优先级高于用户全局配置
+#[derive(serde::Deserialize)]
+struct ProjectConfig {
+    model: Option<String>,
+}
+
+/// 从当前目录开始向上查找最近的项目配置文件
+fn find_project_config() -> Option<std::path::PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+fn load_project_config(path: &std::path::Path) -> Result<ProjectConfig> {
+    let config_str = fs::read_to_string(path).context("Unable to read project config file")?;
+    toml::from_str(&config_str).context("Unable to parse project config file")
+}
+
+/// 加载用户配置并依次叠加项目级配置和 ASKAI_* 环境变量，作为本次运行实际使用的配置
+fn load_effective_config() -> Result<Config> {
+    let mut config = load_config()?;
+    config.history.check_redact_patterns()?;
+    if let Some(path) = find_project_config() {
+        if let Some(model) = load_project_config(&path)?.model {
+            config.api.model = model;
+        }
+    }
+    if let Ok(base_url) = env::var("ASKAI_BASE_URL") {
+        config.api.base_url = base_url;
+    }
+    if let Ok(api_key) = env::var("ASKAI_API_KEY") {
+        config.api.api_key = api_key;
+    }
+    if let Ok(model) = env::var("ASKAI_MODEL") {
+        config.api.model = model;
+    }
+    if config.ui.bell {
+        BELL_AFTER_SECS.store(config.ui.bell_after_secs, Ordering::Relaxed);
+    }
+    Ok(config)
+}
+
+/// 程序会读取的环境变量，以及是否需要隐藏其值
+const ENV_VARS: [(&str, bool); 16] = [
+    ("ASKAI_API_KEY", true),
+    ("ASKAI_SERVE_TOKEN", true),
+    ("ASKAI_BASE_URL", false),
+    ("ASKAI_MODEL", false),
+    ("SHELL", false),
+    ("TERM", false),
+    ("USER", false),
+    ("PWD", false),
+    ("PATH", false),
+    ("LANG", false),
+    ("LC_ALL", false),
+    ("LANGUAGE", false),
+    ("RUST_LOG", false),
+    ("NO_COLOR", false),
+    ("PAGER", false),
+    ("HOME", false),
+];
+
+/// 隐藏密钥等敏感值，只保留首尾少量字符便于辨认
+fn mask_secret(value: &str) -> String {
+    let chars: Vec<char> = value.chars().collect();
+    if chars.len() <= 8 {
+        return "*".repeat(chars.len());
+    }
+    let head: String = chars[..4].iter().collect();
+    let tail: String = chars[chars.len() - 4..].iter().collect();
+    format!("{}****{}", head, tail)
+}
+
+/// 用单引号包裹字符串，使其可以安全地作为一个 shell 参数
+fn shell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+/// 命令中的命名占位符，写作 `{{name}}`
+fn placeholder_regex() -> Regex {
+    Regex::new(r"\{\{\s*([A-Za-z_][A-Za-z0-9_]*)\s*\}\}").unwrap()
+}
+
+/// 需求中是否写了 `{name}` 形式的参数；`${name}` 是 shell 变量，不算参数
+fn mentions_placeholders(prompt: &str) -> bool {
+    Regex::new(r"(?:^|[^$])\{[A-Za-z_][A-Za-z0-9_]*\}")
+        .unwrap()
+        .is_match(prompt)
+}
+
+/// 按首次出现的顺序返回命令中的占位符名称，重复的只保留一个
+fn find_placeholders(command: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for captures in placeholder_regex().captures_iter(command) {
+        if !names.iter().any(|name| name == &captures[1]) {
+            names.push(captures[1].to_string());
+        }
+    }
+    names
+}
+
+/// 把占位符替换为用户填写的值。值会按占位符所在位置的引号加以转义，
+/// 保证无论包含空格、引号还是换行，都只作为一个参数（或参数的一部分）传给命令
+fn substitute_placeholders(
+    command: &str,
+    values: &std::collections::HashMap<String, String>,
+) -> String {
+    substitute_placeholders_for(command, values, cfg!(target_os = "windows"))
+}
+
+/// 占位符所在位置的引号上下文。`$( )`、`( )` 和反引号内部重新开始一层未加引号的上下文
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum QuoteContext {
+    None,
+    Single,
+    Double,
+    Subshell,
+    Backtick,
+}
+
+/// 按执行命令的 shell 替换占位符：`cmd` 为 true 时按 cmd 的规则处理，
+/// 其中只有双引号是引号，反斜杠和 `$( )` 都没有特殊含义
+fn substitute_placeholders_for(
+    command: &str,
+    values: &std::collections::HashMap<String, String>,
+    cmd: bool,
+) -> String {
+    let mut result = String::new();
+    let mut stack = vec![QuoteContext::None];
+    let mut last = 0;
+    for captures in placeholder_regex().captures_iter(command) {
+        let placeholder = captures.get(0).unwrap();
+        let before = &command[last..placeholder.start()];
+        track_quotes(before, &mut stack, cmd);
+        result.push_str(before);
+        let value = values.get(&captures[1]).map(String::as_str).unwrap_or("");
+        let mut quoted = match (stack.last().copied().unwrap_or(QuoteContext::None), cmd) {
+            (QuoteContext::Double, true) => value.replace('"', "\"\""),
+            (_, true) => format!("\"{}\"", value.replace('"', "\"\"")),
+            // 在单引号内先结束引号，插入转义后的单引号，再重新开始
+            (QuoteContext::Single, false) => value.replace('\'', "'\\''"),
+            (QuoteContext::Double, false) => escape_with_backslash(value, &['\\', '"', '$', '`']),
+            (_, false) => shell_quote(value),
+        };
+        // 反引号内部的文本在执行前还会去掉一层 `\\`、`\$` 和 `` \` `` 转义，
+        // 每层反引号都要再转义一次
+        for context in stack.iter().rev() {
+            if *context == QuoteContext::Backtick {
+                quoted = escape_with_backslash(&quoted, &['\\', '$', '`']);
+            }
+        }
+        result.push_str(&quoted);
+        last = placeholder.end();
+    }
+    result.push_str(&command[last..]);
+    result
+}
+
+/// 依次读取文本，更新引号上下文的栈，反斜杠转义的字符不改变状态
+fn track_quotes(text: &str, stack: &mut Vec<QuoteContext>, cmd: bool) {
+    walk_quotes(text, stack, cmd, |_, _| {});
+}
+
+/// 把一行命令中位于单引号或双引号内的字符替换为空格（`$( )` 和反引号内的除外），
+/// 用于只在 shell 实际解析的部分中查找管道等语法
+fn mask_quoted(line: &str) -> String {
+    let mut masked: Vec<char> = line.chars().collect();
+    walk_quotes(
+        line,
+        &mut vec![QuoteContext::None],
+        false,
+        |index, context| {
+            if matches!(context, QuoteContext::Single | QuoteContext::Double) {
+                masked[index] = ' ';
+            }
+        },
+    );
+    masked.into_iter().collect()
+}
+
+/// track_quotes 的实现：读取每个字符之前先以字符序号和当时的上下文调用 `visit`，
+/// 被反斜杠转义的字符按单引号内处理
+fn walk_quotes(
+    text: &str,
+    stack: &mut Vec<QuoteContext>,
+    cmd: bool,
+    mut visit: impl FnMut(usize, QuoteContext),
+) {
+    let mut chars = text.chars().enumerate().peekable();
+    while let Some((index, c)) = chars.next() {
+        let current = stack.last().copied().unwrap_or(QuoteContext::None);
+        visit(index, current);
+        if cmd {
+            match (current, c) {
+                (QuoteContext::Double, '"') => {
+                    stack.pop();
+                }
+                (_, '"') => stack.push(QuoteContext::Double),
+                _ => {}
+            }
+            continue;
+        }
+        match (current, c) {
+            (QuoteContext::Single, '\'') => {
+                stack.pop();
+            }
+            (QuoteContext::Single, _) => {}
+            (_, '\\') => {
+                // 转义的字符与单引号内的字符一样没有特殊含义
+                if let Some((index, _)) = chars.next() {
+                    visit(index, QuoteContext::Single);
+                }
+            }
+            (QuoteContext::Double, '"') | (QuoteContext::Backtick, '`') => {
+                stack.pop();
+            }
+            (_, '$') if chars.peek().is_some_and(|(_, next)| *next == '(') => {
+                chars.next();
+                stack.push(QuoteContext::Subshell);
+            }
+            (_, '`') => stack.push(QuoteContext::Backtick),
+            (QuoteContext::Double, _) => {}
+            (_, '\'') => stack.push(QuoteContext::Single),
+            (_, '"') => stack.push(QuoteContext::Double),
+            (_, '(') => stack.push(QuoteContext::Subshell),
+            (QuoteContext::Subshell, ')') => {
+                stack.pop();
+            }
+            _ => {}
+        }
+    }
+}
+
+/// 在指定的字符前加反斜杠
+fn escape_with_backslash(value: &str, special: &[char]) -> String {
+    let mut escaped = String::new();
+    for c in value.chars() {
+        if special.contains(&c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// 占位符名称看起来是文件或目录时，输入值时提供路径补全
+fn looks_like_path(name: &str) -> bool {
+    const HINTS: [&str; 11] = [
+        "file", "path", "dir", "folder", "src", "dst", "dest", "source", "target", "input",
+        "output",
+    ];
+    let name = name.to_lowercase();
+    HINTS.iter().any(|hint| name.contains(hint))
+}
+
+/// 按 Tab 补全文件路径：只有一个候选时补全完整名称（目录末尾加 /），
+/// 有多个候选时补全到它们的公共前缀
+struct PathCompletion;
+
+impl dialoguer::Completion for PathCompletion {
+    fn get(&self, input: &str) -> Option<String> {
+        let (dir, prefix) = match input.rfind('/') {
+            Some(index) => input.split_at(index + 1),
+            None => ("", input),
+        };
+        let search_dir = match dir {
+            "" => std::path::PathBuf::from("."),
+            _ => match dir.strip_prefix("~/") {
+                Some(rest) => dirs::home_dir()?.join(rest),
+                None => std::path::PathBuf::from(dir),
+            },
+        };
+        let mut matches: Vec<(String, bool)> = fs::read_dir(search_dir)
+            .ok()?
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                let is_dir = entry.file_type().map(|kind| kind.is_dir()).unwrap_or(false);
+                (name.starts_with(prefix) && (prefix.starts_with('.') || !name.starts_with('.')))
+                    .then_some((name, is_dir))
+            })
+            .collect();
+        matches.sort();
+        let completed = match matches.as_slice() {
+            [] => return None,
+            [(name, true)] => format!("{}/", name),
+            [(name, false)] => name.clone(),
+            [(first, _), rest @ ..] => {
+                let mut common = first.clone();
+                for (name, _) in rest {
+                    while !name.starts_with(&common) {
+                        common.pop();
+                    }
+                }
+                common
+            }
+        };
+        (completed.len() > prefix.len()).then(|| format!("{}{}", dir, completed))
+    }
+}
+
+/// 依次询问每个占位符的值，返回替换后的命令
+fn fill_placeholders(term: &UiTerm, ui_text: &UiText, command: &str) -> Result<String> {
+    let mut values = std::collections::HashMap::new();
+    for name in find_placeholders(command) {
+        let prompt = ui_text.placeholder_value_prompt.replace("{}", &name);
+        ensure_interactive(ui_text, &prompt)?;
+        let theme = dialog_theme();
+        let mut input = dialoguer::Input::<String>::with_theme(&theme)
+            .with_prompt(&prompt)
+            .allow_empty(true);
+        let completion = PathCompletion;
+        if looks_like_path(&name) {
+            input = input.completion_with(&completion);
+        }
+        values.insert(name, input.interact_text()?);
+    }
+    let filled = substitute_placeholders(command, &values);
+    output::heading(term, ui_text.placeholder_filled)?;
+    output::write_block(term, &styled_command(&filled))?;
+    Ok(filled)
+}
+
+/// 以可被 shell 直接执行的格式输出程序读取的环境变量
+fn print_env() {
+    for (name, secret) in ENV_VARS {
+        match env::var(name) {
+            Ok(value) if secret => {
+                println!("export {}={}", name, shell_quote(&mask_secret(&value)))
+            }
+            Ok(value) => println!("export {}={}", name, shell_quote(&value)),
+            Err(_) => println!("# {} is not set", name),
+        }
+    }
+}
+
+/// `ask whoami`：显示本次运行实际使用的配置（已叠加项目级配置和环境变量），
+/// API 地址只显示主机名，密钥只显示首尾几位
+fn whoami() -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    // 与写入执行记录的操作者一致：优先使用 [history] operator
+    let user = config
+        .history
+        .operator()
+        .unwrap_or_else(|| style(ui_text.whoami_not_set).secondary().to_string());
+    let host = reqwest::Url::parse(&config.api.base_url)
+        .ok()
+        .and_then(|url| {
+            let host = url.host_str()?.to_string();
+            Some(match url.port() {
+                Some(port) => format!("{}:{}", host, port),
+                None => host,
+            })
+        })
+        .unwrap_or_else(|| config.api.base_url.clone());
+    let api_key = if config.api.api_key.is_empty() {
+        style(ui_text.whoami_not_set).secondary().to_string()
+    } else {
+        mask_secret(&config.api.api_key)
+    };
+    // 模型不是来自用户配置文件时注明来源
+    let model_source = if env::var("ASKAI_MODEL").is_ok() {
+        Some("ASKAI_MODEL".to_string())
+    } else {
+        find_project_config()
+            .filter(|path| load_project_config(path).is_ok_and(|project| project.model.is_some()))
+            .map(|path| path.display().to_string())
+    };
+    let model = match model_source {
+        Some(source) => format!(
+            "{} {}",
+            style(&config.api.model).code(),
+            style(format!("({})", source)).secondary()
+        ),
+        None => style(&config.api.model).code().to_string(),
+    };
+
+    output::key_values(
+        &UiTerm::stdout(),
+        &[
+            (ui_text.whoami_user, user),
+            (
+                ui_text.whoami_provider,
+                config.api.provider.label(ui_text).to_string(),
+            ),
+            (ui_text.whoami_api, host),
+            (ui_text.whoami_api_key, api_key),
+            (ui_text.whoami_model, model),
+            (ui_text.whoami_language, config.language.clone()),
+            (
+                ui_text.whoami_config,
+                get_config_path()?.display().to_string(),
+            ),
+        ],
+    )?;
+    Ok(())
+}
+
+fn pin_model(model: Option<&str>, global: bool, show: bool) -> Result<()> {
+    let ui_text = get_ui_text(&configured_language());
+
+    if show {
+        // 与 load_effective_config 的优先级一致：环境变量、项目配置、用户配置
+        let project = match find_project_config() {
+            Some(path) => load_project_config(&path)?
+                .model
+                .map(|model| (model, path.display().to_string())),
+            None => None,
+        };
+        let pinned = env::var("ASKAI_MODEL")
+            .ok()
+            .map(|model| (model, "ASKAI_MODEL".to_string()))
+            .or(project)
+            .or(match peek_config() {
+                Some(config) => Some((config.api.model, get_config_path()?.display().to_string())),
+                None => None,
+            });
+        let Some((model, source)) = pinned else {
+            output::warning(&UiTerm::stdout(), ui_text.no_model_configured)?;
+            return Ok(());
+        };
+        output::key_values(
+            &UiTerm::stdout(),
+            &[
+                (ui_text.pinned_model, style(model).code().to_string()),
+                (ui_text.pinned_model_source, source),
+            ],
+        )?;
+        return Ok(());
+    }
+
+    let model = model.context(ui_text.missing_model_id)?.to_string();
+    let path = if global {
+        let mut config = load_config()?;
+        config.api.model = model;
+        save_config(&config)?;
+        get_config_path()?
+    } else {
+        let path = match find_project_config() {
+            Some(path) => path,
+            None => env::current_dir()
+                .context("Unable to get current directory")?
+                .join(PROJECT_CONFIG_FILE),
+        };
+        // 只修改 model 一项，保留文件中的注释和其他键
+        storage::update(&path, |existing| {
+            let mut document = existing
+                .unwrap_or_default()
+                .parse::<toml_edit::DocumentMut>()
+                .context("Unable to parse project config file")?;
+            let mut updated = document.clone();
+            updated["model"] = toml_edit::value(model);
+            merge_toml_table(document.as_table_mut(), updated.as_table());
+            Ok(document.to_string())
+        })
+        .context("Unable to save project config file")?;
+        path
+    };
+
+    output::success(
+        &UiTerm::stdout(),
+        &format!("{} {}", ui_text.model_pinned, path.display()),
+    )?;
+    Ok(())
+}
+
+/// 读取配置文件但不启动配置向导，文件不存在或无法解析时返回 None。
+/// 用于加载配置之前就需要的设置，例如配色
+fn peek_config() -> Option<Config> {
+    let content = fs::read_to_string(get_config_path().ok()?).ok()?;
+    toml::from_str(&content).ok()
+}
+
+#[tracing::instrument]
+fn load_config() -> Result<Config> {
+    let config_path = get_config_path()?;
+    if !config_path.exists() {
+        let config = run_config_wizard(None)?;
+        save_config(&config)?;
+        let ui_text = get_ui_text(&config.language);
+        output::success(&UiTerm::stdout(), ui_text.config_saved)?;
+        return Ok(config);
+    }
+    let config_str = fs::read_to_string(&config_path).context("Unable to read config file")?;
+
+    // 尝试解析配置文件，如果失败可能是旧版本配置缺少language字段
+    match toml::from_str::<Config>(&config_str) {
+        Ok(config) => Ok(config),
+        Err(_) => {
+            // 尝试解析为不包含language字段的旧版本配置
+            #[derive(serde::Deserialize)]
+            struct OldConfig {
+                api: ApiConfig,
+            }
+
+            let old_config: OldConfig =
+                toml::from_str(&config_str).context("Unable to parse config file")?;
+
+            // 获取系统默认语言
+            let default_language = get_system_language();
+            // 获取对应语言的UI文本
+            let ui_text = get_ui_text(&default_language);
+
+            ensure_config_dir_writable(ui_text)?;
+            // 提示用户选择语言
+            println!(
+                "{}",
+                style(ui_text.language_preference_required).info().bold()
+            );
+            let language = select_language(ui_text, &default_language)?;
+
+            // 创建新的配置并保存
+            let config = Config::new(old_config.api, language);
+
+            save_config(&config)?;
+            output::success(&UiTerm::stdout(), ui_text.config_saved)?;
+
+            Ok(config)
+        }
+    }
+}
+
+fn select_language(ui_text: &UiText, default_language: &str) -> Result<String> {
+    const LANGUAGES: [(&str, &str); 2] = [("en", "English"), ("zh", "中文")];
+    ensure_interactive(ui_text, ui_text.language_prompt)?;
+    let index = Select::with_theme(&dialog_theme())
+        .with_prompt(ui_text.language_prompt)
+        .items(&LANGUAGES.map(|(_, label)| label))
+        .default(
+            LANGUAGES
+                .iter()
+                .position(|(code, _)| *code == default_language)
+                .unwrap_or(0),
+        )
+        .interact()?;
+    Ok(LANGUAGES[index].0.to_string())
+}
+
+/// 询问服务商及其相关的配置项。传入当前配置时以其服务商和取值作为默认值，
+/// 并保留与服务商无关的设置（请求 ID 请求头、TLS 选项）
+fn prompt_api_config(ui_text: &UiText, current: Option<&ApiConfig>) -> Result<ApiConfig> {
+    let theme = dialog_theme();
+    ensure_interactive(ui_text, ui_text.provider_prompt)?;
+    let labels = Provider::ALL.map(|provider| provider.label(ui_text));
+    let current_index = current
+        .and_then(|api| {
+            Provider::ALL
+                .iter()
+                .position(|provider| *provider == api.provider)
+        })
+        .unwrap_or(0);
+    let index = Select::with_theme(&theme)
+        .with_prompt(ui_text.provider_prompt)
+        .items(&labels)
+        .default(current_index)
+        .interact()?;
+    let provider = Provider::ALL[index];
+    // 服务商不变时沿用当前的地址、模型和密钥作为默认值
+    let same = current.filter(|api| api.provider == provider);
+
+    let mut api = ApiConfig {
+        provider,
+        base_url: same
+            .map(|api| api.base_url.clone())
+            .unwrap_or_else(|| provider.default_base_url().to_string()),
+        api_key: String::new(),
+        model: same
+            .map(|api| api.model.clone())
+            .unwrap_or_else(|| provider.default_model().to_string()),
+        azure_api_version: None,
+        request_id_header: current.and_then(|api| api.request_id_header.clone()),
+        context_window: same.and_then(|api| api.context_window),
+        price_per_million: same.and_then(|api| api.price_per_million),
+        organization: same.and_then(|api| api.organization.clone()),
+        project: same.and_then(|api| api.project.clone()),
+        tls_ca_cert: current.and_then(|api| api.tls_ca_cert.clone()),
+        tls_verify: current.map(|api| api.tls_verify).unwrap_or(true),
+    };
+
+    match provider {
+        Provider::Azure => {
+            let mut endpoint = dialoguer::Input::<String>::with_theme(&theme)
+                .with_prompt(ui_text.azure_endpoint_prompt);
+            let mut deployment = dialoguer::Input::<String>::with_theme(&theme)
+                .with_prompt(ui_text.azure_deployment_prompt);
+            if let Some(same) = same {
+                endpoint = endpoint.default(same.base_url.clone());
+                deployment = deployment.default(same.model.clone());
+            }
+            api.base_url = endpoint.interact_text()?;
+            api.model = deployment.interact_text()?;
+            api.azure_api_version = Some(
+                dialoguer::Input::<String>::with_theme(&theme)
+                    .with_prompt(ui_text.azure_api_version_prompt)
+                    .default(
+                        same.and_then(|api| api.azure_api_version.clone())
+                            .unwrap_or_else(|| DEFAULT_AZURE_API_VERSION.to_string()),
+                    )
+                    .interact_text()?,
+            );
+        }
+        Provider::Ollama | Provider::Custom => {
+            let mut input =
+                dialoguer::Input::<String>::with_theme(&theme).with_prompt(ui_text.base_url_prompt);
+            if !api.base_url.is_empty() {
+                input = input.default(api.base_url.clone());
+            }
+            api.base_url = input.interact_text()?;
+        }
+        _ => {}
+    }
+
+    if provider.requires_api_key() {
+        let current_key = same.map(|api| api.api_key.as_str()).unwrap_or_default();
+        api.api_key = if current_key.is_empty() {
+            dialoguer::Password::with_theme(&theme)
+                .with_prompt(ui_text.api_key_prompt)
+                .interact()?
+        } else {
+            let key = dialoguer::Password::with_theme(&theme)
+                .with_prompt(ui_text.api_key_keep_prompt)
+                .allow_empty_password(true)
+                .interact()?;
+            if key.is_empty() {
+                current_key.to_string()
+            } else {
+                key
+            }
+        };
+    }
+
+    if provider != Provider::Azure {
+        api.model = dialoguer::Input::<String>::with_theme(&theme)
+            .with_prompt(ui_text.model_prompt)
+            .default(api.model)
+            .interact_text()?;
+    }
+    Ok(api)
+}
+
+/// 交互式配置向导：先选择服务商，再只询问该服务商需要的配置项。
+/// 传入已有配置时保留其中与 API 和语言无关的部分。
+fn run_config_wizard(existing: Option<Config>) -> Result<Config> {
+    let default_language = existing
+        .as_ref()
+        .map(|config| config.language.clone())
+        .unwrap_or_else(get_system_language);
+    let ui_text = get_ui_text(&default_language);
+    ensure_config_dir_writable(ui_text)?;
+
+    if existing.is_none() {
+        output::heading(&UiTerm::stdout(), ui_text.first_run_config)?;
+        println!();
+    }
+
+    let api = prompt_api_config(ui_text, existing.as_ref().map(|config| &config.api))?;
+
+    let language = select_language(ui_text, &default_language)?;
+
+    Ok(match existing {
+        Some(mut config) => {
+            config.api = api;
+            config.language = language;
+            config
+        }
+        None => Config::new(api, language),
+    })
+}
+
+/// 重新运行配置向导，保留已有配置中的其他设置
+fn init_config() -> Result<()> {
+    let existing = if get_config_path()?.exists() {
+        Some(load_config()?)
+    } else {
+        None
+    };
+    let config = run_config_wizard(existing)?;
+    save_config(&config)?;
+    let ui_text = get_ui_text(&config.language);
+    output::success(&UiTerm::stdout(), ui_text.config_saved)?;
+    Ok(())
+}
+
+/// 只重新选择服务商及其相关配置，其余设置保持不变
+fn switch_provider() -> Result<()> {
+    let mut config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
+    ensure_config_dir_writable(ui_text)?;
+    config.api = prompt_api_config(ui_text, Some(&config.api))?;
+    save_config(&config)?;
+    output::success(&UiTerm::stdout(), ui_text.config_saved)?;
+    Ok(())
+}
+
+/// 校验并保存 `[defaults] flags`，只接受程序本身的选项，不能包含需求描述或子命令
+fn set_default_flags(flags: Vec<String>) -> Result<()> {
+    let mut config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let program = env!("CARGO_BIN_NAME");
+    let cli = Cli::try_parse_from(std::iter::once(program.to_string()).chain(flags.clone()))
+        .map_err(|err| anyhow::anyhow!("{}\n{}", ui_text.default_flags_invalid, err))?;
+    if cli.prompt.is_some() || cli.command.is_some() {
+        return Err(anyhow::anyhow!(
+            "{}\n{}",
+            ui_text.default_flags_invalid,
+            ui_text.default_flags_options_only
+        ));
+    }
+
+    config.defaults.flags = flags;
+    save_config(&config)?;
+    output::success(&UiTerm::stdout(), ui_text.config_saved)?;
+    if !config.defaults.flags.is_empty() {
+        println!(
+            "{} {}",
+            ui_text.default_flags_current,
+            config.defaults.flags.join(" ")
+        );
+    }
+    Ok(())
+}
+
+/// 把配置文件中的默认选项与命令行参数合并；配置文件不存在或无法解析时不使用默认选项，
+/// 具体的错误留给之后加载配置的地方报告
+fn args_with_default_flags() -> Vec<std::ffi::OsString> {
+    let flags = peek_config()
+        .map(|config| config.defaults.flags)
+        .unwrap_or_default();
+    merge_default_flags(&flags, env::args_os().collect())
+}
+
+/// `token` 对应的选项（短选项可以合并，如 `-vD`）以及它的值是否在下一个参数中
+fn options_in_token<'a>(command: &'a clap::Command, token: &str) -> (Vec<&'a clap::Arg>, bool) {
+    if let Some(long) = token.strip_prefix("--") {
+        let (name, inline) = match long.split_once('=') {
+            Some((name, _)) => (name, true),
+            None => (long, false),
+        };
+        return match command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name))
+        {
+            Some(arg) => (vec![arg], arg.get_action().takes_values() && !inline),
+            None => (Vec::new(), false),
+        };
+    }
+    let Some(shorts) = token.strip_prefix('-').filter(|shorts| !shorts.is_empty()) else {
+        return (Vec::new(), false);
+    };
+    let mut found = Vec::new();
+    for (index, short) in shorts.char_indices() {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_short() == Some(short))
+        else {
+            break;
+        };
+        found.push(arg);
+        if arg.get_action().takes_values() {
+            // 值可以紧跟在短选项后面（`-mgpt-4o`），否则在下一个参数中
+            return (found, index + short.len_utf8() == shorts.len());
+        }
+    }
+    (found, false)
+}
+
+/// 合并默认选项与命令行参数：默认选项放在最前面，但命令行中出现过的选项会整体替换对应的默认值，
+/// 而不是与之叠加（例如 `--watch`、`--race`）；`--no-<flag>` 关闭默认选项中的开关，
+/// 它只在默认选项中有效，本身不会传给命令行解析
+fn merge_default_flags(
+    defaults: &[String],
+    args: Vec<std::ffi::OsString>,
+) -> Vec<std::ffi::OsString> {
+    use clap::CommandFactory;
+
+    let command = Cli::command();
+    let mut args = args.into_iter();
+    let mut own: Vec<std::ffi::OsString> = args.next().into_iter().collect();
+    let mut given = std::collections::HashSet::new();
+    let mut skip_value = false;
+    let mut scanning = true;
+    for arg in args {
+        let token = arg.to_string_lossy().into_owned();
+        if !scanning || std::mem::take(&mut skip_value) {
+            own.push(arg);
+            continue;
+        }
+        if token == "--" || command.find_subcommand(&token).is_some() {
+            // 之后的参数属于子命令或需求描述，不再与默认选项比较
+            scanning = false;
+        } else if let Some(negated) = token
+            .strip_prefix("--no-")
+            .filter(|_| options_in_token(&command, &token).0.is_empty())
+            .and_then(|name| {
+                command
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(name))
+            })
+            .filter(|arg| matches!(arg.get_action(), clap::ArgAction::SetTrue))
+        {
+            given.insert(negated.get_id().clone());
+            continue;
+        } else {
+            let (options, takes_value) = options_in_token(&command, &token);
+            given.extend(options.into_iter().map(|option| option.get_id().clone()));
+            skip_value = takes_value;
+        }
+        own.push(arg);
+    }
+
+    let mut merged: Vec<std::ffi::OsString> = own.drain(..own.len().min(1)).collect();
+    let mut defaults = defaults.iter();
+    while let Some(flag) = defaults.next() {
+        let (options, takes_value) = options_in_token(&command, flag);
+        let value = if takes_value { defaults.next() } else { None };
+        if options.iter().any(|option| given.contains(option.get_id())) {
+            continue;
+        }
+        merged.push(flag.into());
+        merged.extend(value.map(Into::into));
+    }
+    merged.extend(own);
+    merged
+}
+
+/// 在编辑器中编辑配置文件的副本，只有能正确解析时才写回配置文件
+fn edit_config() -> Result<()> {
+    let config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let path = get_config_path()?;
+    let original = fs::read_to_string(&path).context("Unable to read config file")?;
+
+    ensure_interactive(ui_text, ui_text.config_edit_prompt)?;
+    let edited = dialoguer::Editor::new()
+        .extension(".toml")
+        .trim_newlines(false)
+        .edit(&original)
+        .context("Unable to launch editor")?;
+    let edited = match edited {
+        Some(edited) if edited != original => edited,
+        _ => {
+            output::note(&UiTerm::stdout(), ui_text.config_unchanged)?;
+            return Ok(());
+        }
+    };
+
+    if let Err(err) = toml::from_str::<Config>(&edited) {
+        return Err(anyhow::anyhow!(
+            "{} {}\n{}",
+            ui_text.config_edit_invalid,
+            path.display(),
+            err
+        ));
+    }
+    storage::replace(&path, edited).context("Unable to save config file")?;
+    output::success(&UiTerm::stdout(), ui_text.config_saved)?;
+    Ok(())
+}
+
+fn save_config(config: &Config) -> Result<()> {
+    let config_path = get_config_path()?;
+    let config_str = toml::to_string_pretty(config).context("Unable to serialize config")?;
+    // 在已有文件的基础上只修改变化的项，保留用户手写的注释和格式；读取和写回都在锁内完成
+    storage::update(&config_path, |existing| {
+        match existing.and_then(|existing| existing.parse::<toml_edit::DocumentMut>().ok()) {
+            Some(mut document) => {
+                let updated: toml_edit::DocumentMut =
+                    config_str.parse().context("Unable to serialize config")?;
+                merge_toml_table(document.as_table_mut(), updated.as_table());
+                Ok(document.to_string())
+            }
+            None => Ok(config_str),
+        }
+    })
+    .map_err(|err| {
+        let ui_text = get_ui_text(&config.language);
+        anyhow::anyhow!(
+            "{} ({:#})",
+            ui_text
+                .config_save_failed
+                .replace("{}", &config_path.display().to_string()),
+            err
+        )
+    })?;
+    Ok(())
+}
+
+/// 把 `updated` 中的内容合并到 `existing`：值相同的项保持原样，变化的项只替换值（保留行尾注释），
+/// 新增的项追加到对应的表中，`updated` 中已没有的项被删除
+fn merge_toml_table(existing: &mut toml_edit::Table, updated: &toml_edit::Table) {
+    let removed: Vec<String> = existing
+        .iter()
+        .filter(|(key, _)| !updated.contains_key(key))
+        .map(|(key, _)| key.to_string())
+        .collect();
+    for key in removed {
+        existing.remove(&key);
+    }
+    for (key, item) in updated.iter() {
+        let Some(current) = existing.get_mut(key) else {
+            existing.insert(key, item.clone());
+            continue;
+        };
+        match (current, item) {
+            (toml_edit::Item::Table(current), toml_edit::Item::Table(item)) => {
+                merge_toml_table(current, item)
+            }
+            (current, item) if same_toml_item(current, item) => {}
+            (toml_edit::Item::Value(current), toml_edit::Item::Value(value)) => {
+                let decor = current.decor().clone();
+                *current = value.clone();
+                *current.decor_mut() = decor;
+            }
+            (current, item) => *current = item.clone(),
+        }
+    }
+}
+
+/// 两个 TOML 项的取值是否相同，不考虑格式和注释
+fn same_toml_item(left: &toml_edit::Item, right: &toml_edit::Item) -> bool {
+    let parse = |item: &toml_edit::Item| {
+        let mut table = toml_edit::Table::new();
+        table.insert("value", item.clone());
+        toml_edit::DocumentMut::from(table)
+            .to_string()
+            .parse::<toml::Table>()
+            .ok()
+    };
+    parse(left).is_some_and(|left| Some(left) == parse(right))
+}
+
+/// 持久化的执行记录，每行一个 JSON 对象，保存在 `~/.askai/history.jsonl` 中
+#[derive(serde::Deserialize, serde::Serialize)]
+struct HistoryEntry {
+    /// Unix 时间戳（秒）
+    timestamp: u64,
+    prompt: String,
+    command: String,
+    output: String,
+    success: bool,
+    /// 用户对“是否达到预期目标”的回答，命令执行失败时不会询问
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    goal_achieved: Option<bool>,
+    /// 分组ID（--session-id），同一次运行写入的记录相同
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_id: Option<String>,
+    /// 这条记录是本次会话的第几次尝试
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    attempt: Option<u32>,
+    /// 达成目标时记录会话的总耗时（秒），这条记录即为会话的最终结果
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    duration_secs: Option<u64>,
+    /// 生成命令时使用的 --seed 以及响应中的 system_fingerprint，用于复现
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    system_fingerprint: Option<String>,
+    /// 请求的模型、响应中实际使用的模型以及请求的温度，用于事后排查是哪个模型版本生成的命令
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    response_model: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    temperature: Option<f64>,
+    /// 重试策略（same 不记录）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    strategy: Option<AttemptsStrategy>,
+    /// 执行命令的操作者（[history] operator 或 $USER）
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    operator: Option<String>,
+}
+
+impl HistoryEntry {
+    fn new(
+        prompt: &str,
+        command: &str,
+        output: &str,
+        success: bool,
+        goal_achieved: Option<bool>,
+    ) -> Self {
+        HistoryEntry {
+            timestamp: unix_timestamp(),
+            prompt: prompt.to_string(),
+            command: command.to_string(),
+            output: output.to_string(),
+            success,
+            goal_achieved,
+            session_id: SESSION_ID.get().cloned(),
+            attempt: None,
+            duration_secs: None,
+            seed: None,
+            system_fingerprint: None,
+            model: None,
+            response_model: None,
+            temperature: None,
+            strategy: None,
+            operator: None,
+        }
+    }
+}
+
+fn get_history_path() -> Result<std::path::PathBuf> {
+    Ok(get_config_dir()?.join("history.jsonl"))
+}
+
+fn append_history(entry: &HistoryEntry) -> Result<()> {
+    storage::append_line(&get_history_path()?, &serde_json::to_string(entry)?)
+}
+
+/// 找出被同一次会话的最终结果取代的尝试：达成目标的记录之前、需求和分组ID相同且尝试次数更小的记录
+fn superseded_attempts(entries: &[HistoryEntry]) -> Vec<bool> {
+    let mut superseded = vec![false; entries.len()];
+    for (index, entry) in entries.iter().enumerate() {
+        let (Some(attempt), Some(_)) = (entry.attempt, entry.duration_secs) else {
+            continue;
+        };
+        for previous in (0..index).rev() {
+            let candidate = &entries[previous];
+            if candidate.prompt != entry.prompt
+                || candidate.session_id != entry.session_id
+                || candidate.attempt.unwrap_or(u32::MAX) >= attempt
+            {
+                break;
+            }
+            superseded[previous] = true;
+        }
+    }
+    superseded
+}
+
+/// 列出执行记录，指定分组ID时只显示该分组的记录。
+/// 默认只显示会话的最终结果，`all` 为 true 时显示每一次尝试
+fn show_history(session: Option<&str>, all: bool) -> Result<()> {
+    let config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let term = UiTerm::stdout();
+    let now = unix_timestamp();
+
+    // 编号是记录在历史文件中的位置（从 1 开始），筛选后保持不变，供 `ask history export` 使用
+    let (numbers, entries): (Vec<usize>, Vec<HistoryEntry>) = load_history()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, entry)| (index + 1, entry))
+        .filter(|(_, entry)| session.is_none() || entry.session_id.as_deref() == session)
+        .unzip();
+    let superseded = superseded_attempts(&entries);
+    let entries: Vec<(usize, &HistoryEntry)> = numbers
+        .into_iter()
+        .zip(&entries)
+        .zip(superseded)
+        .filter(|(_, superseded)| all || !superseded)
+        .map(|(entry, _)| entry)
+        .collect();
+    if entries.is_empty() {
+        output::warning(&term, ui_text.no_history)?;
+        return Ok(());
+    }
+
+    let width = entries
+        .last()
+        .map(|(number, _)| number.to_string().len())
+        .unwrap_or_default();
+    // 记录来自多个操作者时（多人共用的历史文件）才显示操作者
+    let mut operators: Vec<&str> = entries
+        .iter()
+        .filter_map(|(_, entry)| entry.operator.as_deref())
+        .collect();
+    operators.sort_unstable();
+    operators.dedup();
+    let show_operator = operators.len() > 1;
+    let mut listing = Vec::new();
+    for (number, entry) in entries {
+        let status = match (entry.success, entry.goal_achieved) {
+            (true, Some(false)) => style(ui_text.history_goal_missed).notice(),
+            (true, _) => style(ui_text.history_success).positive(),
+            (false, _) => style(ui_text.history_failure).red(),
+        };
+        let group = match (session, &entry.session_id) {
+            // 按分组筛选时每条记录的ID都相同，不再重复显示
+            (None, Some(id)) => format!("  {}", style(id.get(..8).unwrap_or(id)).secondary()),
+            _ => String::new(),
+        };
+        let group = match (show_operator, &entry.operator) {
+            (true, Some(operator)) => format!("{}  {}", group, style(operator).magenta()),
+            _ => group,
+        };
+        // 会话的最终结果附带尝试次数和总耗时
+        let summary = match (entry.attempt, entry.duration_secs) {
+            (Some(attempts), Some(duration)) => format!(
+                "  {}",
+                style(
+                    ui_text
+                        .history_summary
+                        .replacen("{}", &attempts.to_string(), 1)
+                        .replacen("{}", &format_duration(duration), 1)
+                )
+                .secondary()
+            ),
+            _ => String::new(),
+        };
+        listing.push(format!(
+            "{}  {}  {}{}  {}{}",
+            style(format!("#{:<width$}", number, width = width)).secondary(),
+            style(format!(
+                "{:>4}",
+                format_age(now.saturating_sub(entry.timestamp))
+            ))
+            .secondary(),
+            status,
+            group,
+            entry.prompt,
+            summary
+        ));
+        listing.push(format!(
+            "{}{}",
+            " ".repeat(width + 3),
+            style(&entry.command).code()
+        ));
+    }
+    output::write_block(&term, &listing.join("\n"))
+}
+
+/// `ask history export`：把编号在 [from, to] 范围内、执行成功且没有被标记为未达到目标的命令写入脚本，
+/// 每条命令之前以注释写出对应的需求
+fn export_history(
+    from: Option<usize>,
+    to: Option<usize>,
+    output: &std::path::Path,
+    force: bool,
+) -> Result<()> {
+    let config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
+    if output.exists() && !force {
+        return Err(anyhow::anyhow!(ui_text
+            .export_file_exists
+            .replace("{}", &output.display().to_string())));
+    }
+
+    let from = from.unwrap_or(1);
+    let to = to.unwrap_or(usize::MAX);
+    let entries: Vec<HistoryEntry> = load_history()?
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| (from..=to).contains(&(index + 1)))
+        .map(|(_, entry)| entry)
+        .filter(|entry| entry.success && entry.goal_achieved != Some(false))
+        .collect();
+    if entries.is_empty() {
+        return Err(anyhow::anyhow!(ui_text.export_nothing));
+    }
+
+    let mut script = String::from("#!/bin/sh\n# Exported by `ask history export`\nset -e\n");
+    for entry in &entries {
+        script.push('\n');
+        for line in entry.prompt.lines() {
+            script.push_str(&format!("# {}\n", line));
+        }
+        script.push_str(&entry.command);
+        script.push('\n');
+    }
+    fs::write(output, script).with_context(|| format!("Unable to write {}", output.display()))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(output, fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Unable to make {} executable", output.display()))?;
+    }
+
+    output::success(
+        &UiTerm::stdout(),
+        &ui_text
+            .history_exported
+            .replacen("{}", &entries.len().to_string(), 1)
+            .replacen("{}", &output.display().to_string(), 1),
+    )?;
+    Ok(())
+}
+
+/// 把 YYYY-MM-DD 解析为当天 0 点（UTC）的 Unix 时间戳
+fn parse_date(value: &str) -> Result<u64, String> {
+    let invalid = || format!("invalid date `{}`, expected YYYY-MM-DD", value);
+    let parts: Vec<&str> = value.trim().split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    let (Ok(year), Ok(month), Ok(day)) = (
+        year.parse::<i64>(),
+        month.parse::<i64>(),
+        day.parse::<i64>(),
+    ) else {
+        return Err(invalid());
+    };
+    let leap = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+    let days_in_month = match month {
+        2 if leap => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    };
+    if !(1970..=9999).contains(&year)
+        || !(1..=12).contains(&month)
+        || !(1..=days_in_month).contains(&day)
+    {
+        return Err(invalid());
+    }
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let year_of_era = y - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    Ok(days as u64 * 86400)
+}
+
+/// `ask clear-history`：显示要删除的记录数并确认后清空执行记录；
+/// 指定 `before` 时只删除早于该时间的记录，无法解析的行会保留
+fn clear_history(before: Option<u64>) -> Result<()> {
+    let config = load_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let term = UiTerm::stdout();
+    let path = get_history_path()?;
+    let content = if path.exists() {
+        fs::read_to_string(&path).context("Unable to read history file")?
+    } else {
+        String::new()
+    };
+
+    let removed: Vec<&str> = content
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter(|line| match before {
+            Some(before) => serde_json::from_str::<HistoryEntry>(line)
+                .is_ok_and(|entry| entry.timestamp < before),
+            None => true,
+        })
+        .collect();
+    if removed.is_empty() {
+        output::warning(&term, ui_text.no_history)?;
+        return Ok(());
+    }
+
+    let prompt = ui_text
+        .clear_history_confirm
+        .replace("{}", &removed.len().to_string());
+    ensure_interactive(ui_text, &prompt)?;
+    if !Confirm::with_theme(&dialog_theme())
+        .with_prompt(&prompt)
+        .default(false)
+        .interact()?
+    {
+        return Ok(());
+    }
+
+    // 确认期间可能有其他进程追加了记录，因此在锁内重新读取，只删除确认时列出的那些记录
+    let mut removed_count = 0;
+    storage::update(&path, |current| {
+        let mut pending = removed;
+        let mut contents = String::new();
+        for line in current.unwrap_or_default().lines() {
+            match pending.iter().position(|removed| *removed == line) {
+                Some(index) => {
+                    pending.swap_remove(index);
+                    removed_count += 1;
+                }
+                None if line.trim().is_empty() => {}
+                None => {
+                    contents.push_str(line);
+                    contents.push('\n');
+                }
+            }
+        }
+        Ok(contents)
+    })?;
+    term.write_line(&format!(
+        "{}",
+        style(
+            ui_text
+                .clear_history_done
+                .replace("{}", &removed_count.to_string())
+        )
+        .positive()
+    ))?;
+    Ok(())
+}
+
+/// 读取全部执行记录，无法解析的行会被忽略
+fn load_history() -> Result<Vec<HistoryEntry>> {
+    let path = get_history_path()?;
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let content = fs::read_to_string(&path).context("Unable to read history file")?;
+    Ok(content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect())
+}
+
+/// 值为密钥的配置项，显示时只保留首尾几位
+const SECRET_CONFIG_KEYS: [&str; 1] = ["api_key"];
+
+/// `ask set config key=value ...`：所有配置项都校验通过后才一起保存，任一项无效时配置文件保持不变。
+/// 值为 `-` 的配置项从标准输入读取（去掉首尾空白），避免密钥出现在 shell 历史和进程列表中
+fn set_config(config_type: &str, config_values: &[String]) -> Result<()> {
+    let mut config = if let Ok(existing_config) = load_config() {
+        existing_config
+    } else {
+        Config::new(
+            ApiConfig {
+                provider: Provider::OpenAI,
+                base_url: String::from("https://api.openai.com/v1"),
+                api_key: String::new(),
+                model: String::from("gpt-3.5-turbo"),
+                azure_api_version: None,
+                request_id_header: None,
+                context_window: None,
+                price_per_million: None,
+                organization: None,
+                project: None,
+                tls_ca_cert: None,
+                tls_verify: true,
+            },
+            // 没有可用的配置时，错误信息使用系统语言
+            get_system_language(),
+        )
+    };
+    let ui_text = get_ui_text(&config.language);
+    if config_type != "config" {
+        return Err(anyhow::anyhow!(
+            "{} {}",
+            ui_text.unknown_config_type,
+            config_type
+        ));
+    }
+    let mut pairs = Vec::new();
+    for pair in config_values {
+        match pair.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => pairs.push((key.trim(), value)),
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "{} ({})",
+                    ui_text.config_format_error,
+                    pair
+                ))
+            }
+        }
+    }
+    if pairs.iter().filter(|(_, value)| *value == "-").count() > 1 {
+        return Err(anyhow::anyhow!(ui_text.config_stdin_once));
+    }
+
+    let mut changed = Vec::new();
+    for (key, value) in pairs {
+        let value = if value == "-" {
+            read_config_value_from_stdin(ui_text, key)?
+        } else {
+            value.to_string()
+        };
+        let before = toml::to_string(&config).context("Unable to serialize config")?;
+        apply_config_value(&mut config, ui_text, key, &value).map_err(|err| {
+            let shown = if SECRET_CONFIG_KEYS.contains(&key) {
+                mask_secret(&value)
+            } else {
+                value.clone()
+            };
+            anyhow::anyhow!("{}={}: {}", key, shown, err)
+        })?;
+        if toml::to_string(&config).context("Unable to serialize config")? != before {
+            let shown = if SECRET_CONFIG_KEYS.contains(&key) {
+                mask_secret(&value)
+            } else {
+                value
+            };
+            changed.push((key, shown));
+        }
+    }
+
+    save_config(&config)?;
+    let ui_text = get_ui_text(&config.language);
+    let term = UiTerm::stdout();
+    if changed.is_empty() {
+        output::note(&term, ui_text.config_unchanged)?;
+        return Ok(());
+    }
+    output::success(&term, ui_text.config_updated)?;
+    let rows: Vec<(&str, String)> = changed
+        .iter()
+        .map(|(key, value)| (*key, style(value).code().to_string()))
+        .collect();
+    output::key_values(&term, &rows)?;
+    Ok(())
+}
+
+/// 读取 `key=-` 的值：标准输入是终端时以不回显的方式询问，否则读取管道中的全部内容
+fn read_config_value_from_stdin(ui_text: &UiText, key: &str) -> Result<String> {
+    use std::io::{IsTerminal, Read};
+
+    if std::io::stdin().is_terminal() {
+        return Ok(dialoguer::Password::with_theme(&dialog_theme())
+            .with_prompt(key)
+            .interact()?);
+    }
+    let mut value = String::new();
+    std::io::stdin()
+        .read_to_string(&mut value)
+        .context("Unable to read standard input")?;
+    let value = value.trim().to_string();
+    if value.is_empty() {
+        return Err(anyhow::anyhow!(ui_text
+            .config_stdin_empty
+            .replace("{}", key)));
+    }
+    Ok(value)
+}
+
+/// 把一个配置项写入内存中的配置，键未知或值无效时返回错误
+fn apply_config_value(config: &mut Config, ui_text: &UiText, key: &str, value: &str) -> Result<()> {
+    match key {
+        "base_url" => config.api.base_url = value.to_string(),
+        "api_key" => config.api.api_key = value.to_string(),
+        "model" => config.api.model = value.to_string(),
+        "provider" => {
+            config.api.provider = Provider::from_name(value)
+                .ok_or_else(|| anyhow::anyhow!("{} {}", ui_text.unknown_provider, value))?
+        }
+        "azure_api_version" => config.api.azure_api_version = Some(value.to_string()),
+        "request_id_header" => config.api.request_id_header = Some(value.to_string()),
+        "organization" => config.api.organization = Some(value.to_string()),
+        "project" => config.api.project = Some(value.to_string()),
+        "tls_ca_cert" => config.api.tls_ca_cert = Some(value.to_string()),
+        "tls_verify" => {
+            config.api.tls_verify = value
+                .parse()
+                .map_err(|_| anyhow::anyhow!("{} {}", ui_text.invalid_config_value, key))?
+        }
+        "context_window" => {
+            config.api.context_window = Some(
+                value
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("{} {}", ui_text.invalid_config_value, key))?,
+            )
+        }
+        "language" => {
+            if !["en", "zh"].contains(&value) {
+                return Err(anyhow::anyhow!(ui_text
+                    .unsupported_language
+                    .replace("{}", value)));
+            }
+            config.language = value.to_string()
+        }
+        "danger_confirm_phrase" => config.safety.danger_confirm_phrase = value.to_string(),
+        _ => return Err(anyhow::anyhow!("{} {}", ui_text.unknown_config_key, key)),
+    }
+    Ok(())
+}
+
+/// 单次请求可覆盖的参数，未设置的项使用配置中的值或服务商的默认值
+#[derive(Debug, Clone, Default)]
+struct RequestOptions {
+    model: Option<String>,
+    temperature: Option<f64>,
+    /// 附加在用户提示之后的上下文，例如 --with-ls 的目录列表
+    context: Vec<String>,
+    /// 读取响应时两个数据块之间允许的最长间隔
+    response_timeout: Option<Duration>,
+    /// --system-prompt-file 指定的系统提示，替换 get_prompt 的结果
+    system_prompt: Option<String>,
+    /// 要求模型在命令之前附带一行理由
+    with_explanation: bool,
+    /// --syntax：要求模型按指定 shell 的语法生成命令
+    syntax: Option<TargetShell>,
+    /// --user-message-template 指定的重试时的用户消息模板
+    user_message_template: Option<String>,
+    /// --seed：支持的服务商会收到 seed 参数，不支持的服务商不会发送
+    seed: Option<u64>,
+    /// 需求中写了 `{file}` 之类的参数，要求模型保留为命名占位符
+    placeholders: bool,
+    /// --raw-output：回复原样作为命令，不做任何清理
+    raw_output: bool,
+}
+
+impl RequestOptions {
+    fn with_model(&self, model: &str) -> RequestOptions {
+        RequestOptions {
+            model: Some(model.to_string()),
+            ..self.clone()
+        }
+    }
+}
+
+#[tracing::instrument(skip(config, client, history, debug))]
+async fn get_ai_response(
+    config: &Config,
+    client: &Client,
+    prompt: &str,
+    history: Option<&ExecutionHistory>,
+    options: &RequestOptions,
+    debug: bool,
+) -> Result<AiResponse> {
+    let full_prompt = build_system_prompt(config, options)?;
+    if let Some(h) = history {
+        tracing::info!(
+            attempt = h.attempt,
+            model = %h.model,
+            success = h.success,
+            "retrying after previous attempt"
+        );
+    }
+    let user_prompt = build_user_prompt(config, prompt, history, options)?;
+
+    let (reply, metadata) =
+        chat_completion_with_metadata(config, client, &full_prompt, &user_prompt, options, debug)
+            .await?;
+    if options.raw_output {
+        return Ok(AiResponse {
+            command: reply,
+            explanation: None,
+            rationale: None,
+            system_fingerprint: metadata.system_fingerprint,
+            total_tokens: metadata.total_tokens,
+            usage: metadata.usage,
+            response_model: metadata.model,
+        });
+    }
+    let (reply, rationale) = if options.with_explanation {
+        split_rationale(&reply)
+    } else {
+        (reply, None)
+    };
+    Ok(AiResponse {
+        rationale,
+        system_fingerprint: metadata.system_fingerprint,
+        total_tokens: metadata.total_tokens,
+        usage: metadata.usage,
+        response_model: metadata.model,
+        ..split_explanation(&clean_command_output(&reply))
+    })
+}
+
+/// 内置的用户消息模板，可用 ~/.askai/user_message_initial_template.txt 和
+/// ~/.askai/user_message_template.txt（或 --user-message-template）替换
+const USER_MESSAGE_INITIAL_EN: &str =
+    "The user's request is: {prompt}. Generate the shell command that fulfills it.";
+const USER_MESSAGE_INITIAL_ZH: &str =
+    "现在，用户的问题为：{prompt}，请你根据用户的问题生成对应的shell命令来实现用户的需求。";
+const USER_MESSAGE_RETRY_EN: &str = "The user's request is: {prompt}
+The previous command was: {command}
+Its output was: {output}
+Succeeded: {success}
+This is attempt {attempt}.
+Analyze the result above and decide whether the goal was achieved. If it was not, explain why and generate an improved command.";
+const USER_MESSAGE_RETRY_ZH: &str = "用户的问题为：{prompt}
+上一次执行的命令是：{command}
+执行结果是：{output}
+执行是否成功：{success}
+这是第{attempt}次尝试。
+请根据上述信息分析执行结果，判断是否达到预期目标，如果没有达到目标，分析原因并生成改进的命令。";
+
+/// 读取用户消息模板，优先级：--user-message-template（仅重试时）> ~/.askai 中的模板文件 > 内置模板
+fn user_message_template(config: &Config, options: &RequestOptions, retry: bool) -> Result<String> {
+    if let (true, Some(template)) = (retry, &options.user_message_template) {
+        return Ok(template.clone());
+    }
+    let (file, builtin) = match (retry, config.language.as_str()) {
+        (false, "zh") => ("user_message_initial_template.txt", USER_MESSAGE_INITIAL_ZH),
+        (false, _) => ("user_message_initial_template.txt", USER_MESSAGE_INITIAL_EN),
+        (true, "zh") => ("user_message_template.txt", USER_MESSAGE_RETRY_ZH),
+        (true, _) => ("user_message_template.txt", USER_MESSAGE_RETRY_EN),
+    };
+    let path = get_config_dir()?.join(file);
+    if path.is_file() {
+        return fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read user message template {}", path.display()));
+    }
+    Ok(builtin.to_string())
+}
+
+/// 替换模板中的 {prompt}、{command} 等变量。只替换一遍，用户的问题或命令输出中
+/// 出现的 `{output}` 之类的文本不会再被替换
+fn render_template(template: &str, variables: &[(&str, &str)]) -> String {
+    let placeholder = Regex::new(r"\{([a-z_]+)\}").unwrap();
+    placeholder
+        .replace_all(template, |captures: &regex::Captures| {
+            variables
+                .iter()
+                .find(|(name, _)| *name == &captures[1])
+                .map(|(_, value)| value.to_string())
+                .unwrap_or_else(|| captures[0].to_string())
+        })
+        .into_owned()
+}
+
+/// 组装用户提示：用户的问题、上一次执行的结果以及附加的上下文
+fn build_user_prompt(
+    config: &Config,
+    prompt: &str,
+    history: Option<&ExecutionHistory>,
+    options: &RequestOptions,
+) -> Result<String> {
+    let mut user_prompt = match history {
+        Some(h) => {
+            let template = user_message_template(config, options, true)?;
+            let output = output_for_prompt(config, &h.output);
+            let feedback = h.feedback.as_ref().map(|feedback| {
+                let line = match config.language.as_str() {
+                    "zh" => "\n用户对这次结果不满意，用户的反馈是：{}",
+                    _ => "\nThe user was not satisfied with this result. Their feedback: {}",
+                };
+                line.replace("{}", feedback)
+            });
+            render_template(
+                &template,
+                &[
+                    ("prompt", prompt),
+                    ("command", &h.command),
+                    ("output", &output),
+                    ("success", &h.success.to_string()),
+                    ("attempt", &h.attempt.to_string()),
+                ],
+            ) + &feedback.unwrap_or_default()
+        }
+        None => render_template(
+            &user_message_template(config, options, false)?,
+            &[("prompt", prompt)],
+        ),
+    };
+
+    for context in &options.context {
+        user_prompt.push_str("\n\n");
+        user_prompt.push_str(context);
+    }
+    Ok(user_prompt)
+}
+
+/// 常见模型的上下文窗口大小（token 数），按模型名前缀匹配，更具体的前缀放在前面
+const CONTEXT_WINDOWS: [(&str, usize); 20] = [
+    ("gpt-4.1", 1_047_576),
+    ("gpt-4o", 128_000),
+    ("gpt-4-turbo", 128_000),
+    ("gpt-4-1106", 128_000),
+    ("gpt-4-0125", 128_000),
+    ("gpt-4-32k", 32_768),
+    ("gpt-4", 8_192),
+    ("gpt-3.5-turbo", 16_385),
+    ("o1", 200_000),
+    ("o3", 200_000),
+    ("o4", 200_000),
+    ("claude", 200_000),
+    ("gemini-1.5", 1_048_576),
+    ("gemini-2", 1_048_576),
+    ("gemini", 32_768),
+    ("deepseek", 65_536),
+    ("llama3.1", 131_072),
+    ("llama3", 8_192),
+    ("qwen", 32_768),
+    ("mistral", 32_768),
+];
+
+/// 提示超过上下文窗口的该比例时发出警告
+const CONTEXT_WARNING_RATIO: f64 = 0.9;
+
+/// 模型的上下文窗口大小，配置中的 context_window 优先，未知的模型返回 None
+fn context_window(config: &Config, model: &str) -> Option<usize> {
+    config.api.context_window.or_else(|| {
+        let model = model.to_lowercase();
+        // OpenRouter 等服务商的模型名带有前缀，例如 openai/gpt-4o
+        let name = model.rsplit('/').next().unwrap_or_default();
+        CONTEXT_WINDOWS
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map(|(_, size)| *size)
+    })
+}
+
+/// 常见模型每百万 token 的价格（美元，输入/输出），按模型名前缀匹配，更具体的前缀放在前面
+const MODEL_PRICES: [(&str, f64, f64); 24] = [
+    ("gpt-4.1-nano", 0.1, 0.4),
+    ("gpt-4.1-mini", 0.4, 1.6),
+    ("gpt-4.1", 2.0, 8.0),
+    ("gpt-4o-mini", 0.15, 0.6),
+    ("gpt-4o", 2.5, 10.0),
+    ("gpt-4-turbo", 10.0, 30.0),
+    ("gpt-4", 30.0, 60.0),
+    ("gpt-3.5-turbo", 0.5, 1.5),
+    ("o1-mini", 1.1, 4.4),
+    ("o1", 15.0, 60.0),
+    ("o3-mini", 1.1, 4.4),
+    ("o3", 2.0, 8.0),
+    ("o4-mini", 1.1, 4.4),
+    ("claude-3-5-haiku", 0.8, 4.0),
+    ("claude-3-haiku", 0.25, 1.25),
+    ("claude-3-opus", 15.0, 75.0),
+    ("claude-opus", 15.0, 75.0),
+    ("claude", 3.0, 15.0),
+    ("gemini-1.5-flash", 0.075, 0.3),
+    ("gemini-1.5-pro", 1.25, 5.0),
+    ("gemini-2.0-flash", 0.1, 0.4),
+    ("gemini-2.5-pro", 1.25, 10.0),
+    ("deepseek-reasoner", 0.55, 2.19),
+    ("deepseek", 0.27, 1.1),
+];
+
+/// 支持前缀缓存的模型命中缓存的输入部分每百万 token 的价格（美元），按模型名前缀匹配
+const CACHE_HIT_PRICES: [(&str, f64); 2] = [("deepseek-reasoner", 0.14), ("deepseek", 0.07)];
+
+/// 估算费用时假定的回复长度（token 数），生成的命令通常很短
+const ESTIMATED_OUTPUT_TOKENS: usize = 100;
+
+/// 响应中的模型是否与请求的模型明显不同（服务商改用了其他模型）。带日期或版本号的快照
+/// （gpt-4o → gpt-4o-2024-08-06）、`latest` 别名以及 OpenRouter 的 `厂商/` 前缀都不算不同
+fn model_rerouted(requested: &str, echoed: &str) -> bool {
+    let snapshot = Regex::new(r"(?:[-:]latest|-\d{4}-\d{2}-\d{2}|-\d{8}|-\d{3,4})$").unwrap();
+    let normalize = |model: &str| {
+        let model = model.rsplit('/').next().unwrap_or(model).to_lowercase();
+        snapshot.replace(&model, "").into_owned()
+    };
+    normalize(requested) != normalize(echoed)
+}
+
+/// 模型每百万 token 的价格（输入、输出），配置中的 price_per_million 优先；
+/// 本地运行的 Ollama 不收费，未知的模型返回 None
+fn model_price(config: &Config, model: &str) -> Option<(f64, f64)> {
+    if let Some([input, output]) = config.api.price_per_million {
+        return Some((input, output));
+    }
+    if config.api.provider == Provider::Ollama {
+        return Some((0.0, 0.0));
+    }
+    let model = model.to_lowercase();
+    let name = model.rsplit('/').next().unwrap_or_default();
+    MODEL_PRICES
+        .iter()
+        .find(|(prefix, _, _)| name.starts_with(prefix))
+        .map(|(_, input, output)| (*input, *output))
+}
+
+/// 按响应中的 usage 计算一次请求的实际费用，命中前缀缓存的输入按缓存价格计算；
+/// 模型价格未知时返回 None
+fn request_cost(config: &Config, model: &str, usage: &TokenUsage) -> Option<f64> {
+    let (input, output) = model_price(config, model)?;
+    let cache_hit = usage.cache_hit_tokens.unwrap_or_default();
+    let cache_miss = usage
+        .cache_miss_tokens
+        .unwrap_or(usage.prompt_tokens.saturating_sub(cache_hit));
+    // 配置了 price_per_million 时不知道缓存价格，全部按输入价格计算
+    let cache_hit_price = if config.api.price_per_million.is_some() {
+        input
+    } else {
+        let model = model.to_lowercase();
+        let name = model.rsplit('/').next().unwrap_or_default();
+        CACHE_HIT_PRICES
+            .iter()
+            .find(|(prefix, _)| name.starts_with(prefix))
+            .map_or(input, |(_, price)| *price)
+    };
+    Some(
+        (cache_miss as f64 * input
+            + cache_hit as f64 * cache_hit_price
+            + usage.completion_tokens as f64 * output)
+            / 1_000_000.0,
+    )
+}
+
+/// --verbose 时显示一次请求的 token 用量、前缀缓存命中率和实际费用
+fn show_token_usage(
+    term: &UiTerm,
+    ui_text: &UiText,
+    config: &Config,
+    model: &str,
+    usage: &TokenUsage,
+) -> Result<()> {
+    let mut summary = ui_text
+        .token_usage_value
+        .replacen("{}", &usage.prompt_tokens.to_string(), 1)
+        .replacen("{}", &usage.completion_tokens.to_string(), 1);
+    if let (Some(cache_hit), Some(rate)) = (usage.cache_hit_tokens, usage.cache_hit_rate()) {
+        summary.push_str(
+            &ui_text
+                .token_cache_hit
+                .replacen("{}", &cache_hit.to_string(), 1)
+                .replacen("{}", &format!("{:.0}", rate), 1),
+        );
+    }
+    if let Some(cost) = request_cost(config, model, usage) {
+        summary.push_str(&ui_text.token_cost.replace("{}", &format_cost(cost)));
+    }
+    term.write_line(&format!(
+        "{} {}",
+        style(ui_text.token_usage).info(),
+        summary
+    ))?;
+    Ok(())
+}
+
+/// 金额很小时保留更多小数位，避免显示为 $0.0000
+fn format_cost(cost: f64) -> String {
+    if cost > 0.0 && cost < 0.0001 {
+        format!("{:.6}", cost)
+    } else {
+        format!("{:.4}", cost)
+    }
+}
+
+/// --estimated-cost：发送前显示本次请求的估算 token 数和费用，并询问是否继续发送。
+/// `requests` 为同时发送的请求数（--race、--candidates）。返回 false 表示用户取消
+fn confirm_estimated_cost(
+    config: &Config,
+    model: &str,
+    prompt: &str,
+    history: Option<&ExecutionHistory>,
+    options: &RequestOptions,
+    requests: usize,
+) -> Result<bool> {
+    let ui_text = get_ui_text(&config.language);
+    let term = ui_term();
+    let tokens = (estimate_tokens(&build_system_prompt(config, options)?)
+        + estimate_tokens(&build_user_prompt(config, prompt, history, options)?))
+        * requests;
+    let message = match model_price(config, model) {
+        Some((input, output)) => {
+            let cost = (tokens as f64 * input
+                + (ESTIMATED_OUTPUT_TOKENS * requests) as f64 * output)
+                / 1_000_000.0;
+            ui_text
+                .estimated_cost
+                .replacen("{}", &tokens.to_string(), 1)
+                .replacen("{}", &format_cost(cost), 1)
+        }
+        None => ui_text
+            .estimated_cost_unknown
+            .replacen("{}", &tokens.to_string(), 1)
+            .replacen("{}", model, 1),
+    };
+    output::note(&term, &message)?;
+    // 无法交互时只显示估算结果
+    if NO_INTERACTIVE.load(Ordering::Relaxed) {
+        return Ok(true);
+    }
+    Ok(Confirm::with_theme(&dialog_theme())
+        .with_prompt(ui_text.estimated_cost_prompt)
+        .default(true)
+        .interact()?)
+}
+
+/// `ask tokenize`：估算文本本身的 token 数，以及把它用作系统提示、用户消息或模型回复时
+/// 一次请求的 token 数和费用。价格按 --model 或配置中的模型计算
+fn tokenize(text: Option<String>, model: Option<&str>) -> Result<()> {
+    use std::io::{IsTerminal, Read};
+
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let term = UiTerm::stdout();
+    let text = match text {
+        Some(text) => text,
+        None if !std::io::stdin().is_terminal() => {
+            let mut text = String::new();
+            std::io::stdin()
+                .read_to_string(&mut text)
+                .context("Unable to read standard input")?;
+            text
+        }
+        None => return Err(anyhow::anyhow!("{}", ui_text.tokenize_no_text)),
+    };
+    let model = model.unwrap_or(&config.api.model);
+    let price = model_price(&config, model);
+
+    let tokens = estimate_tokens(&text);
+    let as_system = estimate_tokens(&build_system_prompt(
+        &config,
+        &RequestOptions {
+            system_prompt: Some(text.clone()),
+            ..RequestOptions::default()
+        },
+    )?);
+    let options = RequestOptions::default();
+    let as_user = estimate_tokens(&build_system_prompt(&config, &options)?)
+        + estimate_tokens(&build_user_prompt(&config, &text, None, &options)?);
+    // 按每百万 token 的价格附上费用，价格未知时只显示 token 数
+    let with_cost = |tokens: usize, per_million: Option<f64>| match per_million {
+        Some(per_million) => format!(
+            "{} (${})",
+            tokens,
+            format_cost(tokens as f64 * per_million / 1_000_000.0)
+        ),
+        None => tokens.to_string(),
+    };
+
+    output::key_values(
+        &term,
+        &[
+            (
+                ui_text.tokenize_characters,
+                text.chars().count().to_string(),
+            ),
+            (
+                ui_text.tokenize_tokens,
+                style(tokens).code().bold().to_string(),
+            ),
+            (
+                ui_text.tokenize_as_system,
+                with_cost(as_system, price.map(|(input, _)| input)),
+            ),
+            (
+                ui_text.tokenize_as_user,
+                with_cost(as_user, price.map(|(input, _)| input)),
+            ),
+            (
+                ui_text.tokenize_as_completion,
+                with_cost(tokens, price.map(|(_, output)| output)),
+            ),
+            (ui_text.tokenize_model, model.to_string()),
+        ],
+    )?;
+    term.write_line("")?;
+    if price.is_none() {
+        output::note(&term, &ui_text.tokenize_price_unknown.replace("{}", model))?;
+    }
+    output::note(&term, ui_text.tokenize_note)?;
+    Ok(())
+}
+
+/// 粗略估算 token 数：ASCII 字符约 4 个一个 token，其他字符（例如中文）每个算一个 token
+fn estimate_tokens(text: &str) -> usize {
+    let ascii = text.bytes().filter(u8::is_ascii).count();
+    let other = text.chars().filter(|c| !c.is_ascii()).count();
+    ascii.div_ceil(4) + other
+}
+
+/// 重试时上一次执行的输出使提示超过 [safety] max_prompt_chars 时，
+/// 按 [behavior] output_truncation_strategy 截短输出。
+/// 用户自己输入的内容不会被裁剪，仍然超出时由 chat_completion 拒绝发送
+fn fit_prompt_limit(
+    config: &Config,
+    prompt: &str,
+    history: &mut Option<ExecutionHistory>,
+    options: &RequestOptions,
+) -> Result<()> {
+    let max_chars = config.safety.max_prompt_chars;
+    let Some(h) = history
+        .as_mut()
+        .filter(|h| max_chars > 0 && !h.output.is_empty())
+    else {
+        return Ok(());
+    };
+    h.output = output_for_prompt(config, &h.output);
+    let chars = build_system_prompt(config, options)?.chars().count()
+        + build_user_prompt(config, prompt, Some(h), options)?
+            .chars()
+            .count();
+    if chars <= max_chars {
+        return Ok(());
+    }
+    let keep = h.output.chars().count().saturating_sub(chars - max_chars);
+    h.output = truncate_output(&h.output, keep, config.behavior.output_truncation_strategy);
+    let ui_text = get_ui_text(&config.language);
+    output::warning(
+        &ui_term(),
+        &ui_text
+            .prompt_output_trimmed
+            .replace("{}", &max_chars.to_string()),
+    )?;
+    Ok(())
+}
+
+/// 发送前检查提示是否接近模型的上下文窗口，接近时发出警告并询问是否自动裁剪：
+/// 先裁剪上一次执行的输出（保留末尾），再按顺序移除附加的上下文
+fn check_context_window(
+    config: &Config,
+    model: &str,
+    prompt: &str,
+    history: &mut Option<ExecutionHistory>,
+    options: &mut RequestOptions,
+) -> Result<()> {
+    let Some(window) = context_window(config, model) else {
+        return Ok(());
+    };
+    let limit = (window as f64 * CONTEXT_WARNING_RATIO) as usize;
+    let system_tokens = estimate_tokens(&build_system_prompt(config, options)?);
+    let estimate =
+        |history: &Option<ExecutionHistory>, options: &RequestOptions| -> Result<usize> {
+            Ok(system_tokens
+                + estimate_tokens(&build_user_prompt(
+                    config,
+                    prompt,
+                    history.as_ref(),
+                    options,
+                )?))
+        };
+    let tokens = estimate(history, options)?;
+    if tokens <= limit {
+        return Ok(());
+    }
+
+    let ui_text = get_ui_text(&config.language);
+    let term = ui_term();
+    output::warning(
+        &term,
+        &ui_text
+            .context_window_warning
+            .replacen("{}", &tokens.to_string(), 1)
+            .replacen("{}", &window.to_string(), 1),
+    )?;
+    // 无法交互时只给出警告，由 API 决定是否接受
+    if NO_INTERACTIVE.load(Ordering::Relaxed) {
+        return Ok(());
+    }
+    let trim = Confirm::with_theme(&dialog_theme())
+        .with_prompt(ui_text.context_trim_prompt)
+        .default(true)
+        .interact()?;
+    if !trim {
+        return Ok(());
+    }
+
+    loop {
+        if estimate(history, options)? <= limit {
+            break;
+        }
+        match history {
+            Some(h) if !h.output.is_empty() => {
+                let chars = h.output.chars().count();
+                h.output = if chars < 64 {
+                    String::new()
+                } else {
+                    h.output.chars().skip(chars / 2).collect()
+                };
+            }
+            _ if !options.context.is_empty() => {
+                options.context.remove(0);
+            }
+            _ => break,
+        }
+    }
+    output::note(
+        &term,
+        &ui_text
+            .context_trimmed
+            .replace("{}", &estimate(history, options)?.to_string()),
+    )?;
+    Ok(())
+}
+
+/// 根据 [api] 的 TLS 设置创建 HTTP 客户端。每次运行只创建一个，多次尝试、--race 和 --candidates
+/// 的请求复用已建立的连接，不必每次都重新握手
+fn build_client(api: &ApiConfig, ui_text: &UiText) -> Result<Client> {
+    let mut builder = Client::builder();
+    if let Some(path) = &api.tls_ca_cert {
+        let pem =
+            fs::read(path).with_context(|| format!("Unable to read CA certificate {}", path))?;
+        let certificate = reqwest::Certificate::from_pem(&pem)
+            .with_context(|| format!("Invalid CA certificate {}", path))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+    if !api.tls_verify {
+        output::error(&ui_term(), ui_text.tls_verify_disabled)?;
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    builder.build().context("Unable to create HTTP client")
+}
+
+/// 发送一次对话请求并返回模型回复的原始文本
+async fn chat_completion(
+    config: &Config,
+    client: &Client,
+    system_prompt: &str,
+    user_prompt: &str,
+    options: &RequestOptions,
+    debug: bool,
+) -> Result<String> {
+    chat_completion_with_metadata(config, client, system_prompt, user_prompt, options, debug)
+        .await
+        .map(|(reply, _)| reply)
+}
+
+/// 响应中除回复内容以外的信息，服务商没有返回的项为 None
+#[derive(Debug, Default)]
+struct ReplyMetadata {
+    system_fingerprint: Option<String>,
+    /// usage 中的 token 总数
+    total_tokens: Option<u64>,
+    usage: Option<TokenUsage>,
+    /// 服务商实际使用的模型，例如请求 gpt-4o 时返回 gpt-4o-2024-08-06
+    model: Option<String>,
+}
+
+/// 同 chat_completion，同时返回响应中的 system_fingerprint、token 用量和实际使用的模型
+async fn chat_completion_with_metadata(
+    config: &Config,
+    client: &Client,
+    system_prompt: &str,
+    user_prompt: &str,
+    options: &RequestOptions,
+    debug: bool,
+) -> Result<(String, ReplyMetadata)> {
+    mark_waiting();
+    let ui_text = get_ui_text(&config.language);
+    // 在建立连接之前检查，避免上传过大的提示后才被服务商拒绝
+    let max_chars = config.safety.max_prompt_chars;
+    let chars = system_prompt.chars().count() + user_prompt.chars().count();
+    if max_chars > 0 && chars > max_chars {
+        return Err(anyhow::anyhow!(ui_text
+            .prompt_too_long
+            .replacen("{}", &chars.to_string(), 1)
+            .replacen("{}", &max_chars.to_string(), 1)));
+    }
+    let model = options.model.as_deref().unwrap_or(&config.api.model);
+    let term = ui_term();
+
+    if debug {
+        output::heading(&term, ui_text.debug_info)?;
+        output::info(&term, ui_text.debug_system_prompt)?;
+        term.write_line(system_prompt)?;
+        output::info(&term, ui_text.debug_user_prompt)?;
+        term.write_line(user_prompt)?;
+        term.write_line("")?;
+    }
+
+    let body = chat_request_body(config, model, system_prompt, user_prompt, options);
+    let mut stalls = 0;
+    let (status, body) = loop {
+        let mut request = chat_completions_request(client, &config.api, model).json(&body);
+        if let Some(header) = &config.api.request_id_header {
+            let request_id = uuid::Uuid::new_v4().to_string();
+            tracing::debug!(%request_id, "sending request");
+            if debug {
+                term.write_line(&format!(
+                    "{} {}",
+                    style(ui_text.debug_request_id).info(),
+                    request_id
+                ))?;
+            }
+            request = request.header(header.as_str(), request_id);
+        }
+        let response = request.send().await.context("Failed to send request")?;
+
+        let status = response.status();
+        tracing::debug!(%status, "received response");
+        if let Some(body) = read_response_body(response, options.response_timeout).await? {
+            break (status, body);
+        }
+
+        // 连接卡住时重新发送请求
+        let millis = options
+            .response_timeout
+            .unwrap_or_default()
+            .as_millis()
+            .to_string();
+        stalls += 1;
+        tracing::warn!(stalls, "response stalled");
+        if stalls > RESPONSE_TIMEOUT_RETRIES {
+            return Err(anyhow::anyhow!(ui_text
+                .response_stalled
+                .replace("{}", &millis)));
+        }
+        output::warning(
+            &term,
+            &ui_text.response_stalled_retry.replace("{}", &millis),
+        )?;
+    };
+    if debug {
+        output::info(&term, ui_text.debug_raw_response)?;
+        term.write_line(&body)?;
+        term.write_line("")?;
+    }
+
+    let reply = parse_chat_response(status, &body, ui_text)?;
+    let metadata = serde_json::from_str::<ChatResponse>(&body)
+        .map(|response| ReplyMetadata {
+            system_fingerprint: response.system_fingerprint,
+            model: response.model,
+            total_tokens: response.usage.as_ref().and_then(|usage| {
+                usage.total_tokens.or_else(|| {
+                    Some(usage.prompt_tokens? + usage.completion_tokens.unwrap_or_default())
+                })
+            }),
+            usage: response.usage.as_ref().and_then(ChatUsage::token_usage),
+        })
+        .unwrap_or_default();
+    Ok((reply, metadata))
+}
+
+/// chat/completions 的请求体。系统提示放在最前面，并且在同一会话的各次尝试中保持逐字节相同
+/// （执行历史只出现在用户消息中），这样 DeepSeek 等支持前缀缓存的服务商在重试时可以命中缓存
+fn chat_request_body(
+    config: &Config,
+    model: &str,
+    system_prompt: &str,
+    user_prompt: &str,
+    options: &RequestOptions,
+) -> serde_json::Value {
+    let mut body = json!({
+        "model": model,
+        "messages": [
+            {
+                "role": "system",
+                "content": system_prompt,
+            },
+            {
+                "role": "user",
+                "content": user_prompt,
+            }
+        ]
+    });
+    if let Some(temperature) = options.temperature {
+        body["temperature"] = json!(temperature);
+    }
+    if let (Some(seed), true) = (options.seed, config.api.provider.supports_seed()) {
+        body["seed"] = json!(seed);
+    }
+    body
+}
+
+/// --response-timeout 触发后重新发送请求的次数
+const RESPONSE_TIMEOUT_RETRIES: u32 = 2;
+
+/// 逐块读取响应内容；设置了 `timeout` 时，等待下一个数据块超过该时间则返回 None
+async fn read_response_body(
+    mut response: reqwest::Response,
+    timeout: Option<Duration>,
+) -> Result<Option<String>> {
+    let mut body = Vec::new();
+    loop {
+        let chunk = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, response.chunk()).await {
+                Ok(chunk) => chunk,
+                Err(_) => return Ok(None),
+            },
+            None => response.chunk().await,
+        };
+        match chunk.context("Failed to read response")? {
+            Some(chunk) => body.extend_from_slice(&chunk),
+            None => break,
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+#[derive(serde::Deserialize)]
+struct ChatResponse {
+    choices: Option<Vec<ChatChoice>>,
+    system_fingerprint: Option<String>,
+    usage: Option<ChatUsage>,
+    model: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatUsage {
+    prompt_tokens: Option<u64>,
+    completion_tokens: Option<u64>,
+    total_tokens: Option<u64>,
+    /// DeepSeek 返回的命中和未命中前缀缓存的输入 token 数，两者之和为 prompt_tokens
+    prompt_cache_hit_tokens: Option<u64>,
+    prompt_cache_miss_tokens: Option<u64>,
+}
+
+impl ChatUsage {
+    /// 没有返回输入 token 数时无法计算费用，返回 None
+    fn token_usage(&self) -> Option<TokenUsage> {
+        Some(TokenUsage {
+            prompt_tokens: self.prompt_tokens?,
+            completion_tokens: self.completion_tokens.unwrap_or_default(),
+            cache_hit_tokens: self.prompt_cache_hit_tokens,
+            cache_miss_tokens: self.prompt_cache_miss_tokens,
+        })
+    }
+}
+
+/// 一次请求输入、输出的 token 数
+#[derive(Debug, Clone, Copy)]
+struct TokenUsage {
+    prompt_tokens: u64,
+    completion_tokens: u64,
+    /// 输入中命中和未命中前缀缓存的部分，服务商不支持或没有返回时为 None
+    cache_hit_tokens: Option<u64>,
+    cache_miss_tokens: Option<u64>,
+}
+
+impl TokenUsage {
+    /// 前缀缓存命中率（百分比），返回了未命中数时按命中数与未命中数之和计算
+    fn cache_hit_rate(&self) -> Option<f64> {
+        let hit = self.cache_hit_tokens?;
+        let total = match self.cache_miss_tokens {
+            Some(miss) => hit + miss,
+            None => self.prompt_tokens,
+        };
+        Some(hit as f64 * 100.0 / total.max(1) as f64)
+    }
+}
+
+#[derive(serde::Deserialize)]
+struct ChatChoice {
+    message: Option<ChatMessage>,
+    finish_reason: Option<String>,
+    /// Azure OpenAI 在内容被过滤时返回的各分类结果
+    content_filter_results: Option<HashMap<String, ContentFilterResult>>,
+}
+
+#[derive(serde::Deserialize)]
+struct ChatMessage {
+    content: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ContentFilterResult {
+    #[serde(default)]
+    filtered: bool,
+    severity: Option<String>,
+}
+
+#[derive(serde::Deserialize)]
+struct ApiErrorResponse {
+    error: ApiErrorDetail,
+}
+
+#[derive(serde::Deserialize)]
+struct ApiErrorDetail {
+    message: String,
+}
+
+/// 解析 chat/completions 的响应体，针对各种异常情况给出明确的错误信息
+fn parse_chat_response(
+    status: reqwest::StatusCode,
+    body: &str,
+    ui_text: &UiText,
+) -> Result<String> {
+    if !status.is_success() {
+        let message = serde_json::from_str::<ApiErrorResponse>(body)
+            .map(|response| response.error.message)
+            .unwrap_or_else(|_| body.trim().to_string());
+        let lowercase = body.to_lowercase();
+        if status == reqwest::StatusCode::BAD_REQUEST
+            && [
+                "context_length_exceeded",
+                "maximum context length",
+                "context window",
+            ]
+            .iter()
+            .any(|marker| lowercase.contains(marker))
+        {
+            return Err(anyhow::anyhow!(
+                "{} {}",
+                ui_text.context_length_exceeded,
+                message
+            ));
+        }
+        return Err(anyhow::anyhow!(
+            "{} ({}) {}",
+            ui_text.response_api_error,
+            status,
+            message
+        ));
+    }
+
+    let response: ChatResponse = serde_json::from_str(body)
+        .map_err(|err| anyhow::anyhow!("{} {}", ui_text.response_unexpected_shape, err))?;
+    let choices = response
+        .choices
+        .ok_or_else(|| anyhow::anyhow!(ui_text.response_unexpected_shape))?;
+    let choice = choices
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!(ui_text.response_no_choices))?;
+
+    if choice.finish_reason.as_deref() == Some("content_filter") {
+        let categories: Vec<String> = choice
+            .content_filter_results
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|(_, result)| result.filtered)
+            .map(|(category, result)| match result.severity {
+                Some(severity) => format!("{} ({})", category, severity),
+                None => category,
+            })
+            .collect();
+        if categories.is_empty() {
+            return Err(anyhow::anyhow!(ui_text.response_content_filtered));
+        }
+        return Err(anyhow::anyhow!(
+            "{} {}",
+            ui_text.response_content_filtered,
+            categories.join(", ")
+        ));
+    }
+
+    choice
+        .message
+        .and_then(|message| message.content)
+        .ok_or_else(|| anyhow::anyhow!(ui_text.response_null_content))
+}
+
+/// 并发请求多个模型，返回最先成功的模型及其生成的命令，其余请求随即取消
+async fn race_ai_responses(
+    config: &Arc<Config>,
+    client: &Client,
+    models: &[String],
+    prompt: &str,
+    history: Option<&ExecutionHistory>,
+    options: &RequestOptions,
+    debug: bool,
+) -> Result<(String, AiResponse)> {
+    let mut tasks = tokio::task::JoinSet::new();
+    for model in models {
+        let model = model.clone();
+        let prompt = prompt.to_string();
+        let history = history.cloned();
+        let options = options.with_model(&model);
+        let config = Arc::clone(config);
+        let client = client.clone();
+        tasks.spawn(async move {
+            let command =
+                get_ai_response(&config, &client, &prompt, history.as_ref(), &options, debug).await;
+            (model, command)
+        });
+    }
+
+    let mut last_error = None;
+    while let Some(joined) = tasks.join_next().await {
+        match joined.context("Model request task failed")? {
+            (model, Ok(command)) => {
+                tasks.abort_all();
+                return Ok((model, command));
+            }
+            (model, Err(err)) => last_error = Some(err.context(model)),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No models to race")))
+}
+
+/// --candidates 最多同时生成的候选数量
+const MAX_CANDIDATES: u8 = 5;
+
+/// 并行请求多个候选命令，按请求顺序返回成功的结果并去掉重复的命令，全部失败时返回最后一个错误
+async fn generate_candidates(
+    config: &Arc<Config>,
+    client: &Client,
+    count: u8,
+    prompt: &str,
+    history: Option<&ExecutionHistory>,
+    options: &RequestOptions,
+    debug: bool,
+) -> Result<Vec<AiResponse>> {
+    let mut tasks = tokio::task::JoinSet::new();
+    for index in 0..count {
+        let prompt = prompt.to_string();
+        let history = history.cloned();
+        let options = options.clone();
+        let config = Arc::clone(config);
+        let client = client.clone();
+        tasks.spawn(async move {
+            let response =
+                get_ai_response(&config, &client, &prompt, history.as_ref(), &options, debug).await;
+            (index, response)
+        });
+    }
+
+    let mut responses = Vec::new();
+    let mut last_error = None;
+    while let Some(joined) = tasks.join_next().await {
+        match joined.context("Model request task failed")? {
+            (index, Ok(response)) => responses.push((index, response)),
+            (_, Err(err)) => last_error = Some(err),
+        }
+    }
+    if responses.is_empty() {
+        return Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No candidates requested")));
+    }
+    responses.sort_by_key(|(index, _)| *index);
+    let mut seen = std::collections::HashSet::new();
+    Ok(responses
+        .into_iter()
+        .map(|(_, response)| response)
+        .filter(|response| seen.insert(response.command.clone()))
+        .collect())
+}
+
+/// 用 `sh -n` 只解析不执行命令，返回第一行语法错误；cmd 和 PowerShell 没有只解析的模式，不做检查
+fn check_syntax(command: &str) -> Option<String> {
+    use std::process::Stdio;
+
+    if !interpreter_is_posix() {
+        return None;
+    }
+    let (program, flag) = shell_interpreter();
+    let output = Command::new(program)
+        .arg("-n")
+        .arg(flag)
+        .arg(command)
+        .stdin(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        return None;
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    Some(
+        stderr
+            .lines()
+            .find(|line| !line.trim().is_empty())
+            .unwrap_or_default()
+            .trim()
+            .to_string(),
+    )
+}
+
+/// 运行 `program --help` 等待输出的最长时间，超时视为无法取得帮助信息
+const HELP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// 运行 `program --help` 并返回 stdout 和 stderr 的内容；无法运行或超时时返回 None。
+/// stdin 为空，程序不会等待输入
+fn program_help(program: &str) -> Option<String> {
+    use std::io::Read;
+    use std::process::Stdio;
+
+    let mut child = Command::new(program)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let readers = [
+        child
+            .stdout
+            .take()
+            .map(|pipe| Box::new(pipe) as Box<dyn Read + Send>),
+        child
+            .stderr
+            .take()
+            .map(|pipe| Box::new(pipe) as Box<dyn Read + Send>),
+    ]
+    .map(|pipe| {
+        std::thread::spawn(move || {
+            let mut text = String::new();
+            if let Some(mut pipe) = pipe {
+                let _ = pipe.read_to_string(&mut text);
+            }
+            text
+        })
+    });
+    let started = Instant::now();
+    loop {
+        match child.try_wait() {
+            Ok(Some(_)) => break,
+            Ok(None) if started.elapsed() < HELP_TIMEOUT => {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            _ => {
+                // 程序启动的子进程可能仍占用输出，超时后不再等待读取
+                let _ = child.kill();
+                let _ = child.wait();
+                return None;
+            }
+        }
+    }
+    let [stdout, stderr] = readers.map(|reader| reader.join().unwrap_or_default());
+    Some(stdout + &stderr)
+}
+
+/// 帮助信息中是否列出了 `flag`：作为独立的单词出现（`--color[=WHEN]` 也算），
+/// 单个短选项也可以出现在 BSD 风格的 `[-abc]` 中
+fn help_lists_flag(help: &str, flag: &str) -> bool {
+    let listed = Regex::new(&format!(
+        r"(?:^|[^A-Za-z0-9-]){}(?:$|[^A-Za-z0-9-])",
+        regex::escape(flag)
+    ))
+    .unwrap();
+    if listed.is_match(help) {
+        return true;
+    }
+    match flag.strip_prefix('-') {
+        Some(short) if short.chars().count() == 1 => {
+            Regex::new(&format!(r"\[-[^\]\s]*{}[^\]\s]*\]", regex::escape(short)))
+                .unwrap()
+                .is_match(help)
+        }
+        _ => false,
+    }
+}
+
+/// 命令第一段的主程序在第一个非选项参数之前使用、但 `--help` 没有列出的第一个选项。
+/// 只检查 PATH 中的程序；遇到非选项参数就停止，之后的可能是子命令（`git commit -m`）
+/// 或选项的值，不属于主程序。取不到帮助信息时不做判断。
+/// 需要运行 `<program> --help`，命中危险模式的程序（例如 `dd`、`mkfs`）不运行，也不做判断
+fn unknown_flag(command: &str, dangerous_patterns: &[String]) -> Option<(String, String)> {
+    let separators = Regex::new(r"&&|\|\||\||;").unwrap();
+    let line = command.lines().next()?;
+    let segment = separators.split(split_comment(line).0).next()?;
+    let mut tokens = segment
+        .split_whitespace()
+        .map(|token| token.trim_start_matches(['(', '{', '!']))
+        .skip_while(|token| token.is_empty() || token.contains('=') || *token == "sudo");
+    let program = tokens.next()?;
+    if program.contains('/')
+        || SHELL_BUILTINS.contains(&program)
+        || !find_in_path(program)
+        || find_dangerous_pattern(program, dangerous_patterns).is_some()
+    {
+        return None;
+    }
+    let numeric = Regex::new(r"^-[0-9]+$").unwrap();
+    let flags: Vec<&str> = tokens
+        .take_while(|token| token.starts_with('-') && *token != "--")
+        .filter(|token| token.len() > 1 && !numeric.is_match(token))
+        .map(|token| token.split('=').next().unwrap_or(token))
+        .collect();
+    if flags.is_empty() {
+        return None;
+    }
+    let help = program_help(program)?;
+    // `-la` 这样合并写的短选项，每个字母单独列出也算
+    let listed = |flag: &str| {
+        help_lists_flag(&help, flag)
+            || (!flag.starts_with("--")
+                && flag.len() > 2
+                && flag[1..]
+                    .chars()
+                    .all(|c| help_lists_flag(&help, &format!("-{}", c))))
+    };
+    flags
+        .into_iter()
+        .find(|flag| !listed(flag))
+        .map(|flag| (program.to_string(), flag.to_string()))
+}
+
+/// 不执行命令本身的本地预检：命令中的程序是否都能找到、语法是否正确、主程序的选项是否存在。
+/// 检查选项时会运行 `<program> --help`（命中危险模式的程序除外）。返回未通过的原因
+fn preflight(command: &str, ui_text: &UiText, dangerous_patterns: &[String]) -> Option<String> {
+    if let Some(program) = find_missing_programs(command).first() {
+        return Some(ui_text.preflight_missing_program.replace("{}", program));
+    }
+    if let Some(error) = check_syntax(command) {
+        return Some(ui_text.preflight_syntax_error.replace("{}", &error));
+    }
+    unknown_flag(command, dangerous_patterns).map(|(program, flag)| {
+        ui_text
+            .preflight_unknown_flag
+            .replacen("{}", &program, 1)
+            .replacen("{}", &flag, 1)
+    })
+}
+
+/// 显示带预检结果的候选列表并让用户选择；未通过预检的候选排在后面但仍可选择。
+/// 无法交互时（--print、--no-interactive）直接使用排在最前面的候选
+fn choose_candidate(
+    term: &UiTerm,
+    ui_text: &UiText,
+    dangerous_patterns: &[String],
+    responses: Vec<AiResponse>,
+) -> Result<AiResponse> {
+    let mut checked: Vec<(AiResponse, Option<String>)> = responses
+        .into_iter()
+        .map(|response| {
+            let failure = preflight(&response.command, ui_text, dangerous_patterns);
+            (response, failure)
+        })
+        .collect();
+    checked.sort_by_key(|(_, failure)| failure.is_some());
+
+    let items: Vec<String> = checked
+        .iter()
+        .map(|(response, failure)| {
+            // 多行命令在列表中只显示第一行
+            let mut lines = response.command.lines();
+            let mut command = lines.next().unwrap_or_default().to_string();
+            if lines.next().is_some() {
+                command.push_str(" ...");
+            }
+            match failure {
+                None => format!("{} {}", style(ui_text.preflight_passed).positive(), command),
+                Some(reason) => format!(
+                    "{} {}  {}",
+                    style(ui_text.preflight_failed).red(),
+                    command,
+                    style(reason).secondary()
+                ),
+            }
+        })
+        .collect();
+
+    let index = if PRINT_MODE.load(Ordering::Relaxed) || NO_INTERACTIVE.load(Ordering::Relaxed) {
+        output::heading(term, ui_text.candidates_title)?;
+        for item in &items {
+            term.write_line(&format!("  {}", item))?;
+        }
+        0
+    } else {
+        ensure_interactive(ui_text, ui_text.candidates_title)?;
+        Select::with_theme(&dialog_theme())
+            .with_prompt(ui_text.candidates_title)
+            .items(&items)
+            .default(0)
+            .interact()?
+    };
+    Ok(checked.swap_remove(index).0)
+}
+
+/// 按行对比两段文本并左右并排显示，左侧为删除的行，右侧为新增的行
+fn print_side_by_side_diff(
+    term: &UiTerm,
+    left_title: &str,
+    left: &str,
+    right_title: &str,
+    right: &str,
+) -> Result<()> {
+    let (_, columns) = term.size();
+    let width = (usize::from(columns).saturating_sub(3) / 2).max(20);
+    let (bar, cross, rule, ellipsis) = if ascii_mode() {
+        ("|", "+", "-", "~")
+    } else {
+        ("│", "┼", "─", "…")
+    };
+    let cell = |text: &str| {
+        let text = console::truncate_str(text, width, ellipsis);
+        format!(
+            "{}{}",
+            text,
+            " ".repeat(width - console::measure_text_width(&text))
+        )
+    };
+
+    term.write_line(&format!(
+        "{} {} {}",
+        style(cell(left_title)).bold(),
+        bar,
+        style(right_title).bold()
+    ))?;
+    term.write_line(&format!(
+        "{}{}{}",
+        rule.repeat(width + 1),
+        cross,
+        rule.repeat(width + 1)
+    ))?;
+
+    let diff = similar::TextDiff::from_lines(left, right);
+    let mut deleted: Vec<String> = Vec::new();
+    let mut inserted: Vec<String> = Vec::new();
+    let flush = |deleted: &mut Vec<String>, inserted: &mut Vec<String>| -> Result<()> {
+        for row in 0..deleted.len().max(inserted.len()) {
+            let old = deleted.get(row).map(String::as_str).unwrap_or("");
+            let new = inserted.get(row).map(String::as_str).unwrap_or("");
+            term.write_line(&format!(
+                "{} {} {}",
+                style(cell(old)).red(),
+                bar,
+                style(new).positive()
+            ))?;
+        }
+        deleted.clear();
+        inserted.clear();
+        Ok(())
+    };
+
+    for change in diff.iter_all_changes() {
+        let line = change.value().trim_end_matches('\n').to_string();
+        match change.tag() {
+            similar::ChangeTag::Delete => deleted.push(line),
+            similar::ChangeTag::Insert => inserted.push(line),
+            similar::ChangeTag::Equal => {
+                flush(&mut deleted, &mut inserted)?;
+                term.write_line(&format!("{} {} {}", cell(&line), bar, line))?;
+            }
+        }
+    }
+    flush(&mut deleted, &mut inserted)
+}
+
+async fn diff_models(model1: &str, model2: &str, prompt: &str, debug: bool) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+
+    output::info(&term, ui_text.thinking)?;
+    let (first_options, second_options) = (
+        RequestOptions::default().with_model(model1),
+        RequestOptions::default().with_model(model2),
+    );
+    let (first, second) = tokio::join!(
+        get_ai_response(&config, &client, prompt, None, &first_options, debug),
+        get_ai_response(&config, &client, prompt, None, &second_options, debug)
+    );
+    let (first, second) = (
+        first.context(model1.to_string())?,
+        second.context(model2.to_string())?,
+    );
+
+    for (model, response) in [(model1, &first), (model2, &second)] {
+        term.write_line("")?;
+        term.write_line(&format!(
+            "{} {}",
+            style(ui_text.generated_command).info().bold(),
+            style(model).bold()
+        ))?;
+        output::write_block(&term, &styled_command(&response.command))?;
+    }
+
+    term.write_line("")?;
+    if first.command == second.command {
+        term.write_line(&format!(
+            "{}",
+            style(ui_text.diff_models_identical).positive()
+        ))?;
+        return Ok(());
+    }
+    print_side_by_side_diff(&term, model1, &first.command, model2, &second.command)
+}
+
+/// 从模型回复中提取 "1. xxx" / "1) xxx" 形式的步骤
+fn parse_plan(text: &str) -> Vec<String> {
+    let re = Regex::new(r"^\s*\d+[.)、]\s*(.+)$").unwrap();
+    text.lines()
+        .filter_map(|line| re.captures(line))
+        .map(|captures| captures[1].trim().to_string())
+        .collect()
+}
+
+/// 命令中一个参数或选项的解释
+#[derive(Clone, serde::Deserialize, serde::Serialize)]
+struct Annotation {
+    token: String,
+    explanation: String,
+}
+
+/// 教学模式的解释缓存，按界面语言和命令保存，重新生成相同的命令时不会重复请求
+fn get_annotation_cache_path() -> Result<std::path::PathBuf> {
+    Ok(get_config_dir()?.join("annotations.json"))
+}
+
+fn load_annotation_cache() -> HashMap<String, Vec<Annotation>> {
+    get_annotation_cache_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+/// 从模型的回复中取出 JSON 数组，兼容带有代码块标记或前后说明文字的回复
+fn parse_annotations(text: &str) -> Option<Vec<Annotation>> {
+    let start = text.find('[')?;
+    let end = text.rfind(']')?;
+    serde_json::from_str(text.get(start..=end)?).ok()
+}
+
+/// 获取命令的逐项解释，优先使用缓存
+async fn annotate_command(
+    config: &Config,
+    client: &Client,
+    command: &str,
+    options: &RequestOptions,
+    debug: bool,
+) -> Result<(Vec<Annotation>, Option<u64>)> {
+    let key = format!("{}:{}", config.language, command);
+    if let Some(annotations) = load_annotation_cache().get(&key) {
+        return Ok((annotations.clone(), Some(0)));
+    }
+
+    let annotate_prompt = match config.language.as_str() {
+        "zh" => ANNOTATE_PROMPT_ZH,
+        _ => ANNOTATE_PROMPT_EN,
+    };
+    let (reply, metadata) =
+        chat_completion_with_metadata(config, client, annotate_prompt, command, options, debug)
+            .await?;
+    let ui_text = get_ui_text(&config.language);
+    let annotations = parse_annotations(&reply)
+        .ok_or_else(|| anyhow::anyhow!("{}\n{}", ui_text.annotation_parse_error, reply.trim()))?;
+
+    // 在锁内重新读取缓存再加入这一项，不会覆盖其他会话同时写入的解释。
+    // 缓存写入失败（例如其他会话正在写入）只影响下次是否需要重新请求
+    let path = get_annotation_cache_path()?;
+    let updated = storage::update(&path, |current| {
+        let mut cache: HashMap<String, Vec<Annotation>> = current
+            .and_then(|content| serde_json::from_str(content).ok())
+            .unwrap_or_default();
+        cache.insert(key, annotations.clone());
+        Ok(serde_json::to_string_pretty(&cache)?)
+    });
+    if let Err(err) = updated {
+        tracing::warn!(error = %err, "failed to update annotation cache");
+    }
+    Ok((annotations, metadata.total_tokens))
+}
+
+/// 以编号、对齐的表格显示命令的逐项解释
+fn show_annotations(term: &UiTerm, ui_text: &UiText, annotations: &[Annotation]) -> Result<()> {
+    let width = annotations
+        .iter()
+        .map(|annotation| console::measure_text_width(&annotation.token))
+        .max()
+        .unwrap_or_default();
+    let number_width = annotations.len().to_string().len();
+    output::heading(term, ui_text.annotations_title)?;
+    for (index, annotation) in annotations.iter().enumerate() {
+        term.write_line(&format!(
+            "{:>number_width$}. {}  {}",
+            index + 1,
+            style(console::pad_str(
+                &annotation.token,
+                width,
+                console::Alignment::Left,
+                None
+            ))
+            .code(),
+            annotation.explanation
+        ))?;
+    }
+    term.write_line("")?;
+    Ok(())
+}
+
+/// `ask mnemonic` 的结果：记忆口诀以及命令各部分的解释
+#[derive(serde::Deserialize)]
+struct Mnemonic {
+    mnemonic: String,
+    #[serde(default)]
+    flags: Vec<Annotation>,
+}
+
+/// 从模型的回复中取出 JSON 对象，兼容带有代码块标记或前后说明文字的回复
+fn parse_json_object<T: serde::de::DeserializeOwned>(text: &str) -> Option<T> {
+    let start = text.find('{')?;
+    let end = text.rfind('}')?;
+    serde_json::from_str(text.get(start..=end)?).ok()
+}
+
+/// 为命令生成逐项解释和记忆口诀，只展示不执行
+async fn mnemonic(command: &str, raw: bool, debug: bool) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+
+    output::info(&term, ui_text.thinking)?;
+    let mnemonic_prompt = match config.language.as_str() {
+        "zh" => MNEMONIC_PROMPT_ZH,
+        _ => MNEMONIC_PROMPT_EN,
+    };
+    let reply = chat_completion(
+        &config,
+        &client,
+        mnemonic_prompt,
+        command,
+        &RequestOptions::default(),
+        debug,
+    )
+    .await?;
+    // --raw-output：原样输出模型的回复，不解析
+    if raw {
+        println!("{}", reply);
+        return Ok(());
+    }
+    let mnemonic = parse_json_object::<Mnemonic>(&reply)
+        .ok_or_else(|| anyhow::anyhow!("{}\n{}", ui_text.mnemonic_parse_error, reply.trim()))?;
+
+    term.write_line("")?;
+    output::write_block(&term, &styled_command(command))?;
+    term.write_line("")?;
+    if !mnemonic.flags.is_empty() {
+        show_annotations(&term, ui_text, &mnemonic.flags)?;
+    }
+    output::heading(&term, ui_text.mnemonic_title)?;
+    term.write_line(&format!(
+        "{}",
+        style(output::wrap(&term, mnemonic.mnemonic.trim())).positive()
+    ))?;
+    Ok(())
+}
+
+/// `ask security-audit` 的结果
+#[derive(serde::Deserialize)]
+struct SecurityAudit {
+    /// low / medium / high / critical
+    risk_level: String,
+    #[serde(default)]
+    summary: String,
+    #[serde(default)]
+    risks: Vec<String>,
+    #[serde(default)]
+    data_access: Vec<String>,
+    #[serde(default)]
+    network: Vec<String>,
+}
+
+/// 让模型对命令做安全审查，先醒目地显示风险等级，再列出风险、访问的数据和网络请求，只展示不执行
+async fn security_audit(command: &str, raw: bool, debug: bool) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+
+    output::info(&term, ui_text.thinking)?;
+    let audit_prompt = match config.language.as_str() {
+        "zh" => SECURITY_AUDIT_PROMPT_ZH,
+        _ => SECURITY_AUDIT_PROMPT_EN,
+    };
+    let reply = chat_completion(
+        &config,
+        &client,
+        audit_prompt,
+        command,
+        &RequestOptions::default(),
+        debug,
+    )
+    .await?;
+    // --raw-output：原样输出模型的回复，不解析
+    if raw {
+        println!("{}", reply);
+        return Ok(());
+    }
+    let audit = parse_json_object::<SecurityAudit>(&reply)
+        .ok_or_else(|| anyhow::anyhow!("{}\n{}", ui_text.audit_parse_error, reply.trim()))?;
+
+    term.write_line("")?;
+    output::write_block(&term, &styled_command(command))?;
+    term.write_line("")?;
+    let level = audit.risk_level.trim().to_lowercase();
+    let (label, styled) = match level.as_str() {
+        "low" => (ui_text.risk_low, Style::new().positive()),
+        "medium" => (ui_text.risk_medium, Style::new().notice()),
+        "high" => (ui_text.risk_high, Style::new().red()),
+        "critical" => (ui_text.risk_critical, Style::new().red().reverse()),
+        _ => (level.as_str(), Style::new().notice()),
+    };
+    term.write_line(&format!(
+        "{} {}",
+        style(ui_text.risk_level).bold(),
+        styled
+            .bold()
+            .apply_to(format!(" {} ", label.to_uppercase()))
+    ))?;
+    if !audit.summary.trim().is_empty() {
+        term.write_line(&output::wrap(&term, audit.summary.trim()))?;
+    }
+    for (title, items) in [
+        (ui_text.audit_risks, &audit.risks),
+        (ui_text.audit_data_access, &audit.data_access),
+        (ui_text.audit_network, &audit.network),
+    ] {
+        term.write_line("")?;
+        output::heading(&term, title)?;
+        if items.is_empty() {
+            term.write_line(&format!("  {}", style(ui_text.audit_none).secondary()))?;
+        }
+        for item in items {
+            term.write_line(&format!("  - {}", item.trim()))?;
+        }
+    }
+    Ok(())
+}
+
+/// `ask refactor` 的结果
+#[derive(serde::Deserialize)]
+struct Refactoring {
+    command: String,
+    #[serde(default)]
+    improvements: Vec<String>,
+}
+
+/// 让模型改写命令，并排显示原命令和改写后的命令以及逐词的差异，再列出改进点，只展示不执行
+async fn refactor(command: &str, raw: bool, debug: bool) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+
+    output::info(&term, ui_text.thinking)?;
+    let refactor_prompt = match config.language.as_str() {
+        "zh" => REFACTOR_PROMPT_ZH,
+        _ => REFACTOR_PROMPT_EN,
+    };
+    let reply = chat_completion(
+        &config,
+        &client,
+        refactor_prompt,
+        command,
+        &RequestOptions::default(),
+        debug,
+    )
+    .await?;
+    // --raw-output：原样输出模型的回复，不解析
+    if raw {
+        println!("{}", reply);
+        return Ok(());
+    }
+    let refactoring = parse_json_object::<Refactoring>(&reply)
+        .filter(|refactoring| !refactoring.command.trim().is_empty())
+        .ok_or_else(|| anyhow::anyhow!("{}\n{}", ui_text.refactor_parse_error, reply.trim()))?;
+    let improved = refactoring.command.trim();
+
+    term.write_line("")?;
+    if improved == command.trim() {
+        output::write_block(&term, &styled_command(command))?;
+        term.write_line("")?;
+        output::success(&term, ui_text.refactor_unchanged)?;
+        return Ok(());
+    }
+    print_side_by_side_diff(
+        &term,
+        ui_text.refactor_original,
+        command.trim(),
+        ui_text.refactor_improved,
+        improved,
+    )?;
+
+    // 单行命令按行对比只能看出整行不同，再按词显示具体改动
+    term.write_line("")?;
+    output::heading(&term, ui_text.refactor_changes)?;
+    // 没有颜色时使用 git diff --word-diff 的 [-删除-]{+新增+} 标记
+    let colors = console::colors_enabled();
+    let diff = similar::TextDiff::from_words(command.trim(), improved);
+    // 相邻的同类改动合并成一段，避免每个词都单独带标记
+    let mut runs: Vec<(similar::ChangeTag, String)> = Vec::new();
+    for change in diff.iter_all_changes() {
+        match runs.last_mut() {
+            Some((tag, text)) if *tag == change.tag() => text.push_str(change.value()),
+            _ => runs.push((change.tag(), change.value().to_string())),
+        }
+    }
+    let changes: String = runs
+        .into_iter()
+        .map(|(tag, text)| match (tag, colors) {
+            (similar::ChangeTag::Delete, true) => style(text).red().strikethrough().to_string(),
+            (similar::ChangeTag::Delete, false) => format!("[-{}-]", text),
+            (similar::ChangeTag::Insert, true) => style(text).positive().to_string(),
+            (similar::ChangeTag::Insert, false) => format!("{{+{}+}}", text),
+            (similar::ChangeTag::Equal, _) => text,
+        })
+        .collect();
+    output::write_block(&term, &changes)?;
+
+    if let Some(pattern) = find_dangerous_pattern(improved, &config.safety.dangerous_patterns) {
+        term.write_line("")?;
+        output::warning(&term, ui_text.refactor_dangerous)?;
+        term.write_line(&danger_details(&pattern, &config.language))?;
+    }
+
+    term.write_line("")?;
+    output::heading(&term, ui_text.refactor_improvements)?;
+    if refactoring.improvements.is_empty() {
+        term.write_line(&format!("  {}", style(ui_text.audit_none).secondary()))?;
+    }
+    for improvement in &refactoring.improvements {
+        term.write_line(&format!("  - {}", improvement.trim()))?;
+    }
+    Ok(())
+}
+
+/// 先让模型给出编号的执行计划，再依次为每个步骤生成命令，只展示不执行
+async fn trace(prompt: &str, debug: bool) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+
+    output::info(&term, ui_text.thinking)?;
+    let trace_prompt = match config.language.as_str() {
+        "zh" => TRACE_PROMPT_ZH,
+        _ => TRACE_PROMPT_EN,
+    };
+    let plan = chat_completion(
+        &config,
+        &client,
+        trace_prompt,
+        prompt,
+        &RequestOptions::default(),
+        debug,
+    )
+    .await?;
+    let steps = parse_plan(&plan);
+    if steps.is_empty() {
+        return Err(anyhow::anyhow!(
+            "{}\n{}",
+            ui_text.trace_no_plan,
+            plan.trim()
+        ));
+    }
+
+    term.write_line("")?;
+    output::heading(&term, ui_text.trace_plan)?;
+    for (index, step) in steps.iter().enumerate() {
+        term.write_line(&format!("{}. {}", index + 1, step))?;
+    }
+
+    for (index, step) in steps.iter().enumerate() {
+        // 每个步骤都带上完整的计划，让模型知道前后步骤做了什么
+        let context = match config.language.as_str() {
+            "zh" => format!(
+                "这是整体任务“{}”的第 {} 步（共 {} 步），完整计划：\n{}",
+                prompt,
+                index + 1,
+                steps.len(),
+                plan.trim()
+            ),
+            _ => format!(
+                "This is step {} of {} of the overall task \"{}\". Full plan:\n{}",
+                index + 1,
+                steps.len(),
+                prompt,
+                plan.trim()
+            ),
+        };
+        let options = RequestOptions {
+            context: vec![context],
+            ..RequestOptions::default()
+        };
+        let response = get_ai_response(&config, &client, step, None, &options, debug).await?;
+
+        term.write_line("")?;
+        output::heading(
+            &term,
+            &ui_text
+                .trace_step
+                .replacen("{}", &(index + 1).to_string(), 1)
+                .replacen("{}", step, 1),
+        )?;
+        output::write_block(&term, &styled_command(&response.command))?;
+        if let Some(explanation) = &response.explanation {
+            output::note(&term, &output::wrap(&term, explanation))?;
+        }
+    }
+
+    term.write_line("")?;
+    output::note(&term, ui_text.trace_not_executed)?;
+    Ok(())
+}
+
+/// `ask learn` 最多参考最近的多少条失败记录
+const LEARN_MAX_FAILURES: usize = 20;
+
+/// 根据历史中“未达到预期目标”的记录，让 AI 给出系统提示的改进建议
+async fn learn(debug: bool) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+
+    let history = load_history()?;
+    let failures: Vec<&HistoryEntry> = history
+        .iter()
+        .filter(|entry| entry.goal_achieved == Some(false))
+        .collect();
+    if failures.is_empty() {
+        output::warning(&term, ui_text.learn_no_failures)?;
+        return Ok(());
+    }
+    let failures = &failures[failures.len().saturating_sub(LEARN_MAX_FAILURES)..];
+
+    let current = get_prompt(&config.language)?;
+    let mut user_prompt = format!("Current system prompt:\n{}\n\nFailed attempts:\n", current);
+    for (index, entry) in failures.iter().enumerate() {
+        let output: String = entry.output.trim().chars().take(500).collect();
+        user_prompt.push_str(&format!(
+            "\n{}. Request: {}\nCommand: {}\nOutput: {}\n",
+            index + 1,
+            entry.prompt,
+            entry.command,
+            output
+        ));
+    }
+    user_prompt.push_str("\nBased on these failed attempts, how should I update my system prompt?");
+
+    output::info(
+        &term,
+        &ui_text
+            .learn_analyzing
+            .replace("{}", &failures.len().to_string()),
+    )?;
+    let learn_prompt = match config.language.as_str() {
+        "zh" => LEARN_PROMPT_ZH,
+        _ => LEARN_PROMPT_EN,
+    };
+    let suggestion = chat_completion(
+        &config,
+        &client,
+        learn_prompt,
+        &user_prompt,
+        &RequestOptions::default(),
+        debug,
+    )
+    .await?;
+    let suggestion = format!("{}\n", suggestion.trim());
+
+    let diff = similar::TextDiff::from_lines(current.as_str(), suggestion.as_str());
+    let groups = diff.grouped_ops(3);
+    if groups.is_empty() {
+        output::success(&term, ui_text.learn_no_changes)?;
+        return Ok(());
+    }
+
+    term.write_line("")?;
+    output::heading(&term, ui_text.learn_suggested_diff)?;
+    let mut lines = Vec::new();
+    for (index, group) in groups.iter().enumerate() {
+        if index > 0 {
+            lines.push(style("...").secondary().to_string());
+        }
+        for op in group {
+            for change in diff.iter_changes(op) {
+                let line = change.value().trim_end_matches('\n');
+                let line = match change.tag() {
+                    similar::ChangeTag::Delete => style(format!("- {}", line)).red(),
+                    similar::ChangeTag::Insert => style(format!("+ {}", line)).positive(),
+                    similar::ChangeTag::Equal => style(format!("  {}", line)).secondary(),
+                };
+                lines.push(line.to_string());
+            }
+        }
+    }
+    output::write_block(&term, &lines.join("\n"))?;
+    term.write_line("")?;
+
+    ensure_interactive(ui_text, ui_text.learn_apply_prompt)?;
+    if Confirm::with_theme(&dialog_theme())
+        .with_prompt(ui_text.learn_apply_prompt)
+        .default(false)
+        .interact()?
+    {
+        let path = get_system_prompt_path(&config.language)?;
+        storage::replace(&path, &suggestion).context("Unable to save system prompt file")?;
+        term.write_line(&format!(
+            "{} {}",
+            style(ui_text.learn_applied).positive().bold(),
+            path.display()
+        ))?;
+    }
+    Ok(())
+}
+
+/// 设置 --print 后，界面输出全部写到 stderr，stdout 上只有生成的命令
+static PRINT_MODE: AtomicBool = AtomicBool::new(false);
+
+/// --print 模式下命令被判定为危险或被拒绝时的退出码
+const DANGEROUS_EXIT_CODE: i32 = 2;
+
+/// 界面输出使用的终端
+fn ui_term() -> UiTerm {
+    if PRINT_MODE.load(Ordering::Relaxed) {
+        UiTerm::stderr()
+    } else {
+        UiTerm::stdout()
+    }
+}
+
+/// 设置 --prepend-timestamp 后，界面输出的每一行前都会加上 UTC 时间
+static PREPEND_TIMESTAMP: AtomicBool = AtomicBool::new(false);
+
+/// 本次运行写入执行记录时使用的分组ID，来自 --session-id 或在启动时生成
+static SESSION_ID: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// 设置 --no-interactive 后，任何交互式提示都会直接报错
+static NO_INTERACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// [ui] bell 开启时的响铃阈值（秒），u64::MAX 表示关闭
+static BELL_AFTER_SECS: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// 开始等待（请求 AI、执行命令）的时间，显示交互式提示后清除
+static WAITING_SINCE: Mutex<Option<Instant>> = Mutex::new(None);
+
+/// 在请求 AI、执行命令等用户需要等待的操作开始时调用
+fn mark_waiting() {
+    if let Ok(mut since) = WAITING_SINCE.lock() {
+        since.get_or_insert_with(Instant::now);
+    }
+}
+
+/// 等待超过 [ui] bell_after_secs 后才出现的提示会响铃提醒，--print 和非终端环境下不会响铃
+fn ring_bell_if_waited() {
+    let since = WAITING_SINCE.lock().ok().and_then(|mut since| since.take());
+    let threshold = BELL_AFTER_SECS.load(Ordering::Relaxed);
+    let Some(since) = since else {
+        return;
+    };
+    if threshold == u64::MAX
+        || PRINT_MODE.load(Ordering::Relaxed)
+        || !console::user_attended_stderr()
+        || since.elapsed() < Duration::from_secs(threshold)
+    {
+        return;
+    }
+    let _ = console::Term::stderr().write_str("\x07");
+}
+
+/// 在显示交互式提示之前调用，--no-interactive 模式下返回错误
+fn ensure_interactive(ui_text: &UiText, prompt: &str) -> Result<()> {
+    if NO_INTERACTIVE.load(Ordering::Relaxed) {
+        return Err(anyhow::anyhow!(
+            "{} {}",
+            ui_text.interactive_prompt_required,
+            prompt
+        ));
+    }
+    ring_bell_if_waited();
+    Ok(())
+}
+
+/// 执行危险命令前要求用户完整输入确认短语，输入不一致时视为取消
+fn confirm_dangerous(term: &UiTerm, ui_text: &UiText, phrase: &str) -> Result<bool> {
+    let prompt = ui_text.danger_confirm_prompt.replace("{}", phrase);
+    ensure_interactive(ui_text, &prompt)?;
+    let input = dialoguer::Input::<String>::with_theme(&dialog_theme())
+        .with_prompt(&prompt)
+        .allow_empty(true)
+        .interact_text()?;
+    if input != phrase {
+        output::warning(term, ui_text.danger_confirm_mismatch)?;
+        return Ok(false);
+    }
+    Ok(true)
+}
+
+/// 在原始模式下监听按键，直到用户按下 Esc / Ctrl+C（返回 true）或 `stop` 被置位
+fn wait_for_cancel_key(stop: &AtomicBool) -> bool {
+    if terminal::enable_raw_mode().is_err() {
+        return false;
+    }
+    let mut cancelled = false;
+    while !stop.load(Ordering::Relaxed) {
+        if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+            continue;
+        }
+        if let Ok(Event::Key(key)) = event::read() {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.code == KeyCode::Esc || ctrl_c {
+                cancelled = true;
+                break;
+            }
+        }
+    }
+    let _ = terminal::disable_raw_mode();
+    cancelled
+}
+
+/// 自动重试前显示倒计时，按回车跳过等待；按 Esc / Ctrl+C 返回 false 表示不再重试
+fn retry_cooldown(term: &UiTerm, ui_text: &UiText, seconds: u64) -> Result<bool> {
+    // 输出被重定向时不能原地刷新倒计时，否则管道中会混入光标控制字符，只输出一行纯文本
+    if !term.is_term() {
+        term.write_line(
+            &ui_text
+                .retry_cooldown_plain
+                .replace("{}", &seconds.to_string()),
+        )?;
+        std::thread::sleep(Duration::from_secs(seconds));
+        return Ok(true);
+    }
+    let listen = console::user_attended() && terminal::enable_raw_mode().is_ok();
+    let mut proceed = true;
+    'countdown: for remaining in (1..=seconds).rev() {
+        term.clear_line()?;
+        term.write_str(&format!(
+            "{}",
+            style(ui_text.retry_cooldown.replace("{}", &remaining.to_string())).secondary()
+        ))?;
+        if !listen {
+            std::thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        let deadline = std::time::Instant::now() + Duration::from_secs(1);
+        while let Some(timeout) = deadline.checked_duration_since(std::time::Instant::now()) {
+            if !event::poll(timeout).unwrap_or(false) {
+                break;
+            }
+            if let Ok(Event::Key(key)) = event::read() {
+                let ctrl_c =
+                    key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.code == KeyCode::Esc || ctrl_c {
+                    proceed = false;
+                    break 'countdown;
+                }
+                if key.code == KeyCode::Enter {
+                    break 'countdown;
+                }
+            }
+        }
+    }
+    if listen {
+        let _ = terminal::disable_raw_mode();
+    }
+    term.clear_line()?;
+    Ok(proceed)
+}
+
+/// [behavior] confirm_threshold_ms：倒计时结束后自动执行命令。按 Ctrl+C / Esc 取消（返回 false），
+/// 按其他任意键立即执行
+fn auto_confirm_countdown(term: &UiTerm, ui_text: &UiText, threshold: Duration) -> Result<bool> {
+    let deadline = Instant::now() + threshold;
+    let listen = terminal::enable_raw_mode().is_ok();
+    let mut proceed = true;
+    while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+        // 按整秒向上取整显示剩余时间，最后不足一秒时显示 1
+        let seconds = left.as_millis().div_ceil(1000).max(1);
+        term.clear_line()?;
+        term.write_str(&format!(
+            "{}",
+            style(
+                ui_text
+                    .auto_confirm_countdown
+                    .replace("{}", &seconds.to_string())
+            )
+            .notice()
+        ))?;
+        // 每次最多等到下一个整秒，以便刷新显示
+        let tick = left - Duration::from_millis((seconds as u64 - 1) * 1000);
+        if !listen {
+            std::thread::sleep(tick);
+            continue;
+        }
+        if !event::poll(tick).unwrap_or(false) {
+            continue;
+        }
+        if let Ok(Event::Key(key)) = event::read() {
+            let ctrl_c =
+                key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            proceed = !(key.code == KeyCode::Esc || ctrl_c);
+            break;
+        }
+    }
+    if listen {
+        let _ = terminal::disable_raw_mode();
+    }
+    term.clear_line()?;
+    if !proceed {
+        output::warning(term, ui_text.auto_confirm_aborted)?;
+    }
+    Ok(proceed)
+}
+
+/// 等待 future 完成，期间用户可以按 Esc / Ctrl+C 取消；取消时返回 None 并丢弃进行中的请求
+async fn cancellable<T>(future: impl std::future::Future<Output = T>, listen: bool) -> Option<T> {
+    if !listen {
+        return Some(future.await);
+    }
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut listener = tokio::task::spawn_blocking({
+        let stop = Arc::clone(&stop);
+        move || wait_for_cancel_key(&stop)
+    });
+    tokio::pin!(future);
+
+    tokio::select! {
+        result = &mut future => {
+            stop.store(true, Ordering::Relaxed);
+            let _ = listener.await;
+            Some(result)
+        }
+        cancelled = &mut listener => match cancelled {
+            Ok(true) => None,
+            _ => Some(future.await),
+        },
+    }
+}
+
+/// 指定了 --syntax 且目标 shell 与当前 shell 一致时，命令改用目标 shell 执行，
+/// 避免例如 bash 语法的命令被交给 sh 执行
+static SYNTAX_SHELL: std::sync::OnceLock<TargetShell> = std::sync::OnceLock::new();
+
+/// 执行命令使用的解释器及其参数
+fn shell_interpreter() -> (&'static str, &'static str) {
+    if let Some(shell) = SYNTAX_SHELL.get() {
+        shell.interpreter()
+    } else if cfg!(target_os = "windows") {
+        ("cmd", "/C")
+    } else {
+        ("sh", "-c")
+    }
+}
+
+/// 执行命令的 shell 是否兼容 POSIX sh
+fn interpreter_is_posix() -> bool {
+    SYNTAX_SHELL
+        .get()
+        .map_or(!cfg!(target_os = "windows"), |shell| shell.is_posix())
+}
+
+/// 构造通过系统 shell 运行命令的 Command
+fn shell_command(command: &str) -> Command {
+    let (program, flag) = shell_interpreter();
+    let mut shell = Command::new(program);
+    shell.arg(flag).arg(command);
+    shell
+}
+
+/// 重试循环的状态，暂存会话时会完整保存下来
+#[derive(serde::Deserialize, serde::Serialize)]
+struct SessionState {
+    prompt: String,
+    /// 本次会话临时指定的模型（--model / --race）
+    model: Option<String>,
+    /// 本次会话中已执行的命令，最后一条会作为上下文发送给模型
+    history: Vec<ExecutionHistory>,
+    attempt: u32,
+    /// `ask run-tests` 的测试命令，命令执行成功后运行，以测试结果判断是否达到目标
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    test_command: Option<String>,
+    /// 会话开始的 Unix 时间戳（秒），用于统计达成目标的总耗时
+    #[serde(default = "unix_timestamp")]
+    started_at: u64,
+    /// 附加在用户提示之后的额外上下文，例如 `ask chain` 中上一步命令的输出
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    context: Vec<String>,
+    /// 本次会话的请求已消耗的 token 数，用于 --max-cost-tokens
+    #[serde(default)]
+    tokens_used: u64,
+}
+
+/// 暂存在 `~/.askai/sessions/<id>.json` 中的会话
+#[derive(serde::Deserialize, serde::Serialize)]
+struct SavedSession {
+    id: String,
+    /// Unix 时间戳（秒）
+    created_at: u64,
+    /// 命令执行时所在的目录，继续会话时会切换回这里
+    cwd: std::path::PathBuf,
+    #[serde(flatten)]
+    state: SessionState,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum GoalAnswer {
+    Achieved,
+    NotAchieved,
+    Later,
+}
+
+impl GoalAnswer {
+    /// 转换为历史记录中的 goal_achieved，稍后回答时为 None
+    fn achieved(self) -> Option<bool> {
+        match self {
+            GoalAnswer::Achieved => Some(true),
+            GoalAnswer::NotAchieved => Some(false),
+            GoalAnswer::Later => None,
+        }
+    }
+}
+
+fn ask_goal_achieved(ui_text: &UiText) -> Result<GoalAnswer> {
+    ensure_interactive(ui_text, ui_text.goal_achieved_prompt)?;
+    let choice = Select::with_theme(&dialog_theme())
+        .with_prompt(ui_text.goal_achieved_prompt)
+        .items(&[ui_text.goal_yes, ui_text.goal_no, ui_text.goal_later])
+        .default(0)
+        .interact()?;
+    Ok(match choice {
+        0 => GoalAnswer::Achieved,
+        1 => GoalAnswer::NotAchieved,
+        _ => GoalAnswer::Later,
+    })
+}
+
+/// 询问命令哪里不符合预期，留空表示不补充说明
+fn ask_feedback(ui_text: &UiText) -> Result<Option<String>> {
+    ensure_interactive(ui_text, ui_text.feedback_prompt)?;
+    let feedback = dialoguer::Input::<String>::with_theme(&dialog_theme())
+        .with_prompt(ui_text.feedback_prompt)
+        .allow_empty(true)
+        .interact_text()?;
+    let feedback = feedback.trim();
+    Ok((!feedback.is_empty()).then(|| feedback.to_string()))
+}
+
+/// 写入持久化的执行记录，失败时只记录日志，不影响当前会话
+fn record_history(
+    config: &HistoryConfig,
+    prompt: &str,
+    started_at: u64,
+    execution: &ExecutionHistory,
+    goal_achieved: Option<bool>,
+) {
+    let entry = redact_output(&execution.output, &config.redact_patterns).map(|output| {
+        let mut entry = HistoryEntry::new(
+            prompt,
+            &execution.command,
+            &output,
+            execution.success,
+            goal_achieved,
+        );
+        entry.attempt = Some(execution.attempt);
+        entry.seed = execution.seed;
+        entry.system_fingerprint = execution.system_fingerprint.clone();
+        entry.model = Some(execution.model.clone());
+        entry.response_model = execution.response_model.clone();
+        entry.temperature = execution.temperature;
+        entry.strategy = execution.strategy;
+        entry.operator = config.operator();
+        if goal_achieved == Some(true) {
+            entry.duration_secs = Some(entry.timestamp.saturating_sub(started_at));
+        }
+        entry
+    });
+    if let Err(err) = entry.and_then(|entry| append_history(&entry)) {
+        tracing::warn!(error = %err, "failed to record history");
+        // 其他会话正在写入历史时跳过这一条，不阻塞当前会话
+        if err.downcast_ref::<storage::Contended>().is_some() {
+            let ui_text = get_ui_text(&configured_language());
+            let _ = output::warning(&ui_term(), ui_text.history_write_skipped);
+        }
+    }
+}
+
+/// `ask batch` 文件中的一项
+#[derive(serde::Deserialize)]
+struct BatchCase {
+    prompt: String,
+    /// 命令的标准输出应匹配的正则表达式；省略时只生成命令，不执行
+    #[serde(default)]
+    expect: Option<String>,
+}
+
+/// 一项的检查结果
+enum BatchOutcome {
+    Generated,
+    Passed,
+    Failed(String),
+}
+
+/// 读取并校验 batch 文件，格式或正则有误时在发送任何请求之前报错
+fn read_batch_cases(path: &std::path::Path) -> Result<Vec<(BatchCase, Option<Regex>)>> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Unable to read {}", path.display()))?;
+    let mut cases = Vec::new();
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let case: BatchCase = serde_json::from_str(line)
+            .with_context(|| format!("{}:{}: invalid batch entry", path.display(), index + 1))?;
+        let expect = case
+            .expect
+            .as_deref()
+            .map(Regex::new)
+            .transpose()
+            .with_context(|| format!("{}:{}: invalid expect pattern", path.display(), index + 1))?;
+        cases.push((case, expect));
+    }
+    Ok(cases)
+}
+
+/// 生成一项的命令，设置了 expect 时再执行并检查输出
+async fn run_batch_case(
+    cli: &Cli,
+    config: &Config,
+    client: &Client,
+    case: &BatchCase,
+    expect: Option<&Regex>,
+) -> Result<(String, BatchOutcome)> {
+    let options = RequestOptions {
+        model: cli.model.clone(),
+        seed: cli.seed,
+        temperature: cli.seed.map(|_| 0.0),
+        syntax: cli.syntax,
+        ..RequestOptions::default()
+    };
+    let command = get_ai_response(config, client, &case.prompt, None, &options, cli.debug)
+        .await?
+        .command;
+    let Some(expect) = expect else {
+        return Ok((command, BatchOutcome::Generated));
+    };
+    let outcome = check_batch_command(config, &command, expect, cli.strict)?;
+    Ok((command, outcome))
+}
+
+/// 执行生成的命令并检查标准输出是否匹配 `expect`。与交互模式相同的安全检查会拦截命令，
+/// 被拦截的命令不会执行，直接视为失败
+fn check_batch_command(
+    config: &Config,
+    command: &str,
+    expect: &Regex,
+    strict: bool,
+) -> Result<BatchOutcome> {
+    let ui_text = get_ui_text(&config.language);
+    let blocked = if let Some(pattern) =
+        find_dangerous_pattern(command, &config.safety.dangerous_patterns)
+    {
+        Some(ui_text.batch_blocked.replace("{}", &pattern))
+    } else if let Some(segment) = find_pipe_to_shell(command) {
+        Some(ui_text.batch_blocked.replace("{}", &segment))
+    } else if !find_placeholders(command).is_empty() {
+        Some(ui_text.batch_placeholders.to_string())
+    } else {
+        match validate_command(config, command) {
+            (Verdict::Deny, message) => Some(format!("{} {}", ui_text.validator_denied, message)),
+            _ => None,
+        }
+    };
+    if let Some(reason) = blocked {
+        return Ok(BatchOutcome::Failed(reason));
+    }
+
+    let script = strict.then(|| with_strict_mode(command)).flatten();
+    let output = execute_command(script.as_deref().unwrap_or(command), None)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let outcome = if expect.is_match(&stdout) {
+        BatchOutcome::Passed
+    } else {
+        let exit = output
+            .status
+            .code()
+            .map_or_else(|| String::from("?"), |code| code.to_string());
+        BatchOutcome::Failed(
+            ui_text
+                .batch_mismatch
+                .replacen("{}", expect.as_str(), 1)
+                .replacen("{}", &exit, 1),
+        )
+    };
+    Ok(outcome)
+}
+
+/// `ask batch`：依次处理文件中的每一项，最后显示汇总；有失败项时以退出码 1 结束
+async fn run_batch(cli: &Cli, path: &std::path::Path) -> Result<()> {
+    let config = load_effective_config()?;
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = UiTerm::stdout();
+    let cases = read_batch_cases(path)?;
+
+    let (mut passed, mut failed) = (0, 0);
+    for (index, (case, expect)) in cases.iter().enumerate() {
+        term.write_line(&format!(
+            "{}",
+            style(format!("[{}/{}] {}", index + 1, cases.len(), case.prompt)).bold()
+        ))?;
+        let outcome = match run_batch_case(cli, &config, &client, case, expect.as_ref()).await {
+            Ok((command, outcome)) => {
+                output::write_block(&term, &styled_command(&command))?;
+                outcome
+            }
+            Err(err) => BatchOutcome::Failed(format!("{:#}", err)),
+        };
+        match outcome {
+            BatchOutcome::Generated => {}
+            BatchOutcome::Passed => {
+                passed += 1;
+                output::success(&term, ui_text.batch_pass)?;
+            }
+            BatchOutcome::Failed(reason) => {
+                failed += 1;
+                output::error(&term, &format!("{} {}", ui_text.batch_fail, reason))?;
+            }
+        }
+        term.write_line("")?;
+    }
+
+    let summary = ui_text
+        .batch_summary
+        .replacen("{}", &passed.to_string(), 1)
+        .replacen("{}", &failed.to_string(), 1)
+        .replacen("{}", &(cases.len() - passed - failed).to_string(), 1);
+    if failed > 0 {
+        output::error(&term, &summary)?;
+        std::process::exit(1);
+    }
+    output::success(&term, &summary)?;
+    Ok(())
+}
+
+/// 以 "45s"、"1m 12s"、"2h 5m" 的形式显示耗时
+fn format_duration(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m {}s", seconds / 60, seconds % 60),
+        _ => format!("{}h {}m", seconds / 3600, seconds % 3600 / 60),
+    }
+}
+
+/// 达成目标后可以对最终命令执行的操作
+#[derive(Clone, Copy)]
+enum FollowUp {
+    Copy,
+    Snippet,
+    ShellHistory,
+}
+
+/// 后续操作菜单等待按键的时间，超时后自动结束，避免会话停在菜单上
+const FOLLOW_UP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// 读取一次按键选择后续操作，其他按键（回车、Esc 等）或 FOLLOW_UP_TIMEOUT 内没有按键表示结束
+fn read_follow_up() -> Option<FollowUp> {
+    terminal::enable_raw_mode().ok()?;
+    let deadline = Instant::now() + FOLLOW_UP_TIMEOUT;
+    let choice = loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if !event::poll(remaining).unwrap_or(false) {
+            break None;
+        }
+        match event::read() {
+            Ok(Event::Key(key)) if key.modifiers.contains(KeyModifiers::CONTROL) => break None,
+            Ok(Event::Key(key)) => {
+                break match key.code {
+                    KeyCode::Char('c') => Some(FollowUp::Copy),
+                    KeyCode::Char('s') => Some(FollowUp::Snippet),
+                    KeyCode::Char('h') => Some(FollowUp::ShellHistory),
+                    _ => None,
+                }
+            }
+            Ok(_) => continue,
+            Err(_) => break None,
+        }
+    };
+    let _ = terminal::disable_raw_mode();
+    choice
+}
+
+/// 目标达成时显示会话摘要（需求、最终命令、尝试次数和耗时），`follow_ups` 为 true 时
+/// 再提供复制、保存等后续操作
+fn finish_session(
+    term: &UiTerm,
+    ui_text: &UiText,
+    prompt: &str,
+    started_at: u64,
+    execution: &ExecutionHistory,
+    follow_ups: bool,
+) -> Result<()> {
+    let duration = format_duration(unix_timestamp().saturating_sub(started_at));
+    term.write_line("")?;
+    output::success(term, ui_text.summary_title)?;
+    let rows = [
+        (ui_text.summary_prompt, prompt.to_string()),
+        (
+            ui_text.summary_command,
+            style(&execution.command).code().to_string(),
+        ),
+        (ui_text.summary_attempts, execution.attempt.to_string()),
+        (ui_text.summary_duration, duration),
+    ];
+    output::key_values(term, &rows)?;
+
+    if !follow_ups || NO_INTERACTIVE.load(Ordering::Relaxed) || !console::user_attended() {
+        return Ok(());
+    }
+    loop {
+        term.write_line("")?;
+        output::note(term, ui_text.follow_up_keys)?;
+        let result = match read_follow_up() {
+            Some(FollowUp::Copy) => copy_to_clipboard(&execution.command)
+                .map(|program| ui_text.copied.replace("{}", program)),
+            Some(FollowUp::Snippet) => {
+                let command = execution.template.as_ref().unwrap_or(&execution.command);
+                save_snippet(prompt, command).map(|path| {
+                    ui_text
+                        .snippet_saved
+                        .replace("{}", &path.display().to_string())
+                })
+            }
+            Some(FollowUp::ShellHistory) => append_shell_history(&execution.command).map(|path| {
+                ui_text
+                    .shell_history_added
+                    .replace("{}", &path.display().to_string())
+            }),
+            None => return Ok(()),
+        };
+        match result {
+            Ok(message) => output::success(term, &message)?,
+            Err(err) => output::warning(term, &err.to_string())?,
+        }
+    }
+}
+
+/// 按平台依次尝试的剪贴板程序
+const CLIPBOARD_PROGRAMS: [(&str, &[&str]); 5] = [
+    ("pbcopy", &[]),
+    ("clip", &[]),
+    ("wl-copy", &[]),
+    ("xclip", &["-selection", "clipboard"]),
+    ("xsel", &["--clipboard", "--input"]),
+];
+
+/// 通过系统剪贴板程序复制文本，返回使用的程序名
+fn copy_to_clipboard(text: &str) -> Result<&'static str> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let (program, args) = CLIPBOARD_PROGRAMS
+        .iter()
+        .find(|(program, _)| find_in_path(program))
+        .ok_or_else(|| {
+            let names: Vec<&str> = CLIPBOARD_PROGRAMS.iter().map(|(name, _)| *name).collect();
+            anyhow::anyhow!("No clipboard program found ({})", names.join(", "))
+        })?;
+    // xclip 等程序会在后台继续持有剪贴板，不能等待它们的 stdout 关闭
+    let mut child = Command::new(program)
+        .args(*args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("Unable to run {}", program))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(text.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(anyhow::anyhow!("{} exited with {}", program, status));
+    }
+    Ok(program)
+}
+
+/// 保存的命令片段，每行一个 JSON 对象，保存在 `~/.askai/snippets.jsonl` 中
+#[derive(serde::Deserialize, serde::Serialize)]
+struct Snippet {
+    /// Unix 时间戳（秒）
+    timestamp: u64,
+    prompt: String,
+    command: String,
+}
+
+fn get_snippets_path() -> Result<std::path::PathBuf> {
+    Ok(get_config_dir()?.join("snippets.jsonl"))
+}
+
+fn save_snippet(prompt: &str, command: &str) -> Result<std::path::PathBuf> {
+    let snippet = Snippet {
+        timestamp: unix_timestamp(),
+        prompt: prompt.to_string(),
+        command: command.to_string(),
+    };
+    let path = get_snippets_path()?;
+    storage::append_line(&path, &serde_json::to_string(&snippet)?)
+        .context("Unable to write snippets file")?;
+    Ok(path)
+}
+
+/// 把命令追加到当前 shell（bash、zsh、fish）的历史文件中，返回历史文件路径
+fn append_shell_history(command: &str) -> Result<std::path::PathBuf> {
+    use std::io::Write;
+
+    let shell = env::var("SHELL").unwrap_or_default();
+    let shell = std::path::Path::new(&shell)
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let home = dirs::home_dir().context("Unable to get home directory")?;
+    let histfile = env::var_os("HISTFILE").map(std::path::PathBuf::from);
+    let (path, entry) = match shell.as_str() {
+        "bash" => (
+            histfile.unwrap_or_else(|| home.join(".bash_history")),
+            format!("{}\n", command),
+        ),
+        // zsh 的扩展格式：`: <开始时间>:<耗时>;<命令>`，多行命令用反斜杠续行
+        "zsh" => (
+            histfile.unwrap_or_else(|| home.join(".zsh_history")),
+            format!(
+                ": {}:0;{}\n",
+                unix_timestamp(),
+                command.replace('\n', "\\\n")
+            ),
+        ),
+        "fish" => (
+            home.join(".local/share/fish/fish_history"),
+            format!(
+                "- cmd: {}\n  when: {}\n",
+                command.replace('\\', "\\\\").replace('\n', "\\n"),
+                unix_timestamp()
+            ),
+        ),
+        _ => {
+            return Err(anyhow::anyhow!(
+                "Unsupported shell `{}` for adding to history",
+                shell
+            ))
+        }
+    };
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    file.write_all(entry.as_bytes())
+        .with_context(|| format!("Unable to write {}", path.display()))?;
+    Ok(path)
+}
+
+fn unix_timestamp() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+fn get_sessions_dir() -> Result<std::path::PathBuf> {
+    let dir = get_config_dir()?.join("sessions");
+    if !dir.exists() {
+        fs::create_dir_all(&dir).context("Unable to create sessions directory")?;
+    }
+    Ok(dir)
+}
+
+/// 保存会话并返回会话ID，命令输出会先按 redact_patterns 脱敏
+fn save_session(config: &HistoryConfig, mut state: SessionState) -> Result<String> {
+    for execution in &mut state.history {
+        execution.output = redact_output(&execution.output, &config.redact_patterns)?;
+    }
+    let dir = get_sessions_dir()?;
+    let created_at = unix_timestamp();
+    let mut session = SavedSession {
+        id: String::new(),
+        created_at,
+        cwd: env::current_dir().context("Unable to get current directory")?,
+        state,
+    };
+    // 以时间戳作为ID，同一秒内暂存多个会话时依次递增；文件已存在（包括其他进程刚刚创建）时换下一个ID
+    let mut seed = created_at;
+    loop {
+        session.id = format!("{:x}", seed);
+        let content =
+            serde_json::to_string_pretty(&session).context("Unable to serialize session")?;
+        if storage::create_new(&dir.join(format!("{}.json", session.id)), content)
+            .context("Unable to save session file")?
+        {
+            return Ok(session.id);
+        }
+        seed += 1;
+    }
+}
+
+/// 读取所有暂存的会话，按创建时间从早到晚排序；无法解析的文件会被忽略
+fn load_sessions() -> Result<Vec<(std::path::PathBuf, SavedSession)>> {
+    let mut sessions = Vec::new();
+    for entry in fs::read_dir(get_sessions_dir()?).context("Unable to read sessions directory")? {
+        let path = entry?.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let session = fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|content| Ok(serde_json::from_str::<SavedSession>(&content)?));
+        match session {
+            Ok(session) => sessions.push((path, session)),
+            Err(err) => {
+                tracing::warn!(path = %path.display(), error = %err, "skipping session file")
+            }
+        }
+    }
+    sessions.sort_by_key(|(_, session)| session.created_at);
+    Ok(sessions)
+}
+
+/// 以 "5m"、"3h"、"2d" 的形式显示经过的时间
+fn format_age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => format!("{}s", seconds),
+        60..=3599 => format!("{}m", seconds / 60),
+        3600..=86399 => format!("{}h", seconds / 3600),
+        _ => format!("{}d", seconds / 86400),
+    }
+}
+
+async fn resume_session(cli: &Cli, id: Option<&str>, list: bool, clean: Option<u64>) -> Result<()> {
+    let config = Arc::new(load_effective_config()?);
+    let ui_text = get_ui_text(&config.language);
+    let term = UiTerm::stdout();
+    let sessions = load_sessions()?;
+    let now = unix_timestamp();
+
+    if list {
+        if sessions.is_empty() {
+            output::warning(&term, ui_text.no_sessions)?;
+        }
+        let mut listing = Vec::new();
+        for (_, session) in &sessions {
+            listing.push(format!(
+                "{}  {}  {}",
+                style(&session.id).bold(),
+                style(format!(
+                    "{:>4}",
+                    format_age(now.saturating_sub(session.created_at))
+                ))
+                .secondary(),
+                session.state.prompt
+            ));
+            if let Some(last) = session.state.history.last() {
+                listing.push(format!("    {}", style(&last.command).code()));
+            }
+        }
+        if !listing.is_empty() {
+            output::write_block(&term, &listing.join("\n"))?;
+        }
+        return Ok(());
+    }
+
+    if let Some(days) = clean {
+        let mut removed = 0;
+        for (path, session) in &sessions {
+            if now.saturating_sub(session.created_at) >= days * 86400 {
+                fs::remove_file(path).context("Unable to remove session file")?;
+                removed += 1;
+            }
+        }
+        term.write_line(&format!(
+            "{}",
+            style(ui_text.sessions_cleaned.replace("{}", &removed.to_string())).positive()
+        ))?;
+        return Ok(());
+    }
+
+    let (path, session) = match id {
+        Some(id) => sessions
+            .into_iter()
+            .find(|(_, session)| session.id == id)
+            .ok_or_else(|| anyhow::anyhow!("{} {}", ui_text.session_not_found, id))?,
+        None => sessions
+            .into_iter()
+            .next_back()
+            .ok_or_else(|| anyhow::anyhow!(ui_text.no_sessions))?,
+    };
+    env::set_current_dir(&session.cwd).with_context(|| {
+        format!(
+            "Unable to change to session directory {}",
+            session.cwd.display()
+        )
+    })?;
+
+    let mut state = session.state;
+    let last = state
+        .history
+        .last_mut()
+        .context("Session has no executed command")?;
+    term.write_line(&format!(
+        "{} {}",
+        style(ui_text.resume_task).info().bold(),
+        state.prompt
+    ))?;
+    output::note(
+        &term,
+        &format!("{} {}", ui_text.execution_directory, session.cwd.display()),
+    )?;
+    term.write_line("")?;
+    output::heading(&term, ui_text.generated_command)?;
+    output::write_block(&term, &styled_command(&last.command))?;
+    if !last.output.is_empty() {
+        term.write_line("")?;
+        term.write_line(&last.output)?;
+    }
+
+    let goal = ask_goal_achieved(ui_text)?;
+    if goal == GoalAnswer::Later {
+        output::info(&term, &ui_text.session_saved.replace("{}", &session.id))?;
+        return Ok(());
+    }
+    if goal == GoalAnswer::NotAchieved {
+        last.feedback = ask_feedback(ui_text)?;
+    }
+    record_history(
+        &config.history,
+        &state.prompt,
+        state.started_at,
+        last,
+        goal.achieved(),
+    );
+    fs::remove_file(&path).context("Unable to remove session file")?;
+    if goal == GoalAnswer::Achieved {
+        return finish_session(&term, ui_text, &state.prompt, state.started_at, last, true);
+    }
+
+    state.attempt += 1;
+    let client = build_client(&config.api, ui_text)?;
+    run_attempts(cli, &config, &client, state).await.map(|_| ())
+}
+
+/// --with-ls 最多列出的目录项数量
+const DIRECTORY_LISTING_LIMIT: usize = 100;
+
+/// 列出当前目录（不递归）的文件和子目录，遵循 .gitignore，超过上限时截断
+fn directory_listing(language: &str, limit: usize) -> Result<String> {
+    let cwd = env::current_dir().context("Unable to get current directory")?;
+    let mut entries: Vec<(bool, String, u64)> = ignore::WalkBuilder::new(&cwd)
+        .max_depth(Some(1))
+        .hidden(false)
+        .require_git(false)
+        .filter_entry(|entry| entry.file_name() != ".git")
+        .build()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.depth() == 1)
+        .map(|entry| {
+            let metadata = entry.metadata().ok();
+            let is_dir = metadata.as_ref().is_some_and(|metadata| metadata.is_dir());
+            let size = metadata.map(|metadata| metadata.len()).unwrap_or_default();
+            (
+                is_dir,
+                entry.file_name().to_string_lossy().to_string(),
+                size,
+            )
+        })
+        .collect();
+    // 目录在前，同类按名称排序
+    entries.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+
+    let mut listing = match language {
+        "zh" => format!("当前目录 {} 中的文件（目录以 / 结尾）：", cwd.display()),
+        _ => format!(
+            "Files in the current directory {} (directories end with /):",
+            cwd.display()
+        ),
+    };
+    for (is_dir, name, size) in entries.iter().take(limit) {
+        if *is_dir {
+            listing.push_str(&format!("\n{}/", name));
+        } else {
+            listing.push_str(&format!("\n{} ({} bytes)", name, size));
+        }
+    }
+    if entries.len() > limit {
+        listing.push_str(&match language {
+            "zh" => format!("\n……还有 {} 项未列出", entries.len() - limit),
+            _ => format!("\n... and {} more entries", entries.len() - limit),
+        });
+    }
+    Ok(listing)
+}
+
+/// 包含 heredoc 的命令写入临时脚本后执行，保证 heredoc 的内容原样交给 shell
+fn uses_temp_script(command: &str) -> bool {
+    if !interpreter_is_posix() {
+        return false;
+    }
+    let lines: Vec<&str> = command.lines().collect();
+    classify_lines(&lines)
+        .iter()
+        .any(|kind| *kind != LineKind::Command)
+}
+
+/// 严格模式下多行脚本的开头：遇错即停、引用未定义变量时报错，
+/// sh 不一定支持 pipefail，因此先在子 shell 中试一下再开启
+const STRICT_MODE_PREAMBLE: &str = "set -eu\n(set -o pipefail) 2>/dev/null && set -o pipefail\n";
+
+/// --strict 时给多行脚本加上严格模式的开头，单行命令原样返回。
+/// cmd、PowerShell 和 fish 没有对应的设置，也原样返回
+fn with_strict_mode(command: &str) -> Option<String> {
+    if !interpreter_is_posix() || !command.trim().contains('\n') {
+        return None;
+    }
+    Some(format!("{}{}", STRICT_MODE_PREAMBLE, command))
+}
+
+/// 把脚本写入临时目录中随机命名的新文件。文件以 create_new 创建（已存在时报错，不会跟随
+/// 其他用户预先放置的符号链接），Unix 上权限为 0700，其他用户无法在执行前读取或替换内容
+fn write_temp_script(command: &str) -> Result<std::path::PathBuf> {
+    use std::io::Write;
+
+    let path = env::temp_dir().join(format!("askai-{}.sh", uuid::Uuid::new_v4().simple()));
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o700);
+    }
+    let mut file = options
+        .open(&path)
+        .with_context(|| format!("Unable to create {}", path.display()))?;
+    file.write_all(format!("{}\n", command).as_bytes())
+        .context("Failed to write script file")?;
+    Ok(path)
+}
+
+/// PowerShell 中 cmdlet 失败（如 `Get-Item missing.txt`）默认不会影响进程退出码，
+/// 这里把 `-Command` 后的脚本包一层：遇错即停，并以 `$LASTEXITCODE` 或 1 退出
+#[cfg(windows)]
+const POWERSHELL_WRAPPER: &str = "$ErrorActionPreference = 'Stop'; try { {}; if ($LASTEXITCODE) { exit $LASTEXITCODE } } catch { [Console]::Error.WriteLine(($_ | Out-String).Trim()); exit 1 }";
+
+/// 识别 `powershell -Command ...` / `pwsh -c ...` 形式的单行命令，
+/// 返回直接启动 PowerShell 所需的程序名和参数（绕过 `cmd /C`，脚本已按 `POWERSHELL_WRAPPER` 包装）。
+/// 只有 Windows 上命令由 cmd 执行，其他系统上 shell 会自己处理引号和退出码
+#[cfg(windows)]
+fn powershell_invocation(command: &str) -> Option<(String, Vec<String>)> {
+    if command.trim().contains('\n') {
+        return None;
+    }
+    let re =
+        Regex::new(r"(?i)^\s*((?:powershell|pwsh)(?:\.exe)?)\s+(.*?)\s*-(?:command|c)\s+(.+?)\s*$")
+            .unwrap();
+    let captures = re.captures(command)?;
+    if !find_in_path(&captures[1]) {
+        return None;
+    }
+    let script = strip_outer_double_quotes(&captures[3]);
+
+    let mut args: Vec<String> = captures[2].split_whitespace().map(String::from).collect();
+    for flag in ["-NoProfile", "-NonInteractive"] {
+        if !args.iter().any(|arg| arg.eq_ignore_ascii_case(flag)) {
+            args.push(flag.to_string());
+        }
+    }
+    args.push("-Command".to_string());
+    args.push(POWERSHELL_WRAPPER.replacen("{}", &script, 1));
+    Some((captures[1].to_string(), args))
+}
+
+#[cfg(not(windows))]
+fn powershell_invocation(_command: &str) -> Option<(String, Vec<String>)> {
+    None
+}
+
+/// cmd 把 `-Command "..."` 中整个双引号包裹的部分作为一个参数传给 PowerShell，
+/// 其中的 `""` 和 `\"` 表示一个双引号。只有开头的引号一直到最后一个字符才结束时才去掉，
+/// `"a" | "b"` 这样首尾恰好是引号的脚本原样保留。单引号在 cmd 中不是引号，也原样保留
+#[cfg(windows)]
+fn strip_outer_double_quotes(script: &str) -> String {
+    let Some(inner) = script.strip_prefix('"') else {
+        return script.to_string();
+    };
+    let mut unquoted = String::new();
+    let mut chars = inner.char_indices().peekable();
+    while let Some((index, c)) = chars.next() {
+        match c {
+            '\\' | '"' if chars.peek().is_some_and(|(_, next)| *next == '"') => {
+                chars.next();
+                unquoted.push('"');
+            }
+            '"' if index + 1 == inner.len() => return unquoted,
+            '"' => return script.to_string(),
+            _ => unquoted.push(c),
+        }
+    }
+    script.to_string()
+}
+
+/// Windows PowerShell 在 stderr 被重定向时可能以 CLIXML 输出错误流，
+/// 这里提取其中的错误文本；普通输出原样返回
+#[cfg(windows)]
+fn decode_powershell_errors(stderr: &str) -> String {
+    let Some(xml) = stderr.trim_start().strip_prefix("#< CLIXML") else {
+        return stderr.to_string();
+    };
+    let re = Regex::new(r#"(?s)<S S="Error">(.*?)</S>"#).unwrap();
+    re.captures_iter(xml)
+        .map(|captures| {
+            captures[1]
+                .replace("_x000D_", "")
+                .replace("_x000A_", "\n")
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .collect()
+}
+
+/// 执行命令时实际使用的解释器，用于在确认执行前展示
+fn interpreter_description(command: &str) -> String {
+    let (program, flag) = shell_interpreter();
+    if let Some((program, _)) = powershell_invocation(command) {
+        format!("{} -Command", program)
+    } else if uses_temp_script(command) {
+        format!(
+            "{} {}",
+            program,
+            env::temp_dir().join("askai-<random>.sh").display()
+        )
+    } else {
+        format!("{} {}", program, flag)
+    }
+}
+
+/// 通过系统 shell 执行命令并收集输出，`cwd` 为 None 时在当前目录执行
+#[tracing::instrument]
+fn execute_command(command: &str, cwd: Option<&std::path::Path>) -> Result<std::process::Output> {
+    mark_waiting();
+    let script = uses_temp_script(command)
+        .then(|| write_temp_script(command))
+        .transpose()?;
+    let mut process = match &script {
+        Some(path) => {
+            let mut process = Command::new(shell_interpreter().0);
+            process.arg(path);
+            process
+        }
+        None => match powershell_invocation(command) {
+            Some((program, args)) => {
+                let mut process = Command::new(program);
+                process.args(args);
+                process
+            }
+            None => shell_command(command),
+        },
+    };
+    if let Some(cwd) = cwd {
+        process.current_dir(cwd);
+    }
+    let output = process.output();
+    if let Some(path) = &script {
+        let _ = fs::remove_file(path);
+    }
+    let output = output.context("Failed to execute command")?;
+
+    tracing::debug!(status = ?output.status, "command finished");
+    Ok(output)
+}
+
+/// 把文本通过外部程序的 stdin 传入并返回其 stdout，程序不存在或执行失败时返回 None
+fn pipe_through(program: &str, args: &[&str], input: &str) -> Option<String> {
+    use std::io::Write;
+    use std::process::Stdio;
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+    // 在单独的线程中写入，避免输出较大时双方互相等待
+    let mut stdin = child.stdin.take()?;
+    let input = input.to_string();
+    let writer = std::thread::spawn(move || stdin.write_all(input.as_bytes()));
+    let output = child.wait_with_output().ok()?;
+    let _ = writer.join();
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// 按逗号拆分 CSV 的一行，支持双引号包裹的字段以及 "" 转义
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            _ => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// 将 CSV 按列对齐
+fn align_csv(text: &str) -> String {
+    let rows: Vec<Vec<String>> = text
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(split_csv_line)
+        .collect();
+    let mut widths: Vec<usize> = Vec::new();
+    for row in &rows {
+        for (index, field) in row.iter().enumerate() {
+            let width = console::measure_text_width(field);
+            match widths.get_mut(index) {
+                Some(current) => *current = (*current).max(width),
+                None => widths.push(width),
+            }
+        }
+    }
+    rows.iter()
+        .map(|row| {
+            row.iter()
+                .enumerate()
+                .map(|(index, field)| {
+                    console::pad_str(field, widths[index], console::Alignment::Left, None)
+                })
+                .collect::<Vec<_>>()
+                .join("  ")
+                .trim_end()
+                .to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// 根据 --format-output 格式化命令输出用于显示，无法格式化时原样返回
+fn format_command_output(output: &str, format: OutputFormat) -> String {
+    let formatted = match format {
+        OutputFormat::Plain => None,
+        OutputFormat::Json => pipe_through("jq", &["."], output)
+            .or_else(|| pipe_through("python3", &["-m", "json.tool"], output)),
+        OutputFormat::Csv => Some(align_csv(output)),
+        OutputFormat::Table => pipe_through("column", &["-t"], output),
+    };
+    match formatted {
+        Some(formatted) if !formatted.trim().is_empty() => formatted.trim_end().to_string(),
+        _ => output.to_string(),
+    }
+}
+
+/// 命令因缺少 `program` 而失败时，提议用包管理器单独安装它。安装命令与生成的命令一样
+/// 经过校验脚本和危险检测，使用 sudo 时需要输入确认短语；失败的那次执行和安装分别记录
+/// 为独立的历史记录。返回 true 表示安装成功，调用方应再次确认后原样重新执行原命令
+fn install_missing_program(
+    term: &UiTerm,
+    ui_text: &UiText,
+    config: &Config,
+    cli: &Cli,
+    state: &SessionState,
+    failed: &ExecutionHistory,
+    program: &str,
+) -> Result<bool> {
+    term.write_line("")?;
+    let Some((install, sudo)) = install_command_for(program) else {
+        output::warning(
+            term,
+            &ui_text.missing_tool_no_manager.replace("{}", program),
+        )?;
+        return Ok(false);
+    };
+
+    let (verdict, message) = validate_command(config, &install);
+    show_validator_verdict(term, ui_text, verdict, &message)?;
+    if verdict == Verdict::Deny {
+        return Ok(false);
+    }
+    if let Some(pattern) = find_dangerous_pattern(&install, &config.safety.dangerous_patterns) {
+        if !cli.allow_dangerous {
+            output::error(term, ui_text.dangerous_command_warning)?;
+            term.write_line(&danger_details(&pattern, &config.language))?;
+            return Ok(false);
+        }
+    }
+
+    let prompt = ui_text
+        .missing_tool_install_prompt
+        .replacen("{}", program, 1)
+        .replacen("{}", &install, 1);
+    let confirmed = if sudo {
+        term.write_line(&format!("{}", style(&prompt).notice().bold()))?;
+        output::error(term, ui_text.missing_tool_sudo_warning)?;
+        confirm_dangerous(term, ui_text, &config.safety.danger_confirm_phrase)?
+    } else {
+        ensure_interactive(ui_text, &prompt)?;
+        Confirm::with_theme(&dialog_theme())
+            .with_prompt(&prompt)
+            .default(false)
+            .interact()?
+    };
+    if !confirmed {
+        return Ok(false);
+    }
+
+    record_history(
+        &config.history,
+        &state.prompt,
+        state.started_at,
+        failed,
+        None,
+    );
+    term.write_line("")?;
+    output::warning(term, ui_text.executing_command)?;
+    let (success, output, _) = run_and_show(term, ui_text, &install, None, cli.format_output)?;
+    let installation = ExecutionHistory {
+        command: install,
+        output,
+        success,
+        feedback: None,
+        template: None,
+        ..failed.clone()
+    };
+    record_history(
+        &config.history,
+        &state.prompt,
+        state.started_at,
+        &installation,
+        None,
+    );
+    Ok(success)
+}
+
+/// 执行命令并显示结果，返回是否成功、输出（失败时为 stderr）以及退出码
+fn run_and_show(
+    term: &UiTerm,
+    ui_text: &UiText,
+    command: &str,
+    cwd: Option<&std::path::Path>,
+    format: OutputFormat,
+) -> Result<(bool, String, i32)> {
+    let output = execute_command(command, cwd)?;
+
+    let success = output.status.success();
+    let output_text = if success {
+        String::from_utf8_lossy(&output.stdout).to_string()
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        #[cfg(windows)]
+        let stderr = decode_powershell_errors(&stderr);
+        stderr
+    };
+
+    if success {
+        output::success(term, ui_text.command_success)?;
+    } else {
+        output::error(term, ui_text.command_failure)?;
+    }
+
+    if !output_text.is_empty() {
+        term.write_line("")?;
+        if success {
+            output::write_block(term, &format_command_output(&output_text, format))?;
+        } else {
+            output::write_block(term, &output_text)?;
+        }
+    }
+    Ok((
+        success,
+        output_text,
+        output.status.code().unwrap_or_default(),
+    ))
+}
+
+/// 会改变当前 shell 状态（变量、选项、别名、目录栈等）的内建命令。拆分后每一步在单独的
+/// shell 中执行，这些改变无法传给后续步骤
+const STATEFUL_BUILTINS: [&str; 19] = [
+    "export", "unset", "set", "source", ".", "alias", "unalias", "shopt", "pushd", "popd", "umask",
+    "eval", "exec", "declare", "typeset", "local", "readonly", "trap", "ulimit",
+];
+
+/// 这一步是否会改变后续步骤依赖的 shell 状态（`cd` 由 run_command_steps 单独处理）
+fn changes_shell_state(step: &str) -> bool {
+    let mut words = step.split_whitespace();
+    let Some(first) = words.next() else {
+        return false;
+    };
+    // 只有变量赋值、没有命令的一步（`FOO=1`）会设置 shell 变量
+    let assignment_only = first.contains('=')
+        && !first.starts_with('=')
+        && words.all(|word| word.contains('=') && !word.starts_with('='));
+    STATEFUL_BUILTINS.contains(&first)
+        || first == "function"
+        || step.contains("()")
+        || assignment_only
+}
+
+/// 按顶层的 `&&` 拆分命令，引号、括号、`[[ ]]` 和 `$(...)` 中的 `&&` 不会被拆开。
+/// 包含 heredoc、只有一条命令、顶层还有 `||`、`;`、`&` 或换行（拆分会改变执行逻辑），
+/// 或者某一步会改变 shell 状态时返回 None，整条命令照常一起执行
+fn split_command_chain(command: &str) -> Option<Vec<String>> {
+    let lines: Vec<&str> = command.trim().lines().collect();
+    if lines.len() > 1 {
+        return None;
+    }
+
+    let mut steps = Vec::new();
+    let mut current = String::new();
+    let mut quote: Option<char> = None;
+    let mut depth = 0usize;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some('\''), '\'') => quote = None,
+            (Some('\''), _) => {}
+            (_, '\\') => {
+                current.push(c);
+                if let Some(next) = chars.next() {
+                    current.push(next);
+                }
+                continue;
+            }
+            (Some('"'), '"') => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '(' | '{') => depth += 1,
+            (None, ')' | '}') => depth = depth.saturating_sub(1),
+            (None, '[') if chars.peek() == Some(&'[') => depth += 1,
+            (None, ']') if chars.peek() == Some(&']') => depth = depth.saturating_sub(1),
+            (None, '&') if depth == 0 && chars.peek() == Some(&'&') => {
+                chars.next();
+                steps.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            // `2>&1`、`&>file` 是重定向，不是后台执行
+            (None, '&') if current.ends_with(['>', '<']) || chars.peek() == Some(&'>') => {}
+            (None, '&' | ';') if depth == 0 => return None,
+            (None, '|') if depth == 0 && chars.peek() == Some(&'|') => return None,
+            _ => {}
+        }
+        current.push(c);
+    }
+    steps.push(current.trim().to_string());
+
+    if steps.len() < 2
+        || steps
+            .iter()
+            .any(|step| step.is_empty() || changes_shell_state(step))
+    {
+        return None;
+    }
+    Some(steps)
+}
+
+/// 把 `cd` 的目标解析为绝对路径，不支持的写法（例如 `cd -`）返回 None
+fn resolve_cd_target(step: &str, cwd: &std::path::Path) -> Option<std::path::PathBuf> {
+    let mut parts = step.split_whitespace();
+    if parts.next()? != "cd" {
+        return None;
+    }
+    let target = match (parts.next(), parts.next()) {
+        (None, _) => return dirs::home_dir(),
+        (Some(target), None) => target.trim_matches(['\'', '"']),
+        _ => return None,
+    };
+    if target == "-" {
+        return None;
+    }
+    // 只展开 `~` 和 `~/…`，`~user` 指向其他用户的主目录，交给 shell 解析
+    let path = match target.strip_prefix('~') {
+        Some("") => dirs::home_dir()?,
+        Some(rest) => dirs::home_dir()?.join(rest.strip_prefix('/')?),
+        None => cwd.join(target),
+    };
+    path.is_dir().then_some(path)
+}
+
+/// --split-commands：逐个执行 `&&` 拆分后的命令，第一步之后的每一步执行前都会确认。
+/// `cd` 不会在子 shell 中执行，而是切换后续步骤的工作目录；目标无法在执行前解析时
+/// （例如 `cd "$HOME/x"`、`cd $DIR`），从这一步起剩下的步骤作为一条命令一起执行，
+/// 保证后续步骤仍在 cd 之后的目录中运行
+fn run_command_steps(
+    term: &UiTerm,
+    ui_text: &UiText,
+    steps: &[String],
+    format: OutputFormat,
+) -> Result<(bool, String, i32)> {
+    let mut cwd = env::current_dir().context("Unable to get current directory")?;
+    let mut outputs = Vec::new();
+    for (index, step) in steps.iter().enumerate() {
+        let header = ui_text
+            .split_step
+            .replacen("{}", &(index + 1).to_string(), 1)
+            .replacen("{}", &steps.len().to_string(), 1);
+        term.write_line(&format!(
+            "{} {}",
+            style(header).info().bold(),
+            styled_command(step)
+        ))?;
+        if index > 0 {
+            ensure_interactive(ui_text, ui_text.split_continue_prompt)?;
+            let confirmed = Confirm::with_theme(&dialog_theme())
+                .with_prompt(ui_text.split_continue_prompt)
+                .default(true)
+                .interact()?;
+            if !confirmed {
+                output::warning(term, ui_text.split_stopped)?;
+                break;
+            }
+        }
+
+        let (step, together) = if step.split_whitespace().next() == Some("cd") {
+            if let Some(target) = resolve_cd_target(step, &cwd) {
+                output::success(term, ui_text.command_success)?;
+                term.write_line("")?;
+                cwd = target;
+                continue;
+            }
+            let rest = steps[index..].join(" && ");
+            if index + 1 < steps.len() {
+                term.write_line(&format!(
+                    "{} {}",
+                    style(ui_text.split_rest_together).notice(),
+                    styled_command(&rest)
+                ))?;
+            }
+            (rest, true)
+        } else {
+            (step.clone(), false)
+        };
+
+        let (success, output, exit_code) = run_and_show(term, ui_text, &step, Some(&cwd), format)?;
+        outputs.push(format!("$ {}\n{}", step, output));
+        term.write_line("")?;
+        if !success {
+            term.write_line(&format!(
+                "{}",
+                style(ui_text.split_failed.replace("{}", &(index + 1).to_string())).red()
+            ))?;
+            return Ok((false, outputs.join("\n"), exit_code));
+        }
+        if together {
+            break;
+        }
+    }
+    Ok((true, outputs.join("\n"), 0))
+}
+
+/// 运行 `ask run-tests` 的测试命令，返回测试是否通过以及完整的输出（stdout 和 stderr）
+fn run_tests(term: &UiTerm, ui_text: &UiText, test_command: &str) -> Result<(bool, String)> {
+    term.write_line("")?;
+    term.write_line(&format!(
+        "{} {}",
+        style(ui_text.running_tests).notice(),
+        style(test_command).code()
+    ))?;
+    let output = execute_command(test_command, None)?;
+    let report = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    let passed = output.status.success();
+    if passed {
+        output::success(term, ui_text.tests_passed)?;
+    } else {
+        output::error(term, ui_text.tests_failed)?;
+        if !report.trim().is_empty() {
+            term.write_line("")?;
+            output::write_block(term, report.trim_end())?;
+        }
+    }
+    term.write_line("")?;
+    Ok((passed, report))
+}
+
+/// 运行 --pre-hook / --post-hook 脚本，脚本的输出直接显示在终端上
+#[tracing::instrument(skip(envs))]
+fn run_hook(script: &str, envs: &[(&str, &str)]) -> Result<()> {
+    let status = shell_command(script)
+        .envs(envs.iter().copied())
+        .status()
+        .with_context(|| format!("Failed to run hook `{}`", script))?;
+
+    tracing::debug!(?status, "hook finished");
+    if !status.success() {
+        return Err(anyhow::anyhow!("`{}` ({})", script, status));
+    }
+    Ok(())
+}
+
+/// 仅在指定了 --log-level 或 RUST_LOG 时启用结构化日志，默认不影响正常输出
+fn init_tracing(log_level: Option<&str>) -> Result<()> {
+    let filter = match log_level {
+        // 单独的级别只作用于本程序，完整的过滤表达式则原样使用
+        Some(level) if !level.contains(['=', ',']) => tracing_subscriber::EnvFilter::try_new(
+            format!("{}={}", env!("CARGO_CRATE_NAME"), level),
+        )?,
+        Some(level) => tracing_subscriber::EnvFilter::try_new(level)?,
+        None => match tracing_subscriber::EnvFilter::try_from_default_env() {
+            Ok(filter) => filter,
+            Err(_) => return Ok(()),
+        },
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() {
+    if let Err(err) = run().await {
+        let ui_text = get_ui_text(&configured_language());
+        let _ = output::error(&UiTerm::stderr(), &error_message(ui_text, &err));
+        std::process::exit(1);
+    }
+}
+
+/// 退出时显示的错误信息：本地化的前缀加上完整的错误链
+fn error_message(ui_text: &UiText, err: &anyhow::Error) -> String {
+    format!("{} {:#}", ui_text.error_prefix, err)
+}
+
+/// 错误信息使用的语言：配置文件中的 language，配置文件不存在或没有 language 时使用系统语言。
+/// 这里不能调用 load_config，否则配置文件不存在时会在报错前启动配置向导；也不用 peek_config，
+/// 配置文件其他部分无效（这正是常见的报错原因）时仍然使用其中的 language
+fn configured_language() -> String {
+    #[derive(serde::Deserialize)]
+    struct LanguageOnly {
+        language: String,
+    }
+    get_config_path()
+        .ok()
+        .and_then(|path| fs::read_to_string(path).ok())
+        .and_then(|content| toml::from_str::<LanguageOnly>(&content).ok())
+        .map(|config| config.language)
+        .unwrap_or_else(get_system_language)
+}
+
+/// 配置文件中的 [ui] theme，配置无效或缺失时为 auto
+fn configured_theme() -> output::ThemeSetting {
+    peek_config()
+        .map(|config| config.ui.theme)
+        .unwrap_or_default()
+}
+
+async fn run() -> Result<()> {
+    let mut cli = match Cli::try_parse_from(args_with_default_flags()) {
+        Ok(cli) => cli,
+        Err(err) => match Cli::try_parse_from(merge_default_flags(&[], env::args_os().collect())) {
+            // 默认选项本身无效时（例如与本次的子命令冲突）忽略它们，避免任何命令都无法运行
+            Ok(cli) => {
+                let ui_text = get_ui_text(&get_system_language());
+                output::warning(&UiTerm::stderr(), ui_text.default_flags_ignored)?;
+                cli
+            }
+            Err(_) => err.exit(),
+        },
+    };
+    init_tracing(cli.log_level.as_deref())?;
+    // --verbose 默认开启，只能通过 --quiet 关闭
+    if cli.quiet {
+        cli.verbose = false;
+    }
+    NO_INTERACTIVE.store(cli.no_interactive, Ordering::Relaxed);
+    ASCII_MODE.store(cli.ascii || !terminal_supports_unicode(), Ordering::Relaxed);
+    apply_color_choice(cli.color);
+    output::init_theme(configured_theme());
+    PRINT_MODE.store(cli.print, Ordering::Relaxed);
+    PREPEND_TIMESTAMP.store(cli.prepend_timestamp, Ordering::Relaxed);
+    let _ = SESSION_ID.set(
+        cli.session_id
+            .unwrap_or_else(uuid::Uuid::new_v4)
+            .to_string(),
+    );
+
+    let test_command = match cli.command.take() {
+        Some(Commands::Set {
+            config_type,
+            config_values,
+        }) => return set_config(&config_type, &config_values),
+        Some(Commands::CheckDanger { command }) => return check_danger(&command),
+        Some(Commands::DiffModels {
+            model1,
+            model2,
+            prompt,
+        }) => return diff_models(&model1, &model2, &prompt, cli.debug).await,
+        Some(Commands::Config {
+            action: ConfigAction::Init,
+        }) => return init_config(),
+        Some(Commands::Config {
+            action: ConfigAction::Edit,
+        }) => return edit_config(),
+        Some(Commands::Config {
+            action: ConfigAction::Provider,
+        }) => return switch_provider(),
+        Some(Commands::Config {
+            action: ConfigAction::SetDefaultFlags { flags },
+        }) => return set_default_flags(flags),
+        Some(Commands::Env) => {
+            print_env();
+            return Ok(());
+        }
+        Some(Commands::Whoami) => return whoami(),
+        Some(Commands::Pin {
+            model,
+            global,
+            show,
+        }) => return pin_model(model.as_deref(), global, show),
+        Some(Commands::Learn) => return learn(cli.debug).await,
+        Some(Commands::Batch { file }) => return run_batch(&cli, &file).await,
+        Some(Commands::Serve {
+            port,
+            host,
+            allow_model_override,
+        }) => {
+            let config = Arc::new(load_effective_config()?);
+            let ui_text = get_ui_text(&config.language);
+            let client = build_client(&config.api, ui_text)?;
+            return serve::serve(
+                config,
+                client,
+                ui_text,
+                (host, port).into(),
+                cli.model,
+                cli.debug,
+                allow_model_override,
+            )
+            .await;
+        }
+        Some(Commands::Tokenize { text }) => return tokenize(text, cli.model.as_deref()),
+        Some(Commands::Trace { prompt }) => return trace(&prompt, cli.debug).await,
+        Some(Commands::Mnemonic { command }) => {
+            return mnemonic(&command, cli.raw_output, cli.debug).await
+        }
+        Some(Commands::SecurityAudit { command }) => {
+            return security_audit(&command, cli.raw_output, cli.debug).await
+        }
+        Some(Commands::Refactor { command }) => {
+            return refactor(&command, cli.raw_output, cli.debug).await
+        }
+        Some(Commands::History {
+            action:
+                Some(HistoryAction::Export {
+                    from,
+                    to,
+                    output,
+                    force,
+                }),
+            ..
+        }) => return export_history(from, to, &output, force),
+        Some(Commands::History {
+            action: None,
+            session,
+            all,
+        }) => return show_history(session.map(|id| id.to_string()).as_deref(), all),
+        Some(Commands::ClearHistory { before }) => return clear_history(before),
+        Some(Commands::Session {
+            action: SessionAction::New,
+        }) => {
+            println!("{}", uuid::Uuid::new_v4());
+            return Ok(());
+        }
+        Some(Commands::Resume { id, list, clean }) => {
+            return resume_session(&cli, id.as_deref(), list, clean).await
+        }
+        Some(Commands::Chain { prompts }) => return run_chain(&cli, &prompts).await,
+        Some(Commands::RunTests { test_cmd, prompt }) => {
+            cli.prompt = Some(prompt);
+            Some(test_cmd)
+        }
+        None => None,
+    };
+
+    let config = Arc::new(load_effective_config()?);
+    let ui_text = get_ui_text(&config.language);
+    if cli.show_prompt {
+        let options = RequestOptions {
+            system_prompt: read_system_prompt_file(&cli)?,
+            with_explanation: cli.with_explanation,
+            syntax: cli.syntax,
+            ..RequestOptions::default()
+        };
+        println!("{}", build_system_prompt(&config, &options)?);
+        return Ok(());
+    }
+    let prompt = cli
+        .prompt
+        .take()
+        .ok_or_else(|| anyhow::anyhow!(ui_text.provide_description))?;
+
+    if let Some(path) = cli.watch.iter().find(|path| !path.exists()) {
+        return Err(anyhow::anyhow!(
+            "{} {}",
+            ui_text.watch_missing,
+            path.display()
+        ));
+    }
+
+    if let Some(script) = &cli.pre_hook {
+        run_hook(script, &[("ASKAI_PROMPT", prompt.as_str())])
+            .map_err(|err| anyhow::anyhow!("{} {}", ui_text.pre_hook_failed, err))?;
+    }
+
+    let client = build_client(&config.api, ui_text)?;
+    let mut context = Vec::new();
+    loop {
+        let state = SessionState {
+            prompt: prompt.clone(),
+            model: cli.race.first().or(cli.model.as_ref()).cloned(),
+            history: Vec::new(),
+            attempt: 1,
+            test_command: test_command.clone(),
+            started_at: unix_timestamp(),
+            context,
+            tokens_used: 0,
+        };
+        run_attempts(&cli, &config, &client, state).await?;
+        if cli.watch.is_empty() {
+            return Ok(());
+        }
+
+        // 在命令执行之后读取，命令本身对文件的修改不会触发重新生成
+        let snapshot = watch_snapshot(&cli.watch);
+        let Some(changed) = wait_for_changes(&cli, &config, &snapshot).await? else {
+            return Ok(());
+        };
+        context = vec![describe_changes(&config.language, &snapshot, &changed)];
+    }
+}
+
+/// 收到文件系统事件后需要保持多久没有新的事件才重新生成，编辑器保存时常会连续写入多次或先删除再重命名
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(300);
+/// 读取被监视文件的大小上限，更大的文件只比较是否存在
+const WATCH_MAX_FILE_BYTES: u64 = 1024 * 1024;
+/// 附加到提示中的改动内容的字符数上限
+const WATCH_DIFF_MAX_CHARS: usize = 4000;
+
+/// 被监视文件的内容，按路径排序
+type WatchSnapshot = std::collections::BTreeMap<std::path::PathBuf, String>;
+
+/// 编辑器保存时产生的临时文件：vim 的交换文件和写入测试文件 4913、emacs 的锁文件和自动保存文件、备份文件等
+fn is_editor_temp_file(name: &str) -> bool {
+    name == "4913"
+        || name.starts_with(".#")
+        || (name.starts_with('#') && name.ends_with('#'))
+        || name.ends_with('~')
+        || [".swp", ".swo", ".swx", ".tmp", ".crswap"]
+            .iter()
+            .any(|suffix| name.ends_with(suffix))
+}
+
+/// 读取被监视的文件；目录会递归展开（遵循 .gitignore，跳过编辑器的临时文件）
+fn watch_snapshot(paths: &[std::path::PathBuf]) -> WatchSnapshot {
+    let mut snapshot = WatchSnapshot::new();
+    for path in paths {
+        let files: Vec<std::path::PathBuf> = if path.is_dir() {
+            ignore::WalkBuilder::new(path)
+                .hidden(false)
+                .require_git(false)
+                .filter_entry(|entry| entry.file_name() != ".git")
+                .build()
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.file_type().is_some_and(|kind| kind.is_file()))
+                .filter(|entry| !is_editor_temp_file(&entry.file_name().to_string_lossy()))
+                .map(|entry| entry.into_path())
+                .collect()
+        } else {
+            vec![path.clone()]
+        };
+        for file in files {
+            let Ok(metadata) = fs::metadata(&file) else {
+                continue;
+            };
+            let content = if metadata.len() > WATCH_MAX_FILE_BYTES {
+                String::new()
+            } else {
+                match fs::read(&file) {
+                    Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+                    Err(_) => continue,
+                }
+            };
+            snapshot.insert(file, content);
+        }
+    }
+    snapshot
+}
+
+/// 文件所在的目录，相对路径没有父目录时是当前目录
+fn parent_dir(path: &std::path::Path) -> &std::path::Path {
+    path.parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(std::path::Path::new("."))
+}
+
+/// 事件是否与被监视的路径有关：文件只看它本身（监视的是所在目录，保存时的重命名也能收到），
+/// 目录看其中除 .git 和编辑器临时文件以外的所有文件
+fn is_watched_event(paths: &[std::path::PathBuf], event: &notify::Event) -> bool {
+    if event.kind.is_access() {
+        return false;
+    }
+    event.paths.iter().any(|changed| {
+        let temp = changed
+            .file_name()
+            .is_some_and(|name| is_editor_temp_file(&name.to_string_lossy()));
+        paths.iter().any(|path| {
+            if path.is_dir() {
+                changed.starts_with(path)
+                    && !temp
+                    && !changed
+                        .strip_prefix(path)
+                        .is_ok_and(|rest| rest.starts_with(".git"))
+            } else {
+                changed.file_name() == path.file_name()
+                    && changed.parent().and_then(|dir| fs::canonicalize(dir).ok())
+                        == fs::canonicalize(parent_dir(path)).ok()
+            }
+        })
+    })
+}
+
+/// 用文件系统通知监视 `paths`：文件监视其所在目录，目录递归监视。
+/// 事件通过 channel 发回异步的等待循环，返回的 watcher 被丢弃时停止监视
+fn start_watcher(
+    paths: &[std::path::PathBuf],
+) -> Result<(
+    notify::RecommendedWatcher,
+    tokio::sync::mpsc::UnboundedReceiver<notify::Event>,
+)> {
+    use notify::Watcher;
+
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher =
+        notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) => {
+                let _ = sender.send(event);
+            }
+            Err(err) => tracing::debug!(%err, "watch error"),
+        })
+        .context("Unable to start watching files")?;
+    for path in paths {
+        let (target, mode) = if path.is_dir() {
+            (path.as_path(), notify::RecursiveMode::Recursive)
+        } else {
+            (parent_dir(path), notify::RecursiveMode::NonRecursive)
+        };
+        watcher
+            .watch(target, mode)
+            .with_context(|| format!("Unable to watch {}", target.display()))?;
+    }
+    Ok((watcher, receiver))
+}
+
+/// 等待被监视的文件发生变化并稳定下来，返回变化后的内容；按 Ctrl+C 时返回 None
+async fn wait_for_changes(
+    cli: &Cli,
+    config: &Config,
+    snapshot: &WatchSnapshot,
+) -> Result<Option<WatchSnapshot>> {
+    let ui_text = get_ui_text(&config.language);
+    let term = ui_term();
+    let paths: Vec<String> = cli
+        .watch
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    let (_watcher, mut events) = start_watcher(&cli.watch)?;
+    term.write_line("")?;
+    output::info(
+        &term,
+        &ui_text.watch_waiting.replace("{}", &paths.join(", ")),
+    )?;
+
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                output::note(&term, ui_text.watch_stopped)?;
+                return Ok(None);
+            }
+            event = events.recv() => {
+                let Some(event) = event else {
+                    return Ok(None);
+                };
+                if !is_watched_event(&cli.watch, &event) {
+                    continue;
+                }
+            }
+        }
+        // 等到 WATCH_DEBOUNCE 内没有新的事件，说明写入已经结束
+        while let Ok(Some(_)) = tokio::time::timeout(WATCH_DEBOUNCE, events.recv()).await {}
+        // 事件只说明文件被写过，内容可能没有变化（例如只是 touch）
+        let current = watch_snapshot(&cli.watch);
+        if current != *snapshot {
+            let changed: Vec<String> = current
+                .keys()
+                .chain(snapshot.keys())
+                .filter(|path| current.get(*path) != snapshot.get(*path))
+                .map(|path| path.display().to_string())
+                .collect::<std::collections::BTreeSet<_>>()
+                .into_iter()
+                .collect();
+            output::warning(
+                &term,
+                &ui_text.watch_changed.replace("{}", &changed.join(", ")),
+            )?;
+            return Ok(Some(current));
+        }
+    }
+}
+
+/// 重新生成时附加的上下文：变化的文件及其 diff，总长度超过上限时截断
+fn describe_changes(language: &str, before: &WatchSnapshot, after: &WatchSnapshot) -> String {
+    let mut diff = String::new();
+    let paths: std::collections::BTreeSet<_> = before.keys().chain(after.keys()).collect();
+    for path in paths {
+        let name = path.display().to_string();
+        match (before.get(path), after.get(path)) {
+            (Some(old), Some(new)) if old != new => diff.push_str(
+                &similar::TextDiff::from_lines(old, new)
+                    .unified_diff()
+                    .context_radius(2)
+                    .header(&name, &name)
+                    .to_string(),
+            ),
+            (None, Some(_)) => diff.push_str(&format!("+++ {} (created)\n", name)),
+            (Some(_), None) => diff.push_str(&format!("--- {} (deleted)\n", name)),
+            _ => {}
+        }
+    }
+    if diff.chars().count() > WATCH_DIFF_MAX_CHARS {
+        diff = diff.chars().take(WATCH_DIFF_MAX_CHARS).collect();
+        diff.push_str("\n...");
+    }
+    let template = match language {
+        "zh" => "上一条命令生成之后，被监视的文件发生了以下变化，请根据变化后的内容生成命令：\n{}",
+        _ => "Since the previous command was generated, the watched files changed as follows. Generate the command for their current content:\n{}",
+    };
+    template.replace("{}", diff.trim_end())
+}
+
+/// `ask chain`：依次处理以 `--` 分隔的多个需求，每一步达到目标后把命令的输出
+/// 作为下一步的上下文，任一步未达到目标时停止
+async fn run_chain(cli: &Cli, parts: &[String]) -> Result<()> {
+    let config = Arc::new(load_effective_config()?);
+    let ui_text = get_ui_text(&config.language);
+    let client = build_client(&config.api, ui_text)?;
+    let term = ui_term();
+    let prompts: Vec<String> = parts
+        .split(|part| part == "--")
+        .map(|words| words.join(" ").trim().to_string())
+        .filter(|prompt| !prompt.is_empty())
+        .collect();
+    if prompts.is_empty() {
+        return Err(anyhow::anyhow!(ui_text.provide_description));
+    }
+
+    let mut previous_output: Option<String> = None;
+    for (index, prompt) in prompts.iter().enumerate() {
+        if index > 0 {
+            term.write_line("")?;
+        }
+        output::heading(
+            &term,
+            &ui_text
+                .chain_step
+                .replacen("{}", &(index + 1).to_string(), 1)
+                .replacen("{}", &prompts.len().to_string(), 1)
+                .replacen("{}", prompt, 1),
+        )?;
+        let context = previous_output
+            .take()
+            .map(|output| {
+                let template = match config.language.as_str() {
+                    "zh" => "上一条命令的输出：\n{}",
+                    _ => "Previous command output:\n{}",
+                };
+                vec![template.replace("{}", output.trim_end())]
+            })
+            .unwrap_or_default();
+        let state = SessionState {
+            prompt: prompt.clone(),
+            model: cli.race.first().or(cli.model.as_ref()).cloned(),
+            history: Vec::new(),
+            attempt: 1,
+            test_command: None,
+            started_at: unix_timestamp(),
+            context,
+            tokens_used: 0,
+        };
+        match run_attempts(cli, &config, &client, state).await? {
+            Some(execution) => previous_output = Some(execution.output),
+            None if index + 1 < prompts.len() => {
+                term.write_line("")?;
+                output::warning(
+                    &term,
+                    &ui_text
+                        .chain_stopped
+                        .replace("{}", &(index + 1).to_string()),
+                )?;
+                return Ok(());
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// escalate 策略换用模型之前说明原因以及费用变化；设置了 --max-cost-tokens 时同时显示剩余预算
+fn show_escalation(
+    term: &UiTerm,
+    config: &Config,
+    cli: &Cli,
+    state: &SessionState,
+    current: &str,
+    target: &str,
+) -> Result<()> {
+    let ui_text = get_ui_text(&config.language);
+    output::warning(
+        term,
+        &ui_text
+            .escalate_model
+            .replacen("{}", &state.attempt.saturating_sub(1).to_string(), 1)
+            .replacen("{}", target, 1),
+    )?;
+    let cost = match (model_price(config, current), model_price(config, target)) {
+        (Some((from_input, from_output)), Some((to_input, to_output))) => ui_text
+            .escalate_cost
+            .replacen("{}", &from_input.to_string(), 1)
+            .replacen("{}", &from_output.to_string(), 1)
+            .replacen("{}", &to_input.to_string(), 1)
+            .replacen("{}", &to_output.to_string(), 1),
+        _ => ui_text.escalate_cost_unknown.replace("{}", target),
+    };
+    output::note(term, &cost)?;
+    if let Some(budget) = cli.max_cost_tokens {
+        output::note(
+            term,
+            &ui_text
+                .escalate_budget
+                .replace("{}", &budget.saturating_sub(state.tokens_used).to_string()),
+        )?;
+    }
+    Ok(())
+}
+
+/// 生成命令、确认执行并根据结果重试，直到达到目标或超过最大尝试次数。
+/// 返回达到目标的那次执行，未达到目标时返回 None
+async fn run_attempts(
+    cli: &Cli,
+    config: &Arc<Config>,
+    client: &Client,
+    mut state: SessionState,
+) -> Result<Option<ExecutionHistory>> {
+    let term = ui_term();
+    let ui_text = get_ui_text(&config.language);
+    // 目标 shell 与当前 shell 不一致时命令无法在本机正确执行，只显示不执行
+    let foreign_syntax = cli.syntax.filter(|shell| !shell.matches_current_shell());
+    if let (Some(shell), None) = (cli.syntax, foreign_syntax) {
+        let _ = SYNTAX_SHELL.set(shell);
+    }
+    // 未经清理的回复可能包含代码块标记和说明文字，不能交给 shell 执行
+    let dry_run = cli.dry_run || cli.print || cli.raw_output || foreign_syntax.is_some();
+    let max_attempts = 3;
+    if cli.raw_output && !(cli.dry_run || cli.print) {
+        output::warning(&term, ui_text.raw_output_dry_run)?;
+    }
+    if let (Some(shell), false) = (foreign_syntax, cli.dry_run || cli.print) {
+        output::warning(&term, &ui_text.syntax_dry_run.replace("{}", shell.label()))?;
+    }
+    // 调试输出会在请求过程中打印，此时不能切换到原始模式监听按键
+    let listen_for_cancel =
+        !cli.debug && cli.log_level.is_none() && !cli.no_interactive && console::user_attended();
+    // 在调用 API 之前读取，文件有问题时不会产生任何请求
+    let system_prompt = read_system_prompt_file(cli)?;
+    let user_message_template = read_user_message_template(cli)?;
+
+    if cli.verbose && cli.race.len() <= 1 {
+        let model = state.model.as_deref().unwrap_or(&config.api.model);
+        let marker = if state.model.is_some() {
+            ui_text.model_override_marker
+        } else {
+            ""
+        };
+        output::note(
+            &term,
+            &format!("{} {}{}", ui_text.session_model, model, marker),
+        )?;
+    }
+
+    if cli.seed.is_some() && !config.api.provider.supports_seed() {
+        output::warning(
+            &term,
+            &ui_text
+                .seed_unsupported
+                .replace("{}", config.api.provider.label(ui_text)),
+        )?;
+    }
+
+    let mut context = state.context.clone();
+    if cli.with_ls {
+        context.push(directory_listing(
+            &config.language,
+            DIRECTORY_LISTING_LIMIT,
+        )?);
+    }
+    if cli.detect_tools {
+        context.push(tool_availability(&config.language));
+    }
+    if let Some(since) = &cli.show_diff_since {
+        context.push(git_diff_context(&config.language, since)?);
+    }
+    if cli.with_last_success {
+        match last_success(&state.prompt)? {
+            Some(entry) => {
+                if cli.verbose {
+                    output::note(
+                        &term,
+                        &format!("{} {}", ui_text.last_success_example, entry.command),
+                    )?;
+                }
+                context.push(last_success_context(&config.language, &entry));
+            }
+            None => output::note(&term, ui_text.last_success_none)?,
+        }
+    }
+
+    // 尝试次数上限始终有效；指定 --max-cost-tokens 时还要求用量未超出预算，
+    // 服务商没有返回用量时无法判断是否超出，不再自动重试
+    let usage_missing = std::cell::Cell::new(false);
+    let can_continue = |attempt: u32, tokens_used: u64| {
+        attempt <= max_attempts
+            && cli
+                .max_cost_tokens
+                .is_none_or(|budget| tokens_used < budget && !usage_missing.get())
+    };
+    // 附带请求（逐项解释、安装建议）的用量同样计入预算
+    let count_usage = |state: &mut SessionState, tokens: Option<u64>| -> Result<()> {
+        match tokens {
+            Some(tokens) => state.tokens_used += tokens,
+            None if cli.max_cost_tokens.is_some() && !usage_missing.get() => {
+                usage_missing.set(true);
+                output::warning(&term, ui_text.token_usage_missing)?;
+            }
+            None => {}
+        }
+        Ok(())
+    };
+    // 命令执行成功即视为达到目标、不再询问
+    let skip_goal_prompt = cli.retry_on_error_only
+        || cli.no_confirm_success
+        || config.behavior.skip_goal_prompt_on_success;
+
+    let strategy = cli
+        .attempts_strategy
+        .unwrap_or(config.generation.attempts_strategy);
+    if cli.verbose && strategy != AttemptsStrategy::Same {
+        output::note(
+            &term,
+            &format!("{} {}", ui_text.attempts_strategy, strategy.name()),
+        )?;
+    }
+    // --race 由多个模型竞争，不再换用模型
+    let escalate_model = match strategy {
+        AttemptsStrategy::Escalate if cli.race.len() <= 1 => {
+            if config.generation.escalate_model.is_none() {
+                output::warning(&term, ui_text.escalate_model_missing)?;
+            }
+            config.generation.escalate_model.as_deref()
+        }
+        _ => None,
+    };
+
+    while can_continue(state.attempt, state.tokens_used) {
+        tracing::info!(attempt = state.attempt, "starting attempt");
+        if let Some(target) = escalate_model {
+            let failures = state.attempt.saturating_sub(1);
+            let current = state.model.as_deref().unwrap_or(&config.api.model);
+            if failures >= config.generation.escalate_after.unwrap_or(1) && current != target {
+                show_escalation(&term, config, cli, &state, current, target)?;
+                state.model = Some(target.to_string());
+            }
+        }
+        let mut options = RequestOptions {
+            model: state.model.clone(),
+            // 固定种子时温度固定为 0，否则结果仍会随机变化
+            temperature: match (cli.seed, strategy) {
+                (Some(_), _) => Some(0.0),
+                (None, AttemptsStrategy::Warmer) => Some(warmer_temperature(
+                    &config.generation.temperature_schedule,
+                    config
+                        .generation
+                        .temperature_step
+                        .unwrap_or(DEFAULT_TEMPERATURE_STEP),
+                    state.attempt,
+                )),
+                (None, _) => {
+                    temperature_for_attempt(&config.generation.temperature_schedule, state.attempt)
+                }
+            },
+            context: context.clone(),
+            response_timeout: cli.response_timeout.map(Duration::from_millis),
+            system_prompt: system_prompt.clone(),
+            with_explanation: cli.with_explanation,
+            syntax: cli.syntax,
+            user_message_template: user_message_template.clone(),
+            seed: cli.seed,
+            placeholders: mentions_placeholders(&state.prompt),
+            raw_output: cli.raw_output,
+        };
+        let mut history = state.history.last().cloned();
+        fit_prompt_limit(config, &state.prompt, &mut history, &options)?;
+        check_context_window(
+            config,
+            state.model.as_deref().unwrap_or(&config.api.model),
+            &state.prompt,
+            &mut history,
+            &mut options,
+        )?;
+        if cli.estimated_cost {
+            let requests = if cli.race.len() > 1 {
+                cli.race.len()
+            } else {
+                usize::from(cli.candidates.unwrap_or(1))
+            };
+            if !confirm_estimated_cost(
+                config,
+                state.model.as_deref().unwrap_or(&config.api.model),
+                &state.prompt,
+                history.as_ref(),
+                &options,
+                requests,
+            )? {
+                return Ok(None);
+            }
+        }
+        // 第一次尝试的模型已在会话开始时显示
+        if cli.verbose
+            && strategy != AttemptsStrategy::Same
+            && state.attempt > 1
+            && cli.race.len() <= 1
+        {
+            let model = state.model.as_deref().unwrap_or(&config.api.model);
+            output::note(&term, &format!("{} {}", ui_text.session_model, model))?;
+        }
+        if let (true, Some(temperature)) = (cli.verbose, options.temperature) {
+            output::note(
+                &term,
+                &format!("{} {}", ui_text.attempt_temperature, temperature),
+            )?;
+        }
+        if listen_for_cancel {
+            term.write_line(&format!(
+                "{} {}",
+                style(ui_text.thinking).info(),
+                style(ui_text.cancel_hint).secondary()
+            ))?;
+        } else {
+            output::info(&term, ui_text.thinking)?;
+        }
+        let request = async {
+            if cli.race.len() > 1 {
+                race_ai_responses(
+                    config,
+                    client,
+                    &cli.race,
+                    state.prompt.as_str(),
+                    history.as_ref(),
+                    &options,
+                    cli.debug,
+                )
+                .await
+                .map(|(model, response)| (Some(model), vec![response]))
+            } else if let Some(count @ 2..) = cli.candidates {
+                generate_candidates(
+                    config,
+                    client,
+                    count,
+                    state.prompt.as_str(),
+                    history.as_ref(),
+                    &options,
+                    cli.debug,
+                )
+                .await
+                .map(|responses| (None, responses))
+            } else {
+                get_ai_response(
+                    config,
+                    client,
+                    state.prompt.as_str(),
+                    history.as_ref(),
+                    &options,
+                    cli.debug,
+                )
+                .await
+                .map(|response| (None, vec![response]))
+            }
+        };
+        let Some(result) = cancellable(request, listen_for_cancel).await else {
+            // 取消的请求不计入尝试次数
+            output::warning(&term, ui_text.request_cancelled)?;
+            ensure_interactive(ui_text, ui_text.cancel_action_prompt)?;
+            let choice = Select::with_theme(&dialog_theme())
+                .with_prompt(ui_text.cancel_action_prompt)
+                .items(&[ui_text.cancel_reenter, ui_text.cancel_quit])
+                .default(0)
+                .interact()?;
+            if choice != 0 {
+                return Ok(None);
+            }
+            state.prompt = dialoguer::Input::<String>::with_theme(&dialog_theme())
+                .with_prompt(ui_text.new_description_prompt)
+                .with_initial_text(state.prompt)
+                .interact_text()?;
+            continue;
+        };
+        let (winner, mut responses) = result?;
+        for response in &responses {
+            count_usage(&mut state, response.total_tokens)?;
+        }
+        let response = if responses.len() > 1 {
+            term.write_line("")?;
+            choose_candidate(&term, ui_text, &config.safety.dangerous_patterns, responses)?
+        } else {
+            responses.remove(0)
+        };
+        if let Some(model) = &winner {
+            if cli.verbose {
+                term.write_line(&format!("{} {}", style(ui_text.race_winner).info(), model))?;
+            }
+        }
+        let model = winner
+            .or_else(|| state.model.clone())
+            .unwrap_or_else(|| config.api.model.clone());
+        if let Some(response_model) = &response.response_model {
+            if config.api.provider != Provider::Azure && model_rerouted(&model, response_model) {
+                output::warning(
+                    &term,
+                    &ui_text.model_rerouted.replacen("{}", &model, 1).replacen(
+                        "{}",
+                        response_model,
+                        1,
+                    ),
+                )?;
+            } else if cli.verbose {
+                term.write_line(&format!(
+                    "{} {}",
+                    style(ui_text.response_model).info(),
+                    response_model
+                ))?;
+            }
+        }
+        if let (true, Some(fingerprint)) = (cli.verbose, &response.system_fingerprint) {
+            term.write_line(&format!(
+                "{} {}",
+                style(ui_text.system_fingerprint).info(),
+                fingerprint
+            ))?;
+        }
+        if let (true, Some(usage)) = (cli.verbose, &response.usage) {
+            show_token_usage(&term, ui_text, config, &model, usage)?;
+        }
+        let mut command = response.command;
+        let mut generalized = None;
+        // 只有 POSIX shell 认识 `~` 和 `$HOME`，--syntax 指定了其他 shell 时不替换
+        let posix = cli
+            .syntax
+            .map_or(interpreter_is_posix(), TargetShell::is_posix);
+        if config.privacy.generalize_paths && !cli.raw_output && posix {
+            if let (Some(home), Ok(cwd)) = (dirs::home_dir(), env::current_dir()) {
+                let result =
+                    generalize_paths(&command, &home.to_string_lossy(), &cwd.to_string_lossy());
+                command = result.command.clone();
+                generalized = Some(result);
+            }
+        }
+
+        term.write_line("")?;
+        if let Some(rationale) = &response.rationale {
+            output::note(&term, &output::wrap(&term, rationale))?;
+        }
+        if !cli.print {
+            output::heading(&term, ui_text.generated_command)?;
+            output::write_block(&term, &styled_command(&command))?;
+        }
+        if let Some(result) = &generalized {
+            show_generalized_paths(&term, ui_text, result)?;
+        }
+        if let Some(explanation) = &response.explanation {
+            term.write_line("")?;
+            output::note(&term, &output::wrap(&term, explanation))?;
+        }
+        term.write_line("")?;
+
+        // 教学模式只增加显示，解释失败不影响后续流程
+        if (cli.ask_each_flag || config.ui.teach) && !cli.print {
+            let options = options.with_model(&model);
+            match annotate_command(config, client, &command, &options, cli.debug).await {
+                Ok((annotations, tokens)) => {
+                    count_usage(&mut state, tokens)?;
+                    show_annotations(&term, ui_text, &annotations)?
+                }
+                Err(err) => term.write_line(&format!(
+                    "{} {}\n",
+                    style(ui_text.annotation_failed).notice(),
+                    err
+                ))?,
+            }
+        }
+
+        // 命令中有占位符时先询问各个值，之后的安全检查、确认、执行、记录和重试
+        // 都针对替换后的命令
+        let template =
+            (!dry_run && !find_placeholders(&command).is_empty()).then(|| command.clone());
+        if template.is_some() {
+            command = fill_placeholders(&term, ui_text, &command)?;
+            term.write_line("")?;
+        }
+
+        let max_length = cli
+            .max_command_length
+            .unwrap_or(config.safety.max_command_length);
+        let length = command.chars().count();
+        if length > max_length {
+            output::error(
+                &term,
+                &ui_text
+                    .command_too_long
+                    .replacen("{}", &length.to_string(), 1)
+                    .replacen("{}", &max_length.to_string(), 1),
+            )?;
+            if cli.print {
+                std::process::exit(DANGEROUS_EXIT_CODE);
+            }
+            return Ok(None);
+        }
+
+        // 外部校验脚本先于内置检测运行，二者中更严格的结论生效
+        let (verdict, message) = validate_command(config, &command);
+        show_validator_verdict(&term, ui_text, verdict, &message)?;
+        if verdict != Verdict::Allow {
+            term.write_line("")?;
+        }
+        if verdict == Verdict::Deny && cli.print {
+            std::process::exit(DANGEROUS_EXIT_CODE);
+        }
+        if verdict == Verdict::Deny && !dry_run {
+            return Ok(None);
+        }
+
+        let dangerous_pattern = find_dangerous_pattern(&command, &config.safety.dangerous_patterns);
+        let dangerous = dangerous_pattern.is_some();
+        if let (true, Some(pattern)) = (dry_run, &dangerous_pattern) {
+            // dry-run 不会执行命令，危险检测的结果仅供参考
+            output::error(&term, ui_text.dry_run_danger_advisory)?;
+            term.write_line(&danger_details(pattern, &config.language))?;
+            term.write_line("")?;
+        } else if let (false, Some(pattern)) = (cli.allow_dangerous, &dangerous_pattern) {
+            output::error(&term, ui_text.dangerous_command_warning)?;
+            term.write_line(&danger_details(pattern, &config.language))?;
+            return Ok(None);
+        } else if let Some(pattern) = &dangerous_pattern {
+            output::error(&term, ui_text.dangerous_command_override)?;
+            term.write_line(&danger_details(pattern, &config.language))?;
+        }
+        // 管道交给 shell 执行的命令不直接拦截，但需要输入确认短语才能执行
+        let pipe_to_shell = find_pipe_to_shell(&command);
+        if let Some(segment) = &pipe_to_shell {
+            show_pipe_to_shell_warning(&term, ui_text, segment)?;
+        }
+        let high_risk = dangerous || pipe_to_shell.is_some();
+
+        if cli.function_check {
+            let missing = find_missing_programs(&command);
+            for program in &missing {
+                output::warning(&term, &ui_text.program_not_found.replace("{}", program))?;
+            }
+            if !missing.is_empty() {
+                ensure_interactive(ui_text, ui_text.suggest_install_prompt)?;
+            }
+            if !missing.is_empty()
+                && Confirm::with_theme(&dialog_theme())
+                    .with_prompt(ui_text.suggest_install_prompt)
+                    .default(false)
+                    .interact()?
+            {
+                output::info(&term, ui_text.thinking)?;
+                let install_prompt = format!("install {}", missing.join(" "));
+                let install_response = get_ai_response(
+                    config,
+                    client,
+                    &install_prompt,
+                    None,
+                    &RequestOptions::default(),
+                    cli.debug,
+                )
+                .await?;
+                count_usage(&mut state, install_response.total_tokens)?;
+                let install_command = install_response.command;
+                output::heading(&term, ui_text.install_suggestion)?;
+                term.write_line(&format!("{}", style(&install_command).code()))?;
+            }
+            term.write_line("")?;
+        }
+
+        if cli.print {
+            println!("{}", command);
+            if high_risk {
+                std::process::exit(DANGEROUS_EXIT_CODE);
+            }
+        }
+
+        if !dry_run {
+            if cli.verbose {
+                let cwd = env::current_dir().context("Unable to get current directory")?;
+                output::note(
+                    &term,
+                    &format!("{} {}", ui_text.execution_directory, cwd.display()),
+                )?;
+                output::note(
+                    &term,
+                    &format!(
+                        "{} {}",
+                        ui_text.execution_shell,
+                        interpreter_description(&command)
+                    ),
+                )?;
+            }
+            let auto_confirm = config
+                .behavior
+                .confirm_threshold_ms
+                .filter(|ms| *ms > 0 && term.is_term() && console::user_attended());
+            let confirmed = if high_risk {
+                confirm_dangerous(&term, ui_text, &config.safety.danger_confirm_phrase)?
+            } else if let Some(threshold_ms) = auto_confirm {
+                auto_confirm_countdown(&term, ui_text, Duration::from_millis(threshold_ms))?
+            } else {
+                ensure_interactive(ui_text, ui_text.execute_command_prompt)?;
+                Confirm::with_theme(&dialog_theme())
+                    .with_prompt(ui_text.execute_command_prompt)
+                    .default(false)
+                    .interact()?
+            };
+            if confirmed {
+                term.write_line("")?;
+                output::warning(&term, ui_text.executing_command)?;
+
+                let steps = cli
+                    .split_commands
+                    .then(|| split_command_chain(&command))
+                    .flatten();
+                let strict = cli.strict.then(|| with_strict_mode(&command)).flatten();
+                if strict.is_some() {
+                    output::note(&term, ui_text.strict_mode)?;
+                }
+                let run = || match (&steps, &strict) {
+                    (Some(steps), _) => run_command_steps(&term, ui_text, steps, cli.format_output),
+                    (None, Some(script)) => {
+                        run_and_show(&term, ui_text, script, None, cli.format_output)
+                    }
+                    (None, None) => run_and_show(&term, ui_text, &command, None, cli.format_output),
+                };
+                let (mut success, mut output_text, mut exit_code) = run()?;
+                // 缺少程序时先单独安装，再原样重新执行，而不是让 AI 换一种写法
+                let missing_program = (!success)
+                    .then(|| missing_program_from_failure(&command, exit_code, &output_text))
+                    .flatten();
+                if let Some(program) = missing_program {
+                    let failed = ExecutionHistory {
+                        model: model.clone(),
+                        command: command.clone(),
+                        output: output_text.clone(),
+                        success,
+                        attempt: state.attempt,
+                        feedback: None,
+                        seed: cli.seed,
+                        system_fingerprint: response.system_fingerprint.clone(),
+                        response_model: response.response_model.clone(),
+                        temperature: options.temperature,
+                        strategy: Some(strategy).filter(|s| *s != AttemptsStrategy::Same),
+                        template: template.clone(),
+                    };
+                    if install_missing_program(
+                        &term, ui_text, config, cli, &state, &failed, &program,
+                    )? {
+                        // 安装可能改变了环境，重新执行前像第一次执行时一样再确认一次
+                        term.write_line("")?;
+                        let rerun = if high_risk {
+                            term.write_line(&format!(
+                                "{}",
+                                style(ui_text.missing_tool_rerun_prompt).notice().bold()
+                            ))?;
+                            confirm_dangerous(&term, ui_text, &config.safety.danger_confirm_phrase)?
+                        } else {
+                            ensure_interactive(ui_text, ui_text.missing_tool_rerun_prompt)?;
+                            Confirm::with_theme(&dialog_theme())
+                                .with_prompt(ui_text.missing_tool_rerun_prompt)
+                                .default(true)
+                                .interact()?
+                        };
+                        if !rerun {
+                            return Ok(None);
+                        }
+                        (success, output_text, exit_code) = run()?;
+                    }
+                }
+
+                if let (true, Some(script)) = (success, &cli.post_hook) {
+                    let exit_code = exit_code.to_string();
+                    let envs = [
+                        ("ASKAI_PROMPT", state.prompt.as_str()),
+                        ("ASKAI_COMMAND", command.as_str()),
+                        ("ASKAI_EXIT_CODE", exit_code.as_str()),
+                    ];
+                    if let Err(err) = run_hook(script, &envs) {
+                        term.write_line(&format!(
+                            "{} {}",
+                            style(ui_text.post_hook_failed).notice(),
+                            err
+                        ))?;
+                    }
+                }
+
+                // run-tests：以测试结果判断是否达到目标，失败的测试输出会随执行结果一起发给 AI
+                let tests_passed = match (success, &state.test_command) {
+                    (true, Some(test_command)) => {
+                        let (passed, report) = run_tests(&term, ui_text, test_command)?;
+                        if !passed {
+                            output_text.push_str(
+                                &ui_text
+                                    .tests_failed_context
+                                    .replacen("{}", test_command, 1)
+                                    .replacen("{}", &report, 1),
+                            );
+                        }
+                        Some(passed)
+                    }
+                    _ => None,
+                };
+
+                let mut execution = ExecutionHistory {
+                    model,
+                    command,
+                    output: output_text,
+                    success,
+                    attempt: state.attempt,
+                    feedback: None,
+                    seed: cli.seed,
+                    system_fingerprint: response.system_fingerprint.clone(),
+                    response_model: response.response_model.clone(),
+                    temperature: options.temperature,
+                    strategy: Some(strategy).filter(|s| *s != AttemptsStrategy::Same),
+                    template,
+                };
+                let goal = if let Some(passed) = tests_passed {
+                    Some(if passed {
+                        GoalAnswer::Achieved
+                    } else {
+                        GoalAnswer::NotAchieved
+                    })
+                } else if success && skip_goal_prompt {
+                    Some(GoalAnswer::Achieved)
+                } else if success || (config.behavior.goal_prompt_on_failure && !skip_goal_prompt) {
+                    let goal = ask_goal_achieved(ui_text)?;
+                    if goal == GoalAnswer::NotAchieved {
+                        execution.feedback = ask_feedback(ui_text)?;
+                    }
+                    Some(goal)
+                } else {
+                    None
+                };
+                if goal == Some(GoalAnswer::Later) {
+                    state.history.push(execution);
+                    let id = save_session(&config.history, state)?;
+                    term.write_line("")?;
+                    output::info(&term, &ui_text.session_saved.replace("{}", &id))?;
+                    return Ok(None);
+                }
+
+                record_history(
+                    &config.history,
+                    &state.prompt,
+                    state.started_at,
+                    &execution,
+                    goal.and_then(GoalAnswer::achieved),
+                );
+                if goal == Some(GoalAnswer::Achieved) {
+                    // 成功时不再询问的模式下直接结束，不再等待按键
+                    finish_session(
+                        &term,
+                        ui_text,
+                        &state.prompt,
+                        state.started_at,
+                        &execution,
+                        !skip_goal_prompt,
+                    )?;
+                    return Ok(Some(execution));
+                }
+
+                state.history.push(execution);
+                state.attempt += 1;
+                if goal.is_none()
+                    && cli.no_confirm_success
+                    && can_continue(state.attempt, state.tokens_used)
+                {
+                    ensure_interactive(ui_text, ui_text.retry_after_failure_prompt)?;
+                    if !Confirm::with_theme(&dialog_theme())
+                        .with_prompt(ui_text.retry_after_failure_prompt)
+                        .default(true)
+                        .interact()?
+                    {
+                        return Ok(None);
+                    }
+                    continue;
+                }
+                // 命令执行失败时自动重试，用户主动选择重试时不需要等待
+                let cooldown = config.generation.retry_cooldown_secs;
+                if goal.is_none()
+                    && cooldown > 0
+                    && can_continue(state.attempt, state.tokens_used)
+                    && !retry_cooldown(&term, ui_text, cooldown)?
+                {
+                    return Ok(None);
+                }
+                continue;
+            }
+            return Ok(None);
+        }
+
+        return Ok(None);
+    }
+
+    let message = match cli.max_cost_tokens {
+        Some(_) if usage_missing.get() => ui_text.token_usage_unknown_stop.to_string(),
+        Some(budget) if state.tokens_used >= budget => ui_text
+            .token_budget_reached
+            .replacen("{}", &state.tokens_used.to_string(), 1)
+            .replacen("{}", &budget.to_string(), 1),
+        _ => ui_text.max_attempts_reached.to_string(),
+    };
+    output::error(&term, &message)?;
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(command: &str) -> Vec<LineKind> {
+        classify_lines(&command.lines().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn heredoc_body_written_to_file_is_data() {
+        use LineKind::*;
+        let command = "cat > notes.txt <<'EOF'\nrm -rf / is a bad idea\nEOF";
+        assert_eq!(kinds(command), [Command, HeredocData, HeredocEnd]);
+        assert_eq!(find_dangerous_pattern(command, &[]), None);
+    }
+
+    #[test]
+    fn heredoc_fed_to_shell_is_checked() {
+        use LineKind::*;
+        let command = "bash <<EOF\nrm -rf ~/tmp\nEOF";
+        assert_eq!(kinds(command), [Command, HeredocScript, HeredocEnd]);
+        assert_eq!(
+            find_dangerous_pattern(command, &[]).as_deref(),
+            Some("rm -rf")
+        );
+    }
+
+    #[test]
+    fn heredoc_written_then_executed_is_checked() {
+        for command in [
+            "cat <<'EOF' > x.sh\nrm -rf ~\nEOF\nbash x.sh",
+            "cat <<'EOF' > /tmp/x.sh\nrm -rf ~\nEOF\nchmod +x /tmp/x.sh && /tmp/x.sh",
+            "cat <<EOF | tee -a run.sh\nrm -rf ~\nEOF\nsh run.sh",
+        ] {
+            assert_eq!(
+                find_dangerous_pattern(command, &[]).as_deref(),
+                Some("rm -rf"),
+                "{}",
+                command
+            );
+        }
+    }
+
+    #[test]
+    fn heredoc_without_file_target_is_checked() {
+        let command = "cat <<EOF | python3\nimport os; os.system('rm -rf ~')\nEOF";
+        assert_eq!(
+            find_dangerous_pattern(command, &[]).as_deref(),
+            Some("rm -rf")
+        );
+    }
+
+    #[test]
+    fn heredoc_with_tabs_and_quoted_terminators() {
+        use LineKind::*;
+        let command = "cat <<-\"END\" > a.txt\n\tsay \"hi\" 'there'\n\tEND\necho done";
+        assert_eq!(kinds(command), [Command, HeredocData, HeredocEnd, Command]);
+        // 没有 `-` 时带制表符的结束标记不会结束 heredoc
+        let command = "cat <<END > a.txt\n\tEND\nEND";
+        assert_eq!(kinds(command), [Command, HeredocData, HeredocEnd]);
+    }
+
+    #[test]
+    fn multiple_heredocs_on_one_line() {
+        use LineKind::*;
+        let command = "paste - - <<A <<'B' > out.txt\none\nA\ntwo\nB\nwc -l out.txt";
+        assert_eq!(
+            kinds(command),
+            [
+                Command,
+                HeredocData,
+                HeredocEnd,
+                HeredocData,
+                HeredocEnd,
+                Command
+            ]
+        );
+    }
+
+    #[test]
+    fn here_string_and_commented_heredoc_are_commands() {
+        use LineKind::*;
+        assert_eq!(kinds("grep x <<< \"$data\"\necho ok"), [Command, Command]);
+        assert_eq!(kinds("echo hi # <<EOF\nEOF"), [Command, Command]);
+    }
+
+    #[test]
+    fn temp_scripts_are_private_and_unique() {
+        let first = write_temp_script("echo one").unwrap();
+        let second = write_temp_script("echo two").unwrap();
+        assert_ne!(first, second);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&first).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+        fs::remove_file(first).unwrap();
+        fs::remove_file(second).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn heredoc_commands_run_from_a_temp_script() {
+        let output = execute_command("cat <<'EOF'\n$HOME \"quoted\"\nEOF", None).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            "$HOME \"quoted\"\n"
+        );
+    }
+
+    #[test]
+    fn splits_plain_and_chains() {
+        assert_eq!(
+            split_command_chain("mkdir -p build && cd build && cmake .. 2>&1").unwrap(),
+            ["mkdir -p build", "cd build", "cmake .. 2>&1"]
+        );
+        assert_eq!(
+            split_command_chain("echo \"a && b\" && echo $(true && echo c)").unwrap(),
+            ["echo \"a && b\"", "echo $(true && echo c)"]
+        );
+    }
+
+    #[test]
+    fn refuses_to_split_when_semantics_would_change() {
+        for command in [
+            "make && make install || echo failed",
+            "cd build; make && make test",
+            "sleep 1 & wait && echo done",
+            "export PATH=/opt/bin:$PATH && tool --version",
+            "FOO=1 && echo $FOO",
+            "source .env && ./run.sh",
+            "greet() { echo hi; } && greet",
+            "echo one\necho two && echo three",
+            "single command",
+        ] {
+            assert_eq!(split_command_chain(command), None, "{}", command);
+        }
+        // `[[ ]]` 中的 `&&` 不是命令分隔符
+        assert_eq!(
+            split_command_chain("[[ -f a && -f b ]] && echo both").unwrap(),
+            ["[[ -f a && -f b ]]", "echo both"]
+        );
+    }
+
+    #[test]
+    fn unresolvable_cd_targets() {
+        let cwd = env::temp_dir();
+        assert_eq!(resolve_cd_target("cd .", &cwd), Some(cwd.join(".")));
+        for step in [
+            "cd \"$HOME/x\"",
+            "cd $DIR",
+            "cd -",
+            "cd ~bob",
+            "cd \"my dir\"",
+            "cd no-such-dir-42",
+        ] {
+            assert_eq!(resolve_cd_target(step, &cwd), None, "{}", step);
+        }
+    }
+
+    /// 用 sh 执行替换后的命令，返回输出
+    fn run_filled(template: &str, value: &str) -> String {
+        let values = std::collections::HashMap::from([(String::from("v"), value.to_string())]);
+        let command = substitute_placeholders_for(template, &values, false);
+        let output = Command::new("sh").arg("-c").arg(&command).output().unwrap();
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn placeholder_values_stay_one_argument() {
+        let templates = [
+            "printf '%s|' {{v}}",
+            "printf '%s|' \"{{v}}\"",
+            "printf '%s|' 'x{{v}}'",
+            "printf '%s|' \"$(printf '%s' {{v}})\"",
+            "printf '%s|' \"$(printf '%s' \"{{v}}\")\"",
+            "printf '%s|' \"$(printf '%s' 'x{{v}}')\"",
+            "printf '%s|' \"`printf '%s' {{v}}`\"",
+            "printf '%s|' \"`printf '%s' \"{{v}}\"`\"",
+            "printf '%s|' \"$(echo \"(\" >/dev/null; printf '%s' {{v}})\"",
+            "(printf '%s|' {{v}})",
+        ];
+        let values = [
+            "plain",
+            "with space",
+            "it's",
+            "say \"hi\"",
+            "a\nb",
+            "$HOME `id` $(id)",
+            "back\\slash \\$x \\`",
+            "*; rm -rf /tmp/none",
+        ];
+        for template in templates {
+            for value in values {
+                let expected = if template.contains("'x{{v}}'") {
+                    format!("x{}|", value)
+                } else {
+                    format!("{}|", value)
+                };
+                assert_eq!(
+                    run_filled(template, value),
+                    expected,
+                    "{} with {:?}",
+                    template,
+                    value
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn placeholder_quoting_for_cmd() {
+        let values =
+            std::collections::HashMap::from([(String::from("v"), String::from("it's \"x\""))]);
+        assert_eq!(
+            substitute_placeholders_for("type {{v}}", &values, true),
+            "type \"it's \"\"x\"\"\""
+        );
+        // cmd 中单引号不是引号，值仍然要加双引号
+        assert_eq!(
+            substitute_placeholders_for("echo '{{v}}'", &values, true),
+            "echo '\"it's \"\"x\"\"\"'"
+        );
+        assert_eq!(
+            substitute_placeholders_for("echo \"a {{v}}\"", &values, true),
+            "echo \"a it's \"\"x\"\"\""
+        );
+    }
+
+    #[test]
+    fn generalizing_paths() {
+        let home = "/home/alice";
+        let cwd = "/home/alice/work/client";
+        // (命令, 替换后的命令, 保持原样的次数)
+        let cases = [
+            // 可以安全替换的位置
+            ("ls /home/alice/notes", "ls ~/notes", 0),
+            ("cat /home/alice/work/client/a.txt", "cat ./a.txt", 0),
+            ("cp /home/alice/a /home/alice/b", "cp ~/a ~/b", 0),
+            ("du -sh /home/alice", "du -sh ~", 0),
+            ("grep x \"/home/alice/a b\"", "grep x \"$HOME/a b\"", 0),
+            ("echo \"/home/alice/work/client\"", "echo \"$PWD\"", 0),
+            ("ls /home/alice/work/client && ls", "ls . && ls", 0),
+            // 可能改变含义的位置保持原样
+            ("sed 's|/home/alice|X|' f", "sed 's|/home/alice|X|' f", 1),
+            ("ls --dir=/home/alice/x", "ls --dir=/home/alice/x", 1),
+            ("scp f host:/home/alice/f", "scp f host:/home/alice/f", 1),
+            ("ls /home/alice2/x", "ls /home/alice2/x", 0),
+            ("ls \\/home/alice", "ls \\/home/alice", 0),
+            (
+                "cd /tmp && ls /home/alice/work/client",
+                "cd /tmp && ls /home/alice/work/client",
+                1,
+            ),
+            (
+                "ssh host ls /home/alice/work",
+                "ssh host ls /home/alice/work",
+                1,
+            ),
+            (
+                "docker run -v /home/alice/data:/data img ls /home/alice",
+                "docker run -v /home/alice/data:/data img ls /home/alice",
+                2,
+            ),
+            (
+                "sudo docker run -v /home/alice/work/client:/src img",
+                "sudo docker run -v /home/alice/work/client:/src img",
+                1,
+            ),
+            (
+                "cat > cfg <<EOF\nroot=/home/alice\nEOF\nls /home/alice",
+                "cat > cfg <<EOF\nroot=/home/alice\nEOF\nls ~",
+                1,
+            ),
+        ];
+        for (command, expected, kept) in cases {
+            let result = generalize_paths(command, home, cwd);
+            assert_eq!(result.command, expected, "{}", command);
+            assert_eq!(result.kept, kept, "{}", command);
+        }
+    }
+
+    #[test]
+    fn parsing_dates() {
+        assert_eq!(parse_date("1970-01-01"), Ok(0));
+        assert_eq!(parse_date("2024-03-01"), Ok(1_709_251_200));
+        assert_eq!(parse_date("2024-02-29"), Ok(1_709_164_800));
+        assert_eq!(parse_date("2000-02-29").map(|_| ()), Ok(()));
+        assert_eq!(parse_date("2024-12-31").map(|_| ()), Ok(()));
+        for invalid in [
+            "2024-02-30",
+            "2024-02-31",
+            "2023-02-29",
+            "1900-02-29",
+            "2024-04-31",
+            "2024-13-01",
+            "2024-00-10",
+            "2024-01-00",
+            "1969-12-31",
+            "2024-1",
+            "yesterday",
+        ] {
+            assert!(parse_date(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn package_install_commands() {
+        let manager = |name: &str| {
+            *PACKAGE_MANAGERS
+                .iter()
+                .find(|(manager, _, _, _)| *manager == name)
+                .unwrap()
+        };
+        assert_eq!(
+            package_install_command(manager("apt-get"), "fd", false),
+            (
+                String::from("sudo apt-get update && sudo apt-get install -y fd-find"),
+                true
+            )
+        );
+        assert_eq!(
+            package_install_command(manager("apt-get"), "jq", true),
+            (
+                String::from("apt-get update && apt-get install -y jq"),
+                false
+            )
+        );
+        assert_eq!(
+            package_install_command(manager("pacman"), "fd", false).0,
+            "sudo pacman -S --noconfirm fd"
+        );
+        assert_eq!(
+            package_install_command(manager("brew"), "fd", false),
+            (String::from("brew install fd"), false)
+        );
+        assert_eq!(
+            package_install_command(manager("dnf"), "fd", true).0,
+            "dnf install -y fd-find"
+        );
+        assert_eq!(
+            package_install_command(manager("apk"), "rg", true).0,
+            "apk add ripgrep"
+        );
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn powershell_outer_quotes() {
+        assert_eq!(strip_outer_double_quotes("\"Get-Item x\""), "Get-Item x");
+        assert_eq!(strip_outer_double_quotes("\"a\" | \"b\""), "\"a\" | \"b\"");
+        assert_eq!(
+            strip_outer_double_quotes("\"Write-Output \"\"hi\"\"\""),
+            "Write-Output \"hi\""
+        );
+        assert_eq!(
+            strip_outer_double_quotes("\"Write-Output \\\"hi\\\"\""),
+            "Write-Output \"hi\""
+        );
+        assert_eq!(strip_outer_double_quotes("'Get-Item x'"), "'Get-Item x'");
+        assert_eq!(strip_outer_double_quotes("Get-Item x"), "Get-Item x");
+        assert_eq!(strip_outer_double_quotes("\"Get-Item x"), "\"Get-Item x");
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn powershell_cmdlet_failures() {
+        for command in [
+            "powershell -Command Get-Item askai-missing.txt",
+            "powershell -Command \"Get-Item askai-missing.txt | Out-Null\"",
+            "powershell -NoProfile -c \"Write-Output start; Get-Item askai-missing.txt\"",
+            "powershell -Command \"Get-Content askai-missing.txt\"",
+        ] {
+            let output = execute_command(command, None).unwrap();
+            assert!(!output.status.success(), "{}", command);
+            let stderr = decode_powershell_errors(&String::from_utf8_lossy(&output.stderr));
+            assert!(
+                stderr.contains("askai-missing.txt"),
+                "{}: {}",
+                command,
+                stderr
+            );
+        }
+        let output = execute_command("powershell -Command \"Write-Output ok\"", None).unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8_lossy(&output.stdout).trim(), "ok");
+        let output = execute_command("powershell -Command \"exit 3\"", None).unwrap();
+        assert_eq!(output.status.code(), Some(3));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn powershell_clixml_errors() {
+        let stderr = "#< CLIXML\r\n<Objs Version=\"1.1.0.1\"><S S=\"Error\">Get-Item : Cannot find path &apos;x&apos;_x000D__x000A_</S><S S=\"Error\">At line:1_x000D__x000A_</S></Objs>";
+        assert_eq!(
+            decode_powershell_errors(stderr),
+            "Get-Item : Cannot find path 'x'\nAt line:1\n"
+        );
+        assert_eq!(decode_powershell_errors("plain error\n"), "plain error\n");
+    }
+
+    #[test]
+    fn syntax_check_never_runs_the_command() {
+        let dir = env::temp_dir().join(format!("askai-syntax-{}", uuid::Uuid::new_v4().simple()));
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("keep"), "").unwrap();
+        let dir_text = dir.display().to_string();
+        let commands = [
+            "touch {}/created",
+            "echo x > {}/redirect",
+            "echo $(touch {}/substituted) `touch {}/backticks`",
+            "rm -f {}/keep",
+            "cat > {}/heredoc <<EOF\ndata\nEOF",
+            "sh -c 'touch {}/nested'",
+            "f() { touch {}/function; }; f",
+            "for i in 1 2; do touch {}/loop$i; done",
+        ];
+        for command in commands {
+            let command = command.replace("{}", &dir_text);
+            assert_eq!(check_syntax(&command), None, "{}", command);
+        }
+        let mut entries: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        entries.sort();
+        assert_eq!(entries, ["keep"]);
+        fs::remove_dir_all(&dir).unwrap();
+
+        for invalid in [
+            "if true; then echo x",
+            "echo \"unclosed",
+            "for; do",
+            "echo )",
+        ] {
+            assert!(check_syntax(invalid).is_some(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn flags_listed_in_help() {
+        let help = "Usage: ls [OPTION]... [FILE]...\n  -a, --all     do not ignore\n  -l            long\n      --color[=WHEN]  colorize\n";
+        assert!(help_lists_flag(help, "-a"));
+        assert!(help_lists_flag(help, "--all"));
+        assert!(help_lists_flag(help, "--color"));
+        assert!(!help_lists_flag(help, "-x"));
+        assert!(!help_lists_flag(help, "--al"));
+        let bsd =
+            "usage: ls [-@ABCFGHILOPRSTUWabcdefghiklmnopqrstuvwxy1%,] [--color=when] [file ...]";
+        assert!(help_lists_flag(bsd, "-l"));
+        assert!(help_lists_flag(bsd, "-1"));
+        assert!(!help_lists_flag(bsd, "-z"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn unknown_flags_from_help() {
+        assert_eq!(unknown_flag("ls -l /tmp", &[]), None);
+        assert_eq!(unknown_flag("ls -la --color=auto", &[]), None);
+        assert_eq!(unknown_flag("LC_ALL=C ls -1 | head -5", &[]), None);
+        assert_eq!(
+            unknown_flag("ls --definitely-not-a-flag", &[]),
+            Some((String::from("ls"), String::from("--definitely-not-a-flag")))
+        );
+        // 子命令之后的选项、选项的值以及 shell 内置命令不做检查
+        assert_eq!(unknown_flag("git commit -m message", &[]), None);
+        assert_eq!(unknown_flag("ls /tmp --definitely-not-a-flag", &[]), None);
+        assert_eq!(unknown_flag("cd -P /tmp", &[]), None);
+        assert_eq!(unknown_flag("askai-missing-program --x", &[]), None);
+        // 命中危险模式的程序不运行 --help
+        assert_eq!(unknown_flag("dd --definitely-not-a-flag", &[]), None);
+        assert_eq!(
+            unknown_flag("ls --definitely-not-a-flag", &[String::from("ls")]),
+            None
+        );
+    }
+
+    #[test]
+    fn redact_patterns() {
+        let config = HistoryConfig {
+            redact_patterns: vec![
+                String::from("sk-[A-Za-z0-9]+"),
+                String::from("(?i)password=\\S+"),
+            ],
+            operator: None,
+        };
+        assert!(config.check_redact_patterns().is_ok());
+        assert_eq!(
+            redact_output("key sk-abc123 PASSWORD=hunter2 ok", &config.redact_patterns).unwrap(),
+            "key [REDACTED] [REDACTED] ok"
+        );
+        let invalid = HistoryConfig {
+            redact_patterns: vec![String::from("sk-.*"), String::from("(unclosed")],
+            operator: None,
+        };
+        let err = invalid.check_redact_patterns().unwrap_err();
+        assert!(err.to_string().contains("(unclosed"), "{}", err);
+    }
+
+    #[test]
+    fn builtin_patterns_match_on_boundaries() {
+        for safe in [
+            "git add .",
+            "echo address",
+            "ddrescue /dev/sda out.img",
+            "yes | head -5 > added.txt",
+            "firm -rf",
+        ] {
+            assert_eq!(find_dangerous_pattern(safe, &[]), None, "{}", safe);
+        }
+        for (dangerous, pattern) in [
+            ("dd if=/dev/zero of=disk.img", "dd"),
+            ("sudo dd if=x of=/dev/sda", "dd"),
+            ("/bin/dd if=x of=y", "dd"),
+            ("ls && dd if=x of=y", "dd"),
+            ("mkfs.ext4 /dev/sdb1", "mkfs"),
+            ("sudo mkfs -t ext4 /dev/sdb1", "mkfs"),
+            ("rm -rfv /tmp/x", "rm -rf"),
+            ("chmod -R 777 .", "chmod -R"),
+        ] {
+            assert_eq!(
+                find_dangerous_pattern(dangerous, &[]).as_deref(),
+                Some(pattern),
+                "{}",
+                dangerous
+            );
+        }
+        // 用户配置的模式仍按子串匹配
+        assert_eq!(
+            find_dangerous_pattern("git add .", &[String::from("add")]).as_deref(),
+            Some("add")
+        );
+    }
+
+    #[test]
+    fn pipes_to_shell() {
+        for (command, segment) in [
+            ("curl -fsSL https://x.sh | sh", "| sh"),
+            ("curl x | bash -s -- --yes", "| bash"),
+            ("curl x | sudo bash", "| sudo bash"),
+            ("curl x | sudo -E bash", "| sudo -E bash"),
+            ("curl x | sudo -u root bash", "| sudo -u root bash"),
+            ("curl x | sudo -u root -H sh", "| sudo -u root -H sh"),
+            ("curl x | /usr/local/bin/bash", "| /usr/local/bin/bash"),
+            ("curl x | /bin/sh", "| /bin/sh"),
+            ("curl x | env FOO=1 bash", "| env FOO=1 bash"),
+            ("curl x | sudo env FOO=1 zsh", "| sudo env FOO=1 zsh"),
+            ("wget -qO- x|sh", "| sh"),
+            ("echo \"$(curl x | sh)\"", "| sh"),
+            ("echo ok; curl x | fish", "| fish"),
+        ] {
+            assert_eq!(
+                find_pipe_to_shell(command).as_deref(),
+                Some(segment),
+                "{}",
+                command
+            );
+        }
+        for command in [
+            "echo \"x | sh\"",
+            "echo 'curl x | bash'",
+            "grep -E 'a|sh' file",
+            "echo a \\| sh",
+            "false || sh script.sh",
+            "ls | shuf",
+            "ls | bash-completion-helper",
+            "cat f | shasum",
+            "# curl x | sh",
+        ] {
+            assert_eq!(find_pipe_to_shell(command), None, "{}", command);
+        }
+    }
+
+    #[test]
+    fn watched_events() {
+        let dir = std::env::temp_dir().join(format!("ask-watch-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        let file = dir.join("notes.txt");
+        fs::write(&file, "").unwrap();
+        let event = |path: std::path::PathBuf| {
+            notify::Event::new(notify::EventKind::Modify(notify::event::ModifyKind::Any))
+                .add_path(path)
+        };
+
+        let watch_file = [file.clone()];
+        assert!(is_watched_event(&watch_file, &event(file.clone())));
+        assert!(!is_watched_event(
+            &watch_file,
+            &event(dir.join("other.txt"))
+        ));
+        let read = notify::Event::new(notify::EventKind::Access(notify::event::AccessKind::Any))
+            .add_path(file.clone());
+        assert!(!is_watched_event(&watch_file, &read));
+
+        let watch_dir = [dir.clone()];
+        assert!(is_watched_event(&watch_dir, &event(dir.join("src/lib.rs"))));
+        assert!(!is_watched_event(
+            &watch_dir,
+            &event(dir.join(".notes.txt.swp"))
+        ));
+        assert!(!is_watched_event(
+            &watch_dir,
+            &event(dir.join(".git/index"))
+        ));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merging_default_flags() {
+        let merge = |defaults: &[&str], args: &[&str]| -> Vec<String> {
+            let defaults: Vec<String> = defaults.iter().map(|flag| flag.to_string()).collect();
+            let args = std::iter::once("ask")
+                .chain(args.iter().copied())
+                .map(std::ffi::OsString::from)
+                .collect();
+            merge_default_flags(&defaults, args)
+                .into_iter()
+                .skip(1)
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect()
+        };
+
+        // 默认选项放在命令行参数之前
+        assert_eq!(
+            merge(&["--dry-run", "--model", "a"], &["list files"]),
+            ["--dry-run", "--model", "a", "list files"]
+        );
+        // 命令行中的同名选项替换默认值，包括可以重复的选项
+        assert_eq!(
+            merge(&["--model", "a", "--dry-run"], &["-m", "b", "x"]),
+            ["--dry-run", "-m", "b", "x"]
+        );
+        assert_eq!(
+            merge(&["--watch", "a.txt"], &["--watch=b.txt", "x"]),
+            ["--watch=b.txt", "x"]
+        );
+        // --no-<flag> 关闭默认开关，本身不会传下去
+        assert_eq!(
+            merge(&["--dry-run", "-D"], &["--no-dry-run", "x"]),
+            ["-D", "x"]
+        );
+        assert_eq!(
+            merge(&["--dry-run"], &["--no-dry-run"]),
+            Vec::<String>::new()
+        );
+        // 真实存在的 --no-* 选项保持原样
+        assert_eq!(
+            merge(&["--no-interactive"], &["--no-interactive", "x"]),
+            ["--no-interactive", "x"]
+        );
+        // 选项的值和子命令之后的参数不与默认选项比较
+        assert_eq!(
+            merge(&["--dry-run"], &["--model", "--dry-run", "x"]),
+            ["--dry-run", "--model", "--dry-run", "x"]
+        );
+        assert_eq!(
+            merge(&["--dry-run"], &["history", "--no-dry-run"]),
+            ["--dry-run", "history", "--no-dry-run"]
+        );
+    }
+
+    #[test]
+    fn truncated_output_fits_the_limit() {
+        let output = "0123456789".repeat(5);
+        for strategy in [
+            OutputTruncationStrategy::Head,
+            OutputTruncationStrategy::Tail,
+            OutputTruncationStrategy::Middle,
+        ] {
+            for max_chars in 0..=output.len() {
+                let truncated = truncate_output(&output, max_chars, strategy);
+                assert!(
+                    truncated.chars().count() <= max_chars,
+                    "{:?} {} {:?}",
+                    strategy,
+                    max_chars,
+                    truncated
+                );
+            }
+        }
+        assert_eq!(
+            truncate_output(&output, 10, OutputTruncationStrategy::Head),
+            "0123\n[...]"
+        );
+        assert_eq!(
+            truncate_output(&output, 10, OutputTruncationStrategy::Tail),
+            "[...]\n6789"
+        );
+        assert_eq!(
+            truncate_output(&output, 4, OutputTruncationStrategy::Tail),
+            "6789"
+        );
+        assert_eq!(
+            truncate_output(&output, 14, OutputTruncationStrategy::Middle),
+            "[...]\n45\n[...]"
+        );
+    }
+
+    fn deepseek_config() -> Config {
+        toml::from_str(
+            r#"
+language = "en"
+
+[api]
+provider = "deepseek"
+base_url = "https://api.deepseek.com"
+api_key = ""
+model = "deepseek-chat"
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn system_prompt_is_a_stable_prefix() {
+        let config = deepseek_config();
+        let options = RequestOptions {
+            system_prompt: Some(String::from("You generate shell commands.")),
+            temperature: Some(0.2),
+            ..Default::default()
+        };
+        let history = ExecutionHistory {
+            model: String::from("deepseek-chat"),
+            command: String::from("ls --colour"),
+            output: String::from("ls: unrecognized option"),
+            success: false,
+            attempt: 1,
+            feedback: Some(String::from("use GNU ls")),
+            seed: None,
+            system_fingerprint: None,
+            response_model: None,
+            temperature: Some(0.2),
+            strategy: None,
+            template: None,
+        };
+        let body = |history: Option<&ExecutionHistory>| {
+            let system_prompt = build_system_prompt(&config, &options).unwrap();
+            let user_prompt = build_user_prompt(&config, "list files", history, &options).unwrap();
+            chat_request_body(
+                &config,
+                "deepseek-chat",
+                &system_prompt,
+                &user_prompt,
+                &options,
+            )
+        };
+        let (first, retry) = (body(None), body(Some(&history)));
+
+        // 系统提示是第一条消息，执行历史只出现在用户消息中
+        for body in [&first, &retry] {
+            assert_eq!(body["messages"][0]["role"], "system");
+            assert_eq!(body["messages"][1]["role"], "user");
+        }
+        assert_eq!(first["messages"][0], retry["messages"][0]);
+        assert!(retry["messages"][1]["content"]
+            .as_str()
+            .unwrap()
+            .contains("ls --colour"));
+
+        // 序列化后的两次请求直到系统提示结束都逐字节相同，前缀缓存可以命中
+        let system = first["messages"][0].to_string();
+        let (first, retry) = (first.to_string(), retry.to_string());
+        let common = first
+            .bytes()
+            .zip(retry.bytes())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let system_end = first.find(&system).unwrap() + system.len();
+        assert!(common >= system_end, "{}\n{}", first, retry);
+    }
+
+    #[test]
+    fn deepseek_cache_usage() {
+        let config = deepseek_config();
+        let response: ChatResponse = serde_json::from_str(
+            r#"{"usage": {"prompt_tokens": 1000, "completion_tokens": 20, "total_tokens": 1020,
+                "prompt_cache_hit_tokens": 800, "prompt_cache_miss_tokens": 200}}"#,
+        )
+        .unwrap();
+        let usage = response.usage.unwrap().token_usage().unwrap();
+        assert_eq!(usage.cache_hit_tokens, Some(800));
+        assert_eq!(usage.cache_miss_tokens, Some(200));
+        assert_eq!(usage.cache_hit_rate(), Some(80.0));
+        // 命中缓存的输入按缓存价格计算
+        let cost = request_cost(&config, "deepseek-chat", &usage).unwrap();
+        assert!((cost - (200.0 * 0.27 + 800.0 * 0.07 + 20.0 * 1.1) / 1e6).abs() < 1e-12);
+
+        // 没有返回未命中数时按 prompt_tokens 减去命中数计算
+        let usage = TokenUsage {
+            cache_miss_tokens: None,
+            ..usage
+        };
+        assert_eq!(usage.cache_hit_rate(), Some(80.0));
+        let cost = request_cost(&config, "deepseek-chat", &usage).unwrap();
+        assert!((cost - (200.0 * 0.27 + 800.0 * 0.07 + 20.0 * 1.1) / 1e6).abs() < 1e-12);
+
+        // 其他服务商不返回缓存字段
+        let response: ChatResponse =
+            serde_json::from_str(r#"{"usage": {"prompt_tokens": 10, "completion_tokens": 2}}"#)
+                .unwrap();
+        let usage = response.usage.unwrap().token_usage().unwrap();
+        assert_eq!(usage.cache_hit_rate(), None);
+    }
+
+    #[test]
+    fn rendered_error_messages() {
+        let err = anyhow::anyhow!("permission denied").context("Unable to read config file");
+        assert_eq!(
+            error_message(&UI_TEXT_EN, &err),
+            "Error: Unable to read config file: permission denied"
+        );
+        assert_eq!(
+            error_message(&UI_TEXT_ZH, &err),
+            "错误： Unable to read config file: permission denied"
+        );
+    }
+
+    #[test]
+    fn trailing_prose_is_split_off() {
+        for (output, command, explanation) in [
+            (
+                "ls -la\nThis command lists all files, including hidden ones.",
+                "ls -la",
+                "This command lists all files, including hidden ones.",
+            ),
+            (
+                "find . -name '*.log' -delete\n\nThis will delete every log file below the current directory.\nMake sure you are in the right place.",
+                "find . -name '*.log' -delete",
+                "This will delete every log file below the current directory.\nMake sure you are in the right place.",
+            ),
+            (
+                "du -sh * | sort -h\n该命令按大小列出当前目录下的文件和目录",
+                "du -sh * | sort -h",
+                "该命令按大小列出当前目录下的文件和目录",
+            ),
+            // 行尾注释中的句子不影响判断
+            (
+                "df -h # Show disk usage in a readable format.\nThe output lists every mounted filesystem.",
+                "df -h # Show disk usage in a readable format.",
+                "The output lists every mounted filesystem.",
+            ),
+        ] {
+            let response = split_explanation(output);
+            assert_eq!(response.command, command, "{:?}", output);
+            assert_eq!(response.explanation.as_deref(), Some(explanation), "{:?}", output);
+        }
+    }
+
+    #[test]
+    fn scripts_are_not_split() {
+        for output in [
+            // heredoc 的内容是数据
+            "cat <<EOF > notes.txt\nThis is a note for the whole team.\nPlease read it carefully.\nEOF",
+            // 循环
+            "for f in *.txt; do\n  echo \"$f\"\n  wc -l \"$f\"\ndone",
+            "while read -r line; do\n  echo \"$line\"\ndone < input.txt",
+            // 续行
+            "find . \\\n  -name '*.tmp' \\\n  -delete",
+            "cat access.log |\n  awk '{print $1}' |\n  sort | uniq -c",
+            "mkdir -p build &&\n  cd build",
+            // 以注释开头的脚本
+            "# Remove the build output.\nrm -rf build",
+            "ls -la",
+        ] {
+            let response = split_explanation(output);
+            assert_eq!(response.command, output, "{:?}", output);
+            assert_eq!(response.explanation, None, "{:?}", output);
+        }
+        // 第一行就是说明文字时不拆分，交给之后的检查
+        let response = split_explanation("This lists every file in the directory.\nls -la");
+        assert_eq!(response.explanation, None);
+    }
+
+    #[test]
+    fn ui_text_tables_are_complete() {
+        let fields = |text: &UiText| -> Vec<(String, String)> {
+            let serde_json::Value::Object(map) = serde_json::to_value(text).unwrap() else {
+                unreachable!()
+            };
+            map.into_iter()
+                .map(|(field, value)| (field, value.as_str().unwrap().to_string()))
+                .collect()
+        };
+        let placeholders = |text: &str| text.matches("{}").count();
+        let en = fields(&UI_TEXT_EN);
+        for (name, table) in [
+            ("zh", &UI_TEXT_ZH),
+            ("en ascii", &UI_TEXT_EN_ASCII),
+            ("zh ascii", &UI_TEXT_ZH_ASCII),
+        ] {
+            for ((field, reference), (_, text)) in en.iter().zip(fields(table)) {
+                // 同一条文本在各个表中必须有相同数量的占位符，否则 replace 之后会留下 {} 或丢掉内容
+                assert_eq!(
+                    placeholders(reference),
+                    placeholders(&text),
+                    "{} {}: {:?} vs {:?}",
+                    name,
+                    field,
+                    reference,
+                    text
+                );
+                assert_eq!(reference.is_empty(), text.is_empty(), "{} {}", name, field);
+            }
+        }
+        for (field, text) in fields(&UI_TEXT_ZH) {
+            // 中文表中的句子应当已经翻译；只有符号、命令和专有名词的条目可以与英文相同
+            let reference = &en.iter().find(|(name, _)| *name == field).unwrap().1;
+            if reference.split_whitespace().count() > 3 {
+                assert_ne!(&text, reference, "zh {} is not translated", field);
+            }
+        }
+        for (name, table) in [
+            ("en ascii", &UI_TEXT_EN_ASCII),
+            ("zh ascii", &UI_TEXT_ZH_ASCII),
+        ] {
+            for (field, text) in fields(table) {
+                // ASCII 表中不能有表情符号；中文和全角标点可以保留
+                assert!(
+                    !text.chars().any(|c| ('\u{2190}'..='\u{2bff}').contains(&c)
+                        || ('\u{1f000}'..='\u{1faff}').contains(&c)
+                        || c == '\u{fe0f}'),
+                    "{} {}: {:?}",
+                    name,
+                    field,
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn cleaning_crlf_replies() {
+        for (reply, command) in [
+            ("ls -la\r\n", "ls -la"),
+            ("echo done\r", "echo done"),
+            ("```bash\r\nls -la\r\n```\r\n", "ls -la"),
+            ("```ls -la```\r\n", "ls -la"),
+            (
+                "Run this:\r\n```bash\r\ncd /tmp\r\nls -la\r\n```\r\nDone.\r\n",
+                "cd /tmp\nls -la",
+            ),
+            (
+                "```bash\r\ncat <<EOF > notes.txt\r\nhello\r\nEOF\r\n```",
+                "cat <<EOF > notes.txt\nhello\nEOF",
+            ),
+        ] {
+            let cleaned = clean_command_output(reply);
+            assert_eq!(cleaned, command, "{:?}", reply);
+            assert!(!cleaned.contains('\r'), "{:?}", reply);
+        }
+        let response = split_explanation(&clean_command_output(
+            "ls -la\r\nThis command lists all files.\r\n",
+        ));
+        assert_eq!(response.command, "ls -la");
+        assert_eq!(
+            response.explanation.as_deref(),
+            Some("This command lists all files.")
+        );
+    }
+
+    #[test]
+    fn splitting_comments() {
+        for (line, code, comment) in [
+            (
+                "ls -la # list everything",
+                "ls -la ",
+                Some("# list everything"),
+            ),
+            ("# only a comment", "", Some("# only a comment")),
+            ("echo hi;# done", "echo hi;", Some("# done")),
+            ("(cd /tmp)# back", "(cd /tmp)", Some("# back")),
+            // 不是注释的 #
+            ("echo $#", "echo $#", None),
+            ("echo ${#name}", "echo ${#name}", None),
+            ("echo a#b", "echo a#b", None),
+            (
+                "echo '#' \"# not a comment\"",
+                "echo '#' \"# not a comment\"",
+                None,
+            ),
+            ("echo a\\ #b", "echo a\\ #b", None),
+            ("echo \"it's\" # quoted", "echo \"it's\" ", Some("# quoted")),
+        ] {
+            assert_eq!(split_comment(line), (code, comment), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn comments_survive_cleaning() {
+        // 单行命令的行尾注释保留
+        assert_eq!(
+            clean_command_output("```bash\ndu -sh * # size of each entry\n```"),
+            "du -sh * # size of each entry"
+        );
+        let script = "# Clean up old logs\nfind /var/log -name '*.gz' -mtime +30 -delete # older than 30 days\necho done";
+        let response = split_explanation(&clean_command_output(&format!("```sh\n{}\n```", script)));
+        assert_eq!(response.command, script);
+        assert_eq!(response.explanation, None);
+
+        // 注释中的程序名和 `<<` 不算数
+        assert_eq!(line_programs("ls -la # then run rm"), ["ls"]);
+        assert_eq!(line_programs("# sort | uniq"), Vec::<String>::new());
+        assert!(classify_lines(&["echo hi # see <<EOF", "echo next"])
+            .iter()
+            .all(|kind| *kind == LineKind::Command));
+        // 注释前的管道仍然表示续行
+        let piped =
+            "cat access.log | # count requests per address\n  Awk prints the first column here.";
+        assert_eq!(split_explanation(piped).explanation, None);
+    }
+
+    #[test]
+    fn reading_batch_files() {
+        let path =
+            std::env::temp_dir().join(format!("ask-batch-test-{}.jsonl", std::process::id()));
+        fs::write(
+            &path,
+            "{\"prompt\": \"print hello\", \"expect\": \"^hello\"}\n\n{\"prompt\": \"list files\"}\n",
+        )
+        .unwrap();
+        let cases = read_batch_cases(&path).unwrap();
+        assert_eq!(cases.len(), 2);
+        assert_eq!(cases[0].0.prompt, "print hello");
+        assert!(cases[0].1.as_ref().unwrap().is_match("hello world"));
+        assert!(cases[1].1.is_none());
+
+        // 格式或正则有误时指出行号
+        fs::write(
+            &path,
+            "{\"prompt\": \"a\"}\n{\"prompt\": \"b\", \"expect\": \"(\"}\n",
+        )
+        .unwrap();
+        let err = read_batch_cases(&path).err().unwrap();
+        assert!(
+            format!("{:#}", err).contains(":2: invalid expect pattern"),
+            "{:#}",
+            err
+        );
+        fs::write(&path, "{\"prompt\": \"a\"}\nnot json\n").unwrap();
+        let err = read_batch_cases(&path).err().unwrap();
+        assert!(
+            format!("{:#}", err).contains(":2: invalid batch entry"),
+            "{:#}",
+            err
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn checking_batch_commands() {
+        let mut config = deepseek_config();
+        config.safety.dangerous_patterns = vec![String::from("forbidden-word")];
+        let hello = Regex::new("^hello").unwrap();
+
+        assert!(matches!(
+            check_batch_command(&config, "echo hello", &hello, false).unwrap(),
+            BatchOutcome::Passed
+        ));
+        match check_batch_command(&config, "echo goodbye", &hello, false).unwrap() {
+            BatchOutcome::Failed(reason) => assert!(reason.contains("^hello"), "{}", reason),
+            _ => panic!("output should not match"),
+        }
+        // 被拦截的命令不会执行
+        for blocked in ["echo forbidden-word", "echo hello | sh", "echo {name}"] {
+            assert!(
+                matches!(
+                    check_batch_command(&config, blocked, &hello, false).unwrap(),
+                    BatchOutcome::Failed(_)
+                ),
+                "{}",
+                blocked
+            );
+        }
+    }
+
+    #[test]
+    fn merging_toml_keeps_comments() {
+        let existing = r#"# 用户手写的注释
+language = "zh"
+
+[api]
+# 服务商地址
+base_url = "https://a.example" # 旧地址
+model = "gpt-4o"
+old_key = 1
+"#;
+        let updated = r#"
+language = "zh"
+
+[api]
+base_url = "https://b.example"
+model = "gpt-4o"
+"#;
+        let mut document: toml_edit::DocumentMut = existing.parse().unwrap();
+        let updated: toml_edit::DocumentMut = updated.parse().unwrap();
+        merge_toml_table(document.as_table_mut(), updated.as_table());
+        assert_eq!(
+            document.to_string(),
+            r#"# 用户手写的注释
+language = "zh"
+
+[api]
+# 服务商地址
+base_url = "https://b.example" # 旧地址
+model = "gpt-4o"
+"#
+        );
+
+        // 写法不同但取值相同的项保持原样
+        let left: toml_edit::DocumentMut = "value = 'a' # 注释".parse().unwrap();
+        let right: toml_edit::DocumentMut = "value = \"a\"".parse().unwrap();
+        assert!(same_toml_item(&left["value"], &right["value"]));
+        let right: toml_edit::DocumentMut = "value = \"b\"".parse().unwrap();
+        assert!(!same_toml_item(&left["value"], &right["value"]));
+    }
+
+    #[test]
+    fn cleaning_keeps_heredoc_byte_exact() {
+        let body = "cat > README.md <<'EOF'\n```bash\nmake\n```\n  indented\nEOF";
+        let reply = format!("Here you go:\n```bash\n{}\n```\nDone.", body);
+        assert_eq!(clean_command_output(&reply), body);
+    }
+}
@@ -0,0 +1,540 @@
+//! 终端输出的渲染：说明文字按终端宽度折行，超过一屏的内容在交互式终端中交给 $PAGER 显示。
+//! 输出被重定向时既不折行也不分页，保证管道和文件中得到原样的内容。
+//!
+//! 颜色按用途（命令、次要文字、提示、警告、成功）通过 [`Themed`] 选取，
+//! 根据终端背景的深浅使用不同的配色，保证浅色背景下同样清晰可读。
+//!
+//! 只有一种样式的整行提示（成功、警告、错误、提示、标题、说明）都通过这里的函数输出；
+//! 由几部分拼成、各部分样式不同的行（标签加值、表格、进度条等）在调用处组合样式后再输出。
+
+use anyhow::Result;
+use console::{style, Style, StyledObject, Term};
+use std::env;
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// 界面输出使用的终端，在 console::Term 的基础上支持 --prepend-timestamp
+#[derive(Debug, Clone)]
+pub(crate) struct UiTerm(Term);
+
+impl UiTerm {
+    pub(crate) fn stdout() -> UiTerm {
+        UiTerm(Term::stdout())
+    }
+
+    pub(crate) fn stderr() -> UiTerm {
+        UiTerm(Term::stderr())
+    }
+
+    /// 输出一行（或多行）文本，设置了 --prepend-timestamp 时每一行前都会加上 UTC 时间
+    pub(crate) fn write_line(&self, text: &str) -> io::Result<()> {
+        if !crate::PREPEND_TIMESTAMP.load(Ordering::Relaxed) {
+            return self.0.write_line(text);
+        }
+        let prefix = format!("[{}] ", iso8601_utc(crate::unix_timestamp()));
+        let lines: Vec<String> = text
+            .split('\n')
+            .map(|line| format!("{}{}", prefix, line))
+            .collect();
+        self.0.write_line(&lines.join("\n"))
+    }
+}
+
+impl Deref for UiTerm {
+    type Target = Term;
+
+    fn deref(&self) -> &Term {
+        &self.0
+    }
+}
+
+/// 把 Unix 时间戳格式化为 ISO 8601 的 UTC 时间，例如 2024-01-15T10:23:45Z
+fn iso8601_utc(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds = timestamp % 86400;
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        seconds / 3600,
+        seconds % 3600 / 60,
+        seconds % 60
+    )
+}
+
+/// 折行时使用的最小宽度，避免终端过窄时每行只剩几个字符
+const MIN_WRAP_WIDTH: usize = 40;
+
+/// 未设置 $PAGER 时使用的分页程序，-R 保留颜色
+const DEFAULT_PAGER: &str = "less -R";
+
+/// 终端的列数和行数，输出不是终端时返回 None
+fn terminal_size(term: &Term) -> Option<(usize, usize)> {
+    if !term.is_term() {
+        return None;
+    }
+    term.size_checked()
+        .map(|(rows, columns)| (columns as usize, rows as usize))
+}
+
+/// 按终端宽度折行，保留原有的换行；输出不是终端时原样返回
+pub(crate) fn wrap(term: &UiTerm, text: &str) -> String {
+    match terminal_size(term) {
+        Some((columns, _)) => textwrap::fill(text, columns.max(MIN_WRAP_WIDTH)),
+        None => text.to_string(),
+    }
+}
+
+/// 输出一整块内容（命令、命令输出、列表等），不会折行。
+/// 交互式终端中超过一屏时交给 $PAGER，分页程序不可用时直接输出
+pub(crate) fn write_block(term: &UiTerm, text: &str) -> Result<()> {
+    if should_page(term, text) && page(text).is_ok() {
+        return Ok(());
+    }
+    term.write_line(text)?;
+    Ok(())
+}
+
+fn should_page(term: &UiTerm, text: &str) -> bool {
+    if crate::NO_INTERACTIVE.load(Ordering::Relaxed)
+        || crate::PRINT_MODE.load(Ordering::Relaxed)
+        || !console::user_attended()
+    {
+        return false;
+    }
+    let Some((columns, rows)) = terminal_size(term) else {
+        return false;
+    };
+    // 超长的行在终端中会占用多行
+    let height: usize = text
+        .lines()
+        .map(|line| {
+            console::measure_text_width(line)
+                .div_ceil(columns.max(1))
+                .max(1)
+        })
+        .sum();
+    height >= rows
+}
+
+fn page(text: &str) -> Result<()> {
+    let pager = env::var("PAGER")
+        .ok()
+        .filter(|pager| !pager.trim().is_empty())
+        .unwrap_or_else(|| DEFAULT_PAGER.to_string());
+    let mut child = crate::shell_command(&pager).stdin(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // 用户提前退出分页程序时写入会失败，这不是错误
+        let _ = writeln!(stdin, "{}", text);
+    }
+    let status = child.wait()?;
+    // 127：shell 找不到分页程序
+    if status.code() == Some(127) {
+        return Err(anyhow::anyhow!("pager `{}` not found", pager));
+    }
+    Ok(())
+}
+
+/// 一行成功提示（绿色加粗），用于 `ask set` 等非交互命令的结果
+pub(crate) fn success(term: &UiTerm, text: &str) -> io::Result<()> {
+    term.write_line(&render_success(text))
+}
+
+/// 一行警告（黄色）
+pub(crate) fn warning(term: &UiTerm, text: &str) -> io::Result<()> {
+    term.write_line(&render_warning(text))
+}
+
+/// 一行错误（红色加粗）
+pub(crate) fn error(term: &UiTerm, text: &str) -> io::Result<()> {
+    term.write_line(&render_error(text))
+}
+
+/// 一行提示信息（蓝色），例如状态和进度
+pub(crate) fn info(term: &UiTerm, text: &str) -> io::Result<()> {
+    term.write_line(&format!("{}", style(text).info()))
+}
+
+/// 一段输出的标题（蓝色加粗）
+pub(crate) fn heading(term: &UiTerm, text: &str) -> io::Result<()> {
+    term.write_line(&format!("{}", style(text).info().bold()))
+}
+
+/// 一行次要的说明（暗色）
+pub(crate) fn note(term: &UiTerm, text: &str) -> io::Result<()> {
+    term.write_line(&format!("{}", style(text).secondary()))
+}
+
+/// 标签左对齐的键值表，标签显示为暗色。值可以已经带有样式，只按标签的显示宽度对齐
+pub(crate) fn key_values(term: &UiTerm, rows: &[(&str, String)]) -> io::Result<()> {
+    if rows.is_empty() {
+        return Ok(());
+    }
+    term.write_line(&render_key_values(rows))
+}
+
+fn render_success(text: &str) -> String {
+    format!("{}", style(text).positive().bold())
+}
+
+fn render_warning(text: &str) -> String {
+    format!("{}", style(text).notice())
+}
+
+fn render_error(text: &str) -> String {
+    format!("{}", style(text).red().bold())
+}
+
+fn render_key_values(rows: &[(&str, String)]) -> String {
+    let width = rows
+        .iter()
+        .map(|(label, _)| console::measure_text_width(label))
+        .max()
+        .unwrap_or_default();
+    let lines: Vec<String> = rows
+        .iter()
+        .map(|(label, value)| {
+            format!(
+                "  {}  {}",
+                style(console::pad_str(
+                    label,
+                    width,
+                    console::Alignment::Left,
+                    None
+                ))
+                .secondary(),
+                value
+            )
+        })
+        .collect();
+    lines.join("\n")
+}
+
+/// [ui] theme：配色跟随终端背景（auto）或固定为深色、浅色
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ThemeSetting {
+    #[default]
+    Auto,
+    Dark,
+    Light,
+}
+
+/// 实际使用的配色
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Theme {
+    Dark,
+    Light,
+}
+
+static LIGHT_THEME: AtomicBool = AtomicBool::new(false);
+
+/// 查询终端背景色时等待回应的最长时间。查询后面跟着一个几乎所有终端都会回应的 DA1 请求，
+/// 通常远早于超时就能确定终端是否支持 OSC 11
+const BACKGROUND_QUERY_TIMEOUT: Duration = Duration::from_millis(300);
+
+/// 根据设置确定配色。auto 时先通过 OSC 11 查询终端背景色，再参考 COLORFGBG，都无法判断时使用深色配色。
+/// 不输出颜色时配色没有意义，也就不查询终端
+pub(crate) fn init_theme(setting: ThemeSetting) {
+    let theme = match setting {
+        ThemeSetting::Dark => Theme::Dark,
+        ThemeSetting::Light => Theme::Light,
+        ThemeSetting::Auto if !console::colors_enabled() => Theme::Dark,
+        ThemeSetting::Auto => query_background()
+            .map(|(red, green, blue)| theme_for_background(red, green, blue))
+            .or_else(|| {
+                env::var("COLORFGBG")
+                    .ok()
+                    .and_then(|value| theme_from_colorfgbg(&value))
+            })
+            .unwrap_or(Theme::Dark),
+    };
+    LIGHT_THEME.store(theme == Theme::Light, Ordering::Relaxed);
+}
+
+pub(crate) fn theme() -> Theme {
+    if LIGHT_THEME.load(Ordering::Relaxed) {
+        Theme::Light
+    } else {
+        Theme::Dark
+    }
+}
+
+/// 按背景色的相对亮度（0~1 的 RGB 分量）判断深浅
+fn theme_for_background(red: f64, green: f64, blue: f64) -> Theme {
+    let luminance = 0.2126 * red + 0.7152 * green + 0.0722 * blue;
+    if luminance > 0.5 {
+        Theme::Light
+    } else {
+        Theme::Dark
+    }
+}
+
+/// COLORFGBG 形如 "15;0" 或 "0;default;15"，最后一项是背景色在 16 色调色板中的编号：
+/// 7（浅灰）和 9~15（亮色）为浅色背景
+fn theme_from_colorfgbg(value: &str) -> Option<Theme> {
+    let background: u8 = value.rsplit(';').next()?.trim().parse().ok()?;
+    Some(match background {
+        7 | 9..=15 => Theme::Light,
+        _ => Theme::Dark,
+    })
+}
+
+/// 解析终端对 OSC 11 的回应，例如 `\x1b]11;rgb:ffff/ffff/ffff\x07`，每个分量为 1~4 位十六进制数。
+/// 回应以 BEL 或 ST（ESC \）结束，之后的内容（例如 DA1 的回应）会被忽略
+fn parse_osc11_response(response: &str) -> Option<(f64, f64, f64)> {
+    let rgb = response.split("rgb:").nth(1)?;
+    let rgb = rgb.split(['\x07', '\x1b']).next()?;
+    let mut components = rgb.split('/').map(|component| {
+        let digits = component.len();
+        let value = u32::from_str_radix(component, 16).ok()?;
+        (1..=4)
+            .contains(&digits)
+            .then(|| f64::from(value) / f64::from((1u32 << (4 * digits)) - 1))
+    });
+    let (red, green, blue) = (
+        components.next()??,
+        components.next()??,
+        components.next()??,
+    );
+    Some((red, green, blue))
+}
+
+/// 终端对 DA1（`ESC [ c`）的回应形如 `ESC [ ? 62 ; 22 c`
+fn contains_da1_response(response: &[u8]) -> bool {
+    response
+        .windows(3)
+        .position(|window| window == b"\x1b[?")
+        .is_some_and(|start| response[start..].contains(&b'c'))
+}
+
+/// 通过 /dev/tty 发送 OSC 11 查询终端背景色。终端按顺序回应请求，因此紧接着发送 DA1 请求，
+/// 读到 DA1 的回应时 OSC 11 的回应（如果支持）一定已经读到；不支持 OSC 11 的终端不会在之后
+/// 才回应，回应也就不会残留在输入中被 shell 当作按键读到。超时或读完后都会丢弃尚未读取的输入
+#[cfg(unix)]
+fn query_background() -> Option<(f64, f64, f64)> {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+    use std::time::Instant;
+
+    if !Term::stdout().is_term() {
+        return None;
+    }
+    let mut tty = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/tty")
+        .ok()?;
+    // 原始模式下回应不会回显，也不需要等待回车
+    crossterm::terminal::enable_raw_mode().ok()?;
+    let response = (|| {
+        tty.write_all(b"\x1b]11;?\x07\x1b[c").ok()?;
+        tty.flush().ok()?;
+        let deadline = Instant::now() + BACKGROUND_QUERY_TIMEOUT;
+        let mut response = Vec::new();
+        while !contains_da1_response(&response) {
+            let left = deadline.checked_duration_since(Instant::now())?;
+            let mut poll = libc::pollfd {
+                fd: tty.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            // SAFETY：poll 只读写传入的这一个 pollfd
+            let ready = unsafe { libc::poll(&mut poll, 1, left.as_millis() as libc::c_int) };
+            if ready <= 0 {
+                return None;
+            }
+            let mut buffer = [0u8; 64];
+            let read = tty.read(&mut buffer).ok()?;
+            if read == 0 {
+                return None;
+            }
+            response.extend_from_slice(&buffer[..read]);
+        }
+        Some(String::from_utf8_lossy(&response).into_owned())
+    })();
+    // SAFETY：tcflush 只丢弃这个 fd 上尚未读取的输入
+    unsafe {
+        libc::tcflush(tty.as_raw_fd(), libc::TCIFLUSH);
+    }
+    let _ = crossterm::terminal::disable_raw_mode();
+    parse_osc11_response(&response?)
+}
+
+#[cfg(not(unix))]
+fn query_background() -> Option<(f64, f64, f64)> {
+    None
+}
+
+/// 按用途选取颜色：深色背景沿用原来的颜色，浅色背景换成更深、对比度足够的颜色
+pub(crate) trait Themed: Sized {
+    /// 命令本身
+    fn code(self) -> Self;
+    /// 次要的说明文字（深色背景下为暗色）
+    fn secondary(self) -> Self;
+    /// 标题和提示信息
+    fn info(self) -> Self;
+    /// 警告和需要注意的信息
+    fn notice(self) -> Self;
+    /// 成功和安全的信息
+    fn positive(self) -> Self;
+}
+
+macro_rules! impl_themed {
+    ($type:ty $(, $generic:ident)?) => {
+        impl$(<$generic>)? Themed for $type {
+            fn code(self) -> Self {
+                match theme() {
+                    Theme::Dark => self.cyan(),
+                    Theme::Light => self.color256(24),
+                }
+            }
+
+            fn secondary(self) -> Self {
+                match theme() {
+                    Theme::Dark => self.dim(),
+                    Theme::Light => self.color256(242),
+                }
+            }
+
+            fn info(self) -> Self {
+                match theme() {
+                    Theme::Dark => self.blue(),
+                    Theme::Light => self.color256(25),
+                }
+            }
+
+            fn notice(self) -> Self {
+                match theme() {
+                    Theme::Dark => self.yellow(),
+                    Theme::Light => self.color256(130),
+                }
+            }
+
+            fn positive(self) -> Self {
+                match theme() {
+                    Theme::Dark => self.green(),
+                    Theme::Light => self.color256(28),
+                }
+            }
+        }
+    };
+}
+
+impl_themed!(Style);
+impl_themed!(StyledObject<D>, D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rendered_lines() {
+        // 不比较颜色：测试时是否输出颜色取决于运行环境
+        let plain = |text: String| console::strip_ansi_codes(&text).into_owned();
+        assert_eq!(
+            plain(render_success("✅ Configuration saved")),
+            "✅ Configuration saved"
+        );
+        assert_eq!(plain(render_warning("⚠️  配置已修改")), "⚠️  配置已修改");
+        assert_eq!(
+            plain(render_error("❌ Error: Unknown config key `modle`")),
+            "❌ Error: Unknown config key `modle`"
+        );
+    }
+
+    #[test]
+    fn rendered_key_values() {
+        let plain = |text: String| console::strip_ansi_codes(&text).into_owned();
+        assert_eq!(
+            plain(render_key_values(&[
+                ("Model", String::from("gpt-4o")),
+                ("API key", String::from("sk-…abcd")),
+                ("Language", String::from("en")),
+            ])),
+            "  Model     gpt-4o\n  API key   sk-…abcd\n  Language  en"
+        );
+        // 中文标签按显示宽度对齐，值中的样式不影响对齐
+        assert_eq!(
+            plain(render_key_values(&[
+                ("模型", style("gpt-4o").code().to_string()),
+                ("API 密钥", String::from("(未设置)")),
+            ])),
+            "  模型      gpt-4o\n  API 密钥  (未设置)"
+        );
+        assert_eq!(render_key_values(&[]), "");
+    }
+
+    #[test]
+    fn osc11_responses() {
+        let white = Some((1.0, 1.0, 1.0));
+        assert_eq!(
+            parse_osc11_response("\x1b]11;rgb:ffff/ffff/ffff\x07"),
+            white
+        );
+        assert_eq!(
+            parse_osc11_response("\x1b]11;rgb:ffff/ffff/ffff\x1b\\"),
+            white
+        );
+        assert_eq!(parse_osc11_response("\x1b]11;rgb:ff/ff/ff\x07"), white);
+        // 之后跟着 DA1 的回应
+        assert_eq!(
+            parse_osc11_response("\x1b]11;rgb:0000/0000/0000\x1b\\\x1b[?62;22c"),
+            Some((0.0, 0.0, 0.0))
+        );
+        let (red, green, blue) = parse_osc11_response("\x1b]11;rgb:8/80/800\x07").unwrap();
+        assert!((red - 8.0 / 15.0).abs() < 1e-9);
+        assert!((green - 128.0 / 255.0).abs() < 1e-9);
+        assert!((blue - 2048.0 / 4095.0).abs() < 1e-9);
+        assert_eq!(parse_osc11_response("\x1b[?62;22c"), None);
+        assert_eq!(parse_osc11_response("\x1b]11;rgb:ffff/ffff\x07"), None);
+        assert_eq!(parse_osc11_response("\x1b]11;rgb:fffff/0/0\x07"), None);
+        assert_eq!(parse_osc11_response("\x1b]11;rgb:gg/00/00\x07"), None);
+    }
+
+    #[test]
+    fn da1_responses() {
+        assert!(contains_da1_response(b"\x1b[?62;22c"));
+        assert!(contains_da1_response(b"\x1b]11;rgb:0/0/0\x07\x1b[?1;2c"));
+        assert!(!contains_da1_response(b"\x1b]11;rgb:0/0/0\x07"));
+        assert!(!contains_da1_response(b"\x1b[?62;22"));
+    }
+
+    #[test]
+    fn colorfgbg_values() {
+        assert_eq!(theme_from_colorfgbg("15;0"), Some(Theme::Dark));
+        assert_eq!(theme_from_colorfgbg("0;15"), Some(Theme::Light));
+        assert_eq!(theme_from_colorfgbg("0;default;15"), Some(Theme::Light));
+        assert_eq!(theme_from_colorfgbg("0;7"), Some(Theme::Light));
+        assert_eq!(theme_from_colorfgbg("7;8"), Some(Theme::Dark));
+        assert_eq!(theme_from_colorfgbg("15;default"), None);
+        assert_eq!(theme_from_colorfgbg(""), None);
+    }
+
+    #[test]
+    fn background_luminance() {
+        assert_eq!(theme_for_background(1.0, 1.0, 1.0), Theme::Light);
+        assert_eq!(theme_for_background(0.0, 0.0, 0.0), Theme::Dark);
+        // Solarized Light / Dark
+        assert_eq!(theme_for_background(0.99, 0.96, 0.89), Theme::Light);
+        assert_eq!(theme_for_background(0.0, 0.17, 0.21), Theme::Dark);
+        // 纯蓝很暗，纯绿很亮
+        assert_eq!(theme_for_background(0.0, 0.0, 1.0), Theme::Dark);
+        assert_eq!(theme_for_background(0.0, 1.0, 0.0), Theme::Light);
+    }
+}
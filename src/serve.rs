@@ -0,0 +1,306 @@
+//! `ask serve` 的 HTTP 接口：接收 JSON 请求，生成命令并附带与交互模式相同的安全检查结果返回。
+//! 服务端只生成命令、从不执行。只监听本机地址时检查 Host 头防止 DNS 重绑定，
+//! 设置 ASKAI_SERVE_TOKEN 后要求请求携带令牌。
+
+use anyhow::{Context, Result};
+use reqwest::Client;
+use serde_json::json;
+use std::env;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::output::{self, UiTerm};
+use crate::{find_dangerous_pattern, find_pipe_to_shell, get_ai_response};
+use crate::{Config, RequestOptions, UiText};
+
+/// `ask serve` 的请求体
+#[derive(serde::Deserialize)]
+struct ServeRequest {
+    prompt: String,
+    /// 覆盖配置中的模型，只有使用 --allow-model-override 启动时才生效
+    #[serde(default)]
+    model: Option<String>,
+}
+
+/// `ask serve` 处理请求时需要的设置
+struct ServeSettings {
+    config: Arc<Config>,
+    client: Client,
+    default_model: Option<String>,
+    debug: bool,
+    /// ASKAI_SERVE_TOKEN：设置后请求必须带 `Authorization: Bearer <token>`
+    token: Option<String>,
+    /// 只监听本机地址时允许的 Host 头（不含端口），防止 DNS 重绑定；为 None 时不检查
+    allowed_hosts: Option<Vec<String>>,
+    allow_model_override: bool,
+}
+
+/// 去掉 Host 头中的端口，IPv6 地址保留方括号
+fn host_without_port(host: &str) -> &str {
+    match host.rfind(':') {
+        Some(index) if !host[index..].contains(']') => &host[..index],
+        _ => host,
+    }
+}
+
+/// 逐字节比较且不提前返回，避免通过响应时间猜出令牌
+fn token_matches(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// 请求来源检查：Host 头（防止 DNS 重绑定）、令牌以及 JSON 的 Content-Type。
+/// 浏览器跨域发送的“简单请求”只能是 text/plain 等类型，要求 application/json 后
+/// 网页无法不经预检就直接调用。返回拒绝请求时的状态码和原因
+fn check_serve_request(
+    settings: &ServeSettings,
+    request: &hyper::Request<hyper::Body>,
+) -> Option<(hyper::StatusCode, &'static str)> {
+    use hyper::{header, StatusCode};
+
+    let header_value = |name: header::HeaderName| {
+        request
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    if let Some(allowed) = &settings.allowed_hosts {
+        let host = header_value(header::HOST).map(host_without_port);
+        if !host.is_some_and(|host| {
+            allowed
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+        }) {
+            return Some((StatusCode::FORBIDDEN, "invalid Host header"));
+        }
+    }
+    if let Some(token) = &settings.token {
+        let provided = header_value(header::AUTHORIZATION)
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if !token_matches(token, provided.trim()) {
+            return Some((StatusCode::UNAUTHORIZED, "missing or invalid bearer token"));
+        }
+    }
+    let json = header_value(header::CONTENT_TYPE).is_some_and(|value| {
+        value
+            .split(';')
+            .next()
+            .is_some_and(|mime| mime.trim().eq_ignore_ascii_case("application/json"))
+    });
+    (!json).then_some((
+        StatusCode::UNSUPPORTED_MEDIA_TYPE,
+        "Content-Type must be application/json",
+    ))
+}
+
+/// `ask serve` 的响应。`dangerous` 为命中的危险命令规则或管道执行片段，调用方应据此决定
+/// 是否需要人工确认
+#[derive(serde::Serialize)]
+struct ServeResponse {
+    command: String,
+    explanation: Option<String>,
+    model: Option<String>,
+    dangerous: Option<String>,
+}
+
+/// 请求体的最大字节数
+const SERVE_MAX_BODY_BYTES: usize = 1024 * 1024;
+
+fn serve_json(
+    status: hyper::StatusCode,
+    body: &impl serde::Serialize,
+) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(hyper::Body::from(
+            serde_json::to_vec(body).unwrap_or_default(),
+        ))
+        .expect("static response parts are valid")
+}
+
+fn serve_error(status: hyper::StatusCode, message: &str) -> hyper::Response<hyper::Body> {
+    serve_json(status, &json!({ "error": message }))
+}
+
+/// 读取请求体，超过 SERVE_MAX_BODY_BYTES 时返回 None
+async fn read_serve_body(body: &mut hyper::Body) -> Result<Option<Vec<u8>>> {
+    use hyper::body::HttpBody;
+
+    let mut bytes = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk?;
+        if bytes.len() + chunk.len() > SERVE_MAX_BODY_BYTES {
+            return Ok(None);
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    Ok(Some(bytes))
+}
+
+/// 处理一个 HTTP 请求：只接受 POST /generate（或 POST /），生成命令并附带安全检查结果
+async fn handle_serve_request(
+    settings: Arc<ServeSettings>,
+    request: hyper::Request<hyper::Body>,
+) -> hyper::Response<hyper::Body> {
+    use hyper::{Method, StatusCode};
+
+    if !matches!(request.uri().path(), "/" | "/generate") {
+        return serve_error(StatusCode::NOT_FOUND, "not found");
+    }
+    if request.method() != Method::POST {
+        return serve_error(StatusCode::METHOD_NOT_ALLOWED, "use POST");
+    }
+    if let Some((status, reason)) = check_serve_request(&settings, &request) {
+        return serve_error(status, reason);
+    }
+    let config = &settings.config;
+    let mut body = request.into_body();
+    let bytes = match read_serve_body(&mut body).await {
+        Ok(Some(bytes)) => bytes,
+        Ok(None) => return serve_error(StatusCode::PAYLOAD_TOO_LARGE, "request body too large"),
+        Err(err) => return serve_error(StatusCode::BAD_REQUEST, &format!("{:#}", err)),
+    };
+    let payload: ServeRequest = match serde_json::from_slice(&bytes) {
+        Ok(payload) => payload,
+        Err(err) => return serve_error(StatusCode::BAD_REQUEST, &err.to_string()),
+    };
+    if payload.prompt.trim().is_empty() {
+        return serve_error(StatusCode::BAD_REQUEST, "prompt is empty");
+    }
+
+    let requested_model = payload.model.filter(|_| settings.allow_model_override);
+    let options = RequestOptions {
+        model: requested_model.or_else(|| settings.default_model.clone()),
+        ..RequestOptions::default()
+    };
+    let response = match get_ai_response(
+        config,
+        &settings.client,
+        &payload.prompt,
+        None,
+        &options,
+        settings.debug,
+    )
+    .await
+    {
+        Ok(response) => response,
+        Err(err) => return serve_error(StatusCode::BAD_GATEWAY, &format!("{:#}", err)),
+    };
+    let dangerous = find_dangerous_pattern(&response.command, &config.safety.dangerous_patterns)
+        .or_else(|| find_pipe_to_shell(&response.command));
+    serve_json(
+        StatusCode::OK,
+        &ServeResponse {
+            command: response.command,
+            explanation: response.explanation,
+            model: response
+                .response_model
+                .or(options.model)
+                .or_else(|| Some(config.api.model.clone())),
+            dangerous,
+        },
+    )
+}
+
+/// `ask serve`：在 `addr` 上提供生成命令的 HTTP 接口，Ctrl+C 时处理完进行中的请求后退出。
+/// `default_model` 为 --model 指定的模型。出于安全考虑，服务端从不执行生成的命令
+pub(crate) async fn serve(
+    config: Arc<Config>,
+    client: Client,
+    ui_text: &UiText,
+    addr: SocketAddr,
+    default_model: Option<String>,
+    debug: bool,
+    allow_model_override: bool,
+) -> Result<()> {
+    use hyper::service::{make_service_fn, service_fn};
+
+    let term = UiTerm::stdout();
+    let token = env::var("ASKAI_SERVE_TOKEN")
+        .ok()
+        .filter(|token| !token.trim().is_empty());
+    let loopback = addr.ip().is_loopback();
+    let settings = Arc::new(ServeSettings {
+        config,
+        client,
+        default_model,
+        debug,
+        token: token.clone(),
+        allowed_hosts: loopback.then(|| {
+            let mut hosts: Vec<String> = ["localhost", "127.0.0.1", "[::1]"]
+                .iter()
+                .map(|host| host.to_string())
+                .collect();
+            hosts.push(match addr {
+                SocketAddr::V6(addr) => format!("[{}]", addr.ip()),
+                SocketAddr::V4(addr) => addr.ip().to_string(),
+            });
+            hosts
+        }),
+        allow_model_override,
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let settings = Arc::clone(&settings);
+        async move {
+            Ok::<_, std::convert::Infallible>(service_fn(move |request| {
+                let response = handle_serve_request(Arc::clone(&settings), request);
+                async move { Ok::<_, std::convert::Infallible>(response.await) }
+            }))
+        }
+    });
+    let server = hyper::Server::try_bind(&addr)
+        .with_context(|| format!("Unable to listen on {}", addr))?
+        .serve(make_service);
+    if !loopback {
+        output::error(
+            &term,
+            &ui_text
+                .serve_public_warning
+                .replace("{}", &addr.to_string()),
+        )?;
+        if token.is_none() {
+            output::warning(&term, ui_text.serve_no_token)?;
+        }
+    }
+    output::success(
+        &term,
+        &ui_text
+            .serve_listening
+            .replace("{}", &format!("http://{}/generate", server.local_addr())),
+    )?;
+    server
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_header_without_port() {
+        assert_eq!(host_without_port("localhost:8080"), "localhost");
+        assert_eq!(host_without_port("127.0.0.1"), "127.0.0.1");
+        assert_eq!(host_without_port("[::1]:8080"), "[::1]");
+        assert_eq!(host_without_port("[::1]"), "[::1]");
+        assert_eq!(host_without_port("evil.example:8080"), "evil.example");
+    }
+
+    #[test]
+    fn token_comparison() {
+        assert!(token_matches("s3cret", "s3cret"));
+        assert!(!token_matches("s3cret", "s3cre"));
+        assert!(!token_matches("s3cret", "s3creT"));
+        assert!(!token_matches("s3cret", ""));
+    }
+}
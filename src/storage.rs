@@ -0,0 +1,290 @@
+//! `~/.askai/` 下共享文件的写入。多个终端中同时运行的 ask 会写同一批文件（历史、片段、缓存、配置），
+//! 这里统一加建议性文件锁（Unix 上为 flock，Windows 上为 LockFileEx），并保证写入是原子的：
+//! 追加时持有独占锁一次写入整行，替换时先写入同目录的临时文件再 rename 覆盖，
+//! 读取的一方只会看到完整的旧内容或新内容。
+//!
+//! 锁加在旁边单独的 `.<文件名>.lock` 上而不是数据文件本身：rename 会换掉数据文件的 inode，
+//! 锁在数据文件上时，先后拿到新旧 inode 的两个进程并不互斥。需要“读取、修改、写回”的调用方
+//! 使用 [`update`]，在整个过程中持有锁，其他进程的追加和替换不会插在中间而丢失。
+//!
+//! 获取锁最多等待 LOCK_WAIT，超时返回 [`Contended`]，不会长时间阻塞交互；
+//! 可选的写入（历史、缓存）由调用方提示后跳过。
+
+use anyhow::{Context, Result};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// 等待其他进程释放锁的最长时间
+const LOCK_WAIT: Duration = Duration::from_millis(500);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(10);
+
+/// 文件正被其他 ask 进程写入，等待 LOCK_WAIT 后仍未拿到锁
+#[derive(Debug)]
+pub(crate) struct Contended(pub(crate) PathBuf);
+
+impl std::fmt::Display for Contended {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} is locked by another process", self.0.display())
+    }
+}
+
+impl std::error::Error for Contended {}
+
+/// `path` 对应的锁文件，与数据文件在同一目录，不会被 rename 替换
+fn lock_path(path: &Path) -> PathBuf {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    path.with_file_name(format!(".{}.lock", file_name))
+}
+
+/// 在 LOCK_WAIT 内反复尝试获取 `path` 的独占锁，返回的锁文件关闭（drop）时自动释放
+fn lock(path: &Path) -> Result<File> {
+    let lock_path = lock_path(path);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&lock_path)
+        .with_context(|| format!("Unable to open {}", lock_path.display()))?;
+    let started = Instant::now();
+    loop {
+        match file.try_lock() {
+            Ok(()) => return Ok(file),
+            Err(TryLockError::WouldBlock) if started.elapsed() < LOCK_WAIT => {
+                std::thread::sleep(LOCK_RETRY_INTERVAL);
+            }
+            Err(TryLockError::WouldBlock) => return Err(Contended(path.to_path_buf()).into()),
+            Err(TryLockError::Error(err)) => {
+                return Err(err).with_context(|| format!("Unable to lock {}", path.display()))
+            }
+        }
+    }
+}
+
+/// 在 JSONL 等按行记录的文件末尾追加一行（`line` 不含换行符）
+pub(crate) fn append_line(path: &Path, line: &str) -> Result<()> {
+    let _guard = lock(path)?;
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Unable to open {}", path.display()))?;
+    file.write_all(format!("{}\n", line).as_bytes())
+        .with_context(|| format!("Unable to write {}", path.display()))
+}
+
+/// 用 `contents` 替换整个文件，持有锁期间不会有其他进程追加或替换
+pub(crate) fn replace(path: &Path, contents: impl AsRef<[u8]>) -> Result<()> {
+    let _guard = lock(path)?;
+    write_atomically(path, contents.as_ref())
+}
+
+/// 持有锁读取文件（不存在时为 None），用 `modify` 的返回值替换整个文件。
+/// 读取和写回之间其他进程的写入会等待锁，不会被覆盖丢失
+pub(crate) fn update(
+    path: &Path,
+    modify: impl FnOnce(Option<&str>) -> Result<String>,
+) -> Result<()> {
+    let _guard = lock(path)?;
+    let current = match fs::read_to_string(path) {
+        Ok(current) => Some(current),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err).with_context(|| format!("Unable to read {}", path.display())),
+    };
+    let contents = modify(current.as_deref())?;
+    write_atomically(path, contents.as_bytes())
+}
+
+/// 先写入同目录的临时文件并刷到磁盘，再 rename 覆盖，中途崩溃也不会留下写了一半的文件。
+/// 调用方需持有锁
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let temp_path = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        file_name,
+        uuid::Uuid::new_v4().simple()
+    ));
+    // 临时文件创建时就只有所有者可读写，写入 API 密钥之后再放宽权限不会留下可读的窗口；
+    // 原文件存在时沿用它的权限
+    let permissions = fs::metadata(path).map(|metadata| metadata.permissions());
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let written = options
+        .open(&temp_path)
+        .and_then(|mut temp| {
+            temp.write_all(contents)?;
+            if let Ok(permissions) = permissions {
+                temp.set_permissions(permissions)?;
+            }
+            temp.sync_all()
+        })
+        .and_then(|()| fs::rename(&temp_path, path));
+    if let Err(err) = written {
+        let _ = fs::remove_file(&temp_path);
+        return Err(err).with_context(|| format!("Unable to write {}", path.display()));
+    }
+    Ok(())
+}
+
+/// 创建新文件并写入内容。文件已存在时不覆盖并返回 false，用于在多个进程之间选择不重复的文件名
+pub(crate) fn create_new(path: &Path, contents: impl AsRef<[u8]>) -> Result<bool> {
+    let mut file = match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => return Ok(false),
+        Err(err) => {
+            return Err(err).with_context(|| format!("Unable to create {}", path.display()))
+        }
+    };
+    file.write_all(contents.as_ref())
+        .with_context(|| format!("Unable to write {}", path.display()))?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    /// 每个测试使用单独的临时目录，结束时删除
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            let dir = std::env::temp_dir()
+                .join(format!("askai-storage-{}", uuid::Uuid::new_v4().simple()));
+            fs::create_dir_all(&dir).unwrap();
+            TempDir(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// 同一进程中的线程各自打开锁文件，flock 同样互斥；测试中锁等待超时后重试
+    fn retry<T>(mut operation: impl FnMut() -> Result<T>) -> T {
+        loop {
+            match operation() {
+                Ok(value) => return value,
+                Err(err) if err.downcast_ref::<Contended>().is_some() => continue,
+                Err(err) => panic!("{:#}", err),
+            }
+        }
+    }
+
+    fn run_threads(threads: usize, work: impl Fn(usize) + Send + Sync + 'static) {
+        let work = Arc::new(work);
+        let handles: Vec<_> = (0..threads)
+            .map(|thread| {
+                let work = Arc::clone(&work);
+                std::thread::spawn(move || work(thread))
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn concurrent_appends_keep_every_line_whole() {
+        let dir = TempDir::new();
+        let path = dir.0.join("history.jsonl");
+        let shared = path.clone();
+        run_threads(8, move |thread| {
+            for index in 0..50 {
+                let line = format!("{}-{}-{}", thread, index, "x".repeat(200));
+                retry(|| append_line(&shared, &line));
+            }
+        });
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.lines().collect();
+        assert_eq!(lines.len(), 400);
+        assert!(lines.iter().all(|line| line.ends_with(&"x".repeat(200))));
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = TempDir::new();
+        let path = dir.0.join("counter");
+        let shared = path.clone();
+        run_threads(8, move |_| {
+            for _ in 0..20 {
+                retry(|| {
+                    update(&shared, |current| {
+                        let count: u32 = current.map_or(0, |value| value.trim().parse().unwrap());
+                        Ok((count + 1).to_string())
+                    })
+                });
+            }
+        });
+        assert_eq!(fs::read_to_string(&path).unwrap(), "160");
+    }
+
+    #[test]
+    fn appends_during_updates_are_not_lost() {
+        let dir = TempDir::new();
+        let path = dir.0.join("history.jsonl");
+        let shared = path.clone();
+        run_threads(6, move |thread| {
+            for index in 0..20 {
+                if thread % 2 == 0 {
+                    retry(|| append_line(&shared, &format!("append {}-{}", thread, index)));
+                } else {
+                    // 原样保留已有的行并追加一行，模拟 clear-history 之类的读取、修改、写回
+                    retry(|| {
+                        update(&shared, |current| {
+                            Ok(format!(
+                                "{}update {}-{}\n",
+                                current.unwrap_or_default(),
+                                thread,
+                                index
+                            ))
+                        })
+                    });
+                }
+            }
+        });
+        let content = fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 120);
+        assert_eq!(
+            content
+                .lines()
+                .filter(|line| line.starts_with("append"))
+                .count(),
+            60
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replaced_files_are_private_unless_the_original_was_not() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new();
+        let path = dir.0.join("config.toml");
+        replace(&path, "api_key = \"secret\"").unwrap();
+        let mode = |path: &Path| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&path), 0o600);
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        replace(&path, "language = \"en\"").unwrap();
+        assert_eq!(mode(&path), 0o644);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "language = \"en\"");
+        // 只剩数据文件和锁文件，没有残留的临时文件
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 2);
+    }
+}